@@ -1,11 +1,12 @@
-//! CPU backend for CrossGPU - SIMD/BLAS fallback implementation
+//! CPU backend for CrossGPU - reference implementation built on `crossgpu_core::ops`
 
 #![deny(warnings)]
 #![deny(missing_docs)]
 
 use crossgpu_core::{
     error::{CoreError, Result},
-    gpu::{GpuDevice, GpuTensor, Kernel, KernelType},
+    gpu::{GpuDevice, GpuTensor, Kernel},
+    ops,
     tensor::Tensor,
 };
 use std::sync::Arc;
@@ -40,33 +41,27 @@ impl GpuDevice for CpuDevice {
     }
 
     fn run_kernel(&self, kernel: Kernel, inputs: &[GpuTensor]) -> Result<GpuTensor> {
-        // Placeholder: Implement CPU kernels using ndarray/SIMD
-        log::info!("Running {:?} kernel on CPU", kernel.kernel_type);
+        log::debug!("Running {:?} kernel on CPU", kernel.kernel_type);
 
         if inputs.is_empty() {
             return Err(CoreError::GpuError("No input tensors".to_string()));
         }
 
-        match kernel.kernel_type {
-            KernelType::MatMul => self.run_matmul(inputs),
-            KernelType::LayerNorm => self.run_layer_norm(inputs, &kernel.params),
-            KernelType::Softmax => self.run_softmax(inputs),
-            KernelType::Gelu => self.run_gelu(inputs),
-            KernelType::FusedGemmGelu => self.run_fused_gemm_gelu(inputs),
-            KernelType::FusedGemmLayerNorm => {
-                self.run_fused_gemm_layer_norm(inputs, &kernel.params)
-            }
-            KernelType::Attention => self.run_attention(inputs),
-        }
+        let inputs = inputs
+            .iter()
+            .map(|input| self.tensor_ref(input))
+            .collect::<Result<Vec<_>>>()?;
+
+        let output = ops::run_reference_kernel(&kernel, &inputs)?;
+        Ok(GpuTensor {
+            shape: output.shape.clone(),
+            handle: Arc::new(output),
+        })
     }
 
     fn download_tensor(&self, gpu_tensor: &GpuTensor) -> Result<Tensor> {
         // For CPU backend, "download" just extracts the tensor
-        let tensor = gpu_tensor
-            .handle
-            .downcast_ref::<Tensor>()
-            .ok_or_else(|| CoreError::GpuError("Invalid tensor handle".to_string()))?;
-        Ok(tensor.clone())
+        self.tensor_ref(gpu_tensor).cloned()
     }
 
     fn synchronize(&self) -> Result<()> {
@@ -84,56 +79,30 @@ impl GpuDevice for CpuDevice {
 }
 
 impl CpuDevice {
-    fn run_matmul(&self, inputs: &[GpuTensor]) -> Result<GpuTensor> {
-        // Placeholder: Implement matrix multiplication using ndarray
-        log::debug!("MatMul on CPU");
-        Ok(inputs[0].clone())
-    }
-
-    fn run_layer_norm(&self, inputs: &[GpuTensor], params: &[f32]) -> Result<GpuTensor> {
-        // Placeholder: Implement layer normalization
-        log::debug!("LayerNorm on CPU with epsilon: {:?}", params.first());
-        Ok(inputs[0].clone())
-    }
-
-    fn run_softmax(&self, inputs: &[GpuTensor]) -> Result<GpuTensor> {
-        // Placeholder: Implement softmax
-        log::debug!("Softmax on CPU");
-        Ok(inputs[0].clone())
-    }
-
-    fn run_gelu(&self, inputs: &[GpuTensor]) -> Result<GpuTensor> {
-        // Placeholder: Implement GELU activation
-        log::debug!("GELU on CPU");
-        Ok(inputs[0].clone())
-    }
-
-    fn run_fused_gemm_gelu(&self, inputs: &[GpuTensor]) -> Result<GpuTensor> {
-        // Placeholder: Implement fused GEMM + GELU
-        log::debug!("Fused GEMM+GELU on CPU");
-        Ok(inputs[0].clone())
-    }
-
-    fn run_fused_gemm_layer_norm(&self, inputs: &[GpuTensor], params: &[f32]) -> Result<GpuTensor> {
-        // Placeholder: Implement fused GEMM + LayerNorm
-        log::debug!(
-            "Fused GEMM+LayerNorm on CPU with epsilon: {:?}",
-            params.first()
-        );
-        Ok(inputs[0].clone())
-    }
-
-    fn run_attention(&self, inputs: &[GpuTensor]) -> Result<GpuTensor> {
-        // Placeholder: Implement multi-head attention
-        log::debug!("Attention on CPU");
-        Ok(inputs[0].clone())
+    fn tensor_ref<'a>(&self, gpu_tensor: &'a GpuTensor) -> Result<&'a Tensor> {
+        gpu_tensor
+            .handle
+            .downcast_ref::<Tensor>()
+            .ok_or_else(|| CoreError::GpuError("Invalid tensor handle".to_string()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crossgpu_core::batch::{PaddedBatch, PaddingSide};
+    use crossgpu_core::classification::{ClassificationHead, SequenceClassifier};
+    use crossgpu_core::embeddings::Pooling;
+    use crossgpu_core::gpu::KernelType;
     use crossgpu_core::tensor::DType;
+    use crossgpu_core::test_utils::{self, assert_close, random_model, random_tensor};
+    use crossgpu_core::transformer::{
+        AttentionMode, BlockTopology, FeedForwardType, MoeConfig, NormType, PositionEncoding,
+        RopeScaling, TransformerConfig, TransformerModel, VisionConfig,
+    };
+    use crossgpu_core::vision::{
+        image_from_rgb8, normalize_image, PatchEmbedding, IMAGENET_MEAN, IMAGENET_STD,
+    };
 
     #[test]
     fn test_cpu_device_creation() {
@@ -153,4 +122,401 @@ mod tests {
         let downloaded = device.download_tensor(&gpu_tensor).unwrap();
         assert_eq!(downloaded.shape, tensor.shape);
     }
+
+    /// The core test configuration with a longer vocabulary and a shorter context
+    fn small_config() -> TransformerConfig {
+        TransformerConfig {
+            vocab_size: 13,
+            max_seq_len: 8,
+            ..test_utils::small_config()
+        }
+    }
+
+    fn small_model() -> TransformerModel {
+        random_model(small_config(), 3)
+    }
+
+    /// Model variants whose every decoding path must match the CPU reference
+    fn parity_models() -> Vec<(&'static str, TransformerModel)> {
+        let variant = |config: TransformerConfig| random_model(config, 3);
+        let mut biased = small_model();
+        let (d, d_ff) = (biased.config.d_model, biased.config.d_ff);
+        for (seed, layer) in (10..).step_by(10).zip(&mut biased.layers) {
+            let attention = &mut layer.attention;
+            attention.bq = Some(random_tensor(vec![d], seed + 1));
+            attention.bk = Some(random_tensor(vec![d], seed + 2));
+            attention.bv = Some(random_tensor(vec![d], seed + 3));
+            attention.bo = Some(random_tensor(vec![d], seed + 4));
+            let feed_forward = layer.feed_forward.dense_mut().unwrap();
+            feed_forward.b1 = Some(random_tensor(vec![d_ff], seed + 5));
+            feed_forward.b2 = Some(random_tensor(vec![d], seed + 6));
+        }
+
+        vec![
+            ("learned positions", small_model()),
+            (
+                "scaled rope",
+                variant(TransformerConfig {
+                    position_encoding: PositionEncoding::Rope {
+                        base: 10000.0,
+                        scaling: Some(RopeScaling::Linear { factor: 4.0 }),
+                    },
+                    ..small_config()
+                }),
+            ),
+            (
+                "alibi",
+                variant(TransformerConfig {
+                    position_encoding: PositionEncoding::Alibi,
+                    ..small_config()
+                }),
+            ),
+            (
+                "llama style",
+                variant(TransformerConfig {
+                    position_encoding: PositionEncoding::rope(10000.0),
+                    norm: NormType::RmsNorm,
+                    feed_forward: FeedForwardType::SwiGlu,
+                    ..small_config()
+                }),
+            ),
+            ("projection biases", biased),
+            (
+                "post-ln",
+                variant(TransformerConfig {
+                    block: BlockTopology::PostLn,
+                    ..small_config()
+                }),
+            ),
+            (
+                "parallel",
+                variant(TransformerConfig {
+                    block: BlockTopology::Parallel,
+                    ..small_config()
+                }),
+            ),
+            (
+                "grouped query",
+                variant(TransformerConfig {
+                    n_kv_heads: Some(1),
+                    ..small_config()
+                }),
+            ),
+            (
+                "sliding window",
+                variant(TransformerConfig {
+                    attention_windows: vec![Some(2)],
+                    ..small_config()
+                }),
+            ),
+            (
+                "mixture of experts",
+                variant(TransformerConfig {
+                    moe: Some(MoeConfig {
+                        n_experts: 3,
+                        top_k: 2,
+                    }),
+                    ..small_config()
+                }),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_decoding_paths_match_cpu_reference() {
+        let device: Arc<dyn GpuDevice> = Arc::new(CpuDevice::new());
+        let tokens = vec![vec![1, 5, 7, 2], vec![12, 3, 3, 9]];
+        let batch =
+            PaddedBatch::new(&[vec![1, 5, 7, 2], vec![12, 3]], 0, PaddingSide::Left).unwrap();
+        let steps = [
+            vec![vec![3, 1, 4], vec![2]],
+            vec![vec![1], vec![6, 7, 8]],
+            vec![vec![5], vec![9]],
+        ];
+
+        for (name, model) in parity_models() {
+            // Runs on the device, then on the CPU reference, comparing logits and routing
+            let check = |path: &str, run: &mut dyn FnMut(bool) -> Tensor| {
+                model.reset_router_stats();
+                let actual = run(true);
+                let load = model.router_stats();
+                model.reset_router_stats();
+                let expected = run(false);
+                assert_eq!(actual.shape, expected.shape, "{} {}", name, path);
+                assert_close(
+                    actual.as_f32_slice().unwrap(),
+                    expected.as_f32_slice().unwrap(),
+                    1e-5,
+                );
+                for ((_, a), (_, b)) in load.iter().zip(&model.router_stats()) {
+                    assert_eq!(a.tokens_per_expert(), b.tokens_per_expert());
+                }
+            };
+
+            check("forward", &mut |on_device| {
+                if on_device {
+                    model.forward(&tokens, &device).unwrap()
+                } else {
+                    model.forward_cpu(&tokens).unwrap()
+                }
+            });
+            check("padded batch", &mut |on_device| {
+                if on_device {
+                    model.forward_batch(&batch, &device).unwrap()
+                } else {
+                    model.forward_batch_cpu(&batch).unwrap()
+                }
+            });
+
+            let (mut cache, mut reference) = (model.new_kv_cache(), model.new_kv_cache());
+            for step in &steps {
+                check("cached", &mut |on_device| {
+                    if on_device {
+                        model
+                            .forward_with_cache(&step[0], &mut cache, &device)
+                            .unwrap()
+                    } else {
+                        model
+                            .forward_cpu_with_cache(&step[0], &mut reference)
+                            .unwrap()
+                    }
+                });
+            }
+
+            let (mut a, mut b) = (model.new_kv_cache(), model.new_kv_cache());
+            let (mut ref_a, mut ref_b) = (model.new_kv_cache(), model.new_kv_cache());
+            for step in &steps {
+                check("batched caches", &mut |on_device| {
+                    if on_device {
+                        model
+                            .forward_with_caches(step, &mut [&mut a, &mut b], &device)
+                            .unwrap()
+                    } else {
+                        model
+                            .forward_cpu_with_caches(step, &mut [&mut ref_a, &mut ref_b])
+                            .unwrap()
+                    }
+                });
+            }
+            assert_eq!((a.len(), b.len()), (5, 5));
+
+            // Paged caches do not support sliding windows
+            if model.config.attention_windows.iter().any(Option::is_some) {
+                continue;
+            }
+            let mut cache = model.new_paged_kv_cache(2, 8).unwrap();
+            let mut reference = model.new_paged_kv_cache(2, 8).unwrap();
+            let (mut a, mut b) = (cache.new_sequence(), cache.new_sequence());
+            let (mut ref_a, mut ref_b) = (reference.new_sequence(), reference.new_sequence());
+            for step in &steps {
+                check("paged", &mut |on_device| {
+                    if on_device {
+                        model
+                            .forward_paged(step, &mut [&mut a, &mut b], &mut cache, &device)
+                            .unwrap()
+                    } else {
+                        model
+                            .forward_cpu_paged(step, &mut [&mut ref_a, &mut ref_b], &mut reference)
+                            .unwrap()
+                    }
+                });
+            }
+            assert_eq!(cache.allocator().num_free(), 2);
+            assert_eq!(cache.pools(0).0.shape[2], model.config.kv_width());
+        }
+    }
+
+    #[test]
+    fn test_encoder_decoder_matches_cpu_reference() {
+        let config = TransformerConfig {
+            n_encoder_layers: 2,
+            ..small_config()
+        };
+        let model = random_model(config, 3);
+        let device: Arc<dyn GpuDevice> = Arc::new(CpuDevice::new());
+
+        let (mut a, mut b) = (
+            model.encode(&[3, 1, 4, 1, 5], &device).unwrap(),
+            model.encode(&[9, 2], &device).unwrap(),
+        );
+        let (mut ref_a, mut ref_b) = (
+            model.encode_cpu(&[3, 1, 4, 1, 5]).unwrap(),
+            model.encode_cpu(&[9, 2]).unwrap(),
+        );
+        for tokens in [vec![vec![0, 7], vec![0]], vec![vec![6], vec![8, 2]]] {
+            let logits = model
+                .forward_with_caches(&tokens, &mut [&mut a, &mut b], &device)
+                .unwrap();
+            let expected = model
+                .forward_cpu_with_caches(&tokens, &mut [&mut ref_a, &mut ref_b])
+                .unwrap();
+            assert_close(
+                logits.as_f32_slice().unwrap(),
+                expected.as_f32_slice().unwrap(),
+                1e-5,
+            );
+        }
+    }
+
+    #[test]
+    fn test_sentence_embeddings_match_cpu_reference() {
+        let mut model = small_model();
+        model.config.attention_mode = AttentionMode::Bidirectional;
+        let device: Arc<dyn GpuDevice> = Arc::new(CpuDevice::new());
+        let batch = vec![vec![3, 1, 4, 1, 5], vec![9, 2]];
+        for pooling in [Pooling::Cls, Pooling::Mean, Pooling::Max] {
+            let pooled = model
+                .sentence_embeddings(&batch, pooling, true, &device)
+                .unwrap();
+            let expected = model
+                .sentence_embeddings_cpu(&batch, pooling, true)
+                .unwrap();
+            assert_eq!(pooled.shape, vec![2, 8]);
+            assert_close(
+                pooled.as_f32_slice().unwrap(),
+                expected.as_f32_slice().unwrap(),
+                1e-5,
+            );
+        }
+    }
+
+    #[test]
+    fn test_classification_heads_match_cpu_reference() {
+        let model = small_model()
+            .with_sequence_classifier(SequenceClassifier {
+                pooling: Pooling::Cls,
+                head: ClassificationHead {
+                    weight: random_tensor(vec![8, 3], 61),
+                    bias: None,
+                    labels: vec!["neg".into(), "neu".into(), "pos".into()],
+                },
+            })
+            .with_token_classifier(ClassificationHead {
+                weight: random_tensor(vec![8, 2], 62),
+                bias: Some(random_tensor(vec![2], 63)),
+                labels: vec!["O".into(), "ENT".into()],
+            });
+        let device: Arc<dyn GpuDevice> = Arc::new(CpuDevice::new());
+        let batch = vec![vec![3, 1, 4, 1, 5], vec![9, 2]];
+
+        let sequences = model.classify_sequences(&batch, &device).unwrap();
+        for (a, b) in sequences
+            .iter()
+            .zip(&model.classify_sequences_cpu(&batch).unwrap())
+        {
+            assert_eq!(a.label, b.label);
+            assert!(
+                (a.score - b.score).abs() < 1e-5,
+                "{} != {}",
+                a.score,
+                b.score
+            );
+        }
+        let tokens = model.classify_tokens(&batch, &device).unwrap();
+        let expected = model.classify_tokens_cpu(&batch).unwrap();
+        assert_eq!((tokens[0].len(), tokens[1].len()), (5, 2));
+        for (a, b) in tokens.iter().flatten().zip(expected.iter().flatten()) {
+            assert_eq!(a.label, b.label);
+            assert!(
+                (a.score - b.score).abs() < 1e-5,
+                "{} != {}",
+                a.score,
+                b.score
+            );
+        }
+    }
+
+    #[test]
+    fn test_vision_transformer_matches_cpu_reference() {
+        let vision = VisionConfig {
+            image_size: 4,
+            patch_size: 2,
+            channels: 3,
+        };
+        let mut model = small_model()
+            .with_patch_embedding(PatchEmbedding {
+                projection: random_tensor(vec![vision.patch_dim(), 8], 71),
+                bias: Some(random_tensor(vec![8], 72)),
+                cls_token: random_tensor(vec![8], 73),
+            })
+            .with_sequence_classifier(SequenceClassifier {
+                pooling: Pooling::Cls,
+                head: ClassificationHead {
+                    weight: random_tensor(vec![8, 2], 74),
+                    bias: Some(random_tensor(vec![2], 75)),
+                    labels: vec!["cat".into(), "dog".into()],
+                },
+            });
+        model.config.vision = Some(vision);
+        model.config.attention_mode = AttentionMode::Bidirectional;
+        let device: Arc<dyn GpuDevice> = Arc::new(CpuDevice::new());
+        let pixels: Vec<u8> = (0..48).map(|i| (i * 37 % 256) as u8).collect();
+        let image = image_from_rgb8(&pixels, 4, 4).unwrap();
+        let images = vec![
+            normalize_image(&image, &IMAGENET_MEAN, &IMAGENET_STD).unwrap(),
+            random_tensor(vec![3, 4, 4], 76),
+        ];
+
+        let output = model
+            .image_embeddings(&images, Pooling::Mean, true, &device)
+            .unwrap();
+        let expected = model
+            .image_embeddings_cpu(&images, Pooling::Mean, true)
+            .unwrap();
+        assert_eq!(output.shape, vec![2, 8]);
+        assert_close(
+            output.as_f32_slice().unwrap(),
+            expected.as_f32_slice().unwrap(),
+            1e-5,
+        );
+        for (a, b) in model
+            .classify_images(&images, &device)
+            .unwrap()
+            .iter()
+            .zip(&model.classify_images_cpu(&images).unwrap())
+        {
+            assert_eq!(a.label, b.label);
+            assert!(
+                (a.score - b.score).abs() < 1e-5,
+                "{} != {}",
+                a.score,
+                b.score
+            );
+        }
+    }
+
+    #[test]
+    fn test_resumed_session_matches_uninterrupted_generation() {
+        use crossgpu_core::generation::{GenerationConfig, GenerationSession, Generator};
+
+        let model = small_model();
+        let device: Arc<dyn GpuDevice> = Arc::new(CpuDevice::new());
+        let config = GenerationConfig {
+            max_new_tokens: 5,
+            seed: 7,
+            ..GenerationConfig::default()
+        };
+        let generator = Generator::new(&model, device.clone(), config);
+        let expected = generator.generate(&[1, 2]).unwrap();
+
+        let mut stream = generator.stream(&[1, 2]).unwrap();
+        let mut tokens: Vec<u32> = stream.by_ref().take(2).map(|e| e.unwrap().token).collect();
+        let bytes = stream.session().to_bytes().unwrap();
+        let session = GenerationSession::from_bytes(&bytes).unwrap();
+        tokens.extend(generator.resume(session).unwrap().map(|e| e.unwrap().token));
+        assert_eq!(tokens, expected.tokens);
+    }
+
+    #[test]
+    fn test_kernel_input_validation() {
+        let device = CpuDevice::new();
+        let a = device
+            .upload_tensor(&Tensor::new(vec![2, 3], DType::F32))
+            .unwrap();
+        let kernel = Kernel::new(KernelType::MatMul);
+        assert!(device
+            .run_kernel(kernel.clone(), std::slice::from_ref(&a))
+            .is_err());
+        assert!(device.run_kernel(kernel, &[a.clone(), a]).is_err());
+    }
 }
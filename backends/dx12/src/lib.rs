@@ -102,16 +102,76 @@ impl GpuDevice for Dx12Device {
 /// HLSL shader templates for DirectX 12
 pub mod shaders {
     /// Matrix multiplication compute shader
+    ///
+    /// One thread per element of the `[M, N]` output, with the leading dimensions of `InputA`
+    /// folded into `M`. `Bias` is the `[N]` bias, read only when `HasBias` is set. A non-zero
+    /// `TransposeB` reads `InputB` as `[N, K]`.
     pub const MATMUL_SHADER: &str = r#"
-        RWStructuredBuffer<float> InputA : register(u0);
-        RWStructuredBuffer<float> InputB : register(u1);
-        RWStructuredBuffer<float> Output : register(u2);
-        
+        cbuffer MatMulParams : register(b0)
+        {
+            uint M;
+            uint K;
+            uint N;
+            uint HasBias;
+            uint TransposeB;
+        };
+
+        StructuredBuffer<float> InputA : register(t0);
+        StructuredBuffer<float> InputB : register(t1);
+        StructuredBuffer<float> Bias : register(t2);
+        RWStructuredBuffer<float> Output : register(u0);
+
         [numthreads(8, 8, 1)]
         void CSMain(uint3 DTid : SV_DispatchThreadID)
         {
-            uint index = DTid.y * 256 + DTid.x;
-            Output[index] = InputA[index] + InputB[index];
+            uint row = DTid.y;
+            uint col = DTid.x;
+            if (row >= M || col >= N)
+                return;
+            float sum = 0.0;
+            for (uint i = 0; i < K; i++)
+            {
+                uint bIndex = TransposeB != 0 ? col * K + i : i * N + col;
+                sum += InputA[row * K + i] * InputB[bIndex];
+            }
+            if (HasBias != 0)
+                sum += Bias[col];
+            Output[row * N + col] = sum;
+        }
+    "#;
+
+    /// Fused GEMM + GELU compute shader
+    ///
+    /// Computes `gelu(InputA @ InputB + Bias)` with the same bindings as [`MATMUL_SHADER`];
+    /// `TransposeB` is ignored.
+    pub const FUSED_GEMM_GELU_SHADER: &str = r#"
+        cbuffer MatMulParams : register(b0)
+        {
+            uint M;
+            uint K;
+            uint N;
+            uint HasBias;
+            uint TransposeB;
+        };
+
+        StructuredBuffer<float> InputA : register(t0);
+        StructuredBuffer<float> InputB : register(t1);
+        StructuredBuffer<float> Bias : register(t2);
+        RWStructuredBuffer<float> Output : register(u0);
+
+        [numthreads(8, 8, 1)]
+        void CSMain(uint3 DTid : SV_DispatchThreadID)
+        {
+            uint row = DTid.y;
+            uint col = DTid.x;
+            if (row >= M || col >= N)
+                return;
+            float x = 0.0;
+            for (uint i = 0; i < K; i++)
+                x += InputA[row * K + i] * InputB[i * N + col];
+            if (HasBias != 0)
+                x += Bias[col];
+            Output[row * N + col] = x * 0.5 * (1.0 + tanh(0.797885 * (x + 0.044715 * x * x * x)));
         }
     "#;
 
@@ -127,6 +187,179 @@ pub mod shaders {
             Output[DTid.x] = x * 0.5 * (1.0 + tanh(0.797885 * (x + 0.044715 * x * x * x)));
         }
     "#;
+
+    /// SiLU gating compute shader (SwiGLU)
+    ///
+    /// Computes `silu(Gate) * Up` elementwise; bind ones as `Up` for a plain SiLU.
+    pub const SILU_SHADER: &str = r#"
+        StructuredBuffer<float> Gate : register(t0);
+        StructuredBuffer<float> Up : register(t1);
+        RWStructuredBuffer<float> Output : register(u0);
+
+        [numthreads(256, 1, 1)]
+        void CSMain(uint3 DTid : SV_DispatchThreadID)
+        {
+            float x = Gate[DTid.x];
+            Output[DTid.x] = x / (1.0 + exp(-x)) * Up[DTid.x];
+        }
+    "#;
+
+    /// RMS normalization compute shader
+    ///
+    /// One thread per row of `Dim` values; there is no mean subtraction and no bias.
+    pub const RMS_NORM_SHADER: &str = r#"
+        cbuffer RmsNormParams : register(b0)
+        {
+            uint Rows;
+            uint Dim;
+            float Eps;
+        };
+
+        StructuredBuffer<float> Input : register(t0);
+        StructuredBuffer<float> Gamma : register(t1);
+        RWStructuredBuffer<float> Output : register(u0);
+
+        [numthreads(64, 1, 1)]
+        void CSMain(uint3 DTid : SV_DispatchThreadID)
+        {
+            uint row = DTid.x;
+            if (row >= Rows)
+                return;
+            uint base = row * Dim;
+            float sumSq = 0.0;
+            for (uint c = 0; c < Dim; c++)
+                sumSq += Input[base + c] * Input[base + c];
+            float invRms = rsqrt(sumSq / (float)Dim + Eps);
+            for (uint c = 0; c < Dim; c++)
+                Output[base + c] = Input[base + c] * invRms * Gamma[c];
+        }
+    "#;
+
+    /// Multi-head attention compute shader with causal and padding masks
+    ///
+    /// One thread per `(batch, head, query)` row, using an online softmax. `Mask` is the
+    /// `[batch, seq_k]` padding mask, read only when `HasMask` is set. A non-zero `Window`
+    /// skips keys that far or farther from the query.
+    pub const ATTENTION_SHADER: &str = r#"
+        cbuffer AttentionParams : register(b0)
+        {
+            uint Batch;
+            uint SeqQ;
+            uint SeqK;
+            uint DModel;
+            uint NHeads;
+            uint Causal;
+            uint HasMask;
+            uint Alibi;
+            uint NKvHeads;
+            uint Window;
+        };
+
+        StructuredBuffer<float> Q : register(t0);
+        StructuredBuffer<float> K : register(t1);
+        StructuredBuffer<float> V : register(t2);
+        StructuredBuffer<float> Mask : register(t3);
+        RWStructuredBuffer<float> Output : register(u0);
+
+        [numthreads(64, 1, 1)]
+        void CSMain(uint3 DTid : SV_DispatchThreadID)
+        {
+            uint row = DTid.x;
+            if (row >= Batch * NHeads * SeqQ)
+                return;
+            uint i = row % SeqQ;
+            uint h = (row / SeqQ) % NHeads;
+            uint b = row / (SeqQ * NHeads);
+            uint headDim = DModel / NHeads;
+            float scale = rsqrt((float)headDim);
+            uint offset = SeqK - SeqQ;
+            uint qBase = (b * SeqQ + i) * DModel + h * headDim;
+            // Grouped-query attention: consecutive query heads share one key/value head
+            uint kvD = NKvHeads * headDim;
+            uint kvH = h / (NHeads / NKvHeads);
+            // ALiBi slopes: geometric over the nearest lower power of two, then every other
+            // slope of the next power of two
+            uint lower = 1u << firstbithigh(NHeads);
+            float slope = h < lower ? exp2(-8.0 * (float)(h + 1) / (float)lower)
+                                    : exp2(-4.0 * (float)(2 * (h - lower) + 1) / (float)lower);
+            if (Alibi == 0)
+                slope = 0.0;
+            uint pos = i + offset;
+
+            for (uint c = 0; c < headDim; c++)
+                Output[qBase + c] = 0.0;
+            float runningMax = -3.4e38;
+            float denom = 0.0;
+            for (uint j = 0; j < SeqK; j++)
+            {
+                if (Causal != 0 && j > i + offset)
+                    break;
+                if (HasMask != 0 && Mask[b * SeqK + j] == 0.0)
+                    continue;
+                // Sliding window: 0 means unlimited
+                if (Window != 0 && max(pos, j) - min(pos, j) >= Window)
+                    continue;
+                uint kBase = (b * SeqK + j) * kvD + kvH * headDim;
+                float score = 0.0;
+                for (uint c = 0; c < headDim; c++)
+                    score += Q[qBase + c] * K[kBase + c];
+                score = score * scale - slope * (float)(max(pos, j) - min(pos, j));
+                float newMax = max(runningMax, score);
+                float correction = exp(runningMax - newMax);
+                float weight = exp(score - newMax);
+                denom = denom * correction + weight;
+                for (uint c = 0; c < headDim; c++)
+                    Output[qBase + c] = Output[qBase + c] * correction + weight * V[kBase + c];
+                runningMax = newMax;
+            }
+            if (denom > 0.0)
+            {
+                for (uint c = 0; c < headDim; c++)
+                    Output[qBase + c] /= denom;
+            }
+        }
+    "#;
+
+    /// Rotary position embedding compute shader
+    ///
+    /// One thread per `(row, head, pair)`, rotating dimension `i` of a head together with
+    /// `i + head_dim / 2`. `Positions` holds one position per row.
+    pub const ROPE_SHADER: &str = r#"
+        cbuffer RopeParams : register(b0)
+        {
+            uint Rows;
+            uint DModel;
+            uint NHeads;
+            float Base;
+            float PositionScale;
+        };
+
+        StructuredBuffer<float> Input : register(t0);
+        StructuredBuffer<float> Positions : register(t1);
+        RWStructuredBuffer<float> Output : register(u0);
+
+        [numthreads(64, 1, 1)]
+        void CSMain(uint3 DTid : SV_DispatchThreadID)
+        {
+            uint headDim = DModel / NHeads;
+            uint halfDim = headDim / 2;
+            uint id = DTid.x;
+            if (id >= Rows * NHeads * halfDim)
+                return;
+            uint i = id % halfDim;
+            uint h = (id / halfDim) % NHeads;
+            uint row = id / (halfDim * NHeads);
+            float freq = pow(Base, -2.0 * (float)i / (float)headDim);
+            float angle = Positions[row] * PositionScale * freq;
+            uint rowBase = row * DModel + h * headDim;
+            float x1 = Input[rowBase + i];
+            float x2 = Input[rowBase + i + halfDim];
+            float s, c;
+            sincos(angle, s, c);
+            Output[rowBase + i] = x1 * c - x2 * s;
+            Output[rowBase + i + halfDim] = x2 * c + x1 * s;
+        }
+    "#;
 }
 
 #[cfg(test)]
@@ -102,19 +102,85 @@ impl GpuDevice for MetalDevice {
 /// Metal Shading Language (MSL) shader templates
 pub mod shaders {
     /// Matrix multiplication kernel
+    ///
+    /// One thread per element of the `[m, n]` output, with the leading dimensions of `a`
+    /// folded into `m`. Buffer 2 is the `[n]` bias, read only when `has_bias` is set. A
+    /// non-zero `transpose_b` reads `b` as `[n, k]`.
     pub const MATMUL_KERNEL: &str = r#"
         #include <metal_stdlib>
         using namespace metal;
-        
+
+        struct MatMulParams {
+            uint m;
+            uint k;
+            uint n;
+            uint has_bias;
+            uint transpose_b;
+        };
+
         kernel void matmul(
             device const float* input_a [[buffer(0)]],
             device const float* input_b [[buffer(1)]],
-            device float* output [[buffer(2)]],
+            device const float* bias [[buffer(2)]],
+            device float* output [[buffer(3)]],
+            constant MatMulParams& params [[buffer(4)]],
             uint2 gid [[thread_position_in_grid]]
         ) {
-            // Placeholder: Implement matrix multiplication
-            uint index = gid.y * 256 + gid.x;
-            output[index] = input_a[index] + input_b[index];
+            uint row = gid.y;
+            uint col = gid.x;
+            if (row >= params.m || col >= params.n) {
+                return;
+            }
+            float sum = 0.0;
+            for (uint i = 0; i < params.k; i++) {
+                uint b_index = params.transpose_b != 0 ? col * params.k + i : i * params.n + col;
+                sum += input_a[row * params.k + i] * input_b[b_index];
+            }
+            if (params.has_bias != 0) {
+                sum += bias[col];
+            }
+            output[row * params.n + col] = sum;
+        }
+    "#;
+
+    /// Fused GEMM + GELU kernel
+    ///
+    /// Computes `gelu(a @ b + bias)` with the same buffers as [`MATMUL_KERNEL`];
+    /// `transpose_b` is ignored.
+    pub const FUSED_GEMM_GELU_KERNEL: &str = r#"
+        #include <metal_stdlib>
+        using namespace metal;
+
+        struct MatMulParams {
+            uint m;
+            uint k;
+            uint n;
+            uint has_bias;
+            uint transpose_b;
+        };
+
+        kernel void fused_gemm_gelu(
+            device const float* input_a [[buffer(0)]],
+            device const float* input_b [[buffer(1)]],
+            device const float* bias [[buffer(2)]],
+            device float* output [[buffer(3)]],
+            constant MatMulParams& params [[buffer(4)]],
+            uint2 gid [[thread_position_in_grid]]
+        ) {
+            uint row = gid.y;
+            uint col = gid.x;
+            if (row >= params.m || col >= params.n) {
+                return;
+            }
+            float x = 0.0;
+            for (uint i = 0; i < params.k; i++) {
+                x += input_a[row * params.k + i] * input_b[i * params.n + col];
+            }
+            if (params.has_bias != 0) {
+                x += bias[col];
+            }
+            output[row * params.n + col] =
+                x * 0.5 * (1.0 + tanh(0.797885 * (x + 0.044715 * x * x * x)));
         }
     "#;
 
@@ -132,6 +198,193 @@ pub mod shaders {
             output[gid] = x * 0.5 * (1.0 + tanh(0.797885 * (x + 0.044715 * x * x * x)));
         }
     "#;
+
+    /// SiLU gating kernel (SwiGLU)
+    ///
+    /// Computes `silu(gate) * up` elementwise; pass ones as `up` for a plain SiLU.
+    pub const SILU_KERNEL: &str = r#"
+        #include <metal_stdlib>
+        using namespace metal;
+
+        kernel void silu(
+            device const float* gate [[buffer(0)]],
+            device const float* up [[buffer(1)]],
+            device float* output [[buffer(2)]],
+            uint gid [[thread_position_in_grid]]
+        ) {
+            float x = gate[gid];
+            output[gid] = x / (1.0 + exp(-x)) * up[gid];
+        }
+    "#;
+
+    /// RMS normalization kernel
+    ///
+    /// One thread per row of `dim` values; there is no mean subtraction and no bias.
+    pub const RMS_NORM_KERNEL: &str = r#"
+        #include <metal_stdlib>
+        using namespace metal;
+
+        struct RmsNormParams {
+            uint rows;
+            uint dim;
+            float eps;
+        };
+
+        kernel void rms_norm(
+            device const float* input [[buffer(0)]],
+            device const float* gamma [[buffer(1)]],
+            device float* output [[buffer(2)]],
+            constant RmsNormParams& params [[buffer(3)]],
+            uint row [[thread_position_in_grid]]
+        ) {
+            if (row >= params.rows) {
+                return;
+            }
+            uint base = row * params.dim;
+            float sum_sq = 0.0;
+            for (uint c = 0; c < params.dim; c++) {
+                sum_sq += input[base + c] * input[base + c];
+            }
+            float inv_rms = rsqrt(sum_sq / float(params.dim) + params.eps);
+            for (uint c = 0; c < params.dim; c++) {
+                output[base + c] = input[base + c] * inv_rms * gamma[c];
+            }
+        }
+    "#;
+
+    /// Multi-head attention kernel with causal and padding masks
+    ///
+    /// One thread per `(batch, head, query)` row, using an online softmax. Buffer 3 is the
+    /// `[batch, seq_k]` padding mask, read only when `has_mask` is set. A non-zero `window`
+    /// skips keys that far or farther from the query.
+    pub const ATTENTION_KERNEL: &str = r#"
+        #include <metal_stdlib>
+        using namespace metal;
+
+        struct AttentionParams {
+            uint batch;
+            uint seq_q;
+            uint seq_k;
+            uint d_model;
+            uint n_heads;
+            uint causal;
+            uint has_mask;
+            uint alibi;
+            uint n_kv_heads;
+            uint window;
+        };
+
+        kernel void attention(
+            device const float* q [[buffer(0)]],
+            device const float* k [[buffer(1)]],
+            device const float* v [[buffer(2)]],
+            device const float* mask [[buffer(3)]],
+            device float* output [[buffer(4)]],
+            constant AttentionParams& params [[buffer(5)]],
+            uint row [[thread_position_in_grid]]
+        ) {
+            if (row >= params.batch * params.n_heads * params.seq_q) {
+                return;
+            }
+            uint i = row % params.seq_q;
+            uint h = (row / params.seq_q) % params.n_heads;
+            uint b = row / (params.seq_q * params.n_heads);
+            uint head_dim = params.d_model / params.n_heads;
+            float scale = rsqrt(float(head_dim));
+            uint offset = params.seq_k - params.seq_q;
+            uint q_base = (b * params.seq_q + i) * params.d_model + h * head_dim;
+            // Grouped-query attention: consecutive query heads share one key/value head
+            uint kv_d = params.n_kv_heads * head_dim;
+            uint kv_h = h / (params.n_heads / params.n_kv_heads);
+            // ALiBi slopes: geometric over the nearest lower power of two, then every other
+            // slope of the next power of two
+            uint lower = 1u << (31 - clz(params.n_heads));
+            float slope = h < lower ? exp2(-8.0 * float(h + 1) / float(lower))
+                                    : exp2(-4.0 * float(2 * (h - lower) + 1) / float(lower));
+            if (params.alibi == 0) {
+                slope = 0.0;
+            }
+            uint pos = i + offset;
+
+            for (uint c = 0; c < head_dim; c++) {
+                output[q_base + c] = 0.0;
+            }
+            float running_max = -INFINITY;
+            float denom = 0.0;
+            for (uint j = 0; j < params.seq_k; j++) {
+                if (params.causal != 0 && j > i + offset) {
+                    break;
+                }
+                if (params.has_mask != 0 && mask[b * params.seq_k + j] == 0.0) {
+                    continue;
+                }
+                // Sliding window: 0 means unlimited
+                if (params.window != 0 && max(pos, j) - min(pos, j) >= params.window) {
+                    continue;
+                }
+                uint k_base = (b * params.seq_k + j) * kv_d + kv_h * head_dim;
+                float score = 0.0;
+                for (uint c = 0; c < head_dim; c++) {
+                    score += q[q_base + c] * k[k_base + c];
+                }
+                score = score * scale - slope * float(max(pos, j) - min(pos, j));
+                float new_max = max(running_max, score);
+                float correction = exp(running_max - new_max);
+                float weight = exp(score - new_max);
+                denom = denom * correction + weight;
+                for (uint c = 0; c < head_dim; c++) {
+                    output[q_base + c] = output[q_base + c] * correction + weight * v[k_base + c];
+                }
+                running_max = new_max;
+            }
+            if (denom > 0.0) {
+                for (uint c = 0; c < head_dim; c++) {
+                    output[q_base + c] /= denom;
+                }
+            }
+        }
+    "#;
+
+    /// Rotary position embedding kernel
+    ///
+    /// One thread per `(row, head, pair)`, rotating dimension `i` of a head together with
+    /// `i + head_dim / 2`. Buffer 1 holds one position per row.
+    pub const ROPE_KERNEL: &str = r#"
+        #include <metal_stdlib>
+        using namespace metal;
+
+        struct RopeParams {
+            uint rows;
+            uint d_model;
+            uint n_heads;
+            float base;
+            float position_scale;
+        };
+
+        kernel void rope(
+            device const float* input [[buffer(0)]],
+            device const float* positions [[buffer(1)]],
+            device float* output [[buffer(2)]],
+            constant RopeParams& params [[buffer(3)]],
+            uint id [[thread_position_in_grid]]
+        ) {
+            uint head_dim = params.d_model / params.n_heads;
+            uint half_dim = head_dim / 2;
+            if (id >= params.rows * params.n_heads * half_dim) {
+                return;
+            }
+            uint i = id % half_dim;
+            uint h = (id / half_dim) % params.n_heads;
+            uint row = id / (half_dim * params.n_heads);
+            float freq = pow(params.base, -2.0 * float(i) / float(head_dim));
+            float angle = positions[row] * params.position_scale * freq;
+            uint base = row * params.d_model + h * head_dim;
+            float x1 = input[base + i];
+            float x2 = input[base + i + half_dim];
+            output[base + i] = x1 * cos(angle) - x2 * sin(angle);
+            output[base + i + half_dim] = x2 * cos(angle) + x1 * sin(angle);
+        }
+    "#;
 }
 
 #[cfg(test)]
@@ -99,6 +99,197 @@ impl GpuDevice for VulkanDevice {
     }
 }
 
+/// GLSL compute shader templates, compiled to SPIR-V for the Vulkan pipelines
+///
+/// Scalar parameters are passed as push constants; storage buffers are bound in set 0 in the
+/// order listed for each [`KernelType`](crossgpu_core::gpu::KernelType).
+pub mod shaders {
+    /// RMS normalization shader template
+    ///
+    /// One invocation per row of `dim` values; there is no mean subtraction and no bias.
+    pub const RMS_NORM_SHADER: &str = r#"
+        #version 450
+        layout(local_size_x = 64) in;
+
+        layout(set = 0, binding = 0) readonly buffer Input { float input_data[]; };
+        layout(set = 0, binding = 1) readonly buffer Gamma { float gamma[]; };
+        layout(set = 0, binding = 2) writeonly buffer Output { float output_data[]; };
+        layout(push_constant) uniform Params {
+            uint rows;
+            uint dim;
+            float eps;
+        } params;
+
+        void main() {
+            uint row = gl_GlobalInvocationID.x;
+            if (row >= params.rows) {
+                return;
+            }
+            uint base = row * params.dim;
+            float sum_sq = 0.0;
+            for (uint c = 0; c < params.dim; c++) {
+                sum_sq += input_data[base + c] * input_data[base + c];
+            }
+            float inv_rms = inversesqrt(sum_sq / float(params.dim) + params.eps);
+            for (uint c = 0; c < params.dim; c++) {
+                output_data[base + c] = input_data[base + c] * inv_rms * gamma[c];
+            }
+        }
+    "#;
+
+    /// SiLU gating shader template (SwiGLU)
+    ///
+    /// Computes `silu(gate) * up` elementwise; bind `up` to ones for a plain SiLU.
+    pub const SILU_SHADER: &str = r#"
+        #version 450
+        layout(local_size_x = 256) in;
+
+        layout(set = 0, binding = 0) readonly buffer Gate { float gate[]; };
+        layout(set = 0, binding = 1) readonly buffer Up { float up[]; };
+        layout(set = 0, binding = 2) writeonly buffer Output { float output_data[]; };
+
+        void main() {
+            uint index = gl_GlobalInvocationID.x;
+            if (index >= output_data.length()) {
+                return;
+            }
+            float x = gate[index];
+            output_data[index] = x / (1.0 + exp(-x)) * up[index];
+        }
+    "#;
+
+    /// Multi-head attention shader template with causal and padding masks
+    ///
+    /// One invocation per `(batch, head, query)` row, using an online softmax. Binding 3 is
+    /// the `[batch, seq_k]` padding mask, read only when `has_mask` is set. A non-zero
+    /// `window` skips keys that far or farther from the query.
+    pub const ATTENTION_SHADER: &str = r#"
+        #version 450
+        layout(local_size_x = 64) in;
+
+        layout(set = 0, binding = 0) readonly buffer Q { float q[]; };
+        layout(set = 0, binding = 1) readonly buffer K { float k[]; };
+        layout(set = 0, binding = 2) readonly buffer V { float v[]; };
+        layout(set = 0, binding = 3) readonly buffer Mask { float mask[]; };
+        layout(set = 0, binding = 4) buffer Output { float output_data[]; };
+        layout(push_constant) uniform Params {
+            uint batch;
+            uint seq_q;
+            uint seq_k;
+            uint d_model;
+            uint n_heads;
+            uint causal;
+            uint has_mask;
+            uint alibi;
+            uint n_kv_heads;
+            uint window;
+        } params;
+
+        void main() {
+            uint row = gl_GlobalInvocationID.x;
+            if (row >= params.batch * params.n_heads * params.seq_q) {
+                return;
+            }
+            uint i = row % params.seq_q;
+            uint h = (row / params.seq_q) % params.n_heads;
+            uint b = row / (params.seq_q * params.n_heads);
+            uint head_dim = params.d_model / params.n_heads;
+            float scale = inversesqrt(float(head_dim));
+            uint offset = params.seq_k - params.seq_q;
+            uint q_base = (b * params.seq_q + i) * params.d_model + h * head_dim;
+            // Grouped-query attention: consecutive query heads share one key/value head
+            uint kv_d = params.n_kv_heads * head_dim;
+            uint kv_h = h / (params.n_heads / params.n_kv_heads);
+            // ALiBi slopes: geometric over the nearest lower power of two, then every other
+            // slope of the next power of two
+            uint lower = 1u << findMSB(params.n_heads);
+            float slope = h < lower ? exp2(-8.0 * float(h + 1) / float(lower))
+                                    : exp2(-4.0 * float(2 * (h - lower) + 1) / float(lower));
+            if (params.alibi == 0) {
+                slope = 0.0;
+            }
+            uint pos = i + offset;
+
+            for (uint c = 0; c < head_dim; c++) {
+                output_data[q_base + c] = 0.0;
+            }
+            float running_max = -3.4e38;
+            float denom = 0.0;
+            for (uint j = 0; j < params.seq_k; j++) {
+                if (params.causal != 0 && j > i + offset) {
+                    break;
+                }
+                if (params.has_mask != 0 && mask[b * params.seq_k + j] == 0.0) {
+                    continue;
+                }
+                // Sliding window: 0 means unlimited
+                if (params.window != 0 && max(pos, j) - min(pos, j) >= params.window) {
+                    continue;
+                }
+                uint k_base = (b * params.seq_k + j) * kv_d + kv_h * head_dim;
+                float score = 0.0;
+                for (uint c = 0; c < head_dim; c++) {
+                    score += q[q_base + c] * k[k_base + c];
+                }
+                score = score * scale - slope * float(max(pos, j) - min(pos, j));
+                float new_max = max(running_max, score);
+                float correction = exp(running_max - new_max);
+                float weight = exp(score - new_max);
+                denom = denom * correction + weight;
+                for (uint c = 0; c < head_dim; c++) {
+                    output_data[q_base + c] =
+                        output_data[q_base + c] * correction + weight * v[k_base + c];
+                }
+                running_max = new_max;
+            }
+            if (denom > 0.0) {
+                for (uint c = 0; c < head_dim; c++) {
+                    output_data[q_base + c] /= denom;
+                }
+            }
+        }
+    "#;
+
+    /// Rotary position embedding shader template
+    ///
+    /// One invocation per `(row, head, pair)`, rotating dimension `i` of a head together with
+    /// `i + head_dim / 2`. Binding 1 holds one position per row.
+    pub const ROPE_SHADER: &str = r#"
+        #version 450
+        layout(local_size_x = 64) in;
+
+        layout(set = 0, binding = 0) readonly buffer Input { float input_data[]; };
+        layout(set = 0, binding = 1) readonly buffer Positions { float positions[]; };
+        layout(set = 0, binding = 2) writeonly buffer Output { float output_data[]; };
+        layout(push_constant) uniform Params {
+            uint rows;
+            uint d_model;
+            uint n_heads;
+            float base;
+            float position_scale;
+        } params;
+
+        void main() {
+            uint head_dim = params.d_model / params.n_heads;
+            uint half_dim = head_dim / 2;
+            uint id = gl_GlobalInvocationID.x;
+            if (id >= params.rows * params.n_heads * half_dim) {
+                return;
+            }
+            uint i = id % half_dim;
+            uint h = (id / half_dim) % params.n_heads;
+            uint row = id / (half_dim * params.n_heads);
+            float freq = pow(params.base, -2.0 * float(i) / float(head_dim));
+            float angle = positions[row] * params.position_scale * freq;
+            uint base = row * params.d_model + h * head_dim;
+            float x1 = input_data[base + i];
+            float x2 = input_data[base + i + half_dim];
+            output_data[base + i] = x1 * cos(angle) - x2 * sin(angle);
+            output_data[base + i + half_dim] = x2 * cos(angle) + x1 * sin(angle);
+        }
+    "#;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -140,16 +140,88 @@ impl GpuDevice for WebGpuDevice {
 /// WGSL shader templates for common operations
 pub mod shaders {
     /// Matrix multiplication shader template
+    ///
+    /// One invocation per element of the `[m, n]` output, with the leading dimensions of `a`
+    /// folded into `m`. Binding 2 is the `[n]` bias, read only when `has_bias` is set. A
+    /// non-zero `transpose_b` reads `b` as `[n, k]`.
     pub const MATMUL_SHADER: &str = r#"
+        struct Params {
+            m: u32,
+            k: u32,
+            n: u32,
+            has_bias: u32,
+            transpose_b: u32,
+            _pad0: u32,
+            _pad1: u32,
+            _pad2: u32,
+        }
+
         @group(0) @binding(0) var<storage, read> input_a: array<f32>;
         @group(0) @binding(1) var<storage, read> input_b: array<f32>;
-        @group(0) @binding(2) var<storage, read_write> output: array<f32>;
-        
+        @group(0) @binding(2) var<storage, read> bias: array<f32>;
+        @group(0) @binding(3) var<storage, read_write> output: array<f32>;
+        @group(0) @binding(4) var<uniform> params: Params;
+
         @compute @workgroup_size(8, 8)
         fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-            // Placeholder: Implement matrix multiplication
-            let index = global_id.y * 256u + global_id.x;
-            output[index] = input_a[index] + input_b[index];
+            let row = global_id.y;
+            let col = global_id.x;
+            if (row >= params.m || col >= params.n) {
+                return;
+            }
+            var sum = 0.0;
+            for (var i = 0u; i < params.k; i++) {
+                var b_index = i * params.n + col;
+                if (params.transpose_b != 0u) {
+                    b_index = col * params.k + i;
+                }
+                sum += input_a[row * params.k + i] * input_b[b_index];
+            }
+            if (params.has_bias != 0u) {
+                sum += bias[col];
+            }
+            output[row * params.n + col] = sum;
+        }
+    "#;
+
+    /// Fused GEMM + GELU shader template
+    ///
+    /// Computes `gelu(a @ b + bias)` with the same bindings as [`MATMUL_SHADER`];
+    /// `transpose_b` is ignored.
+    pub const FUSED_GEMM_GELU_SHADER: &str = r#"
+        struct Params {
+            m: u32,
+            k: u32,
+            n: u32,
+            has_bias: u32,
+            transpose_b: u32,
+            _pad0: u32,
+            _pad1: u32,
+            _pad2: u32,
+        }
+
+        @group(0) @binding(0) var<storage, read> input_a: array<f32>;
+        @group(0) @binding(1) var<storage, read> input_b: array<f32>;
+        @group(0) @binding(2) var<storage, read> bias: array<f32>;
+        @group(0) @binding(3) var<storage, read_write> output: array<f32>;
+        @group(0) @binding(4) var<uniform> params: Params;
+
+        @compute @workgroup_size(8, 8)
+        fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+            let row = global_id.y;
+            let col = global_id.x;
+            if (row >= params.m || col >= params.n) {
+                return;
+            }
+            var x = 0.0;
+            for (var i = 0u; i < params.k; i++) {
+                x += input_a[row * params.k + i] * input_b[i * params.n + col];
+            }
+            if (params.has_bias != 0u) {
+                x += bias[col];
+            }
+            output[row * params.n + col] =
+                x * 0.5 * (1.0 + tanh(0.797885 * (x + 0.044715 * x * x * x)));
         }
     "#;
 
@@ -166,6 +238,40 @@ pub mod shaders {
         }
     "#;
 
+    /// RMS normalization shader template
+    ///
+    /// One invocation per row of `dim` values; there is no mean subtraction and no bias.
+    pub const RMS_NORM_SHADER: &str = r#"
+        struct Params {
+            rows: u32,
+            dim: u32,
+            eps: f32,
+            _pad: u32,
+        }
+
+        @group(0) @binding(0) var<storage, read> input: array<f32>;
+        @group(0) @binding(1) var<storage, read> gamma: array<f32>;
+        @group(0) @binding(2) var<storage, read_write> output: array<f32>;
+        @group(0) @binding(3) var<uniform> params: Params;
+
+        @compute @workgroup_size(64)
+        fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+            let row = global_id.x;
+            if (row >= params.rows) {
+                return;
+            }
+            let base = row * params.dim;
+            var sum_sq = 0.0;
+            for (var c = 0u; c < params.dim; c++) {
+                sum_sq += input[base + c] * input[base + c];
+            }
+            let inv_rms = inverseSqrt(sum_sq / f32(params.dim) + params.eps);
+            for (var c = 0u; c < params.dim; c++) {
+                output[base + c] = input[base + c] * inv_rms * gamma[c];
+            }
+        }
+    "#;
+
     /// GELU activation shader template
     pub const GELU_SHADER: &str = r#"
         @group(0) @binding(0) var<storage, read> input: array<f32>;
@@ -179,6 +285,158 @@ pub mod shaders {
             output[index] = x * 0.5 * (1.0 + tanh(0.797885 * (x + 0.044715 * x * x * x)));
         }
     "#;
+
+    /// SiLU gating shader template (SwiGLU)
+    ///
+    /// Computes `silu(gate) * up` elementwise; bind `up` to ones for a plain SiLU.
+    pub const SILU_SHADER: &str = r#"
+        @group(0) @binding(0) var<storage, read> gate: array<f32>;
+        @group(0) @binding(1) var<storage, read> up: array<f32>;
+        @group(0) @binding(2) var<storage, read_write> output: array<f32>;
+
+        @compute @workgroup_size(256)
+        fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+            let index = global_id.x;
+            if (index >= arrayLength(&output)) {
+                return;
+            }
+            let x = gate[index];
+            output[index] = x / (1.0 + exp(-x)) * up[index];
+        }
+    "#;
+
+    /// Multi-head attention shader template with causal and padding masks
+    ///
+    /// One invocation per `(batch, head, query)` row, using an online softmax. Binding 3 is
+    /// the `[batch, seq_k]` padding mask, read only when `has_mask` is set. A non-zero
+    /// `window` skips keys that far or farther from the query.
+    pub const ATTENTION_SHADER: &str = r#"
+        struct Params {
+            batch: u32,
+            seq_q: u32,
+            seq_k: u32,
+            d_model: u32,
+            n_heads: u32,
+            causal: u32,
+            has_mask: u32,
+            alibi: u32,
+            n_kv_heads: u32,
+            window: u32,
+        }
+
+        @group(0) @binding(0) var<storage, read> q: array<f32>;
+        @group(0) @binding(1) var<storage, read> k: array<f32>;
+        @group(0) @binding(2) var<storage, read> v: array<f32>;
+        @group(0) @binding(3) var<storage, read> mask: array<f32>;
+        @group(0) @binding(4) var<storage, read_write> output: array<f32>;
+        @group(0) @binding(5) var<uniform> params: Params;
+
+        @compute @workgroup_size(64)
+        fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+            let row = global_id.x;
+            if (row >= params.batch * params.n_heads * params.seq_q) {
+                return;
+            }
+            let i = row % params.seq_q;
+            let h = (row / params.seq_q) % params.n_heads;
+            let b = row / (params.seq_q * params.n_heads);
+            let head_dim = params.d_model / params.n_heads;
+            let scale = 1.0 / sqrt(f32(head_dim));
+            let offset = params.seq_k - params.seq_q;
+            let q_base = (b * params.seq_q + i) * params.d_model + h * head_dim;
+            // Grouped-query attention: consecutive query heads share one key/value head
+            let kv_d = params.n_kv_heads * head_dim;
+            let kv_h = h / (params.n_heads / params.n_kv_heads);
+            // ALiBi slopes: geometric over the nearest lower power of two, then every other
+            // slope of the next power of two
+            let lower = 1u << firstLeadingBit(params.n_heads);
+            var slope = exp2(-8.0 * f32(h + 1u) / f32(lower));
+            if (h >= lower) {
+                slope = exp2(-4.0 * f32(2u * (h - lower) + 1u) / f32(lower));
+            }
+            if (params.alibi == 0u) {
+                slope = 0.0;
+            }
+            let pos = i + offset;
+
+            for (var c = 0u; c < head_dim; c++) {
+                output[q_base + c] = 0.0;
+            }
+            var running_max = -3.4e38;
+            var denom = 0.0;
+            for (var j = 0u; j < params.seq_k; j++) {
+                if (params.causal != 0u && j > i + offset) {
+                    break;
+                }
+                if (params.has_mask != 0u && mask[b * params.seq_k + j] == 0.0) {
+                    continue;
+                }
+                // Sliding window: 0 means unlimited
+                if (params.window != 0u && max(pos, j) - min(pos, j) >= params.window) {
+                    continue;
+                }
+                let k_base = (b * params.seq_k + j) * kv_d + kv_h * head_dim;
+                var score = 0.0;
+                for (var c = 0u; c < head_dim; c++) {
+                    score += q[q_base + c] * k[k_base + c];
+                }
+                score = score * scale - slope * f32(max(pos, j) - min(pos, j));
+                let new_max = max(running_max, score);
+                let correction = exp(running_max - new_max);
+                let weight = exp(score - new_max);
+                denom = denom * correction + weight;
+                for (var c = 0u; c < head_dim; c++) {
+                    output[q_base + c] = output[q_base + c] * correction + weight * v[k_base + c];
+                }
+                running_max = new_max;
+            }
+            if (denom > 0.0) {
+                for (var c = 0u; c < head_dim; c++) {
+                    output[q_base + c] /= denom;
+                }
+            }
+        }
+    "#;
+
+    /// Rotary position embedding shader template
+    ///
+    /// One invocation per `(row, head, pair)`, rotating dimension `i` of a head together with
+    /// `i + head_dim / 2`. Binding 1 holds one `f32` position per row.
+    pub const ROPE_SHADER: &str = r#"
+        struct Params {
+            rows: u32,
+            d_model: u32,
+            n_heads: u32,
+            _pad: u32,
+            base: f32,
+            position_scale: f32,
+        }
+
+        @group(0) @binding(0) var<storage, read> input: array<f32>;
+        @group(0) @binding(1) var<storage, read> positions: array<f32>;
+        @group(0) @binding(2) var<storage, read_write> output: array<f32>;
+        @group(0) @binding(3) var<uniform> params: Params;
+
+        @compute @workgroup_size(64)
+        fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+            let head_dim = params.d_model / params.n_heads;
+            let half_dim = head_dim / 2u;
+            let id = global_id.x;
+            if (id >= params.rows * params.n_heads * half_dim) {
+                return;
+            }
+            let i = id % half_dim;
+            let h = (id / half_dim) % params.n_heads;
+            let row = id / (half_dim * params.n_heads);
+            let freq = pow(params.base, -2.0 * f32(i) / f32(head_dim));
+            let angle = positions[row] * params.position_scale * freq;
+            let row_base = row * params.d_model + h * head_dim;
+            let x1 = input[row_base + i];
+            let x2 = input[row_base + i + half_dim];
+            output[row_base + i] = x1 * cos(angle) - x2 * sin(angle);
+            output[row_base + i + half_dim] = x2 * cos(angle) + x1 * sin(angle);
+        }
+    "#;
 }
 
 #[cfg(test)]
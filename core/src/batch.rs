@@ -0,0 +1,171 @@
+//! Padded batches of variable-length token sequences
+//!
+//! A [`PaddedBatch`] lines sequences of different lengths up into a rectangular
+//! `[batch, seq_len]` block, together with the attention mask that hides the padding and the
+//! position ids each real token should be embedded at.
+
+use crate::error::{CoreError, Result};
+use crate::tensor::Tensor;
+
+/// Which end of a sequence padding tokens are added to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaddingSide {
+    /// Pad at the start, so every sequence ends at the last column (preferred for generation)
+    #[default]
+    Left,
+    /// Pad at the end, so every sequence starts at the first column
+    Right,
+}
+
+/// Token sequences padded to a common length, with their attention mask and position ids
+#[derive(Debug, Clone)]
+pub struct PaddedBatch {
+    token_ids: Vec<Vec<u32>>,
+    position_ids: Vec<Vec<usize>>,
+    attention_mask: Tensor,
+    lengths: Vec<usize>,
+    padding_side: PaddingSide,
+}
+
+impl PaddedBatch {
+    /// Pad `sequences` with `pad_token` on `padding_side` up to the longest sequence
+    ///
+    /// Real tokens get positions `0..len` regardless of padding; padding gets position 0 and
+    /// a zero in the attention mask.
+    pub fn new(sequences: &[Vec<u32>], pad_token: u32, padding_side: PaddingSide) -> Result<Self> {
+        if sequences.is_empty() || sequences.iter().any(Vec::is_empty) {
+            return Err(CoreError::InvalidDimension(
+                "Batch must contain at least one non-empty sequence".to_string(),
+            ));
+        }
+        let seq_len = sequences.iter().map(Vec::len).max().unwrap_or(0);
+
+        let mut token_ids = Vec::with_capacity(sequences.len());
+        let mut position_ids = Vec::with_capacity(sequences.len());
+        let mut mask = Vec::with_capacity(sequences.len() * seq_len);
+        for seq in sequences {
+            let pad = seq_len - seq.len();
+            let (lead, trail) = match padding_side {
+                PaddingSide::Left => (pad, 0),
+                PaddingSide::Right => (0, pad),
+            };
+            let mut ids = vec![pad_token; lead];
+            ids.extend_from_slice(seq);
+            ids.resize(seq_len, pad_token);
+            token_ids.push(ids);
+
+            let mut positions = vec![0; lead];
+            positions.extend(0..seq.len());
+            positions.resize(seq_len, 0);
+            position_ids.push(positions);
+
+            mask.extend(std::iter::repeat(0.0).take(lead));
+            mask.extend(std::iter::repeat(1.0).take(seq.len()));
+            mask.extend(std::iter::repeat(0.0).take(trail));
+        }
+
+        Ok(Self {
+            token_ids,
+            position_ids,
+            attention_mask: Tensor::from_f32(vec![sequences.len(), seq_len], mask)?,
+            lengths: sequences.iter().map(Vec::len).collect(),
+            padding_side,
+        })
+    }
+
+    /// Padded token ids, `[batch][seq_len]`
+    pub fn token_ids(&self) -> &[Vec<u32>] {
+        &self.token_ids
+    }
+
+    /// Position id of every slot, `[batch][seq_len]`
+    pub fn position_ids(&self) -> &[Vec<usize>] {
+        &self.position_ids
+    }
+
+    /// `[batch, seq_len]` mask with 1 for real tokens and 0 for padding
+    pub fn attention_mask(&self) -> &Tensor {
+        &self.attention_mask
+    }
+
+    /// Unpadded length of each sequence
+    pub fn lengths(&self) -> &[usize] {
+        &self.lengths
+    }
+
+    /// Number of sequences
+    pub fn batch_size(&self) -> usize {
+        self.token_ids.len()
+    }
+
+    /// Padded sequence length
+    pub fn seq_len(&self) -> usize {
+        self.token_ids.first().map_or(0, Vec::len)
+    }
+
+    /// Side the padding was added to
+    pub fn padding_side(&self) -> PaddingSide {
+        self.padding_side
+    }
+
+    /// Column of the last real token of sequence `index`
+    pub fn last_token_index(&self, index: usize) -> usize {
+        match self.padding_side {
+            PaddingSide::Left => self.seq_len() - 1,
+            PaddingSide::Right => self.lengths[index] - 1,
+        }
+    }
+
+    /// Pick each sequence's last real row out of `[batch, seq_len, vocab_size]` logits,
+    /// returning `[batch, vocab_size]`
+    pub fn last_token_logits(&self, logits: &Tensor) -> Result<Tensor> {
+        let (batch, seq_len) = (self.batch_size(), self.seq_len());
+        if logits.ndim() != 3 || logits.shape[..2] != [batch, seq_len] {
+            return Err(CoreError::ShapeMismatch {
+                expected: vec![batch, seq_len, logits.shape.last().copied().unwrap_or(0)],
+                actual: logits.shape.clone(),
+            });
+        }
+        let vocab_size = logits.shape[2];
+        let data = logits.as_f32_slice()?;
+        let mut out = Vec::with_capacity(batch * vocab_size);
+        for b in 0..batch {
+            let row = (b * seq_len + self.last_token_index(b)) * vocab_size;
+            out.extend_from_slice(&data[row..row + vocab_size]);
+        }
+        Tensor::from_f32(vec![batch, vocab_size], out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_left_and_right_padding() {
+        let sequences = vec![vec![5, 6, 7], vec![8]];
+
+        let left = PaddedBatch::new(&sequences, 0, PaddingSide::Left).unwrap();
+        assert_eq!(left.token_ids(), &[vec![5, 6, 7], vec![0, 0, 8]]);
+        assert_eq!(left.position_ids(), &[vec![0, 1, 2], vec![0, 0, 0]]);
+        assert_eq!(
+            left.attention_mask().as_f32_slice().unwrap(),
+            &[1.0, 1.0, 1.0, 0.0, 0.0, 1.0]
+        );
+        assert_eq!(left.last_token_index(1), 2);
+
+        let right = PaddedBatch::new(&sequences, 0, PaddingSide::Right).unwrap();
+        assert_eq!(right.token_ids(), &[vec![5, 6, 7], vec![8, 0, 0]]);
+        assert_eq!(
+            right.attention_mask().as_f32_slice().unwrap(),
+            &[1.0, 1.0, 1.0, 1.0, 0.0, 0.0]
+        );
+        assert_eq!(right.last_token_index(1), 0);
+
+        let logits = Tensor::from_f32(vec![2, 3, 1], vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]).unwrap();
+        let last = right.last_token_logits(&logits).unwrap();
+        assert_eq!(last.as_f32_slice().unwrap(), &[2.0, 3.0]);
+
+        assert!(PaddedBatch::new(&[vec![1], vec![]], 0, PaddingSide::Left).is_err());
+    }
+}
@@ -0,0 +1,408 @@
+//! Beam search decoding
+//!
+//! Deterministic alternative to sampling in [`crate::generation`]. Each beam owns a
+//! [`KvCache`]; when a beam is extended by several tokens its cache is forked, and the last
+//! child takes over the parent's cache without copying.
+
+use crate::error::{CoreError, Result};
+use crate::generation::FinishReason;
+use crate::gpu::GpuDevice;
+use crate::kv_cache::KvCache;
+use crate::transformer::TransformerModel;
+use std::sync::Arc;
+
+/// Parameters for beam search
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamSearchConfig {
+    /// Number of hypotheses kept at every step
+    pub beam_width: usize,
+    /// Maximum number of tokens to generate after the prompt
+    pub max_new_tokens: usize,
+    /// Exponent applied to the hypothesis length when normalizing scores; values above zero
+    /// favour longer outputs
+    pub length_penalty: f32,
+    /// Stop as soon as `beam_width` hypotheses have finished, instead of continuing while an
+    /// active beam could still beat them
+    pub early_stopping: bool,
+    /// Number of best hypotheses to return (at most `beam_width`)
+    pub num_return_sequences: usize,
+    /// Tokens that finish a hypothesis
+    pub stop_tokens: Vec<u32>,
+}
+
+impl Default for BeamSearchConfig {
+    fn default() -> Self {
+        Self {
+            beam_width: 4,
+            max_new_tokens: 64,
+            length_penalty: 1.0,
+            early_stopping: false,
+            num_return_sequences: 1,
+            stop_tokens: Vec::new(),
+        }
+    }
+}
+
+impl BeamSearchConfig {
+    /// Check that the beam settings are consistent
+    pub fn validate(&self) -> Result<()> {
+        if self.beam_width == 0 {
+            return Err(CoreError::Other(
+                "Beam width must be at least 1".to_string(),
+            ));
+        }
+        if self.num_return_sequences == 0 || self.num_return_sequences > self.beam_width {
+            return Err(CoreError::Other(format!(
+                "num_return_sequences must be in 1..={}, got {}",
+                self.beam_width, self.num_return_sequences
+            )));
+        }
+        if !self.length_penalty.is_finite() {
+            return Err(CoreError::Other(format!(
+                "Length penalty must be finite, got {}",
+                self.length_penalty
+            )));
+        }
+        Ok(())
+    }
+
+    /// Length-normalized score of a hypothesis
+    fn score(&self, sum_logprob: f32, len: usize) -> f32 {
+        sum_logprob / (len.max(1) as f32).powf(self.length_penalty)
+    }
+}
+
+/// A finished beam search hypothesis
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamHypothesis {
+    /// Generated token ids, excluding the prompt and any stop token
+    pub tokens: Vec<u32>,
+    /// Sum of the token log-probabilities (including the stop token, if any)
+    pub sum_logprob: f32,
+    /// Length-normalized score used for ranking
+    pub score: f32,
+    /// Why this hypothesis ended
+    pub finish_reason: FinishReason,
+}
+
+/// An active beam: its generated tokens, cumulative log-probability, decoding state and the
+/// logits for its next position
+struct Beam {
+    tokens: Vec<u32>,
+    sum_logprob: f32,
+    cache: KvCache,
+    logits: Vec<f32>,
+}
+
+/// Runs beam search over a [`TransformerModel`] on a [`GpuDevice`]
+pub struct BeamSearch<'a> {
+    model: &'a TransformerModel,
+    device: Arc<dyn GpuDevice>,
+    config: BeamSearchConfig,
+}
+
+impl<'a> BeamSearch<'a> {
+    /// Create a beam search for `model` running on `device`
+    pub fn new(
+        model: &'a TransformerModel,
+        device: Arc<dyn GpuDevice>,
+        config: BeamSearchConfig,
+    ) -> Self {
+        Self {
+            model,
+            device,
+            config,
+        }
+    }
+
+    /// The beam search configuration
+    pub fn config(&self) -> &BeamSearchConfig {
+        &self.config
+    }
+
+    /// Search for the best continuations of `prompt`, returning up to
+    /// `num_return_sequences` hypotheses sorted by descending score
+    pub fn search(&self, prompt: &[u32]) -> Result<Vec<BeamHypothesis>> {
+        self.config.validate()?;
+        if prompt.is_empty() {
+            return Err(CoreError::InvalidDimension(
+                "Prompt must contain at least one token".to_string(),
+            ));
+        }
+        // Like `Generator`, an empty budget yields one empty output without running the model
+        if self.config.max_new_tokens == 0 {
+            return Ok(vec![BeamHypothesis {
+                tokens: Vec::new(),
+                sum_logprob: 0.0,
+                score: self.config.score(0.0, 0),
+                finish_reason: FinishReason::MaxNewTokens,
+            }]);
+        }
+
+        let config = &self.config;
+        let width = config.beam_width;
+        let max_seq_len = self.model.config.context_len();
+        let mut finished: Vec<BeamHypothesis> = Vec::new();
+
+        let mut cache = self.model.new_kv_cache();
+        let logits = self.last_logits(prompt, &mut cache)?;
+        let mut beams = vec![Beam {
+            tokens: Vec::new(),
+            sum_logprob: 0.0,
+            cache,
+            logits,
+        }];
+
+        for step in 0..config.max_new_tokens {
+            // Rank the best 2 * width extensions of every beam
+            let mut candidates: Vec<(usize, u32, f32)> = Vec::new();
+            for (b, beam) in beams.iter().enumerate() {
+                let logprobs = log_softmax(&beam.logits);
+                candidates.extend(
+                    top_tokens(&logprobs, 2 * width)
+                        .into_iter()
+                        .map(|(token, lp)| (b, token, beam.sum_logprob + lp)),
+                );
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            let mut selected = Vec::with_capacity(width);
+            for (rank, &(b, token, sum_logprob)) in candidates.iter().enumerate() {
+                if config.stop_tokens.contains(&token) {
+                    // A stop token only counts if it would have ranked inside the beam
+                    if rank < width {
+                        let tokens = beams[b].tokens.clone();
+                        finished.push(BeamHypothesis {
+                            score: config.score(sum_logprob, tokens.len() + 1),
+                            tokens,
+                            sum_logprob,
+                            finish_reason: FinishReason::StopToken(token),
+                        });
+                    }
+                    continue;
+                }
+                selected.push((b, token, sum_logprob));
+                if selected.len() == width {
+                    break;
+                }
+            }
+
+            let cur_len = step + 1;
+            let last_step = cur_len == config.max_new_tokens;
+            let context_full = prompt.len() + cur_len >= max_seq_len;
+            if selected.is_empty() || self.is_done(&finished, selected[0].2, cur_len) {
+                beams.clear();
+                break;
+            }
+            if last_step || context_full {
+                let reason = if last_step {
+                    FinishReason::MaxNewTokens
+                } else {
+                    FinishReason::ContextFull
+                };
+                for (b, token, sum_logprob) in selected {
+                    let mut tokens = beams[b].tokens.clone();
+                    tokens.push(token);
+                    finished.push(BeamHypothesis {
+                        score: config.score(sum_logprob, tokens.len()),
+                        tokens,
+                        sum_logprob,
+                        finish_reason: reason,
+                    });
+                }
+                beams.clear();
+                break;
+            }
+            beams = self.fork_beams(beams, &selected)?;
+        }
+
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(config.num_return_sequences);
+        Ok(finished)
+    }
+
+    /// Whether no active beam can still improve on the finished hypotheses
+    fn is_done(&self, finished: &[BeamHypothesis], best_active: f32, cur_len: usize) -> bool {
+        let width = self.config.beam_width;
+        if finished.len() < width {
+            return false;
+        }
+        if self.config.early_stopping {
+            return true;
+        }
+        let mut scores: Vec<f32> = finished.iter().map(|h| h.score).collect();
+        scores.sort_by(|a, b| b.total_cmp(a));
+        scores[width - 1] >= self.config.score(best_active, cur_len)
+    }
+
+    /// Build the next beams, forking parent caches and running one forward step per beam
+    fn fork_beams(&self, parents: Vec<Beam>, selected: &[(usize, u32, f32)]) -> Result<Vec<Beam>> {
+        let mut children = vec![0usize; parents.len()];
+        for &(b, _, _) in selected {
+            children[b] += 1;
+        }
+
+        let mut parents: Vec<Option<Beam>> = parents.into_iter().map(Some).collect();
+        let mut next = Vec::with_capacity(selected.len());
+        for &(b, token, sum_logprob) in selected {
+            children[b] -= 1;
+            let (mut tokens, mut cache) = if children[b] == 0 {
+                let parent = parents[b].take().expect("parent used after its last child");
+                (parent.tokens, parent.cache)
+            } else {
+                let parent = parents[b]
+                    .as_ref()
+                    .expect("parent used after its last child");
+                (parent.tokens.clone(), parent.cache.clone())
+            };
+            let logits = self.last_logits(&[token], &mut cache)?;
+            tokens.push(token);
+            next.push(Beam {
+                tokens,
+                sum_logprob,
+                cache,
+                logits,
+            });
+        }
+        Ok(next)
+    }
+
+    fn last_logits(&self, tokens: &[u32], cache: &mut KvCache) -> Result<Vec<f32>> {
+        let vocab_size = self.model.config.vocab_size;
+        let logits = self.model.forward_with_cache(tokens, cache, &self.device)?;
+        let logits = logits.as_f32_slice()?;
+        Ok(logits[logits.len() - vocab_size..].to_vec())
+    }
+}
+
+/// Log-softmax of a logits row
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|&l| (l - max).exp()).sum::<f32>().ln();
+    logits.iter().map(|&l| l - max - log_sum).collect()
+}
+
+/// The `k` highest-scoring `(token, logprob)` pairs, best first
+fn top_tokens(logprobs: &[f32], k: usize) -> Vec<(u32, f32)> {
+    let mut ranked: Vec<(u32, f32)> = logprobs
+        .iter()
+        .enumerate()
+        .map(|(i, &lp)| (i as u32, lp))
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked.truncate(k);
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generation::{GenerationConfig, Generator};
+    use crate::test_utils::{random_model, reference_device, small_config};
+
+    #[test]
+    fn test_width_one_matches_greedy() {
+        let model = random_model(small_config(), 5);
+        let greedy = Generator::new(&model, reference_device(), GenerationConfig::greedy(4))
+            .generate(&[2, 7])
+            .unwrap();
+        let beams = BeamSearch::new(
+            &model,
+            reference_device(),
+            BeamSearchConfig {
+                beam_width: 1,
+                max_new_tokens: 4,
+                ..BeamSearchConfig::default()
+            },
+        )
+        .search(&[2, 7])
+        .unwrap();
+        assert_eq!(beams.len(), 1);
+        assert_eq!(beams[0].tokens, greedy.tokens);
+        assert_eq!(beams[0].finish_reason, FinishReason::MaxNewTokens);
+    }
+
+    #[test]
+    fn test_full_width_finds_best_pair() {
+        let model = random_model(small_config(), 5);
+        let prompt = vec![4, 1];
+        let beams = BeamSearch::new(
+            &model,
+            reference_device(),
+            BeamSearchConfig {
+                beam_width: 11,
+                max_new_tokens: 2,
+                num_return_sequences: 3,
+                ..BeamSearchConfig::default()
+            },
+        )
+        .search(&prompt)
+        .unwrap();
+        assert_eq!(beams.len(), 3);
+        assert!(beams.windows(2).all(|w| w[0].score >= w[1].score));
+
+        // Exhaustively score every two-token continuation
+        let first = log_softmax(&last_row(&model, &prompt));
+        let mut best = (f32::NEG_INFINITY, vec![]);
+        for a in 0..11u32 {
+            let mut context = prompt.clone();
+            context.push(a);
+            let second = log_softmax(&last_row(&model, &context));
+            for b in 0..11u32 {
+                let total = first[a as usize] + second[b as usize];
+                if total > best.0 {
+                    best = (total, vec![a, b]);
+                }
+            }
+        }
+        assert_eq!(beams[0].tokens, best.1);
+        assert!((beams[0].sum_logprob - best.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_stop_tokens_finish_hypotheses() {
+        let model = random_model(small_config(), 5);
+        let config = BeamSearchConfig {
+            beam_width: 3,
+            max_new_tokens: 6,
+            early_stopping: true,
+            stop_tokens: (0..11).collect(),
+            ..BeamSearchConfig::default()
+        };
+        let beams = BeamSearch::new(&model, reference_device(), config)
+            .search(&[3])
+            .unwrap();
+        assert!(beams[0].tokens.is_empty());
+        assert!(matches!(beams[0].finish_reason, FinishReason::StopToken(_)));
+    }
+
+    #[test]
+    fn test_zero_budget_and_invalid_length_penalty() {
+        let model = random_model(small_config(), 5);
+        let config = BeamSearchConfig {
+            max_new_tokens: 0,
+            num_return_sequences: 2,
+            ..BeamSearchConfig::default()
+        };
+        let beams = BeamSearch::new(&model, reference_device(), config)
+            .search(&[3])
+            .unwrap();
+        assert_eq!(beams.len(), 1);
+        assert!(beams[0].tokens.is_empty());
+        assert_eq!(beams[0].finish_reason, FinishReason::MaxNewTokens);
+
+        for length_penalty in [f32::NAN, f32::INFINITY] {
+            let config = BeamSearchConfig {
+                length_penalty,
+                ..BeamSearchConfig::default()
+            };
+            assert!(config.validate().is_err());
+        }
+    }
+
+    fn last_row(model: &TransformerModel, tokens: &[u32]) -> Vec<f32> {
+        let logits = model.forward_cpu(&[tokens.to_vec()]).unwrap();
+        let logits = logits.as_f32_slice().unwrap();
+        logits[logits.len() - 11..].to_vec()
+    }
+}
@@ -0,0 +1,216 @@
+//! Task heads for sequence and token classification
+//!
+//! A [`ClassificationHead`] is a linear layer from hidden states to one logit per label.
+//! Attached to a [`TransformerModel`](crate::transformer::TransformerModel) it classifies
+//! whole inputs over pooled hidden states ([`SequenceClassifier`], e.g. sentiment) or every
+//! token (e.g. named-entity tags), and is saved with the model.
+
+use crate::embeddings::Pooling;
+use crate::error::{CoreError, Result};
+use crate::ops;
+use crate::tensor::Tensor;
+use serde::{Deserialize, Serialize};
+
+/// Linear classifier over `d_model` hidden states with named labels
+#[derive(Debug, Clone)]
+pub struct ClassificationHead {
+    /// Weights `[d_model, n_labels]`
+    pub weight: Tensor,
+    /// Optional bias `[n_labels]`
+    pub bias: Option<Tensor>,
+    /// Label names, one per output column
+    pub labels: Vec<String>,
+}
+
+/// Sequence classification: a [`ClassificationHead`] over pooled hidden states
+#[derive(Debug, Clone)]
+pub struct SequenceClassifier {
+    /// How each input's hidden states are pooled before the head
+    pub pooling: Pooling,
+    /// Classifier applied to the pooled vector
+    pub head: ClassificationHead,
+}
+
+/// Most likely label of one classified row, with the softmax scores of every label
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prediction {
+    /// Index of the predicted label
+    pub label_id: usize,
+    /// Name of the predicted label
+    pub label: String,
+    /// Softmax probability of the predicted label
+    pub score: f32,
+    /// Softmax probabilities of all labels, in label order
+    pub scores: Vec<f32>,
+}
+
+impl ClassificationHead {
+    /// Number of labels
+    pub fn num_labels(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Check that the head has labels and maps `d_model` hidden states to one logit per label
+    pub fn validate(&self, d_model: usize) -> Result<()> {
+        let n = self.num_labels();
+        if n == 0 {
+            return Err(CoreError::InvalidDimension(
+                "Classification head has no labels".to_string(),
+            ));
+        }
+        if self.weight.shape != [d_model, n] {
+            return Err(CoreError::ShapeMismatch {
+                expected: vec![d_model, n],
+                actual: self.weight.shape.clone(),
+            });
+        }
+        match &self.bias {
+            Some(bias) if bias.shape != [n] => Err(CoreError::ShapeMismatch {
+                expected: vec![n],
+                actual: bias.shape.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Logits `[.., n_labels]` for hidden states `[.., d_model]` on the host
+    pub fn logits(&self, hidden: &Tensor) -> Result<Tensor> {
+        self.validate(hidden.shape.last().copied().unwrap_or(0))?;
+        ops::linear(hidden, &self.weight, self.bias.as_ref())
+    }
+
+    /// Softmax the rows of `[.., n_labels]` logits into one [`Prediction`] per row
+    pub fn predict(&self, logits: &Tensor) -> Result<Vec<Prediction>> {
+        let n = self.num_labels();
+        if n == 0 {
+            return Err(CoreError::InvalidDimension(
+                "Classification head has no labels".to_string(),
+            ));
+        }
+        if logits.shape.last() != Some(&n) {
+            return Err(CoreError::ShapeMismatch {
+                expected: vec![logits.numel() / n, n],
+                actual: logits.shape.clone(),
+            });
+        }
+        let probs = ops::softmax(logits)?;
+        let predictions = probs
+            .as_f32_slice()?
+            .chunks_exact(n)
+            .map(|scores| {
+                let label_id =
+                    (1..n).fold(0, |best, i| if scores[i] > scores[best] { i } else { best });
+                Prediction {
+                    label_id,
+                    label: self.labels[label_id].clone(),
+                    score: scores[label_id],
+                    scores: scores.to_vec(),
+                }
+            })
+            .collect();
+        Ok(predictions)
+    }
+}
+
+impl Serialize for ClassificationHead {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("ClassificationHead", 3)?;
+        state.serialize_field("weight", &self.weight)?;
+        state.serialize_field("bias", &self.bias)?;
+        state.serialize_field("labels", &self.labels)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ClassificationHead {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Helper {
+            weight: Tensor,
+            bias: Option<Tensor>,
+            labels: Vec<String>,
+        }
+        let helper = Helper::deserialize(deserializer)?;
+        Ok(ClassificationHead {
+            weight: helper.weight,
+            bias: helper.bias,
+            labels: helper.labels,
+        })
+    }
+}
+
+impl Serialize for SequenceClassifier {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("SequenceClassifier", 2)?;
+        state.serialize_field("pooling", &self.pooling)?;
+        state.serialize_field("head", &self.head)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for SequenceClassifier {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Helper {
+            pooling: Pooling,
+            head: ClassificationHead,
+        }
+        let helper = Helper::deserialize(deserializer)?;
+        Ok(SequenceClassifier {
+            pooling: helper.pooling,
+            head: helper.head,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predict_softmax_scores() {
+        let head = ClassificationHead {
+            weight: Tensor::from_f32(vec![2, 3], vec![1.0, 0.0, -1.0, 0.0, 1.0, 0.0]).unwrap(),
+            bias: None,
+            labels: vec!["NEG".into(), "NEU".into(), "POS".into()],
+        };
+        let hidden = Tensor::from_f32(vec![2, 2], vec![2.0, 0.0, 0.0, 3.0]).unwrap();
+        let predictions = head.predict(&head.logits(&hidden).unwrap()).unwrap();
+
+        assert_eq!(predictions.len(), 2);
+        assert_eq!(
+            (predictions[0].label_id, predictions[0].label.as_str()),
+            (0, "NEG")
+        );
+        assert_eq!(predictions[1].label, "NEU");
+        for prediction in &predictions {
+            assert!((prediction.scores.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+            assert_eq!(prediction.score, prediction.scores[prediction.label_id]);
+        }
+        assert!(head.predict(&hidden).is_err());
+
+        // Heads that don't match the hidden size or have no labels are rejected up front
+        assert!(head.validate(3).is_err());
+        let empty = ClassificationHead {
+            weight: Tensor::from_f32(vec![2, 0], vec![]).unwrap(),
+            bias: None,
+            labels: vec![],
+        };
+        assert!(empty.validate(2).is_err());
+        assert!(empty.logits(&hidden).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Sentence embeddings pooled from per-token hidden states
+//!
+//! [`TransformerModel::sentence_embeddings`](crate::transformer::TransformerModel::sentence_embeddings)
+//! runs a batch through the model and reduces each sequence's final hidden states to one
+//! vector with [`pool`], optionally followed by [`l2_normalize`] for cosine similarity.
+
+use crate::error::{CoreError, Result};
+use crate::tensor::Tensor;
+use serde::{Deserialize, Serialize};
+
+/// How the hidden states of a sequence are reduced to one vector
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Pooling {
+    /// Hidden state of the first real token (BERT's `[CLS]`)
+    #[default]
+    Cls,
+    /// Mean over the real tokens (sentence-transformers)
+    Mean,
+    /// Elementwise maximum over the real tokens
+    Max,
+}
+
+/// Pool `hidden` `[batch, seq_len, d]` to `[batch, d]`
+///
+/// `mask` is an optional `[batch, seq_len]` padding mask with 1 for real tokens; padding is
+/// left out of every pooling mode. A sequence without real tokens pools to zeros.
+pub fn pool(hidden: &Tensor, mask: Option<&Tensor>, pooling: Pooling) -> Result<Tensor> {
+    let (batch, seq_len, d) = match hidden.shape[..] {
+        [batch, seq_len, d] => (batch, seq_len, d),
+        _ => {
+            return Err(CoreError::InvalidDimension(format!(
+                "Pooling expects [batch, seq_len, d] hidden states, got {:?}",
+                hidden.shape
+            )))
+        }
+    };
+    let mask = match mask {
+        Some(mask) if mask.shape != [batch, seq_len] => {
+            return Err(CoreError::ShapeMismatch {
+                expected: vec![batch, seq_len],
+                actual: mask.shape.clone(),
+            });
+        }
+        Some(mask) => Some(mask.as_f32_slice()?),
+        None => None,
+    };
+
+    let data = hidden.as_f32_slice()?;
+    let mut out = Vec::with_capacity(batch * d);
+    for b in 0..batch {
+        let mut rows = (0..seq_len)
+            .filter(|&i| mask.map_or(true, |mask| mask[b * seq_len + i] != 0.0))
+            .map(|i| &data[(b * seq_len + i) * d..(b * seq_len + i + 1) * d])
+            .peekable();
+        let Some(&first) = rows.peek() else {
+            out.extend(std::iter::repeat(0.0).take(d));
+            continue;
+        };
+        match pooling {
+            Pooling::Cls => out.extend_from_slice(first),
+            Pooling::Mean => {
+                let mut sum = vec![0.0f32; d];
+                let mut count = 0;
+                for row in rows {
+                    sum.iter_mut().zip(row).for_each(|(s, x)| *s += x);
+                    count += 1;
+                }
+                out.extend(sum.iter().map(|s| s / count as f32));
+            }
+            Pooling::Max => {
+                let mut max = first.to_vec();
+                for row in rows {
+                    max.iter_mut().zip(row).for_each(|(m, &x)| *m = m.max(x));
+                }
+                out.extend(max);
+            }
+        }
+    }
+    Tensor::from_f32(vec![batch, d], out)
+}
+
+/// Scale each row of `x` `[.., d]` to unit L2 norm; all-zero rows stay zero
+pub fn l2_normalize(x: &Tensor) -> Result<Tensor> {
+    let d = x.shape.last().copied().unwrap_or(0).max(1);
+    let data = x.as_f32_slice()?;
+    let mut out = Vec::with_capacity(data.len());
+    for row in data.chunks(d) {
+        let norm = row.iter().map(|v| v * v).sum::<f32>().sqrt();
+        let scale = if norm > 0.0 { 1.0 / norm } else { 0.0 };
+        out.extend(row.iter().map(|v| v * scale));
+    }
+    Tensor::from_f32(x.shape.clone(), out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pooling_skips_padding() {
+        // Two sequences of three rows; the second has padding in its last row
+        let hidden = Tensor::from_f32(
+            vec![2, 3, 2],
+            vec![1.0, 4.0, 3.0, -2.0, 2.0, 1.0, 5.0, 0.0, -1.0, 6.0, 9.0, 9.0],
+        )
+        .unwrap();
+        let mask = Tensor::from_f32(vec![2, 3], vec![1.0, 1.0, 1.0, 1.0, 1.0, 0.0]).unwrap();
+        let pooled = |pooling| {
+            pool(&hidden, Some(&mask), pooling)
+                .unwrap()
+                .as_f32_slice()
+                .unwrap()
+                .to_vec()
+        };
+        assert_eq!(pooled(Pooling::Cls), vec![1.0, 4.0, 5.0, 0.0]);
+        assert_eq!(pooled(Pooling::Mean), vec![2.0, 1.0, 2.0, 3.0]);
+        assert_eq!(pooled(Pooling::Max), vec![3.0, 4.0, 5.0, 6.0]);
+        assert!(pool(&hidden, Some(&hidden), Pooling::Mean).is_err());
+    }
+
+    #[test]
+    fn test_l2_normalize() {
+        let x = Tensor::from_f32(vec![2, 2], vec![3.0, 4.0, 0.0, 0.0]).unwrap();
+        let normalized = l2_normalize(&x).unwrap();
+        assert_eq!(normalized.as_f32_slice().unwrap(), &[0.6, 0.8, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,1030 @@
+//! Autoregressive text generation on top of [`TransformerModel`]
+//!
+//! A [`Generator`] prefills the prompt into a [`KvCache`], then repeatedly turns the logits of
+//! the last position into the next token according to a [`GenerationConfig`].
+
+use crate::error::{CoreError, Result};
+use crate::gpu::GpuDevice;
+use crate::kv_cache::KvCache;
+use crate::logits_processor::{LogitsContext, LogitsProcessor, LogitsProcessorList};
+use crate::ops;
+use crate::prefix_cache::PrefixCache;
+use crate::tensor::{DType, Tensor};
+use crate::transformer::TransformerModel;
+use serde::{Deserialize, Serialize};
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+
+/// Small seedable pseudo-random generator (SplitMix64) for reproducible sampling
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator from a seed
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Next 64 random bits
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform sample in `[0, 1)`
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// Sampling and stopping parameters for generation
+///
+/// The defaults sample at temperature 1.0 with every filter disabled. A temperature of zero
+/// selects greedy decoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationConfig {
+    /// Maximum number of tokens to generate after the prompt
+    pub max_new_tokens: usize,
+    /// Softmax temperature; `0.0` means greedy (argmax) decoding
+    pub temperature: f32,
+    /// Keep only the `top_k` most likely tokens (`0` disables)
+    pub top_k: usize,
+    /// Keep the smallest set of tokens whose cumulative probability reaches `top_p`
+    /// (`1.0` disables)
+    pub top_p: f32,
+    /// Drop tokens whose probability is below `min_p` times the top probability
+    /// (`0.0` disables)
+    pub min_p: f32,
+    /// Divide positive (multiply negative) logits of already seen tokens by this factor
+    /// (`1.0` disables)
+    pub repetition_penalty: f32,
+    /// Subtract this value from the logits of already seen tokens (`0.0` disables)
+    pub presence_penalty: f32,
+    /// Generation stops when one of these tokens is produced
+    pub stop_tokens: Vec<u32>,
+    /// Seed for the sampling RNG
+    pub seed: u64,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            max_new_tokens: 64,
+            temperature: 1.0,
+            top_k: 0,
+            top_p: 1.0,
+            min_p: 0.0,
+            repetition_penalty: 1.0,
+            presence_penalty: 0.0,
+            stop_tokens: Vec::new(),
+            seed: 0,
+        }
+    }
+}
+
+impl GenerationConfig {
+    /// Greedy decoding configuration
+    pub fn greedy(max_new_tokens: usize) -> Self {
+        Self {
+            max_new_tokens,
+            temperature: 0.0,
+            ..Self::default()
+        }
+    }
+
+    /// Check parameters for values that cannot produce a distribution
+    pub fn validate(&self) -> Result<()> {
+        if self.temperature.is_nan() || self.temperature < 0.0 {
+            return Err(CoreError::Other(format!(
+                "Temperature must be non-negative, got {}",
+                self.temperature
+            )));
+        }
+        if self.top_p.is_nan() || self.top_p <= 0.0 || self.top_p > 1.0 {
+            return Err(CoreError::Other(format!(
+                "top_p must be in (0, 1], got {}",
+                self.top_p
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.min_p) {
+            return Err(CoreError::Other(format!(
+                "min_p must be in [0, 1], got {}",
+                self.min_p
+            )));
+        }
+        if self.repetition_penalty.is_nan() || self.repetition_penalty <= 0.0 {
+            return Err(CoreError::Other(format!(
+                "Repetition penalty must be positive, got {}",
+                self.repetition_penalty
+            )));
+        }
+        if !self.presence_penalty.is_finite() {
+            return Err(CoreError::Other(format!(
+                "Presence penalty must be finite, got {}",
+                self.presence_penalty
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether this configuration decodes greedily
+    pub fn is_greedy(&self) -> bool {
+        self.temperature == 0.0
+    }
+}
+
+/// Why generation stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// `max_new_tokens` tokens were generated
+    MaxNewTokens,
+    /// A stop token was sampled (it is not included in the output)
+    StopToken(u32),
+    /// The sequence reached the model's context length, see
+    /// [`TransformerConfig::context_len`](crate::transformer::TransformerConfig::context_len)
+    ContextFull,
+    /// A streaming callback requested cancellation
+    Cancelled,
+}
+
+/// Result of a generation call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationOutput {
+    /// Generated token ids, excluding the prompt and any stop token
+    pub tokens: Vec<u32>,
+    /// Why generation stopped
+    pub finish_reason: FinishReason,
+}
+
+/// One step of a streamed generation
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenEvent {
+    /// Sampled token id
+    pub token: u32,
+    /// Text this token adds to the decoded output (empty without a [`TokenDecoder`] or while
+    /// a multi-token character is incomplete)
+    ///
+    /// The last event flushes any text still held back, so a stop token carries the rest
+    /// of the text before it.
+    pub text: String,
+    /// Log-probability of the token under the processed logits, before temperature and
+    /// filtering
+    pub logprob: f32,
+    /// Set on the last event of the stream
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// Converts token ids back to text for streamed [`TokenEvent`]s
+pub trait TokenDecoder {
+    /// Decode a sequence of generated token ids
+    fn decode(&self, tokens: &[u32]) -> String;
+}
+
+/// Turns next-token logits into a token id according to a [`GenerationConfig`]
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    config: GenerationConfig,
+    rng: Rng,
+}
+
+impl Sampler {
+    /// Create a sampler seeded from `config.seed`
+    pub fn new(config: GenerationConfig) -> Self {
+        let rng = Rng::new(config.seed);
+        Self { config, rng }
+    }
+
+    /// Continue from a saved RNG state instead of the configured seed
+    pub fn with_rng(mut self, rng: Rng) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// The sampling configuration
+    pub fn config(&self) -> &GenerationConfig {
+        &self.config
+    }
+
+    /// Current RNG state
+    pub fn rng(&self) -> &Rng {
+        &self.rng
+    }
+
+    /// Apply repetition and presence penalties for every token in `history`
+    pub fn apply_penalties(&self, logits: &mut [f32], history: &[u32]) {
+        let (repetition, presence) = (self.config.repetition_penalty, self.config.presence_penalty);
+        if repetition == 1.0 && presence == 0.0 {
+            return;
+        }
+        let mut seen = vec![false; logits.len()];
+        for &token in history {
+            let Some(logit) = logits.get_mut(token as usize) else {
+                continue;
+            };
+            if std::mem::replace(&mut seen[token as usize], true) {
+                continue;
+            }
+            *logit = if *logit > 0.0 {
+                *logit / repetition
+            } else {
+                *logit * repetition
+            };
+            *logit -= presence;
+        }
+    }
+
+    /// Probability distribution over candidate tokens after temperature, top-k, top-p and
+    /// min-p filtering, sorted by descending probability and renormalized
+    pub fn candidates(&self, logits: &[f32]) -> Vec<(u32, f32)> {
+        let temperature = self.config.temperature.max(f32::MIN_POSITIVE);
+        let mut probs: Vec<f32> = logits.iter().map(|&l| l / temperature).collect();
+        ops::softmax_in_place(&mut probs);
+
+        let mut candidates: Vec<(u32, f32)> = probs
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, p)| p > 0.0)
+            .map(|(i, p)| (i as u32, p))
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        if self.config.top_k > 0 {
+            candidates.truncate(self.config.top_k);
+        }
+        if self.config.top_p < 1.0 {
+            let mut cumulative = 0.0;
+            let keep = candidates
+                .iter()
+                .position(|&(_, p)| {
+                    cumulative += p;
+                    cumulative >= self.config.top_p
+                })
+                .map_or(candidates.len(), |i| i + 1);
+            candidates.truncate(keep);
+        }
+        if self.config.min_p > 0.0 {
+            let threshold = candidates.first().map_or(0.0, |c| c.1) * self.config.min_p;
+            candidates.retain(|&(_, p)| p >= threshold);
+        }
+
+        let total: f32 = candidates.iter().map(|c| c.1).sum();
+        candidates.iter_mut().for_each(|c| c.1 /= total);
+        candidates
+    }
+
+    /// Dense `[vocab_size]` distribution the sampler draws from for these logits and history
+    ///
+    /// Greedy configurations put all mass on the argmax.
+    pub fn distribution(&self, logits: &[f32], history: &[u32]) -> Vec<f32> {
+        let mut logits = logits.to_vec();
+        self.apply_penalties(&mut logits, history);
+        let mut probs = vec![0.0; logits.len()];
+        if self.config.is_greedy() {
+            if let Some(token) = argmax(&logits).filter(|&t| logits[t as usize].is_finite()) {
+                probs[token as usize] = 1.0;
+            }
+        } else {
+            for (token, p) in self.candidates(&logits) {
+                probs[token as usize] = p;
+            }
+        }
+        probs
+    }
+
+    /// Pick the next token from raw logits, given the tokens seen so far
+    pub fn sample(&mut self, logits: &[f32], history: &[u32]) -> Result<u32> {
+        let mut logits = logits.to_vec();
+        self.apply_penalties(&mut logits, history);
+
+        if self.config.is_greedy() {
+            let token = argmax(&logits)
+                .ok_or_else(|| CoreError::Other("Cannot sample from empty logits".to_string()))?;
+            // Every logit -inf (e.g. all tokens banned) leaves nothing to pick, as when sampling
+            if !logits[token as usize].is_finite() {
+                return Err(CoreError::Other(
+                    "No token has non-zero probability".to_string(),
+                ));
+            }
+            return Ok(token);
+        }
+
+        let candidates = self.candidates(&logits);
+        let mut threshold = self.rng.next_f32();
+        for &(token, p) in &candidates {
+            if threshold < p {
+                return Ok(token);
+            }
+            threshold -= p;
+        }
+        // Rounding can leave a sliver of mass past the last candidate
+        candidates
+            .last()
+            .map(|c| c.0)
+            .ok_or_else(|| CoreError::Other("No token has non-zero probability".to_string()))
+    }
+}
+
+/// Log-probability of `token` under `softmax(logits)`
+pub fn log_softmax_at(logits: &[f32], token: u32) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|&l| (l - max).exp()).sum();
+    logits[token as usize] - max - sum.ln()
+}
+
+/// Index of the largest logit (first one on ties)
+pub fn argmax(logits: &[f32]) -> Option<u32> {
+    logits
+        .iter()
+        .enumerate()
+        .fold(None, |best: Option<(usize, f32)>, (i, &v)| match best {
+            Some((_, b)) if b >= v => best,
+            _ => Some((i, v)),
+        })
+        .map(|(i, _)| i as u32)
+}
+
+/// Runs the decode loop of a [`TransformerModel`] on a [`GpuDevice`]
+pub struct Generator<'a> {
+    model: &'a TransformerModel,
+    device: Arc<dyn GpuDevice>,
+    config: GenerationConfig,
+    processors: LogitsProcessorList,
+    decoder: Option<Box<dyn TokenDecoder>>,
+    prefix_cache: Option<Arc<Mutex<PrefixCache>>>,
+}
+
+impl<'a> Generator<'a> {
+    /// Create a generator for `model` running on `device`
+    pub fn new(
+        model: &'a TransformerModel,
+        device: Arc<dyn GpuDevice>,
+        config: GenerationConfig,
+    ) -> Self {
+        Self {
+            model,
+            device,
+            config,
+            processors: LogitsProcessorList::new(),
+            decoder: None,
+            prefix_cache: None,
+        }
+    }
+
+    /// Append a logits processor, run on every step's logits before sampling
+    pub fn with_processor(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.processors.push(processor);
+        self
+    }
+
+    /// Decode streamed tokens to text with `decoder`
+    pub fn with_decoder(mut self, decoder: impl TokenDecoder + 'static) -> Self {
+        self.decoder = Some(Box::new(decoder));
+        self
+    }
+
+    /// Reuse prompt KV state through `cache`, which may be shared with other generators of
+    /// the same model
+    ///
+    /// Prefill starts from the longest cached prefix of the prompt, and the prompt's state is
+    /// cached afterwards for later requests.
+    pub fn with_prefix_cache(mut self, cache: Arc<Mutex<PrefixCache>>) -> Self {
+        self.prefix_cache = Some(cache);
+        self
+    }
+
+    /// The generation configuration
+    pub fn config(&self) -> &GenerationConfig {
+        &self.config
+    }
+
+    /// Generate a continuation of `prompt`
+    pub fn generate(&self, prompt: &[u32]) -> Result<GenerationOutput> {
+        self.generate_with_callback(prompt, |_| ControlFlow::Continue(()))
+    }
+
+    /// Generate a continuation of `prompt`, calling `callback` with every token as it is
+    /// produced
+    ///
+    /// Returning [`ControlFlow::Break`] stops generation after the current token with
+    /// [`FinishReason::Cancelled`].
+    pub fn generate_with_callback(
+        &self,
+        prompt: &[u32],
+        mut callback: impl FnMut(&TokenEvent) -> ControlFlow<()>,
+    ) -> Result<GenerationOutput> {
+        let mut tokens = Vec::new();
+        let mut finish_reason = FinishReason::MaxNewTokens;
+        for event in self.stream(prompt)? {
+            let event = event?;
+            if let Some(reason) = event.finish_reason {
+                finish_reason = reason;
+            }
+            if !matches!(event.finish_reason, Some(FinishReason::StopToken(_))) {
+                tokens.push(event.token);
+            }
+            if callback(&event).is_break() {
+                if event.finish_reason.is_none() {
+                    finish_reason = FinishReason::Cancelled;
+                }
+                break;
+            }
+        }
+        Ok(GenerationOutput {
+            tokens,
+            finish_reason,
+        })
+    }
+
+    /// Prefill `prompt` and return an iterator over the generated tokens
+    pub fn stream<'g>(&'g self, prompt: &[u32]) -> Result<TokenStream<'g, 'a>> {
+        self.config.validate()?;
+        if prompt.is_empty() {
+            return Err(CoreError::InvalidDimension(
+                "Prompt must contain at least one token".to_string(),
+            ));
+        }
+
+        let mut cache = self.model.new_kv_cache();
+        let logits = if self.config.max_new_tokens > 0 {
+            Some(self.prefill(prompt, &mut cache)?)
+        } else {
+            None
+        };
+        Ok(TokenStream {
+            generator: self,
+            sampler: Sampler::new(self.config.clone()),
+            cache,
+            tokens: prompt.to_vec(),
+            prompt_len: prompt.len(),
+            logits,
+            text_len: 0,
+        })
+    }
+
+    /// Continue a stream saved with [`TokenStream::session`]
+    ///
+    /// The generator must use the same model and configuration as the one that produced the
+    /// session; the continuation is then identical to the one the original stream would have
+    /// produced. Sessions whose cache, tokens or logits do not fit the model, or each other,
+    /// are rejected.
+    pub fn resume<'g>(&'g self, session: GenerationSession) -> Result<TokenStream<'g, 'a>> {
+        self.config.validate()?;
+        let config = &self.model.config;
+        session.cache.validate(config)?;
+        // A live stream has fed every token through the cache and holds the next logits
+        let cache_len_ok = match &session.logits {
+            Some(logits) => {
+                logits.shape == [config.vocab_size]
+                    && logits.dtype == DType::F32
+                    && logits.data.len() == config.vocab_size * DType::F32.size_bytes()
+                    && session.cache.len() == session.tokens.len()
+            }
+            None => session.cache.len() <= session.tokens.len(),
+        };
+        if !cache_len_ok
+            || session.tokens.is_empty()
+            || session.prompt_len == 0
+            || session.prompt_len > session.tokens.len()
+        {
+            return Err(CoreError::InvalidDimension(
+                "Generation session does not match the model".to_string(),
+            ));
+        }
+        Ok(TokenStream {
+            generator: self,
+            sampler: Sampler::new(self.config.clone()).with_rng(session.rng),
+            cache: session.cache,
+            tokens: session.tokens,
+            prompt_len: session.prompt_len,
+            logits: session.logits,
+            text_len: session.text_len,
+        })
+    }
+
+    /// Feed `prompt` into an empty `cache`, starting from the longest prefix in the prefix
+    /// cache, and return the logits of its last position
+    fn prefill(&self, prompt: &[u32], cache: &mut KvCache) -> Result<Tensor> {
+        let Some(prefix_cache) = &self.prefix_cache else {
+            return self.last_logits(prompt, cache);
+        };
+        let lock = || {
+            prefix_cache
+                .lock()
+                .map_err(|_| CoreError::Other("Prefix cache lock poisoned".to_string()))
+        };
+        // Keep at least one token to produce logits from
+        if let Some(cached) = lock()?.lookup(&prompt[..prompt.len() - 1]) {
+            *cache = cached;
+        }
+        let logits = self.last_logits(&prompt[cache.len()..], cache)?;
+        lock()?.insert(prompt, cache);
+        Ok(logits)
+    }
+
+    /// Feed `tokens` through the model and return the `[vocab_size]` logits of the last
+    /// position
+    fn last_logits(&self, tokens: &[u32], cache: &mut KvCache) -> Result<Tensor> {
+        let vocab_size = self.model.config.vocab_size;
+        let logits = self.model.forward_with_cache(tokens, cache, &self.device)?;
+        let logits = logits.as_f32_slice()?;
+        Tensor::from_f32(
+            vec![vocab_size],
+            logits[logits.len() - vocab_size..].to_vec(),
+        )
+    }
+}
+
+/// Saved decoding state of a [`TokenStream`]: KV cache, token history and RNG state
+///
+/// Sessions serialize with serde; [`to_bytes`](Self::to_bytes) uses the same bincode format as
+/// [`TransformerModel::save_to_file`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationSession {
+    cache: KvCache,
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    rng: Rng,
+    /// Logits for the next position; `None` once the stream has finished
+    logits: Option<Tensor>,
+    text_len: usize,
+}
+
+impl GenerationSession {
+    /// Prompt followed by every token generated so far
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// Tokens generated so far
+    pub fn generated(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+
+    /// Whether the saved stream had already finished
+    pub fn is_finished(&self) -> bool {
+        self.logits.is_none()
+    }
+
+    /// Encode the session with bincode
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| {
+            CoreError::SerializationError(format!("Failed to serialize session: {}", e))
+        })
+    }
+
+    /// Decode a session written by [`to_bytes`](Self::to_bytes)
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        bincode::deserialize(bytes).map_err(|e| {
+            CoreError::SerializationError(format!("Failed to deserialize session: {}", e))
+        })
+    }
+}
+
+/// Iterator over streamed [`TokenEvent`]s, created by [`Generator::stream`]
+///
+/// Each call to `next` samples one token and runs one incremental forward pass. The stream
+/// ends after the event carrying a finish reason, or after the first error.
+pub struct TokenStream<'g, 'a> {
+    generator: &'g Generator<'a>,
+    sampler: Sampler,
+    cache: KvCache,
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    /// Logits for the next position; `None` once the stream has finished
+    logits: Option<Tensor>,
+    text_len: usize,
+}
+
+impl TokenStream<'_, '_> {
+    /// Prompt followed by every token generated so far
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// Snapshot the decoding state, to be continued later with [`Generator::resume`]
+    pub fn session(&self) -> GenerationSession {
+        GenerationSession {
+            cache: self.cache.clone(),
+            tokens: self.tokens.clone(),
+            prompt_len: self.prompt_len,
+            rng: self.sampler.rng().clone(),
+            logits: self.logits.clone(),
+            text_len: self.text_len,
+        }
+    }
+
+    /// Tokens generated so far
+    pub fn generated(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+
+    fn step(&mut self, mut logits: Tensor) -> Result<TokenEvent> {
+        let config = &self.generator.config;
+        let context = LogitsContext {
+            tokens: &self.tokens,
+            prompt_len: self.prompt_len,
+            max_new_tokens: config.max_new_tokens,
+        };
+        self.generator.processors.process(&mut logits, &context)?;
+        let logits = logits.as_f32_slice()?;
+        let token = self.sampler.sample(logits, &self.tokens)?;
+        let logprob = log_softmax_at(logits, token);
+
+        if config.stop_tokens.contains(&token) {
+            return Ok(TokenEvent {
+                token,
+                text: self.next_text_fragment(true),
+                logprob,
+                finish_reason: Some(FinishReason::StopToken(token)),
+            });
+        }
+
+        self.tokens.push(token);
+        let finish_reason = if self.generated().len() >= config.max_new_tokens {
+            Some(FinishReason::MaxNewTokens)
+        } else if self.tokens.len() >= self.generator.model.config.context_len() {
+            Some(FinishReason::ContextFull)
+        } else {
+            None
+        };
+        let text = self.next_text_fragment(finish_reason.is_some());
+        if finish_reason.is_none() {
+            self.logits = Some(self.generator.last_logits(&[token], &mut self.cache)?);
+        }
+        Ok(TokenEvent {
+            token,
+            text,
+            logprob,
+            finish_reason,
+        })
+    }
+
+    /// Decode everything generated so far and return the part not yet emitted
+    ///
+    /// Decoding the whole continuation keeps multi-token characters intact; the fragment is
+    /// held back until it ends on a character boundary, unless this is the last event and
+    /// the rest has to be flushed.
+    fn next_text_fragment(&mut self, flush: bool) -> String {
+        let Some(decoder) = &self.generator.decoder else {
+            return String::new();
+        };
+        let text = decoder.decode(&self.tokens[self.prompt_len..]);
+        match text.get(self.text_len..) {
+            Some(fragment) if flush || !fragment.ends_with(char::REPLACEMENT_CHARACTER) => {
+                self.text_len = text.len();
+                fragment.to_string()
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+impl Iterator for TokenStream<'_, '_> {
+    type Item = Result<TokenEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let logits = self.logits.take()?;
+        Some(self.step(logits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_cache::LayerKvCache;
+    use crate::test_utils::{random_model, reference_device, small_config};
+
+    fn config(temperature: f32) -> GenerationConfig {
+        GenerationConfig {
+            temperature,
+            ..GenerationConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_greedy_and_filters() {
+        let logits = [1.0, 3.0, 2.0, 0.5];
+        let mut greedy = Sampler::new(GenerationConfig::greedy(1));
+        assert_eq!(greedy.sample(&logits, &[]).unwrap(), 1);
+        let banned = [f32::NEG_INFINITY; 4];
+        assert!(greedy.sample(&banned, &[]).is_err());
+        assert!(Sampler::new(config(1.0)).sample(&banned, &[]).is_err());
+        assert_eq!(greedy.distribution(&banned, &[]), vec![0.0; 4]);
+
+        let top_k = Sampler::new(GenerationConfig {
+            top_k: 2,
+            ..config(1.0)
+        });
+        let ids: Vec<u32> = top_k.candidates(&logits).iter().map(|c| c.0).collect();
+        assert_eq!(ids, vec![1, 2]);
+
+        let top_p = Sampler::new(GenerationConfig {
+            top_p: 0.5,
+            ..config(1.0)
+        });
+        assert_eq!(top_p.candidates(&logits).len(), 1);
+
+        let min_p = Sampler::new(GenerationConfig {
+            min_p: 0.3,
+            ..config(1.0)
+        });
+        let kept = min_p.candidates(&logits);
+        assert_eq!(kept.len(), 2);
+        assert!((kept.iter().map(|c| c.1).sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_penalties() {
+        let sampler = Sampler::new(GenerationConfig {
+            repetition_penalty: 2.0,
+            presence_penalty: 0.5,
+            ..config(1.0)
+        });
+        let mut logits = vec![4.0, -1.0, 1.0];
+        sampler.apply_penalties(&mut logits, &[0, 1, 0]);
+        assert_eq!(logits, vec![1.5, -2.5, 1.0]);
+
+        for presence_penalty in [f32::NAN, f32::NEG_INFINITY] {
+            let config = GenerationConfig {
+                presence_penalty,
+                ..config(1.0)
+            };
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_seeded_sampling_is_reproducible() {
+        let logits = [0.1, 0.2, 0.3, 0.4, 0.5];
+        let draw = |seed| {
+            let mut sampler = Sampler::new(GenerationConfig {
+                seed,
+                ..config(1.0)
+            });
+            (0..32)
+                .map(|_| sampler.sample(&logits, &[]).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draw(7), draw(7));
+        assert_ne!(draw(7), draw(8));
+    }
+
+    #[test]
+    fn test_generate_greedy_matches_full_forward() {
+        let model = random_model(small_config(), 3);
+        let generator = Generator::new(&model, reference_device(), GenerationConfig::greedy(5));
+        let output = generator.generate(&[1, 2, 3]).unwrap();
+        assert_eq!(output.finish_reason, FinishReason::MaxNewTokens);
+        assert_eq!(output.tokens.len(), 5);
+
+        // Each generated token is the argmax of an uncached forward pass over the context
+        let mut context = vec![1, 2, 3];
+        for &token in &output.tokens {
+            let logits = model.forward_cpu(&[context.clone()]).unwrap();
+            let logits = logits.as_f32_slice().unwrap();
+            assert_eq!(argmax(&logits[logits.len() - 11..]), Some(token));
+            context.push(token);
+        }
+    }
+
+    #[test]
+    fn test_generate_stops() {
+        let model = random_model(small_config(), 3);
+        let first = Generator::new(&model, reference_device(), GenerationConfig::greedy(1))
+            .generate(&[1, 2, 3])
+            .unwrap()
+            .tokens[0];
+
+        let stop = GenerationConfig {
+            stop_tokens: vec![first],
+            ..GenerationConfig::greedy(4)
+        };
+        let output = Generator::new(&model, reference_device(), stop)
+            .generate(&[1, 2, 3])
+            .unwrap();
+        assert!(output.tokens.is_empty());
+        assert_eq!(output.finish_reason, FinishReason::StopToken(first));
+
+        let output = Generator::new(&model, reference_device(), GenerationConfig::greedy(100))
+            .generate(&[1, 2, 3])
+            .unwrap();
+        assert_eq!(output.finish_reason, FinishReason::ContextFull);
+        assert_eq!(output.tokens.len(), 16 - 3);
+    }
+
+    #[test]
+    fn test_stream_events() {
+        struct Letters;
+        impl TokenDecoder for Letters {
+            fn decode(&self, tokens: &[u32]) -> String {
+                tokens.iter().map(|&t| (b'a' + t as u8) as char).collect()
+            }
+        }
+
+        let model = random_model(small_config(), 3);
+        let generator = Generator::new(&model, reference_device(), GenerationConfig::greedy(4))
+            .with_decoder(Letters);
+        let events: Vec<TokenEvent> = generator
+            .stream(&[1, 2, 3])
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+
+        let expected = generator.generate(&[1, 2, 3]).unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events.iter().map(|e| e.token).collect::<Vec<_>>(),
+            expected.tokens
+        );
+        assert!(events.iter().all(|e| e.text.len() == 1 && e.logprob <= 0.0));
+        assert!(events[..3].iter().all(|e| e.finish_reason.is_none()));
+        assert_eq!(events[3].finish_reason, Some(FinishReason::MaxNewTokens));
+    }
+
+    #[test]
+    fn test_stream_flushes_incomplete_characters() {
+        // Every token is the first byte of a two-byte character, so no fragment ever ends on
+        // a character boundary
+        struct LeadBytes;
+        impl TokenDecoder for LeadBytes {
+            fn decode(&self, tokens: &[u32]) -> String {
+                String::from_utf8_lossy(&vec![0xC3; tokens.len()]).into_owned()
+            }
+        }
+
+        let model = random_model(small_config(), 3);
+        let greedy = Generator::new(&model, reference_device(), GenerationConfig::greedy(3))
+            .generate(&[1, 2, 3])
+            .unwrap();
+        let stopped = GenerationConfig {
+            stop_tokens: vec![greedy.tokens[2]],
+            ..GenerationConfig::greedy(8)
+        };
+        for config in [GenerationConfig::greedy(3), stopped] {
+            let events: Vec<TokenEvent> = Generator::new(&model, reference_device(), config)
+                .with_decoder(LeadBytes)
+                .stream(&[1, 2, 3])
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            let (last, held) = events.split_last().unwrap();
+            assert!(last.finish_reason.is_some());
+            assert!(held.iter().all(|e| e.text.is_empty()));
+            let emitted = if matches!(last.finish_reason, Some(FinishReason::StopToken(_))) {
+                held.len()
+            } else {
+                events.len()
+            };
+            assert_eq!(last.text, LeadBytes.decode(&vec![0; emitted]));
+        }
+    }
+
+    #[test]
+    fn test_callback_cancellation() {
+        let model = random_model(small_config(), 3);
+        let generator = Generator::new(&model, reference_device(), GenerationConfig::greedy(8));
+        let mut seen = 0;
+        let output = generator
+            .generate_with_callback(&[1, 2, 3], |_| {
+                seen += 1;
+                if seen == 2 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .unwrap();
+        assert_eq!(output.tokens.len(), 2);
+        assert_eq!(output.finish_reason, FinishReason::Cancelled);
+    }
+
+    #[test]
+    fn test_generate_runs_processors() {
+        use crate::logits_processor::{BannedTokens, ForcedBos};
+
+        let model = random_model(small_config(), 3);
+        let free = Generator::new(&model, reference_device(), GenerationConfig::greedy(3))
+            .generate(&[1, 2, 3])
+            .unwrap();
+
+        let output = Generator::new(&model, reference_device(), GenerationConfig::greedy(3))
+            .with_processor(ForcedBos::new(7))
+            .with_processor(BannedTokens::new(free.tokens.clone()))
+            .generate(&[1, 2, 3])
+            .unwrap();
+        assert_eq!(output.tokens[0], 7);
+        assert!(output.tokens.iter().all(|t| !free.tokens.contains(t)));
+    }
+
+    #[test]
+    fn test_prefix_cache_reuses_shared_prompt() {
+        let model = random_model(small_config(), 9);
+        let cache = Arc::new(Mutex::new(PrefixCache::new(1 << 20)));
+        let config = GenerationConfig {
+            max_new_tokens: 4,
+            seed: 11,
+            ..GenerationConfig::default()
+        };
+        let system = [4, 8, 5, 6];
+        for user in [vec![10, 2], vec![10, 7, 1], vec![9]] {
+            let prompt = [&system[..], &user].concat();
+            let expected = Generator::new(&model, reference_device(), config.clone())
+                .generate(&prompt)
+                .unwrap();
+            let cached = Generator::new(&model, reference_device(), config.clone())
+                .with_prefix_cache(cache.clone())
+                .generate(&prompt)
+                .unwrap();
+            assert_eq!(cached, expected);
+        }
+
+        let stats = cache.lock().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert_eq!(stats.reused_tokens, 5 + 4);
+    }
+
+    #[test]
+    fn test_resumed_session_is_bit_identical() {
+        let model = random_model(small_config(), 13);
+        let config = GenerationConfig {
+            max_new_tokens: 8,
+            top_k: 6,
+            seed: 99,
+            ..GenerationConfig::default()
+        };
+        let generator = Generator::new(&model, reference_device(), config.clone());
+        let expected: Vec<TokenEvent> = generator
+            .stream(&[3, 1, 4])
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+
+        let mut stream = generator.stream(&[3, 1, 4]).unwrap();
+        let mut events: Vec<TokenEvent> = stream.by_ref().take(3).collect::<Result<_>>().unwrap();
+        let bytes = stream.session().to_bytes().unwrap();
+        drop(stream);
+
+        let session = GenerationSession::from_bytes(&bytes).unwrap();
+        assert_eq!(session.generated().len(), 3);
+        let resumed = Generator::new(&model, reference_device(), config);
+        events.extend(resumed.resume(session).unwrap().map(|event| event.unwrap()));
+        assert_eq!(events.len(), expected.len());
+        for (actual, expected) in events.iter().zip(&expected) {
+            assert_eq!(actual.token, expected.token);
+            assert_eq!(actual.logprob.to_bits(), expected.logprob.to_bits());
+            assert_eq!(actual.finish_reason, expected.finish_reason);
+        }
+
+        let other = random_model(
+            crate::transformer::TransformerConfig {
+                n_layers: 1,
+                ..small_config()
+            },
+            13,
+        );
+        let session = GenerationSession::from_bytes(&bytes).unwrap();
+        assert!(
+            Generator::new(&other, reference_device(), GenerationConfig::default())
+                .resume(session)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_tampered_session_is_rejected() {
+        let model = random_model(small_config(), 13);
+        let generator = Generator::new(&model, reference_device(), GenerationConfig::greedy(8));
+        let mut stream = generator.stream(&[3, 1, 4]).unwrap();
+        stream.next().unwrap().unwrap();
+        let session = stream.session();
+        drop(stream);
+        assert!(generator.resume(session.clone()).is_ok());
+
+        let tamper = |edit: &dyn Fn(&mut GenerationSession)| {
+            let mut tampered = GenerationSession::from_bytes(&session.to_bytes().unwrap()).unwrap();
+            edit(&mut tampered);
+            let bytes = tampered.to_bytes().unwrap();
+            generator.resume(GenerationSession::from_bytes(&bytes).unwrap())
+        };
+        // One layer lags behind the others
+        assert!(tamper(&|s| s.cache.layers_mut()[1].truncate(2)).is_err());
+        // A layer built for a sliding window the model does not have
+        assert!(tamper(&|s| {
+            let layer = LayerKvCache::new(&model.config).with_window(Some(2));
+            s.cache.layers_mut()[0] = layer;
+        })
+        .is_err());
+        // The cache no longer covers every token
+        assert!(tamper(&|s| s.tokens.push(5)).is_err());
+        assert!(tamper(&|s| {
+            s.logits = Some(Tensor::from_f32(vec![3], vec![0.0; 3]).unwrap());
+        })
+        .is_err());
+        assert!(tamper(&|s| s.prompt_len = 0).is_err());
+    }
+}
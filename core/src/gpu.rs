@@ -20,22 +20,72 @@ pub struct GpuTensor {
 }
 
 /// Kernel type enumeration for common operations
+///
+/// Each variant documents its input order and `params` layout. The CPU backend implements
+/// them with the reference math in [`crate::ops`].
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KernelType {
     /// Matrix multiplication (GEMM)
+    ///
+    /// Inputs: `[a, b]` or `[a, b, bias]` with `a` `[.., m, k]`, `b` `[k, n]` and an optional
+    /// `[n]` bias added to every output row. Params: `[transpose_b]` (optional); when non-zero
+    /// `b` is `[n, k]` and the kernel computes `a @ bᵀ`.
     MatMul,
     /// Layer normalization
+    ///
+    /// Inputs: `[x, gamma, beta]`. Params: `[epsilon]`.
     LayerNorm,
-    /// Softmax activation
+    /// Softmax activation over the last dimension
+    ///
+    /// Inputs: `[x]`.
     Softmax,
     /// GELU activation
+    ///
+    /// Inputs: `[x]`.
     Gelu,
     /// Fused GEMM + GELU
+    ///
+    /// Inputs: `[x, w]` or `[x, w, bias]`, computes `gelu(x @ w + bias)`.
     FusedGemmGelu,
     /// Fused GEMM + LayerNorm
+    ///
+    /// Inputs: `[x, w, gamma, beta]`, computes `layer_norm(x @ w)`. Params: `[epsilon]`.
     FusedGemmLayerNorm,
     /// Attention kernel (fused Q, K, V computation)
+    ///
+    /// Inputs: projected `[q, k, v]` or `[q, k, v, mask]`, `q` `[.., seq_q, d]` and `k`/`v`
+    /// `[.., seq_k, kv_d]`. The optional `mask` is `[batch, seq_k]` with 1 for real tokens and
+    /// 0 for padding. Params: `[n_heads, causal, paged, alibi, n_kv_heads, window]`; `causal`
+    /// defaults to 1. A non-zero `alibi` adds per-head linear distance biases to the scores,
+    /// with slopes derived from `n_heads` inside the kernel. `kv_d` holds `n_kv_heads` (default
+    /// `n_heads`) heads, each shared by `n_heads / n_kv_heads` consecutive query heads. A
+    /// non-zero `window` hides keys `window` or more positions away from the query (sliding
+    /// window attention).
+    ///
+    /// With a non-zero `paged` the keys and values are read through block tables instead:
+    /// inputs are `[q, k_blocks, v_blocks, block_tables, context_lens]` with `q`
+    /// `[batch, seq_q, d]`, block pools `[num_blocks, block_size, kv_d]`, `block_tables`
+    /// `[batch, max_blocks]` block ids and `context_lens` `[batch]`, both stored as `f32`.
     Attention,
+    /// Elementwise addition (residual connections)
+    ///
+    /// Inputs: `[a, b]` with identical shapes.
+    Add,
+    /// Rotary position embedding (RoPE) on projected queries or keys
+    ///
+    /// Inputs: `[x, positions]` with `x` `[.., seq, d]` and one position per row of `x`
+    /// (`[batch, seq]`, stored as `f32`). Params: `[n_heads, base, position_scale]`; positions
+    /// are multiplied by `position_scale` (default 1) before rotating.
+    Rope,
+    /// RMS normalization (no mean subtraction, no bias)
+    ///
+    /// Inputs: `[x, gamma]`. Params: `[epsilon]`.
+    RmsNorm,
+    /// SiLU activation, optionally gating a second tensor (SwiGLU)
+    ///
+    /// Inputs: `[x]` computes `silu(x)`; `[x, up]` with identical shapes computes
+    /// `silu(x) * up`.
+    Silu,
 }
 
 /// Kernel configuration and parameters
@@ -0,0 +1,506 @@
+//! Key/value cache for incremental autoregressive decoding
+
+use crate::error::{CoreError, Result};
+use crate::tensor::{DType, Tensor};
+use crate::transformer::TransformerConfig;
+use serde::{Deserialize, Serialize};
+
+/// Cached attention keys and values for one transformer layer and one sequence
+///
+/// Keys and values are stored row-major as `[len, n_heads * head_dim]`, so attending over the
+/// cache is a plain slice of the stored rows. `n_heads` counts key/value heads, which is fewer
+/// than the query heads with grouped-query attention. Capacity is `max_seq_len` positions.
+///
+/// With a sliding window (see [`with_window`](Self::with_window)) the cache is a rolling
+/// buffer: before new rows are appended, positions that no later query can see are evicted,
+/// so it holds at most `window - 1` positions plus the rows of the latest append. Evicted rows
+/// are only compacted away once the buffer is full, which happens once per `window` appended
+/// positions, so the buffer stays at `2 * window` rows instead of `max_seq_len`.
+///
+/// Decoder layers of an encoder-decoder model also keep the cross-attention keys and values
+/// of their input (see [`set_cross_attention`](Self::set_cross_attention)), projected once
+/// from the encoder output and reused for every decoded position.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerKvCache {
+    /// Stored rows, after evicted rows not yet compacted away
+    keys: Vec<f32>,
+    values: Vec<f32>,
+    len: usize,
+    /// Position of the first stored row
+    start: usize,
+    window: Option<usize>,
+    /// Cross-attention keys and values, each `[src_len, width]`
+    cross: Option<(Tensor, Tensor)>,
+    max_seq_len: usize,
+    n_heads: usize,
+    head_dim: usize,
+}
+
+impl LayerKvCache {
+    /// Create an empty cache sized for the given configuration
+    pub fn new(config: &TransformerConfig) -> Self {
+        Self::with_buffer(config, None)
+    }
+
+    /// Create an empty cache for layer `layer`, rolling over the layer's sliding window if it
+    /// has one
+    pub fn for_layer(config: &TransformerConfig, layer: usize) -> Self {
+        Self::with_buffer(config, config.attention_window(layer))
+    }
+
+    fn with_buffer(config: &TransformerConfig, window: Option<usize>) -> Self {
+        let rows = Self::buffer_rows(window, config.max_seq_len);
+        let width = config.kv_width();
+        Self {
+            keys: Vec::with_capacity(rows * width),
+            values: Vec::with_capacity(rows * width),
+            len: 0,
+            start: 0,
+            window,
+            cross: None,
+            max_seq_len: config.max_seq_len,
+            n_heads: config.kv_heads(),
+            head_dim: config.head_dim(),
+        }
+    }
+
+    /// Rows reserved up front: the window plus as many again before evicted rows are
+    /// compacted, or every position without a window
+    fn buffer_rows(window: Option<usize>, max_seq_len: usize) -> usize {
+        window.map_or(max_seq_len, |window| {
+            window.saturating_mul(2).min(max_seq_len)
+        })
+    }
+
+    /// Evict positions outside a sliding attention window of `window` positions
+    ///
+    /// The buffers of an empty cache are resized for the window.
+    pub fn with_window(mut self, window: Option<usize>) -> Self {
+        self.window = window;
+        if self.is_empty() {
+            let rows = Self::buffer_rows(window, self.max_seq_len) * self.width();
+            self.keys = Vec::with_capacity(rows);
+            self.values = Vec::with_capacity(rows);
+        }
+        self
+    }
+
+    /// Sliding attention window the cache rolls over, if any
+    pub fn window(&self) -> Option<usize> {
+        self.window
+    }
+
+    /// Number of positions appended so far (the position of the next one)
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Number of positions still stored, the last `stored_len()` of [`len`](Self::len)
+    pub fn stored_len(&self) -> usize {
+        self.len - self.start
+    }
+
+    /// Check if the cache holds no positions
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Maximum number of positions the cache can store
+    pub fn capacity(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Number of key/value heads stored per position
+    pub fn n_heads(&self) -> usize {
+        self.n_heads
+    }
+
+    /// Dimension of each attention head
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+    }
+
+    /// Width of one cached row (`n_heads * head_dim`)
+    pub fn width(&self) -> usize {
+        self.n_heads * self.head_dim
+    }
+
+    /// Append keys and values for new positions; both are `[.., seq_len, width]`
+    pub fn append(&mut self, keys: &Tensor, values: &Tensor) -> Result<()> {
+        let width = self.width();
+        if keys.shape != values.shape || keys.shape.last() != Some(&width) {
+            return Err(CoreError::ShapeMismatch {
+                expected: vec![keys.numel() / width.max(1), width],
+                actual: keys.shape.clone(),
+            });
+        }
+        let new_rows = keys.numel() / width;
+        let start = self.first_needed(self.len).max(self.start);
+        if self.len + new_rows - start > self.max_seq_len {
+            return Err(CoreError::InvalidDimension(format!(
+                "KV cache overflow: {} positions exceed max_seq_len {}",
+                self.len + new_rows - start,
+                self.max_seq_len
+            )));
+        }
+        self.start = start;
+        // Compact only when the new rows would not fit, so the memmove is amortized over the
+        // rows appended since the last one
+        let evicted = self.evicted_rows() * width;
+        if evicted > 0 && self.keys.len() + new_rows * width > self.keys.capacity() {
+            self.keys.drain(..evicted);
+            self.values.drain(..evicted);
+        }
+        self.keys.extend_from_slice(keys.as_f32_slice()?);
+        self.values.extend_from_slice(values.as_f32_slice()?);
+        self.len += new_rows;
+        Ok(())
+    }
+
+    /// Rows at the front of the buffers that were evicted but not yet compacted away
+    fn evicted_rows(&self) -> usize {
+        self.keys.len() / self.width().max(1) - self.stored_len()
+    }
+
+    /// First position a query at `pos` can see
+    fn first_needed(&self, pos: usize) -> usize {
+        self.window
+            .map_or(0, |window| pos.saturating_sub(window.saturating_sub(1)))
+    }
+
+    /// Check that the cache was built for layer `layer` of `config` and that its buffers hold
+    /// every stored position, as they may not after deserializing untrusted bytes
+    pub(crate) fn validate(&self, config: &TransformerConfig, layer: usize) -> Result<()> {
+        if self.n_heads != config.kv_heads()
+            || self.head_dim != config.head_dim()
+            || self.window != config.attention_window(layer)
+            || self.max_seq_len != config.max_seq_len
+        {
+            return Err(CoreError::InvalidDimension(format!(
+                "KV cache of layer {} does not match the model",
+                layer
+            )));
+        }
+        let width = self.width();
+        if self.start > self.len
+            || self.stored_len() > self.max_seq_len
+            || self.keys.len() != self.values.len()
+            || self.keys.len() % width != 0
+            || self.keys.len() < self.stored_len() * width
+        {
+            return Err(CoreError::InvalidDimension(format!(
+                "KV cache of layer {} holds {} values for positions {}..{}",
+                layer,
+                self.keys.len(),
+                self.start,
+                self.len
+            )));
+        }
+        if let Some((keys, values)) = &self.cross {
+            let src_len = keys.shape.first().copied().unwrap_or(0);
+            for tensor in [keys, values] {
+                if tensor.shape != [src_len, width]
+                    || tensor.dtype != DType::F32
+                    || tensor.data.len() != src_len * width * DType::F32.size_bytes()
+                {
+                    return Err(CoreError::ShapeMismatch {
+                        expected: vec![src_len, width],
+                        actual: tensor.shape.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the cross-attention keys and values of the encoded input, both `[src_len, width]`
+    pub fn set_cross_attention(&mut self, keys: Tensor, values: Tensor) -> Result<()> {
+        let width = self.width();
+        let src_len = keys.shape.first().copied().unwrap_or(0).max(1);
+        for tensor in [&keys, &values] {
+            if tensor.shape != [src_len, width] {
+                return Err(CoreError::ShapeMismatch {
+                    expected: vec![src_len, width],
+                    actual: tensor.shape.clone(),
+                });
+            }
+        }
+        self.cross = Some((keys, values));
+        Ok(())
+    }
+
+    /// Cross-attention keys and values of the encoded input, if any
+    pub fn cross_attention(&self) -> Option<(&Tensor, &Tensor)> {
+        self.cross.as_ref().map(|(keys, values)| (keys, values))
+    }
+
+    /// Stored keys as a `[stored_len, width]` tensor
+    pub fn keys(&self) -> Result<Tensor> {
+        let evicted = self.evicted_rows() * self.width();
+        Tensor::from_f32(
+            vec![self.stored_len(), self.width()],
+            self.keys[evicted..].to_vec(),
+        )
+    }
+
+    /// Stored values as a `[stored_len, width]` tensor
+    pub fn values(&self) -> Result<Tensor> {
+        let evicted = self.evicted_rows() * self.width();
+        Tensor::from_f32(
+            vec![self.stored_len(), self.width()],
+            self.values[evicted..].to_vec(),
+        )
+    }
+
+    /// Drop every cached position, keeping the cross-attention keys and values
+    pub fn reset(&mut self) {
+        self.keys.clear();
+        self.values.clear();
+        self.len = 0;
+        self.start = 0;
+    }
+
+    /// Check that [`truncate`](Self::truncate) to `len` keeps every position the next query
+    /// can see, which fails once a rolling cache has evicted them
+    pub fn can_truncate(&self, len: usize) -> bool {
+        len >= self.len || self.start <= self.first_needed(len)
+    }
+
+    /// Keep only the first `len` positions (no-op if the cache is already shorter)
+    ///
+    /// A rolling cache that already evicted positions the next query needs is reset instead,
+    /// so callers should continue from [`len`](Self::len) rather than assume `len` positions.
+    pub fn truncate(&mut self, len: usize) {
+        if !self.can_truncate(len) {
+            self.reset();
+        } else if len < self.len {
+            let kept = (self.evicted_rows() + len - self.start) * self.width();
+            self.keys.truncate(kept);
+            self.values.truncate(kept);
+            self.len = len;
+        }
+    }
+}
+
+/// Per-layer key/value caches for decoding one sequence with a whole model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvCache {
+    layers: Vec<LayerKvCache>,
+    max_seq_len: usize,
+}
+
+impl KvCache {
+    /// Create an empty cache with one [`LayerKvCache`] per model layer, rolling over the
+    /// layer's sliding window if it has one
+    pub fn new(config: &TransformerConfig) -> Self {
+        Self {
+            layers: (0..config.n_layers)
+                .map(|layer| LayerKvCache::for_layer(config, layer))
+                .collect(),
+            max_seq_len: config.max_seq_len,
+        }
+    }
+
+    /// Number of cached positions (the position of the next token)
+    pub fn len(&self) -> usize {
+        self.layers.first().map_or(0, LayerKvCache::len)
+    }
+
+    /// Check if the cache holds no positions
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of positions each layer can store
+    pub fn capacity(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Bytes held by the cached keys and values of all layers, including cross-attention
+    pub fn memory_bytes(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| {
+                let cross_len = layer.cross.as_ref().map_or(0, |(keys, _)| keys.shape[0]);
+                2 * (layer.stored_len() + cross_len) * layer.width() * std::mem::size_of::<f32>()
+            })
+            .sum()
+    }
+
+    /// Check that every layer matches `config` and that all layers hold the same positions,
+    /// see [`LayerKvCache::validate`]
+    pub(crate) fn validate(&self, config: &TransformerConfig) -> Result<()> {
+        if self.layers.len() != config.n_layers || self.max_seq_len != config.max_seq_len {
+            return Err(CoreError::InvalidDimension(
+                "KV cache does not match the model".to_string(),
+            ));
+        }
+        for (layer, cache) in self.layers.iter().enumerate() {
+            cache.validate(config, layer)?;
+            if cache.len() != self.len() {
+                return Err(CoreError::InvalidDimension(format!(
+                    "KV cache layers out of step: layer {} holds {} positions, layer 0 holds {}",
+                    layer,
+                    cache.len(),
+                    self.len()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Per-layer caches
+    pub fn layers(&self) -> &[LayerKvCache] {
+        &self.layers
+    }
+
+    /// Mutable per-layer caches
+    pub fn layers_mut(&mut self) -> &mut [LayerKvCache] {
+        &mut self.layers
+    }
+
+    /// Drop every cached position in all layers, keeping cross-attention keys and values
+    pub fn reset(&mut self) {
+        self.layers.iter_mut().for_each(LayerKvCache::reset);
+    }
+
+    /// Check that [`truncate`](Self::truncate) to `len` keeps every layer's positions, see
+    /// [`LayerKvCache::can_truncate`]
+    pub fn can_truncate(&self, len: usize) -> bool {
+        self.layers.iter().all(|layer| layer.can_truncate(len))
+    }
+
+    /// Whether any layer rolls over a sliding window, so that truncation may reset it
+    pub fn is_rolling(&self) -> bool {
+        self.layers.iter().any(|layer| layer.window.is_some())
+    }
+
+    /// Keep only the first `len` positions in all layers
+    ///
+    /// If a rolling layer cannot go back that far (see [`LayerKvCache::truncate`]) every layer
+    /// is reset, keeping their lengths in step.
+    pub fn truncate(&mut self, len: usize) {
+        if self.can_truncate(len) {
+            self.layers.iter_mut().for_each(|layer| layer.truncate(len));
+        } else {
+            self.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::small_config;
+
+    #[test]
+    fn test_append_truncate_reset() {
+        let config = small_config();
+        let mut cache = LayerKvCache::new(&config);
+        assert_eq!(cache.width(), 8);
+        assert_eq!(cache.head_dim(), 4);
+
+        let rows = Tensor::from_f32(vec![3, 8], (0..24).map(|i| i as f32).collect()).unwrap();
+        cache.append(&rows, &rows).unwrap();
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.keys().unwrap().shape, vec![3, 8]);
+
+        cache.truncate(1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            cache.values().unwrap().as_f32_slice().unwrap(),
+            &rows.as_f32_slice().unwrap()[..8]
+        );
+
+        cache.reset();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_rolling_window() {
+        let config = small_config();
+        let mut cache = LayerKvCache::new(&config).with_window(Some(3));
+        let row = |i: usize| Tensor::from_f32(vec![1, 8], vec![i as f32; 8]).unwrap();
+
+        // Far more positions than max_seq_len, but only the window is ever stored
+        for i in 0..40 {
+            cache.append(&row(i), &row(i)).unwrap();
+            assert!(cache.stored_len() <= 3);
+        }
+        assert_eq!(cache.len(), 40);
+        // Positions 37..40 are still stored; the next query needs 38 and 39
+        assert_eq!(
+            cache.keys().unwrap().as_f32_slice().unwrap()[..8],
+            [37.0; 8]
+        );
+
+        // The buffers never grow past twice the window, whatever the sequence length
+        assert!(cache.keys.capacity() <= 6 * 8 && cache.values.capacity() <= 6 * 8);
+        assert!(LayerKvCache::new(&config).keys.capacity() >= 16 * 8);
+
+        // Rolling back one position keeps what the next query needs, further does not
+        cache.truncate(39);
+        assert_eq!((cache.len(), cache.stored_len()), (39, 2));
+        assert!(!cache.can_truncate(36));
+        cache.truncate(36);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cross_attention_survives_reset() {
+        let config = small_config();
+        let mut cache = LayerKvCache::new(&config);
+        let wrong = Tensor::from_f32(vec![5, 4], vec![0.0; 20]).unwrap();
+        assert!(cache.set_cross_attention(wrong.clone(), wrong).is_err());
+
+        let encoded = Tensor::from_f32(vec![5, 8], vec![1.0; 40]).unwrap();
+        cache.set_cross_attention(encoded.clone(), encoded).unwrap();
+        let rows = Tensor::from_f32(vec![2, 8], vec![0.0; 16]).unwrap();
+        cache.append(&rows, &rows).unwrap();
+        cache.reset();
+        assert!(cache.is_empty());
+        assert_eq!(cache.cross_attention().unwrap().0.shape, vec![5, 8]);
+    }
+
+    #[test]
+    fn test_validate_rejects_inconsistent_layers() {
+        let config = small_config();
+        let mut cache = KvCache::new(&config);
+        let rows = Tensor::from_f32(vec![3, 8], vec![0.0; 24]).unwrap();
+        for layer in cache.layers_mut() {
+            layer.append(&rows, &rows).unwrap();
+        }
+        cache.validate(&config).unwrap();
+
+        // Three positions but a single stored row
+        let mut short = cache.clone();
+        short.layers[1].keys.truncate(8);
+        short.layers[1].values.truncate(8);
+        assert!(short.validate(&config).is_err());
+
+        let mut ahead = cache.clone();
+        ahead.layers[0].start = 4;
+        assert!(ahead.validate(&config).is_err());
+
+        let mut uneven = cache.clone();
+        uneven.layers[1].keys.pop();
+        assert!(uneven.validate(&config).is_err());
+
+        let mut out_of_step = cache.clone();
+        out_of_step.layers[1].truncate(2);
+        assert!(out_of_step.validate(&config).is_err());
+
+        let mut windowed = cache;
+        windowed.layers[0].window = Some(2);
+        assert!(windowed.validate(&config).is_err());
+    }
+
+    #[test]
+    fn test_overflow_is_rejected() {
+        let config = small_config();
+        let mut cache = LayerKvCache::new(&config);
+        let rows = Tensor::from_f32(vec![17, 8], vec![0.0; 17 * 8]).unwrap();
+        assert!(cache.append(&rows, &rows).is_err());
+        assert!(cache.is_empty());
+    }
+}
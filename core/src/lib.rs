@@ -3,19 +3,66 @@
 //! This crate provides the core abstractions for the CrossGPU Tiny Transformer engine:
 //! - Tensor data structures and operations
 //! - Transformer layer interfaces
+//! - Batched inference over padded, variable-length sequences
+//! - Continuous batching scheduler for concurrent generation requests
+//! - Key/value caching for incremental decoding, contiguous or paged with copy-on-write blocks
+//! - Prompt prefix caching that reuses KV state across requests
+//! - Text generation with configurable sampling, beam search and speculative decoding
+//! - Pooled sentence embeddings from causal or bidirectional models
+//! - Sequence and token classification heads
+//! - Mixture-of-experts feed-forward layers with top-k routing
+//! - Vision Transformers over patch-embedded images
 //! - Quantization support (8-bit, 4-bit)
 //! - GPU device abstraction trait
 
 #![deny(warnings)]
 #![deny(missing_docs)]
 
+pub mod batch;
+pub mod beam_search;
+pub mod classification;
+pub mod embeddings;
 pub mod error;
+pub mod generation;
 pub mod gpu;
+pub mod kv_cache;
+pub mod logits_processor;
+pub mod moe;
+pub mod ops;
+pub mod paged_kv_cache;
+pub mod prefix_cache;
 pub mod quantization;
+pub mod scheduler;
+pub mod speculative;
 pub mod tensor;
 pub mod transformer;
+pub mod vision;
 
+#[cfg(any(test, feature = "test-utils"))]
+#[doc(hidden)]
+pub mod test_utils;
+
+pub use batch::{PaddedBatch, PaddingSide};
+pub use beam_search::{BeamHypothesis, BeamSearch, BeamSearchConfig};
+pub use classification::{ClassificationHead, Prediction, SequenceClassifier};
+pub use embeddings::Pooling;
 pub use error::{CoreError, Result};
+pub use generation::{
+    GenerationConfig, GenerationOutput, GenerationSession, Generator, TokenEvent, TokenStream,
+};
 pub use gpu::{GpuDevice, GpuTensor, Kernel};
+pub use kv_cache::{KvCache, LayerKvCache};
+pub use logits_processor::{LogitsProcessor, LogitsProcessorList};
+pub use moe::RouterStats;
+pub use paged_kv_cache::{BlockAllocator, BlockTable, PagedKvCache};
+pub use prefix_cache::{PrefixCache, PrefixCacheStats};
+pub use scheduler::{
+    CompletedRequest, FailedRequest, RequestId, ScheduledToken, Scheduler, SchedulerConfig,
+};
+pub use speculative::{SpeculativeConfig, SpeculativeDecoder, SpeculativeOutput, SpeculativeStats};
 pub use tensor::Tensor;
-pub use transformer::{TransformerConfig, TransformerLayer};
+pub use transformer::{
+    AttentionMode, BlockTopology, FeedForwardType, MoeConfig, NormType, PositionEncoding,
+    RopeScaling, TransformerConfig, TransformerLayer, VisionConfig,
+};
+pub use vision::PatchEmbedding;
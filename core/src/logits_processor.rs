@@ -0,0 +1,285 @@
+//! Pluggable logits processors applied before sampling
+//!
+//! Processors edit the downloaded next-token logits in place, so they behave identically
+//! whichever [`GpuDevice`](crate::gpu::GpuDevice) produced them.
+
+use crate::error::{CoreError, Result};
+use crate::tensor::Tensor;
+use std::collections::HashMap;
+
+/// Decoding state visible to a [`LogitsProcessor`]
+#[derive(Debug, Clone, Copy)]
+pub struct LogitsContext<'a> {
+    /// Every token so far: the prompt followed by the generated tokens
+    pub tokens: &'a [u32],
+    /// Number of prompt tokens at the start of `tokens`
+    pub prompt_len: usize,
+    /// Generation budget from the [`GenerationConfig`](crate::generation::GenerationConfig)
+    pub max_new_tokens: usize,
+}
+
+impl LogitsContext<'_> {
+    /// Tokens generated so far
+    pub fn generated(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+}
+
+/// Transformation of next-token logits, run before sampling
+pub trait LogitsProcessor {
+    /// Modify `logits` (`[vocab_size]`) in place
+    fn process(&self, logits: &mut Tensor, context: &LogitsContext) -> Result<()>;
+}
+
+/// Ordered chain of processors, itself usable as a processor
+#[derive(Default)]
+pub struct LogitsProcessorList {
+    processors: Vec<Box<dyn LogitsProcessor>>,
+}
+
+impl LogitsProcessorList {
+    /// Create an empty chain
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a processor to the end of the chain
+    pub fn push(&mut self, processor: impl LogitsProcessor + 'static) {
+        self.processors.push(Box::new(processor));
+    }
+
+    /// Number of processors in the chain
+    pub fn len(&self) -> usize {
+        self.processors.len()
+    }
+
+    /// Check if the chain is empty
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+}
+
+impl LogitsProcessor for LogitsProcessorList {
+    fn process(&self, logits: &mut Tensor, context: &LogitsContext) -> Result<()> {
+        self.processors
+            .iter()
+            .try_for_each(|processor| processor.process(logits, context))
+    }
+}
+
+/// Borrow logits as a mutable slice, checking that the token is in range
+fn logit_mut(logits: &mut [f32], token: u32) -> Result<&mut f32> {
+    let vocab_size = logits.len();
+    logits.get_mut(token as usize).ok_or_else(|| {
+        CoreError::InvalidDimension(format!(
+            "Token id {} out of range for vocabulary of {}",
+            token, vocab_size
+        ))
+    })
+}
+
+/// Make `token` the only possible choice
+fn force_token(logits: &mut Tensor, token: u32) -> Result<()> {
+    let logits = logits.as_f32_slice_mut()?;
+    logit_mut(logits, token)?;
+    for (i, logit) in logits.iter_mut().enumerate() {
+        *logit = if i == token as usize {
+            0.0
+        } else {
+            f32::NEG_INFINITY
+        };
+    }
+    Ok(())
+}
+
+/// Never sample any of the given tokens
+#[derive(Debug, Clone)]
+pub struct BannedTokens {
+    tokens: Vec<u32>,
+}
+
+impl BannedTokens {
+    /// Ban the given token ids
+    pub fn new(tokens: Vec<u32>) -> Self {
+        Self { tokens }
+    }
+}
+
+impl LogitsProcessor for BannedTokens {
+    fn process(&self, logits: &mut Tensor, _context: &LogitsContext) -> Result<()> {
+        let logits = logits.as_f32_slice_mut()?;
+        for &token in &self.tokens {
+            *logit_mut(logits, token)? = f32::NEG_INFINITY;
+        }
+        Ok(())
+    }
+}
+
+/// Add a fixed bias to the logits of selected tokens
+#[derive(Debug, Clone)]
+pub struct LogitBias {
+    bias: HashMap<u32, f32>,
+}
+
+impl LogitBias {
+    /// Bias each token id by the mapped value
+    pub fn new(bias: HashMap<u32, f32>) -> Self {
+        Self { bias }
+    }
+}
+
+impl LogitsProcessor for LogitBias {
+    fn process(&self, logits: &mut Tensor, _context: &LogitsContext) -> Result<()> {
+        let logits = logits.as_f32_slice_mut()?;
+        for (&token, &bias) in &self.bias {
+            *logit_mut(logits, token)? += bias;
+        }
+        Ok(())
+    }
+}
+
+/// Force a beginning-of-sequence token as the first generated token
+#[derive(Debug, Clone, Copy)]
+pub struct ForcedBos {
+    token: u32,
+}
+
+impl ForcedBos {
+    /// Force `token` at the first generation step
+    pub fn new(token: u32) -> Self {
+        Self { token }
+    }
+}
+
+impl LogitsProcessor for ForcedBos {
+    fn process(&self, logits: &mut Tensor, context: &LogitsContext) -> Result<()> {
+        if context.generated().is_empty() {
+            force_token(logits, self.token)?;
+        }
+        Ok(())
+    }
+}
+
+/// Force an end-of-sequence token as the last token the budget allows
+#[derive(Debug, Clone, Copy)]
+pub struct ForcedEos {
+    token: u32,
+}
+
+impl ForcedEos {
+    /// Force `token` when only one token of `max_new_tokens` remains
+    pub fn new(token: u32) -> Self {
+        Self { token }
+    }
+}
+
+impl LogitsProcessor for ForcedEos {
+    fn process(&self, logits: &mut Tensor, context: &LogitsContext) -> Result<()> {
+        if context.generated().len() + 1 >= context.max_new_tokens {
+            force_token(logits, self.token)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ban any token that would repeat an n-gram already present in the sequence
+#[derive(Debug, Clone, Copy)]
+pub struct NoRepeatNgram {
+    n: usize,
+}
+
+impl NoRepeatNgram {
+    /// Block repeated n-grams of size `n` (values below 1 are treated as 1)
+    pub fn new(n: usize) -> Self {
+        Self { n: n.max(1) }
+    }
+}
+
+impl LogitsProcessor for NoRepeatNgram {
+    fn process(&self, logits: &mut Tensor, context: &LogitsContext) -> Result<()> {
+        let tokens = context.tokens;
+        if tokens.len() + 1 < self.n {
+            return Ok(());
+        }
+        // The last n-1 tokens, which the next token would extend into an n-gram
+        let prefix = &tokens[tokens.len() + 1 - self.n..];
+        let logits = logits.as_f32_slice_mut()?;
+        for window in tokens.windows(self.n) {
+            if &window[..self.n - 1] == prefix {
+                *logit_mut(logits, window[self.n - 1])? = f32::NEG_INFINITY;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logits() -> Tensor {
+        Tensor::from_f32(vec![5], vec![0.0, 1.0, 2.0, 3.0, 4.0]).unwrap()
+    }
+
+    fn context(tokens: &[u32], prompt_len: usize) -> LogitsContext<'_> {
+        LogitsContext {
+            tokens,
+            prompt_len,
+            max_new_tokens: 3,
+        }
+    }
+
+    #[test]
+    fn test_chain_applies_in_order() {
+        let mut chain = LogitsProcessorList::new();
+        chain.push(LogitBias::new(HashMap::from([(0, 10.0)])));
+        chain.push(BannedTokens::new(vec![4]));
+        assert_eq!(chain.len(), 2);
+
+        let mut logits = logits();
+        chain.process(&mut logits, &context(&[1], 1)).unwrap();
+        assert_eq!(
+            logits.as_f32_slice().unwrap(),
+            &[10.0, 1.0, 2.0, 3.0, f32::NEG_INFINITY]
+        );
+
+        let out_of_range = BannedTokens::new(vec![5]);
+        assert!(out_of_range
+            .process(&mut logits, &context(&[1], 1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_forced_tokens() {
+        let mut logits = logits();
+        ForcedBos::new(2)
+            .process(&mut logits, &context(&[1], 1))
+            .unwrap();
+        assert_eq!(logits.as_f32_slice().unwrap()[2], 0.0);
+        assert_eq!(logits.as_f32_slice().unwrap()[4], f32::NEG_INFINITY);
+
+        // Only the last step of the budget is forced to EOS
+        let mut logits = self::logits();
+        ForcedEos::new(0)
+            .process(&mut logits, &context(&[1, 2], 1))
+            .unwrap();
+        assert_eq!(logits.as_f32_slice().unwrap()[4], 4.0);
+        ForcedEos::new(0)
+            .process(&mut logits, &context(&[1, 2, 3], 1))
+            .unwrap();
+        assert_eq!(logits.as_f32_slice().unwrap()[0], 0.0);
+        assert_eq!(logits.as_f32_slice().unwrap()[4], f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_no_repeat_ngram() {
+        // "1 2 3 1" may not continue with 2 because "1 2" already occurred
+        let mut logits = logits();
+        NoRepeatNgram::new(2)
+            .process(&mut logits, &context(&[1, 2, 3, 1], 0))
+            .unwrap();
+        let logits = logits.as_f32_slice().unwrap();
+        assert_eq!(logits[2], f32::NEG_INFINITY);
+        assert_eq!(logits[3], 3.0);
+    }
+}
@@ -0,0 +1,125 @@
+//! Mixture-of-experts routing and router statistics
+//!
+//! A mixture-of-experts feed-forward block (see
+//! [`MoeWeights`](crate::transformer::MoeWeights)) scores every token against each expert
+//! with a router projection, keeps the `top_k` best experts, and mixes their outputs with
+//! the softmax of the kept scores. [`RouterStats`] counts where tokens were sent so the
+//! balance of a checkpoint's experts can be inspected.
+
+use crate::error::{CoreError, Result};
+use crate::ops;
+use std::sync::Mutex;
+
+/// Top-`top_k` experts of one token from its router logits, with gate weights that sum to 1
+///
+/// Experts are returned best first; ties go to the lower expert index. The weights are the
+/// softmax of the kept logits, i.e. the full softmax renormalized over the selection.
+pub fn route(logits: &[f32], top_k: usize) -> Result<Vec<(usize, f32)>> {
+    if top_k == 0 || top_k > logits.len() {
+        return Err(CoreError::InvalidDimension(format!(
+            "Router top_k {} must be between 1 and the number of experts {}",
+            top_k,
+            logits.len()
+        )));
+    }
+    let mut experts: Vec<usize> = (0..logits.len()).collect();
+    // Stable sort keeps the lower index first among equal logits
+    experts.sort_by(|&a, &b| logits[b].total_cmp(&logits[a]));
+    experts.truncate(top_k);
+
+    let mut weights: Vec<f32> = experts.iter().map(|&e| logits[e]).collect();
+    ops::softmax_in_place(&mut weights);
+    Ok(experts.into_iter().zip(weights).collect())
+}
+
+/// Per-expert routing counts of one mixture-of-experts layer
+///
+/// Counts accumulate over every forward pass until [`reset`](Self::reset); padding tokens
+/// are not routed and not counted.
+#[derive(Debug, Default)]
+pub struct RouterStats {
+    counts: Mutex<Vec<u64>>,
+}
+
+impl RouterStats {
+    /// Statistics for `n_experts` experts, all zero
+    pub fn new(n_experts: usize) -> Self {
+        Self {
+            counts: Mutex::new(vec![0; n_experts]),
+        }
+    }
+
+    /// Number of tokens routed to each expert so far
+    pub fn tokens_per_expert(&self) -> Vec<u64> {
+        self.lock().clone()
+    }
+
+    /// Fraction of all routing assignments that went to each expert (zeros before any token)
+    ///
+    /// With `top_k` experts per token the fractions sum to 1; a perfectly balanced router gives
+    /// every expert `1 / n_experts`.
+    pub fn load(&self) -> Vec<f32> {
+        let counts = self.lock();
+        let total = counts.iter().sum::<u64>().max(1) as f32;
+        counts.iter().map(|&count| count as f32 / total).collect()
+    }
+
+    /// Clear the counts
+    pub fn reset(&self) {
+        self.lock().iter_mut().for_each(|count| *count = 0);
+    }
+
+    /// Count one routing assignment per listed expert
+    pub(crate) fn record(&self, experts: impl IntoIterator<Item = usize>) {
+        let mut counts = self.lock();
+        for expert in experts {
+            if expert >= counts.len() {
+                counts.resize(expert + 1, 0);
+            }
+            counts[expert] += 1;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<u64>> {
+        // Counters stay meaningful even if a panic poisoned the lock mid-update
+        self.counts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Clone for RouterStats {
+    fn clone(&self) -> Self {
+        Self {
+            counts: Mutex::new(self.tokens_per_expert()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_top_k_renormalized() {
+        let routed = route(&[0.5, 2.0, -1.0, 2.0], 2).unwrap();
+        assert_eq!((routed[0].0, routed[1].0), (1, 3));
+        assert!((routed[0].1 - 0.5).abs() < 1e-6);
+        assert!((routed[1].1 - 0.5).abs() < 1e-6);
+
+        let routed = route(&[0.0, 1.0, 3.0], 1).unwrap();
+        assert_eq!(routed, vec![(2, 1.0)]);
+        assert!(route(&[0.0, 1.0], 3).is_err());
+        assert!(route(&[0.0, 1.0], 0).is_err());
+    }
+
+    #[test]
+    fn test_router_stats() {
+        let stats = RouterStats::new(4);
+        stats.record([1, 3, 1, 1]);
+        assert_eq!(stats.tokens_per_expert(), vec![0, 3, 0, 1]);
+        assert_eq!(stats.load(), vec![0.0, 0.75, 0.0, 0.25]);
+        stats.reset();
+        assert_eq!(stats.load(), vec![0.0; 4]);
+    }
+}
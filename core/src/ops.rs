@@ -0,0 +1,1021 @@
+//! CPU reference implementations of the transformer math
+//!
+//! Every function here works on F32 [`Tensor`]s in row-major layout and treats all leading
+//! dimensions as batch dimensions. These are the ground truth the GPU backends are checked
+//! against, so they favour clarity over speed.
+
+use crate::error::{CoreError, Result};
+use crate::gpu::{Kernel, KernelType};
+use crate::tensor::Tensor;
+
+/// Split a shape into `(batch, rows, cols)` where `batch` is the product of all leading
+/// dimensions
+fn split_matrix_shape(shape: &[usize]) -> Result<(usize, usize, usize)> {
+    if shape.len() < 2 {
+        return Err(CoreError::InvalidDimension(format!(
+            "Expected at least 2 dimensions, got shape {:?}",
+            shape
+        )));
+    }
+    let rows = shape[shape.len() - 2];
+    let cols = shape[shape.len() - 1];
+    let batch = shape[..shape.len() - 2].iter().product();
+    Ok((batch, rows, cols))
+}
+
+/// Reject matrix products with an empty inner (`k`) or output (`n`) dimension
+fn check_inner_dims(a: &Tensor, b: &Tensor, k: usize, n: usize) -> Result<()> {
+    if k == 0 || n == 0 {
+        return Err(CoreError::InvalidDimension(format!(
+            "Cannot multiply {:?} by {:?}: inner and output dimensions must be non-zero",
+            a.shape, b.shape
+        )));
+    }
+    Ok(())
+}
+
+/// Matrix multiplication `a @ b` where `a` is `[.., m, k]` and `b` is `[k, n]`
+pub fn matmul(a: &Tensor, b: &Tensor) -> Result<Tensor> {
+    let (batch, m, k) = split_matrix_shape(&a.shape)?;
+    if b.ndim() != 2 || b.shape[0] != k {
+        return Err(CoreError::ShapeMismatch {
+            expected: vec![k, b.shape.last().copied().unwrap_or(0)],
+            actual: b.shape.clone(),
+        });
+    }
+    let n = b.shape[1];
+    check_inner_dims(a, b, k, n)?;
+    let a_data = a.as_f32_slice()?;
+    let b_data = b.as_f32_slice()?;
+
+    let mut out = vec![0.0f32; batch * m * n];
+    for (a_row, out_row) in a_data.chunks_exact(k).zip(out.chunks_exact_mut(n)) {
+        for (p, &a_val) in a_row.iter().enumerate() {
+            let b_row = &b_data[p * n..(p + 1) * n];
+            for (o, &b_val) in out_row.iter_mut().zip(b_row) {
+                *o += a_val * b_val;
+            }
+        }
+    }
+
+    let mut shape = a.shape.clone();
+    *shape.last_mut().expect("checked above") = n;
+    Tensor::from_f32(shape, out)
+}
+
+/// Add a `[n]` bias to every row of `x` (`[.., n]`)
+pub fn add_bias(x: &Tensor, bias: &Tensor) -> Result<Tensor> {
+    let n = x.shape.last().copied().unwrap_or(0);
+    if bias.shape != [n] {
+        return Err(CoreError::ShapeMismatch {
+            expected: vec![n],
+            actual: bias.shape.clone(),
+        });
+    }
+    let bias = bias.as_f32_slice()?;
+    let mut out = x.as_f32_slice()?.to_vec();
+    for row in out.chunks_exact_mut(n.max(1)) {
+        row.iter_mut().zip(bias).for_each(|(v, b)| *v += b);
+    }
+    Tensor::from_f32(x.shape.clone(), out)
+}
+
+/// Linear projection `x @ w + bias` with an optional `[n]` bias
+pub fn linear(x: &Tensor, w: &Tensor, bias: Option<&Tensor>) -> Result<Tensor> {
+    let out = matmul(x, w)?;
+    match bias {
+        Some(bias) => add_bias(&out, bias),
+        None => Ok(out),
+    }
+}
+
+/// Matrix multiplication `a @ bᵀ` where `a` is `[.., m, k]` and `b` is `[n, k]`
+///
+/// Used for tied-embedding LM heads, where the `[vocab, d_model]` embedding table doubles as
+/// the output projection without an explicit transpose.
+pub fn matmul_transposed(a: &Tensor, b: &Tensor) -> Result<Tensor> {
+    let (_, _, k) = split_matrix_shape(&a.shape)?;
+    if b.ndim() != 2 || b.shape[1] != k {
+        return Err(CoreError::ShapeMismatch {
+            expected: vec![b.shape.first().copied().unwrap_or(0), k],
+            actual: b.shape.clone(),
+        });
+    }
+    let n = b.shape[0];
+    check_inner_dims(a, b, k, n)?;
+    let a_data = a.as_f32_slice()?;
+    let b_data = b.as_f32_slice()?;
+
+    let mut out = Vec::with_capacity(a_data.len() / k.max(1) * n);
+    for a_row in a_data.chunks_exact(k) {
+        out.extend(
+            b_data
+                .chunks_exact(k)
+                .map(|b_row| a_row.iter().zip(b_row).map(|(x, y)| x * y).sum::<f32>()),
+        );
+    }
+
+    let mut shape = a.shape.clone();
+    *shape.last_mut().expect("checked above") = n;
+    Tensor::from_f32(shape, out)
+}
+
+/// Elementwise addition of two tensors with identical shapes
+pub fn add(a: &Tensor, b: &Tensor) -> Result<Tensor> {
+    if a.shape != b.shape {
+        return Err(CoreError::ShapeMismatch {
+            expected: a.shape.clone(),
+            actual: b.shape.clone(),
+        });
+    }
+    let data = a
+        .as_f32_slice()?
+        .iter()
+        .zip(b.as_f32_slice()?)
+        .map(|(x, y)| x + y)
+        .collect();
+    Tensor::from_f32(a.shape.clone(), data)
+}
+
+/// Layer normalization over the last dimension
+pub fn layer_norm(x: &Tensor, gamma: &Tensor, beta: &Tensor, eps: f32) -> Result<Tensor> {
+    let dim = x.shape.last().copied().unwrap_or(0);
+    if dim == 0 {
+        return Err(CoreError::InvalidDimension(format!(
+            "Cannot normalize {:?}: the last dimension must be non-zero",
+            x.shape
+        )));
+    }
+    if gamma.shape != [dim] || beta.shape != [dim] {
+        return Err(CoreError::ShapeMismatch {
+            expected: vec![dim],
+            actual: gamma.shape.clone(),
+        });
+    }
+    let gamma = gamma.as_f32_slice()?;
+    let beta = beta.as_f32_slice()?;
+
+    let mut out = x.as_f32_slice()?.to_vec();
+    for row in out.chunks_exact_mut(dim) {
+        let mean = row.iter().sum::<f32>() / dim as f32;
+        let var = row.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / dim as f32;
+        let inv_std = 1.0 / (var + eps).sqrt();
+        for ((v, g), b) in row.iter_mut().zip(gamma).zip(beta) {
+            *v = (*v - mean) * inv_std * g + b;
+        }
+    }
+    Tensor::from_f32(x.shape.clone(), out)
+}
+
+/// RMS normalization over the last dimension: `x / sqrt(mean(x²) + eps) * gamma`
+pub fn rms_norm(x: &Tensor, gamma: &Tensor, eps: f32) -> Result<Tensor> {
+    let dim = x.shape.last().copied().unwrap_or(0);
+    if dim == 0 {
+        return Err(CoreError::InvalidDimension(format!(
+            "Cannot normalize {:?}: the last dimension must be non-zero",
+            x.shape
+        )));
+    }
+    if gamma.shape != [dim] {
+        return Err(CoreError::ShapeMismatch {
+            expected: vec![dim],
+            actual: gamma.shape.clone(),
+        });
+    }
+    let gamma = gamma.as_f32_slice()?;
+
+    let mut out = x.as_f32_slice()?.to_vec();
+    for row in out.chunks_exact_mut(dim) {
+        let mean_sq = row.iter().map(|v| v * v).sum::<f32>() / dim as f32;
+        let inv_rms = 1.0 / (mean_sq + eps).sqrt();
+        for (v, g) in row.iter_mut().zip(gamma) {
+            *v *= inv_rms * g;
+        }
+    }
+    Tensor::from_f32(x.shape.clone(), out)
+}
+
+/// Numerically stable softmax of a single row, in place
+pub fn softmax_in_place(row: &mut [f32]) {
+    let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        // Every entry is masked out, so nothing receives any weight
+        row.iter_mut().for_each(|v| *v = 0.0);
+        return;
+    }
+    let mut sum = 0.0;
+    for v in row.iter_mut() {
+        *v = (*v - max).exp();
+        sum += *v;
+    }
+    for v in row.iter_mut() {
+        *v /= sum;
+    }
+}
+
+/// Softmax over the last dimension
+pub fn softmax(x: &Tensor) -> Result<Tensor> {
+    let dim = x.shape.last().copied().unwrap_or(0);
+    let mut out = x.as_f32_slice()?.to_vec();
+    if dim > 0 {
+        out.chunks_exact_mut(dim).for_each(softmax_in_place);
+    }
+    Tensor::from_f32(x.shape.clone(), out)
+}
+
+/// GELU activation of a single value (tanh approximation, matching the GPU shaders)
+pub fn gelu_scalar(x: f32) -> f32 {
+    const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+    0.5 * x * (1.0 + (SQRT_2_OVER_PI * (x + 0.044715 * x * x * x)).tanh())
+}
+
+/// Elementwise GELU activation
+pub fn gelu(x: &Tensor) -> Result<Tensor> {
+    let data = x.as_f32_slice()?.iter().map(|&v| gelu_scalar(v)).collect();
+    Tensor::from_f32(x.shape.clone(), data)
+}
+
+/// SiLU (swish) activation of a single value: `x * sigmoid(x)`
+pub fn silu_scalar(x: f32) -> f32 {
+    x / (1.0 + (-x).exp())
+}
+
+/// Elementwise SiLU activation
+pub fn silu(x: &Tensor) -> Result<Tensor> {
+    let data = x.as_f32_slice()?.iter().map(|&v| silu_scalar(v)).collect();
+    Tensor::from_f32(x.shape.clone(), data)
+}
+
+/// SwiGLU gating: `silu(gate) * up`, elementwise over tensors of identical shape
+pub fn swiglu(gate: &Tensor, up: &Tensor) -> Result<Tensor> {
+    if gate.shape != up.shape {
+        return Err(CoreError::ShapeMismatch {
+            expected: gate.shape.clone(),
+            actual: up.shape.clone(),
+        });
+    }
+    let data = gate
+        .as_f32_slice()?
+        .iter()
+        .zip(up.as_f32_slice()?)
+        .map(|(&g, &u)| silu_scalar(g) * u)
+        .collect();
+    Tensor::from_f32(gate.shape.clone(), data)
+}
+
+/// Rotary position embedding (RoPE) applied to every head of `x`
+///
+/// `x` is `[.., seq, d]` with `d` split into `n_heads` heads of even size, and `positions`
+/// holds one position per row of `x` (e.g. `[batch, seq]`, stored as `f32`). Within a head,
+/// dimension `i` is rotated together with `i + head_dim / 2` by `position * position_scale *
+/// base^(-2i / head_dim)`, the rotate-half layout of GPT-NeoX and LLaMA checkpoints.
+pub fn rope(
+    x: &Tensor,
+    positions: &Tensor,
+    n_heads: usize,
+    base: f32,
+    position_scale: f32,
+) -> Result<Tensor> {
+    let (batch, seq, d) = split_matrix_shape(&x.shape)?;
+    if n_heads == 0 || d % n_heads != 0 || (d / n_heads) % 2 != 0 {
+        return Err(CoreError::InvalidDimension(format!(
+            "RoPE needs an even head dimension: d_model {} with {} heads",
+            d, n_heads
+        )));
+    }
+    if positions.numel() != batch * seq {
+        return Err(CoreError::ShapeMismatch {
+            expected: vec![batch, seq],
+            actual: positions.shape.clone(),
+        });
+    }
+    let head_dim = d / n_heads;
+    let half = head_dim / 2;
+    let inv_freq: Vec<f32> = (0..half)
+        .map(|i| base.powf(-2.0 * i as f32 / head_dim as f32))
+        .collect();
+
+    let mut out = x.as_f32_slice()?.to_vec();
+    for (row, &pos) in out.chunks_exact_mut(d).zip(positions.as_f32_slice()?) {
+        let pos = pos * position_scale;
+        for head in row.chunks_exact_mut(head_dim) {
+            for (i, &freq) in inv_freq.iter().enumerate() {
+                let (sin, cos) = (pos * freq).sin_cos();
+                let (x1, x2) = (head[i], head[i + half]);
+                head[i] = x1 * cos - x2 * sin;
+                head[i + half] = x2 * cos + x1 * sin;
+            }
+        }
+    }
+    Tensor::from_f32(x.shape.clone(), out)
+}
+
+/// Head layout and score biases of an attention call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttentionParams {
+    /// Number of query heads the model dimension is split into
+    pub n_heads: usize,
+    /// Number of key/value heads, each shared by `n_heads / n_kv_heads` consecutive query
+    /// heads (grouped-query attention; 1 is multi-query attention)
+    pub n_kv_heads: usize,
+    /// Hide keys after each query's position
+    pub causal: bool,
+    /// Add ALiBi linear distance biases to the scores (see [`alibi_slopes`])
+    pub alibi: bool,
+    /// Sliding window: hide keys `window` or more positions away from the query
+    pub window: Option<usize>,
+}
+
+impl AttentionParams {
+    /// Plain attention over `n_heads` heads, each with its own keys and values
+    pub fn new(n_heads: usize, causal: bool) -> Self {
+        Self {
+            n_heads,
+            n_kv_heads: n_heads,
+            causal,
+            alibi: false,
+            window: None,
+        }
+    }
+
+    /// Share `n_kv_heads` key/value heads across the query heads
+    pub fn with_kv_heads(mut self, n_kv_heads: usize) -> Self {
+        self.n_kv_heads = n_kv_heads;
+        self
+    }
+
+    /// Width of the key/value rows for query rows of width `d`
+    pub fn kv_width(&self, d: usize) -> usize {
+        d / self.n_heads.max(1) * self.n_kv_heads
+    }
+
+    /// Check that `d`-wide query rows split into heads that `kv_d`-wide key rows can serve,
+    /// and that the window can hold at least the query itself
+    fn check(&self, d: usize, kv_d: usize) -> Result<()> {
+        let (n_heads, n_kv_heads) = (self.n_heads, self.n_kv_heads);
+        if self.window == Some(0) {
+            return Err(CoreError::InvalidDimension(
+                "Attention window must be at least 1".to_string(),
+            ));
+        }
+        if n_heads == 0 || d % n_heads != 0 {
+            return Err(CoreError::InvalidDimension(format!(
+                "Model dimension {} is not divisible by {} heads",
+                d, n_heads
+            )));
+        }
+        if n_kv_heads == 0 || n_heads % n_kv_heads != 0 {
+            return Err(CoreError::InvalidDimension(format!(
+                "{} query heads cannot be grouped over {} key/value heads",
+                n_heads, n_kv_heads
+            )));
+        }
+        if kv_d != self.kv_width(d) {
+            return Err(CoreError::ShapeMismatch {
+                expected: vec![self.kv_width(d)],
+                actual: vec![kv_d],
+            });
+        }
+        Ok(())
+    }
+
+    /// Enable or disable causal masking
+    pub fn with_causal(mut self, causal: bool) -> Self {
+        self.causal = causal;
+        self
+    }
+
+    /// Enable or disable ALiBi biases
+    pub fn with_alibi(mut self, alibi: bool) -> Self {
+        self.alibi = alibi;
+        self
+    }
+
+    /// Restrict every query to the keys less than `window` positions away (`None` for all)
+    pub fn with_window(mut self, window: Option<usize>) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Encode as [`KernelType::Attention`] params `[n_heads, causal, paged, alibi, n_kv_heads,
+    /// window]`, where `paged` selects the block-table inputs
+    pub fn kernel_params(&self, paged: bool) -> Vec<f32> {
+        vec![
+            self.n_heads as f32,
+            self.causal as u8 as f32,
+            paged as u8 as f32,
+            self.alibi as u8 as f32,
+            self.n_kv_heads as f32,
+            self.window.unwrap_or(0) as f32,
+        ]
+    }
+
+    /// Decode [`KernelType::Attention`] params, returning whether the inputs are paged
+    ///
+    /// Missing trailing entries take the defaults documented on the kernel.
+    pub fn from_kernel_params(params: &[f32]) -> (Self, bool) {
+        let flag = |i: usize, default: bool| params.get(i).map_or(default, |&p| p != 0.0);
+        let n_heads = params.first().copied().unwrap_or(1.0) as usize;
+        let attention = Self::new(n_heads, flag(1, true))
+            .with_alibi(flag(3, false))
+            .with_kv_heads(params.get(4).map_or(n_heads, |&n| n as usize))
+            .with_window(params.get(5).filter(|&&w| w != 0.0).map(|&w| w as usize));
+        (attention, flag(2, false))
+    }
+}
+
+/// Per-head ALiBi slopes
+///
+/// For a power-of-two head count head `h` gets `2^(-8 (h + 1) / n_heads)`. Other counts take
+/// the slopes of the nearest lower power of two followed by every other slope of the next
+/// power of two, as in the original paper's reference code.
+pub fn alibi_slopes(n_heads: usize) -> Vec<f32> {
+    let geometric = |n: usize| {
+        let ratio = 2f32.powf(-8.0 / n as f32);
+        (1..=n).map(move |i| ratio.powi(i as i32))
+    };
+    if n_heads == 0 {
+        return Vec::new();
+    }
+    let lower = 1 << (usize::BITS - 1 - n_heads.leading_zeros());
+    let mut slopes: Vec<f32> = geometric(lower).collect();
+    slopes.extend(geometric(2 * lower).step_by(2).take(n_heads - lower));
+    slopes
+}
+
+/// Scaled dot-product multi-head attention
+///
+/// `q` is `[.., seq_q, d]` and `k`/`v` are `[.., seq_k, kv_d]` with matching leading
+/// dimensions. The model dimension `d` is split into `n_heads` contiguous heads and `kv_d`
+/// into `n_kv_heads` heads of the same size; query head `h` attends with key/value head
+/// `h / (n_heads / n_kv_heads)`, so `kv_d == d` unless heads are grouped. With `causal` set,
+/// query `i` only sees keys up to position `i + seq_k - seq_q`, so a query block appended
+/// after cached keys is masked correctly.
+///
+/// `mask` is an optional `[batch, seq_k]` padding mask (batch being the product of the
+/// leading dimensions): keys whose entry is zero are ignored by every query of that sequence.
+///
+/// With `params.alibi` set, head `h` adds `-slope[h] * distance` to every score, where the
+/// distance counts key positions between the query and the key. Padding is expected to be
+/// contiguous (all on one side) so that distances between real tokens are unaffected by it.
+/// The same distance limits each query to its `params.window` nearest keys on either side;
+/// with `causal` set that is the query and the `window - 1` keys before it.
+pub fn attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    params: AttentionParams,
+    mask: Option<&Tensor>,
+) -> Result<Tensor> {
+    let (batch, seq_q, d) = split_matrix_shape(&q.shape)?;
+    let (k_batch, seq_k, k_d) = split_matrix_shape(&k.shape)?;
+    if k.shape != v.shape || k_batch != batch {
+        return Err(CoreError::ShapeMismatch {
+            expected: k.shape.clone(),
+            actual: v.shape.clone(),
+        });
+    }
+    params.check(d, k_d)?;
+    if params.causal && seq_q > seq_k {
+        return Err(CoreError::InvalidDimension(format!(
+            "Causal attention needs at least as many keys ({}) as queries ({})",
+            seq_k, seq_q
+        )));
+    }
+    let mask = match mask {
+        Some(mask) if mask.shape.last() != Some(&seq_k) || mask.numel() != batch * seq_k => {
+            return Err(CoreError::ShapeMismatch {
+                expected: vec![batch, seq_k],
+                actual: mask.shape.clone(),
+            });
+        }
+        Some(mask) => Some(mask.as_f32_slice()?),
+        None => None,
+    };
+
+    let (q_data, k_data, v_data) = (q.as_f32_slice()?, k.as_f32_slice()?, v.as_f32_slice()?);
+    let out = attend(q_data, k_data, v_data, (batch, seq_q, d), params, |b| {
+        (0..seq_k)
+            .map(|j| match mask {
+                Some(mask) if mask[b * seq_k + j] == 0.0 => None,
+                _ => Some(b * seq_k + j),
+            })
+            .collect()
+    });
+    Tensor::from_f32(q.shape.clone(), out)
+}
+
+/// Multi-head attention over a paged KV cache
+///
+/// `k_blocks`/`v_blocks` are block pools `[num_blocks, block_size, kv_d]`. `block_tables` is
+/// `[batch, max_blocks]` holding, per sequence, the ids of the blocks that store its keys in
+/// order, and `context_lens` is `[batch]` with each sequence's number of cached positions
+/// (ids and lengths are stored as `f32`). `q` is `[batch, seq_q, d]`; its rows are the last
+/// `seq_q` positions of each context, which is what `causal` masking, ALiBi distances and the
+/// sliding window are relative to.
+pub fn paged_attention(
+    q: &Tensor,
+    k_blocks: &Tensor,
+    v_blocks: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    params: AttentionParams,
+) -> Result<Tensor> {
+    let (batch, seq_q, d) = split_matrix_shape(&q.shape)?;
+    let (num_blocks, block_size, k_d) = split_matrix_shape(&k_blocks.shape)?;
+    if k_blocks.shape != v_blocks.shape || k_blocks.ndim() != 3 {
+        return Err(CoreError::ShapeMismatch {
+            expected: vec![num_blocks, block_size, params.kv_width(d)],
+            actual: v_blocks.shape.clone(),
+        });
+    }
+    if block_tables.ndim() != 2 || block_tables.shape[0] != batch || context_lens.shape != [batch] {
+        return Err(CoreError::ShapeMismatch {
+            expected: vec![batch, block_tables.shape.last().copied().unwrap_or(0)],
+            actual: block_tables.shape.clone(),
+        });
+    }
+    if block_size == 0 {
+        return Err(CoreError::InvalidDimension(
+            "Paged KV blocks must hold at least one position".to_string(),
+        ));
+    }
+    params.check(d, k_d)?;
+
+    let max_blocks = block_tables.shape[1];
+    let tables = block_tables.as_f32_slice()?;
+    let lens = context_lens.as_f32_slice()?;
+    for (b, &len) in lens.iter().enumerate() {
+        let len = len as usize;
+        let table = &tables[b * max_blocks..(b + 1) * max_blocks];
+        if len > max_blocks * block_size
+            || table[..(len + block_size - 1) / block_size]
+                .iter()
+                .any(|&id| id as usize >= num_blocks)
+        {
+            return Err(CoreError::InvalidDimension(format!(
+                "Block table of sequence {} does not cover {} positions in {} blocks",
+                b, len, num_blocks
+            )));
+        }
+    }
+
+    let out = attend(
+        q.as_f32_slice()?,
+        k_blocks.as_f32_slice()?,
+        v_blocks.as_f32_slice()?,
+        (batch, seq_q, d),
+        params,
+        |b| {
+            let table = &tables[b * max_blocks..(b + 1) * max_blocks];
+            (0..lens[b] as usize)
+                .map(|j| Some(table[j / block_size] as usize * block_size + j % block_size))
+                .collect()
+        },
+    );
+    Tensor::from_f32(q.shape.clone(), out)
+}
+
+/// Shared attention loop over row-major `[.., d]` queries and `[.., kv_d]` keys and values
+///
+/// `key_rows(b)` lists, for every key position of batch entry `b`, the row of `k`/`v` that
+/// holds it, or `None` if that key is masked out. Queries are the last `seq_q` positions of
+/// each entry's keys for causal masking, ALiBi distances and the sliding window.
+fn attend(
+    q: &[f32],
+    k: &[f32],
+    v: &[f32],
+    (batch, seq_q, d): (usize, usize, usize),
+    params: AttentionParams,
+    key_rows: impl Fn(usize) -> Vec<Option<usize>>,
+) -> Vec<f32> {
+    let AttentionParams {
+        n_heads,
+        n_kv_heads,
+        causal,
+        alibi,
+        window,
+    } = params;
+    let head_dim = d / n_heads;
+    let kv_d = params.kv_width(d);
+    let group = n_heads / n_kv_heads;
+    let scale = 1.0 / (head_dim as f32).sqrt();
+    let slopes = if alibi {
+        alibi_slopes(n_heads)
+    } else {
+        vec![0.0; n_heads]
+    };
+
+    let mut out = vec![0.0f32; batch * seq_q * d];
+    for b in 0..batch {
+        let rows = key_rows(b);
+        let offset = rows.len() as isize - seq_q as isize;
+        let mut scores = vec![0.0f32; rows.len()];
+        let q_base = b * seq_q * d;
+        for (h, &slope) in slopes.iter().enumerate() {
+            let col = h * head_dim;
+            let kv_col = h / group * head_dim;
+            for i in 0..seq_q {
+                let q_row = &q[q_base + i * d + col..q_base + i * d + col + head_dim];
+                let pos = i as isize + offset;
+                for (j, (score, row)) in scores.iter_mut().zip(&rows).enumerate() {
+                    let distance = (pos - j as isize).unsigned_abs();
+                    let visible = (!causal || j as isize <= pos)
+                        && window.map_or(true, |window| distance < window);
+                    *score = match row {
+                        Some(row) if visible => {
+                            let k_row = &k[row * kv_d + kv_col..row * kv_d + kv_col + head_dim];
+                            let dot = q_row.iter().zip(k_row).map(|(a, b)| a * b).sum::<f32>();
+                            dot * scale - slope * distance as f32
+                        }
+                        _ => f32::NEG_INFINITY,
+                    };
+                }
+                softmax_in_place(&mut scores);
+
+                let out_row = &mut out[q_base + i * d + col..q_base + i * d + col + head_dim];
+                for (&p, row) in scores.iter().zip(&rows) {
+                    let Some(row) = row.filter(|_| p != 0.0) else {
+                        continue;
+                    };
+                    let v_row = &v[row * kv_d + kv_col..row * kv_d + kv_col + head_dim];
+                    for (o, &val) in out_row.iter_mut().zip(v_row) {
+                        *o += p * val;
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Run `kernel` on `inputs` with the functions above, following the input and parameter
+/// layout documented on each [`KernelType`]
+///
+/// This is the dispatch of the CPU backend, so devices that keep tensors on the host can
+/// share it.
+pub fn run_reference_kernel(kernel: &Kernel, inputs: &[&Tensor]) -> Result<Tensor> {
+    let params = &kernel.params;
+    let expect = |count: usize| {
+        if inputs.len() < count {
+            return Err(CoreError::GpuError(format!(
+                "{:?} kernel expects {} inputs, got {}",
+                kernel.kernel_type,
+                count,
+                inputs.len()
+            )));
+        }
+        Ok(&inputs[..count])
+    };
+    let flag = |i: usize, default: bool| params.get(i).map_or(default, |&p| p != 0.0);
+    let epsilon = params.first().copied().unwrap_or(1e-5);
+    let bias = inputs.get(2).copied();
+
+    match kernel.kernel_type {
+        KernelType::MatMul => {
+            let t = expect(2)?;
+            if flag(0, false) {
+                let out = matmul_transposed(t[0], t[1])?;
+                match bias {
+                    Some(bias) => add_bias(&out, bias),
+                    None => Ok(out),
+                }
+            } else {
+                linear(t[0], t[1], bias)
+            }
+        }
+        KernelType::LayerNorm => {
+            let t = expect(3)?;
+            layer_norm(t[0], t[1], t[2], epsilon)
+        }
+        KernelType::Softmax => softmax(expect(1)?[0]),
+        KernelType::Gelu => gelu(expect(1)?[0]),
+        KernelType::FusedGemmGelu => {
+            let t = expect(2)?;
+            gelu(&linear(t[0], t[1], bias)?)
+        }
+        KernelType::FusedGemmLayerNorm => {
+            let t = expect(4)?;
+            layer_norm(&matmul(t[0], t[1])?, t[2], t[3], epsilon)
+        }
+        KernelType::Attention => {
+            let (attention_params, paged) = AttentionParams::from_kernel_params(params);
+            if paged {
+                let t = expect(5)?;
+                paged_attention(t[0], t[1], t[2], t[3], t[4], attention_params)
+            } else {
+                let t = expect(3)?;
+                attention(t[0], t[1], t[2], attention_params, inputs.get(3).copied())
+            }
+        }
+        KernelType::Add => {
+            let t = expect(2)?;
+            add(t[0], t[1])
+        }
+        KernelType::Rope => {
+            let t = expect(2)?;
+            let n_heads = params.first().copied().unwrap_or(1.0) as usize;
+            let base = params.get(1).copied().unwrap_or(10000.0);
+            let scale = params.get(2).copied().unwrap_or(1.0);
+            rope(t[0], t[1], n_heads, base, scale)
+        }
+        KernelType::RmsNorm => {
+            let t = expect(2)?;
+            rms_norm(t[0], t[1], epsilon)
+        }
+        KernelType::Silu => match inputs {
+            [gate, up, ..] => swiglu(gate, up),
+            _ => silu(expect(1)?[0]),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_matmul() {
+        let a = Tensor::from_f32(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+        let b = Tensor::from_f32(vec![3, 2], vec![7.0, 8.0, 9.0, 10.0, 11.0, 12.0]).unwrap();
+        let c = matmul(&a, &b).unwrap();
+        assert_eq!(c.shape, vec![2, 2]);
+        assert_eq!(c.as_f32_slice().unwrap(), &[58.0, 64.0, 139.0, 154.0]);
+
+        let bt = Tensor::from_f32(vec![2, 3], vec![7.0, 9.0, 11.0, 8.0, 10.0, 12.0]).unwrap();
+        let ct = matmul_transposed(&a, &bt).unwrap();
+        assert_eq!(ct.as_f32_slice().unwrap(), c.as_f32_slice().unwrap());
+
+        // Zeros in `a` still propagate NaN and infinity from `b`, as on the GPU
+        let zeros = Tensor::from_f32(vec![1, 2], vec![0.0, 0.0]).unwrap();
+        let b = Tensor::from_f32(vec![2, 2], vec![f32::NAN, 1.0, f32::INFINITY, 1.0]).unwrap();
+        let c = matmul(&zeros, &b).unwrap();
+        assert!(c.as_f32_slice().unwrap()[0].is_nan());
+        assert_eq!(c.as_f32_slice().unwrap()[1], 0.0);
+
+        let empty = Tensor::from_f32(vec![3, 0], vec![]).unwrap();
+        assert!(matmul(&a, &empty).is_err());
+        assert!(matmul_transposed(&a, &Tensor::from_f32(vec![0, 3], vec![]).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_linear_with_bias() {
+        let x = Tensor::from_f32(vec![2, 2], vec![1.0, 2.0, 3.0, 4.0]).unwrap();
+        let w = Tensor::from_f32(vec![2, 3], vec![1.0, 0.0, 1.0, 0.0, 1.0, 1.0]).unwrap();
+        let bias = Tensor::from_f32(vec![3], vec![0.5, -1.0, 0.0]).unwrap();
+        let out = linear(&x, &w, Some(&bias)).unwrap();
+        assert_eq!(out.as_f32_slice().unwrap(), &[1.5, 1.0, 3.0, 3.5, 3.0, 7.0]);
+        assert_eq!(
+            linear(&x, &w, None).unwrap().as_f32_slice().unwrap(),
+            matmul(&x, &w).unwrap().as_f32_slice().unwrap()
+        );
+        assert!(linear(
+            &x,
+            &w,
+            Some(&Tensor::from_f32(vec![2], vec![0.0; 2]).unwrap())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_layer_norm_and_softmax() {
+        let x = Tensor::from_f32(vec![1, 4], vec![1.0, 2.0, 3.0, 4.0]).unwrap();
+        let gamma = Tensor::from_f32(vec![4], vec![1.0; 4]).unwrap();
+        let beta = Tensor::from_f32(vec![4], vec![0.0; 4]).unwrap();
+        let y = layer_norm(&x, &gamma, &beta, 1e-5).unwrap();
+        let y = y.as_f32_slice().unwrap();
+        assert_relative_eq!(y.iter().sum::<f32>(), 0.0, epsilon = 1e-5);
+        assert_relative_eq!(y[3], -y[0], epsilon = 1e-5);
+
+        let empty = Tensor::from_f32(vec![2, 0], Vec::new()).unwrap();
+        let no_weights = Tensor::from_f32(vec![0], Vec::new()).unwrap();
+        assert!(matches!(
+            layer_norm(&empty, &no_weights, &no_weights, 1e-5),
+            Err(CoreError::InvalidDimension(_))
+        ));
+
+        let p = softmax(&x).unwrap();
+        assert_relative_eq!(
+            p.as_f32_slice().unwrap().iter().sum::<f32>(),
+            1.0,
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn test_rms_norm_and_swiglu() {
+        let x = Tensor::from_f32(vec![1, 2], vec![3.0, -4.0]).unwrap();
+        let gamma = Tensor::from_f32(vec![2], vec![1.0, 2.0]).unwrap();
+        // rms = sqrt((9 + 16) / 2)
+        let rms = 12.5f32.sqrt();
+        let out = rms_norm(&x, &gamma, 0.0).unwrap();
+        let out = out.as_f32_slice().unwrap();
+        assert_relative_eq!(out[0], 3.0 / rms, epsilon = 1e-6);
+        assert_relative_eq!(out[1], -8.0 / rms, epsilon = 1e-6);
+        let empty = Tensor::from_f32(vec![2, 0], Vec::new()).unwrap();
+        let no_weights = Tensor::from_f32(vec![0], Vec::new()).unwrap();
+        assert!(matches!(
+            rms_norm(&empty, &no_weights, 1e-5),
+            Err(CoreError::InvalidDimension(_))
+        ));
+
+        assert_eq!(silu_scalar(0.0), 0.0);
+        assert_relative_eq!(silu_scalar(2.0), 2.0 / (1.0 + (-2.0f32).exp()));
+        let up = Tensor::from_f32(vec![1, 2], vec![2.0, 0.5]).unwrap();
+        let gated = swiglu(&x, &up).unwrap();
+        let expected = silu(&x).unwrap();
+        let expected = expected.as_f32_slice().unwrap();
+        assert_eq!(
+            gated.as_f32_slice().unwrap(),
+            &[expected[0] * 2.0, expected[1] * 0.5]
+        );
+        assert!(swiglu(&x, &gamma).is_err());
+    }
+
+    #[test]
+    fn test_causal_attention() {
+        // Two positions, one head: the first query may only see the first value
+        let q = Tensor::from_f32(vec![2, 2], vec![1.0, 0.0, 1.0, 0.0]).unwrap();
+        let k = q.clone();
+        let v = Tensor::from_f32(vec![2, 2], vec![1.0, 2.0, 3.0, 4.0]).unwrap();
+        let out = attention(&q, &k, &v, AttentionParams::new(1, true), None).unwrap();
+        let out = out.as_f32_slice().unwrap();
+        assert_eq!(&out[..2], &[1.0, 2.0]);
+        // Equal scores for the second query average both values
+        assert_relative_eq!(out[2], 2.0, epsilon = 1e-6);
+        assert_relative_eq!(out[3], 3.0, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn test_rope_depends_on_relative_position() {
+        let x =
+            Tensor::from_f32(vec![1, 8], vec![0.3, -1.0, 0.5, 2.0, 1.5, 0.2, -0.7, 0.9]).unwrap();
+        let at = |pos: f32| {
+            let positions = Tensor::from_f32(vec![1], vec![pos]).unwrap();
+            rope(&x, &positions, 2, 10000.0, 1.0)
+                .unwrap()
+                .as_f32_slice()
+                .unwrap()
+                .to_vec()
+        };
+        assert_eq!(at(0.0), x.as_f32_slice().unwrap());
+
+        // Per-head dot products only depend on the distance between the two positions
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        let (a, b) = (at(3.0), at(7.0));
+        let (c, d) = (at(10.0), at(14.0));
+        for head in 0..2 {
+            let span = head * 4..(head + 1) * 4;
+            let near = dot(&a[span.clone()], &b[span.clone()]);
+            let far = dot(&c[span.clone()], &d[span]);
+            assert!((near - far).abs() < 1e-4, "{} != {}", near, far);
+        }
+        let positions = Tensor::from_f32(vec![1], vec![0.0]).unwrap();
+        assert!(rope(&x, &positions, 8, 10000.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_padding_mask() {
+        // Masking the first key leaves only the second value visible to both queries
+        let q = Tensor::from_f32(vec![1, 2, 2], vec![1.0, 0.0, 1.0, 0.0]).unwrap();
+        let v = Tensor::from_f32(vec![1, 2, 2], vec![1.0, 2.0, 3.0, 4.0]).unwrap();
+        let mask = Tensor::from_f32(vec![1, 2], vec![0.0, 1.0]).unwrap();
+        let out = attention(&q, &q, &v, AttentionParams::new(1, false), Some(&mask)).unwrap();
+        assert_eq!(out.as_f32_slice().unwrap(), &[3.0, 4.0, 3.0, 4.0]);
+
+        // With causal masking the first query sees nothing and gets a zero output
+        let out = attention(&q, &q, &v, AttentionParams::new(1, true), Some(&mask)).unwrap();
+        assert_eq!(out.as_f32_slice().unwrap(), &[0.0, 0.0, 3.0, 4.0]);
+
+        let bad = Tensor::from_f32(vec![1, 3], vec![1.0; 3]).unwrap();
+        assert!(attention(&q, &q, &v, AttentionParams::new(1, false), Some(&bad)).is_err());
+    }
+
+    #[test]
+    fn test_alibi_slopes_and_bias() {
+        assert_eq!(alibi_slopes(2), vec![2f32.powi(-4), 2f32.powi(-8)]);
+        // Six heads: the four-head slopes, then the odd slopes of eight heads
+        let expected = [-2, -4, -6, -8, -1, -3].map(|e| 2f32.powi(e));
+        assert_eq!(alibi_slopes(6), expected);
+
+        // Zero queries leave only the bias: the second query weights its two keys by
+        // softmax([-slope, 0])
+        let q = Tensor::from_f32(vec![2, 2], vec![0.0; 4]).unwrap();
+        let v = Tensor::from_f32(vec![2, 2], vec![1.0, 1.0, 3.0, 3.0]).unwrap();
+        let params = AttentionParams::new(1, true).with_alibi(true);
+        let out = attention(&q, &q, &v, params, None).unwrap();
+        let far = 1.0 / (1.0 + alibi_slopes(1)[0].exp());
+        assert_relative_eq!(out.as_f32_slice().unwrap()[2], far + 3.0 * (1.0 - far));
+
+        // Without causal masking the bias is symmetric in the distance
+        let out = attention(&q, &q, &v, params.with_causal(false), None).unwrap();
+        assert_relative_eq!(out.as_f32_slice().unwrap()[0], (1.0 - far) + 3.0 * far);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        // Zero queries weight every visible key equally, so each output is the mean of the
+        // values in its window
+        let q = Tensor::from_f32(vec![4, 1], vec![0.0; 4]).unwrap();
+        let v = Tensor::from_f32(vec![4, 1], vec![1.0, 2.0, 4.0, 8.0]).unwrap();
+        let params = AttentionParams::new(1, true).with_window(Some(2));
+        let out = attention(&q, &q, &v, params, None).unwrap();
+        assert_eq!(out.as_f32_slice().unwrap(), &[1.0, 1.5, 3.0, 6.0]);
+
+        // Without causal masking the window extends to both sides
+        let out = attention(&q, &q, &v, params.with_causal(false), None).unwrap();
+        assert_relative_eq!(out.as_f32_slice().unwrap()[1], 7.0 / 3.0);
+
+        let params = params.with_window(Some(0));
+        assert!(attention(&q, &q, &v, params, None).is_err());
+    }
+
+    #[test]
+    fn test_grouped_query_attention_matches_repeated_heads() {
+        // Four query heads of width 2 over one shared key/value head, and the same keys and
+        // values repeated for every head
+        let q = Tensor::from_f32(
+            vec![1, 3, 8],
+            (0..24).map(|i| (i % 7) as f32 * 0.3).collect(),
+        )
+        .unwrap();
+        let kv = |seed: f32| {
+            let data: Vec<f32> = (0..6).map(|i| (i as f32 * seed).sin()).collect();
+            let repeated = data.chunks(2).flat_map(|row| row.repeat(4)).collect();
+            (
+                Tensor::from_f32(vec![1, 3, 2], data).unwrap(),
+                Tensor::from_f32(vec![1, 3, 8], repeated).unwrap(),
+            )
+        };
+        let ((k, k_full), (v, v_full)) = (kv(1.3), kv(0.7));
+
+        let params = AttentionParams::new(4, true).with_kv_heads(1);
+        let out = attention(&q, &k, &v, params, None).unwrap();
+        let expected =
+            attention(&q, &k_full, &v_full, AttentionParams::new(4, true), None).unwrap();
+        for (a, b) in out
+            .as_f32_slice()
+            .unwrap()
+            .iter()
+            .zip(expected.as_f32_slice().unwrap())
+        {
+            assert_relative_eq!(a, b, epsilon = 1e-6);
+        }
+
+        // Key/value heads must divide the query heads and match the key width
+        assert!(attention(
+            &q,
+            &k,
+            &v,
+            AttentionParams::new(4, true).with_kv_heads(3),
+            None
+        )
+        .is_err());
+        assert!(attention(&q, &k_full, &v_full, params, None).is_err());
+    }
+
+    #[test]
+    fn test_paged_attention_matches_contiguous() {
+        // Three keys stored out of order in blocks of two: block 2 holds keys 0-1, block 0
+        // holds key 2
+        let q = Tensor::from_f32(vec![1, 2, 2], vec![0.5, -1.0, 1.0, 0.25]).unwrap();
+        let k = Tensor::from_f32(vec![1, 3, 2], vec![1.0, 2.0, -1.0, 0.5, 0.0, 1.0]).unwrap();
+        let v = Tensor::from_f32(vec![1, 3, 2], vec![1.0, 0.0, 0.0, 1.0, 2.0, 2.0]).unwrap();
+        let params = AttentionParams::new(1, true).with_alibi(true);
+        let expected = attention(&q, &k, &v, params, None).unwrap();
+
+        let pool = |t: &Tensor| {
+            let t = t.as_f32_slice().unwrap();
+            let mut data = vec![0.0; 3 * 2 * 2];
+            data[8..12].copy_from_slice(&t[..4]);
+            data[..2].copy_from_slice(&t[4..]);
+            Tensor::from_f32(vec![3, 2, 2], data).unwrap()
+        };
+        let tables = Tensor::from_f32(vec![1, 2], vec![2.0, 0.0]).unwrap();
+        let lens = Tensor::from_f32(vec![1], vec![3.0]).unwrap();
+        let out = paged_attention(&q, &pool(&k), &pool(&v), &tables, &lens, params).unwrap();
+        for (a, b) in out
+            .as_f32_slice()
+            .unwrap()
+            .iter()
+            .zip(expected.as_f32_slice().unwrap())
+        {
+            assert_relative_eq!(a, b, epsilon = 1e-6);
+        }
+
+        let bad_tables = Tensor::from_f32(vec![1, 2], vec![2.0, 5.0]).unwrap();
+        assert!(paged_attention(&q, &pool(&k), &pool(&v), &bad_tables, &lens, params).is_err());
+        let empty = Tensor::from_f32(vec![3, 0, 2], vec![]).unwrap();
+        assert!(paged_attention(&q, &empty, &empty, &tables, &lens, params).is_err());
+
+        // Kernel params round-trip through the positional layout
+        let windowed = params.with_alibi(true).with_window(Some(2));
+        let (decoded, paged) = AttentionParams::from_kernel_params(&windowed.kernel_params(true));
+        assert_eq!((decoded, paged), (windowed, true));
+        assert_eq!(
+            AttentionParams::from_kernel_params(&[4.0]).0,
+            AttentionParams::new(4, true)
+        );
+    }
+}
@@ -0,0 +1,462 @@
+//! Paged key/value cache with a block allocator
+//!
+//! Instead of reserving `max_seq_len` positions per sequence, keys and values live in a shared
+//! pool of fixed-size blocks. Each sequence owns a [`BlockTable`] listing its blocks in order,
+//! so memory grows one block at a time. Blocks are reference counted: [`PagedKvCache::fork`]
+//! shares every block of a sequence, and a shared block is copied only when one of its owners
+//! writes into it (copy-on-write).
+
+use crate::error::{CoreError, Result};
+use crate::tensor::Tensor;
+use crate::transformer::TransformerConfig;
+
+/// Reference-counted allocator over a fixed number of blocks
+#[derive(Debug, Clone)]
+pub struct BlockAllocator {
+    free: Vec<usize>,
+    ref_counts: Vec<u32>,
+}
+
+impl BlockAllocator {
+    /// Create an allocator managing blocks `0..num_blocks`
+    pub fn new(num_blocks: usize) -> Self {
+        Self {
+            free: (0..num_blocks).rev().collect(),
+            ref_counts: vec![0; num_blocks],
+        }
+    }
+
+    /// Total number of blocks
+    pub fn num_blocks(&self) -> usize {
+        self.ref_counts.len()
+    }
+
+    /// Number of unused blocks
+    pub fn num_free(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Number of owners of `block`
+    pub fn ref_count(&self, block: usize) -> u32 {
+        self.ref_counts.get(block).copied().unwrap_or(0)
+    }
+
+    /// Take an unused block with a reference count of one
+    pub fn allocate(&mut self) -> Result<usize> {
+        let block = self.free.pop().ok_or_else(|| {
+            CoreError::Other(format!(
+                "Out of KV cache blocks ({} in use)",
+                self.num_blocks()
+            ))
+        })?;
+        self.ref_counts[block] = 1;
+        Ok(block)
+    }
+
+    /// Add an owner to an allocated block
+    pub fn retain(&mut self, block: usize) {
+        debug_assert!(self.ref_counts[block] > 0, "retaining a free block");
+        self.ref_counts[block] += 1;
+    }
+
+    /// Drop an owner of a block, returning it to the free list when none remain
+    ///
+    /// Releasing a block that is already free is an error and changes nothing.
+    pub fn release(&mut self, block: usize) -> Result<()> {
+        if self.ref_count(block) == 0 {
+            return Err(CoreError::Other(format!(
+                "KV cache block {} is already free",
+                block
+            )));
+        }
+        self.ref_counts[block] -= 1;
+        if self.ref_counts[block] == 0 {
+            self.free.push(block);
+        }
+        Ok(())
+    }
+}
+
+/// Ordered blocks holding one sequence's keys and values
+///
+/// Not `Clone`: a table owns one reference to each of its blocks, so copies that share them
+/// come from [`PagedKvCache::fork`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BlockTable {
+    blocks: Vec<usize>,
+    len: usize,
+}
+
+impl BlockTable {
+    /// Number of cached positions
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if the sequence holds no positions
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Block ids in position order
+    pub fn blocks(&self) -> &[usize] {
+        &self.blocks
+    }
+}
+
+/// Block pools of one layer, each `[num_blocks, block_size, width]`
+#[derive(Debug, Clone)]
+struct BlockPool {
+    keys: Tensor,
+    values: Tensor,
+}
+
+/// Paged KV cache shared by many sequences
+#[derive(Debug, Clone)]
+pub struct PagedKvCache {
+    allocator: BlockAllocator,
+    layers: Vec<BlockPool>,
+    block_size: usize,
+    width: usize,
+    max_seq_len: usize,
+}
+
+impl PagedKvCache {
+    /// Create a cache of `num_blocks` blocks of `block_size` positions for every layer of
+    /// `config`
+    ///
+    /// Block tables are shared by all layers and keep every position, so models with sliding
+    /// attention windows are rejected; use the rolling [`KvCache`](crate::kv_cache::KvCache).
+    pub fn new(config: &TransformerConfig, block_size: usize, num_blocks: usize) -> Result<Self> {
+        if block_size == 0 {
+            return Err(CoreError::InvalidDimension(
+                "Block size must be at least 1".to_string(),
+            ));
+        }
+        if config.attention_windows.iter().any(Option::is_some) {
+            return Err(CoreError::Other(
+                "Paged KV caches do not evict positions outside sliding attention windows; \
+                 use a rolling KvCache"
+                    .to_string(),
+            ));
+        }
+        let width = config.kv_width();
+        let shape = vec![num_blocks, block_size, width];
+        let pool = BlockPool {
+            keys: Tensor::from_f32(shape.clone(), vec![0.0; num_blocks * block_size * width])?,
+            values: Tensor::from_f32(shape, vec![0.0; num_blocks * block_size * width])?,
+        };
+        Ok(Self {
+            allocator: BlockAllocator::new(num_blocks),
+            layers: vec![pool; config.n_layers],
+            block_size,
+            width,
+            max_seq_len: config.max_seq_len,
+        })
+    }
+
+    /// Positions per block
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The block allocator
+    pub fn allocator(&self) -> &BlockAllocator {
+        &self.allocator
+    }
+
+    /// Start an empty sequence
+    pub fn new_sequence(&self) -> BlockTable {
+        BlockTable::default()
+    }
+
+    /// Share every block of `table` with a new sequence
+    pub fn fork(&mut self, table: &BlockTable) -> BlockTable {
+        for &block in &table.blocks {
+            self.allocator.retain(block);
+        }
+        BlockTable {
+            blocks: table.blocks.clone(),
+            len: table.len,
+        }
+    }
+
+    /// Release a sequence's blocks
+    pub fn free(&mut self, table: BlockTable) -> Result<()> {
+        for block in table.blocks {
+            self.allocator.release(block)?;
+        }
+        Ok(())
+    }
+
+    /// Grow `table` by `n` positions, allocating blocks as needed
+    ///
+    /// A partially filled last block that is shared with another sequence is copied first,
+    /// so the new positions never overwrite another sequence's data. Nothing changes if the
+    /// allocation fails.
+    pub fn reserve(&mut self, table: &mut BlockTable, n: usize) -> Result<()> {
+        let new_len = table.len + n;
+        if new_len > self.max_seq_len {
+            return Err(CoreError::InvalidDimension(format!(
+                "KV cache overflow: {} positions exceed max_seq_len {}",
+                new_len, self.max_seq_len
+            )));
+        }
+        let partial = table.len % self.block_size != 0;
+        let copy_last = n > 0
+            && partial
+            && table
+                .blocks
+                .last()
+                .is_some_and(|&block| self.allocator.ref_count(block) > 1);
+        let needed = self.blocks_for(new_len) - table.blocks.len() + copy_last as usize;
+        if needed > self.allocator.num_free() {
+            return Err(CoreError::Other(format!(
+                "Out of KV cache blocks: need {}, {} free",
+                needed,
+                self.allocator.num_free()
+            )));
+        }
+
+        if copy_last {
+            let shared = table.blocks.pop().expect("checked above");
+            let copy = self.allocator.allocate()?;
+            self.copy_block(shared, copy)?;
+            self.allocator.release(shared)?;
+            table.blocks.push(copy);
+        }
+        while table.blocks.len() * self.block_size < new_len {
+            table.blocks.push(self.allocator.allocate()?);
+        }
+        table.len = new_len;
+        Ok(())
+    }
+
+    /// Shrink `table` to its first `len` positions, releasing blocks no longer needed
+    pub fn truncate(&mut self, table: &mut BlockTable, len: usize) -> Result<()> {
+        if len >= table.len {
+            return Ok(());
+        }
+        for block in table.blocks.drain(self.blocks_for(len)..) {
+            self.allocator.release(block)?;
+        }
+        table.len = len;
+        Ok(())
+    }
+
+    /// Write the left-padded new rows of a batch into layer `layer`
+    ///
+    /// `k`/`v` are `[batch, new_len, width]`; sequence `b` owns the last
+    /// `tables[b].len() - starts[b]` rows, which land at positions `starts[b]..`.
+    pub(crate) fn write_new_rows(
+        &mut self,
+        layer: usize,
+        tables: &[&BlockTable],
+        starts: &[usize],
+        k: &Tensor,
+        v: &Tensor,
+    ) -> Result<()> {
+        let width = self.width;
+        let new_len = k.shape[k.ndim() - 2];
+        let (k_data, v_data) = (k.as_f32_slice()?, v.as_f32_slice()?);
+        for (b, (table, &start)) in tables.iter().zip(starts).enumerate() {
+            let real = table.len - start;
+            for i in 0..real {
+                let row = (b * new_len + new_len - real + i) * width;
+                let span = row..row + width;
+                self.write(
+                    layer,
+                    table,
+                    start + i,
+                    &k_data[span.clone()],
+                    &v_data[span],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the key/value rows (`[width]` each) of position `pos` in layer `layer`
+    pub(crate) fn write(
+        &mut self,
+        layer: usize,
+        table: &BlockTable,
+        pos: usize,
+        key: &[f32],
+        value: &[f32],
+    ) -> Result<()> {
+        let row = self.row(table, pos)?;
+        let width = self.width;
+        let pool = &mut self.layers[layer];
+        pool.keys.as_f32_slice_mut()?[row * width..(row + 1) * width].copy_from_slice(key);
+        pool.values.as_f32_slice_mut()?[row * width..(row + 1) * width].copy_from_slice(value);
+        Ok(())
+    }
+
+    /// Key and value block pools of `layer`, each `[num_blocks, block_size, width]`
+    pub fn pools(&self, layer: usize) -> (&Tensor, &Tensor) {
+        let pool = &self.layers[layer];
+        (&pool.keys, &pool.values)
+    }
+
+    /// Block tables and context lengths of a batch in the layout the paged `Attention`
+    /// kernel reads: `[batch, max_blocks]` block ids and `[batch]` lengths, as `f32`
+    pub fn block_table_tensors(&self, tables: &[&BlockTable]) -> Result<(Tensor, Tensor)> {
+        let max_blocks = tables
+            .iter()
+            .map(|t| t.blocks.len())
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let mut ids = Vec::with_capacity(tables.len() * max_blocks);
+        for table in tables {
+            ids.extend(table.blocks.iter().map(|&block| block as f32));
+            ids.resize(ids.len() + max_blocks - table.blocks.len(), 0.0);
+        }
+        let lens = tables.iter().map(|t| t.len as f32).collect();
+        Ok((
+            Tensor::from_f32(vec![tables.len(), max_blocks], ids)?,
+            Tensor::from_f32(vec![tables.len()], lens)?,
+        ))
+    }
+
+    /// Cached keys and values of one sequence in `layer`, gathered into `[len, width]`
+    pub fn gather(&self, layer: usize, table: &BlockTable) -> Result<(Tensor, Tensor)> {
+        let width = self.width;
+        let pool = &self.layers[layer];
+        let (keys, values) = (pool.keys.as_f32_slice()?, pool.values.as_f32_slice()?);
+        let mut k = Vec::with_capacity(table.len * width);
+        let mut v = Vec::with_capacity(table.len * width);
+        for pos in 0..table.len {
+            let row = self.row(table, pos)?;
+            k.extend_from_slice(&keys[row * width..(row + 1) * width]);
+            v.extend_from_slice(&values[row * width..(row + 1) * width]);
+        }
+        Ok((
+            Tensor::from_f32(vec![table.len, width], k)?,
+            Tensor::from_f32(vec![table.len, width], v)?,
+        ))
+    }
+
+    /// Number of blocks covering `len` positions
+    fn blocks_for(&self, len: usize) -> usize {
+        (len + self.block_size - 1) / self.block_size
+    }
+
+    /// Pool row holding position `pos` of a sequence
+    fn row(&self, table: &BlockTable, pos: usize) -> Result<usize> {
+        let block = table.blocks.get(pos / self.block_size).ok_or_else(|| {
+            CoreError::InvalidDimension(format!(
+                "Position {} is beyond the {} reserved positions",
+                pos, table.len
+            ))
+        })?;
+        Ok(block * self.block_size + pos % self.block_size)
+    }
+
+    /// Copy every layer's contents of block `from` into block `to`
+    fn copy_block(&mut self, from: usize, to: usize) -> Result<()> {
+        let span = self.block_size * self.width;
+        for pool in &mut self.layers {
+            for tensor in [&mut pool.keys, &mut pool.values] {
+                tensor
+                    .as_f32_slice_mut()?
+                    .copy_within(from * span..(from + 1) * span, to * span);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::small_config;
+
+    fn row(value: f32) -> Vec<f32> {
+        vec![value; 8]
+    }
+
+    #[test]
+    fn test_reserve_and_free() {
+        let mut cache = PagedKvCache::new(&small_config(), 4, 4).unwrap();
+        let mut table = cache.new_sequence();
+        cache.reserve(&mut table, 5).unwrap();
+        assert_eq!(table.blocks().len(), 2);
+        assert_eq!(cache.allocator().num_free(), 2);
+
+        for pos in 0..5 {
+            cache
+                .write(1, &table, pos, &row(pos as f32), &row(-(pos as f32)))
+                .unwrap();
+        }
+        let (k, v) = cache.gather(1, &table).unwrap();
+        assert_eq!(k.shape, vec![5, 8]);
+        assert_eq!(k.as_f32_slice().unwrap()[4 * 8], 4.0);
+        assert_eq!(v.as_f32_slice().unwrap()[4 * 8], -4.0);
+
+        // 5 + 12 positions need 5 blocks, more than the pool holds
+        assert!(cache.reserve(&mut table, 12).is_err());
+        assert_eq!(table.len(), 5);
+
+        cache.free(table).unwrap();
+        assert_eq!(cache.allocator().num_free(), 4);
+    }
+
+    #[test]
+    fn test_windowed_config_is_rejected() {
+        let config = TransformerConfig {
+            attention_windows: vec![None, Some(4)],
+            ..small_config()
+        };
+        assert!(PagedKvCache::new(&config, 4, 4).is_err());
+    }
+
+    #[test]
+    fn test_fork_copies_on_write() {
+        let mut cache = PagedKvCache::new(&small_config(), 4, 8).unwrap();
+        let mut parent = cache.new_sequence();
+        cache.reserve(&mut parent, 6).unwrap();
+        for pos in 0..6 {
+            cache
+                .write(0, &parent, pos, &row(pos as f32), &row(0.0))
+                .unwrap();
+        }
+
+        let mut child = cache.fork(&parent);
+        assert_eq!(cache.allocator().ref_count(parent.blocks()[0]), 2);
+
+        // Extending the child copies the shared, partially filled last block only
+        cache.reserve(&mut child, 1).unwrap();
+        cache.write(0, &child, 6, &row(60.0), &row(0.0)).unwrap();
+        assert_eq!(child.blocks()[0], parent.blocks()[0]);
+        assert_ne!(child.blocks()[1], parent.blocks()[1]);
+        assert_eq!(cache.allocator().ref_count(parent.blocks()[1]), 1);
+
+        let (child_keys, _) = cache.gather(0, &child).unwrap();
+        let (parent_keys, _) = cache.gather(0, &parent).unwrap();
+        assert_eq!(
+            &child_keys.as_f32_slice().unwrap()[..48],
+            parent_keys.as_f32_slice().unwrap()
+        );
+        assert_eq!(child_keys.as_f32_slice().unwrap()[48], 60.0);
+
+        cache.free(parent).unwrap();
+        cache.free(child).unwrap();
+        assert_eq!(cache.allocator().num_free(), 8);
+    }
+
+    #[test]
+    fn test_release_free_block_is_rejected() {
+        let mut allocator = BlockAllocator::new(2);
+        let block = allocator.allocate().unwrap();
+        allocator.retain(block);
+        allocator.release(block).unwrap();
+        allocator.release(block).unwrap();
+        assert_eq!(allocator.num_free(), 2);
+
+        assert!(allocator.release(block).is_err());
+        assert_eq!(allocator.ref_count(block), 0);
+        assert_eq!(allocator.num_free(), 2);
+    }
+}
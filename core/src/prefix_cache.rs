@@ -0,0 +1,242 @@
+//! Reuse of computed KV state across requests that share a prompt prefix
+//!
+//! A [`PrefixCache`] keeps [`KvCache`] snapshots keyed by the token ids they were computed
+//! from. A lookup returns the state of the longest cached prefix of a new prompt, so only the
+//! remaining tokens need a forward pass. Snapshots are evicted least-recently-used first once
+//! their total size exceeds a byte budget. A cache belongs to one model: snapshots from
+//! different weights are not interchangeable.
+
+use crate::kv_cache::KvCache;
+
+/// Lookup and eviction counters of a [`PrefixCache`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefixCacheStats {
+    /// Lookups that reused at least one cached position
+    pub hits: u64,
+    /// Lookups that found no cached prefix
+    pub misses: u64,
+    /// Positions reused across all hits
+    pub reused_tokens: u64,
+    /// Snapshots evicted to stay within the memory budget
+    pub evictions: u64,
+}
+
+impl PrefixCacheStats {
+    /// Fraction of lookups that were hits, or 0 before any lookup
+    pub fn hit_rate(&self) -> f32 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f32 / lookups as f32
+        }
+    }
+}
+
+/// A cached prompt and its KV state
+#[derive(Debug, Clone)]
+struct Entry {
+    tokens: Vec<u32>,
+    cache: KvCache,
+    last_used: u64,
+}
+
+/// LRU cache of KV state keyed by token-id prefixes
+#[derive(Debug, Clone)]
+pub struct PrefixCache {
+    entries: Vec<Entry>,
+    max_bytes: usize,
+    memory_bytes: usize,
+    clock: u64,
+    stats: PrefixCacheStats,
+}
+
+impl PrefixCache {
+    /// Create an empty cache holding at most `max_bytes` of keys and values
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            max_bytes,
+            memory_bytes: 0,
+            clock: 0,
+            stats: PrefixCacheStats::default(),
+        }
+    }
+
+    /// Memory budget in bytes
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Bytes held by the cached snapshots
+    pub fn memory_bytes(&self) -> usize {
+        self.memory_bytes
+    }
+
+    /// Number of cached snapshots
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if nothing is cached
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Lookup and eviction counters
+    pub fn stats(&self) -> PrefixCacheStats {
+        self.stats
+    }
+
+    /// Return the KV state of the longest cached prefix of `tokens`
+    ///
+    /// Any snapshot sharing leading tokens with `tokens` can serve the lookup, truncated to
+    /// the shared part. The returned cache holds `len()` positions, so the caller feeds
+    /// `tokens[cache.len()..]` next. Callers that need logits for the last token should look
+    /// up all but the last token.
+    pub fn lookup(&mut self, tokens: &[u32]) -> Option<KvCache> {
+        let best = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (i, common_prefix_len(&entry.tokens, tokens)))
+            .filter(|&(_, len)| len > 0)
+            .max_by_key(|&(i, len)| (len, self.entries[i].last_used));
+        let Some((index, len)) = best else {
+            self.stats.misses += 1;
+            return None;
+        };
+
+        let mut cache = self.entries[index].cache.clone();
+        cache.truncate(len);
+        if cache.len() != len {
+            // A rolling cache has already evicted positions needed to continue from `len`
+            self.stats.misses += 1;
+            return None;
+        }
+
+        self.clock += 1;
+        self.entries[index].last_used = self.clock;
+        self.stats.hits += 1;
+        self.stats.reused_tokens += len as u64;
+        Some(cache)
+    }
+
+    /// Cache the KV state of `tokens`, taken from the first `tokens.len()` positions of
+    /// `cache`
+    ///
+    /// Snapshots whose tokens are a prefix of `tokens` are replaced, since the new one serves
+    /// every lookup they could. Least recently used snapshots are then evicted until the
+    /// budget is met; a snapshot larger than the whole budget is not stored.
+    pub fn insert(&mut self, tokens: &[u32], cache: &KvCache) {
+        if tokens.is_empty() || cache.len() < tokens.len() {
+            return;
+        }
+        self.clock += 1;
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.tokens.starts_with(tokens))
+        {
+            entry.last_used = self.clock;
+            return;
+        }
+
+        let mut snapshot = cache.clone();
+        snapshot.truncate(tokens.len());
+        let size = snapshot.memory_bytes();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut freed = 0;
+        self.entries.retain(|entry| {
+            let subsumed = tokens.starts_with(&entry.tokens);
+            if subsumed {
+                freed += entry.cache.memory_bytes();
+            }
+            !subsumed
+        });
+        self.memory_bytes -= freed;
+
+        while self.memory_bytes + size > self.max_bytes {
+            self.evict_lru();
+        }
+        self.memory_bytes += size;
+        self.entries.push(Entry {
+            tokens: tokens.to_vec(),
+            cache: snapshot,
+            last_used: self.clock,
+        });
+    }
+
+    /// Drop every snapshot, keeping the counters
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.memory_bytes = 0;
+    }
+
+    /// Remove the least recently used snapshot
+    fn evict_lru(&mut self) {
+        if let Some(index) = self
+            .entries
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(i, _)| i)
+        {
+            let entry = self.entries.swap_remove(index);
+            self.memory_bytes -= entry.cache.memory_bytes();
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+/// Number of leading tokens `a` and `b` share
+fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{random_model, small_config};
+
+    #[test]
+    fn test_longest_prefix_and_lru_eviction() {
+        let model = random_model(small_config(), 5);
+        let prefill = |tokens: &[u32]| {
+            let mut cache = model.new_kv_cache();
+            model.forward_cpu_with_cache(tokens, &mut cache).unwrap();
+            cache
+        };
+        // Two layers of 8-wide keys and values: 128 bytes per position
+        let mut cache = PrefixCache::new(128 * 8);
+
+        assert!(cache.lookup(&[1, 2, 3]).is_none());
+        cache.insert(&[1, 2, 3, 4], &prefill(&[1, 2, 3, 4]));
+        cache.insert(&[5, 6], &prefill(&[5, 6]));
+        assert_eq!(cache.memory_bytes(), 128 * 6);
+
+        // A diverging prompt reuses the shared part of a longer snapshot
+        let hit = cache.lookup(&[1, 2, 9]).unwrap();
+        assert_eq!(hit.len(), 2);
+        let expected = prefill(&[1, 2]);
+        assert_eq!(
+            hit.layers()[1].keys().unwrap().as_f32_slice().unwrap(),
+            expected.layers()[1].keys().unwrap().as_f32_slice().unwrap()
+        );
+
+        // [5, 6] is now least recently used and makes room for the new snapshot
+        cache.insert(&[7, 8, 9], &prefill(&[7, 8, 9]));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.lookup(&[5, 6]).is_none());
+
+        // Extending a cached prompt replaces its snapshot instead of duplicating it
+        cache.insert(&[7, 8, 9, 10], &prefill(&[7, 8, 9, 10]));
+        assert_eq!(cache.memory_bytes(), 128 * 8);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 2, 1));
+        assert_eq!(stats.reused_tokens, 2);
+    }
+}
@@ -0,0 +1,716 @@
+//! Continuous batching of concurrent generation requests
+//!
+//! A [`Scheduler`] keeps a running batch of sequences on one [`GpuDevice`] and a shared
+//! [`TransformerModel`]. Every [`step`](Scheduler::step) is a token boundary: waiting
+//! requests are admitted while the batch has room, one batched forward pass advances every
+//! running sequence (prefilling newcomers and decoding the rest), and finished sequences are
+//! evicted so their slots and tokens can be reused. A sequence that fails on its own is evicted
+//! as a [`FailedRequest`] so it cannot stall the rest of the batch.
+
+use crate::error::{CoreError, Result};
+use crate::generation::{FinishReason, GenerationConfig, GenerationOutput, Sampler};
+use crate::gpu::GpuDevice;
+use crate::kv_cache::KvCache;
+use crate::prefix_cache::PrefixCache;
+use crate::transformer::TransformerModel;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Identifier assigned to a submitted request
+pub type RequestId = u64;
+
+/// Capacity limits of the running batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedulerConfig {
+    /// Maximum number of sequences in the running batch
+    pub max_batch_size: usize,
+    /// Maximum number of KV positions reserved across running sequences
+    ///
+    /// Each sequence reserves its prompt plus its `max_new_tokens` (capped at the model's
+    /// `max_seq_len`), so admitted sequences can always run to completion.
+    pub max_tokens_in_flight: usize,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 8,
+            max_tokens_in_flight: 4096,
+        }
+    }
+}
+
+/// A token produced for one request during a [`Scheduler::step`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduledToken {
+    /// Request the token belongs to
+    pub request_id: RequestId,
+    /// Sampled token id
+    pub token: u32,
+    /// Set when this token finished the request
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// A request that has finished generating
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletedRequest {
+    /// Request identifier returned by [`Scheduler::submit`]
+    pub request_id: RequestId,
+    /// Generated tokens and finish reason
+    pub output: GenerationOutput,
+}
+
+/// A request evicted because its own forward pass or sampling failed
+#[derive(Debug)]
+pub struct FailedRequest {
+    /// Request identifier returned by [`Scheduler::submit`]
+    pub request_id: RequestId,
+    /// Tokens generated before the failure
+    pub tokens: Vec<u32>,
+    /// Error raised when the sequence was run on its own
+    pub error: CoreError,
+}
+
+/// A submitted request waiting for admission
+struct Request {
+    id: RequestId,
+    prompt: Vec<u32>,
+    config: GenerationConfig,
+    reserved: usize,
+}
+
+/// A sequence in the running batch
+struct Sequence {
+    id: RequestId,
+    sampler: Sampler,
+    cache: KvCache,
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    /// Tokens not yet fed through the model: the prompt, then the last sampled token
+    pending: Vec<u32>,
+    reserved: usize,
+}
+
+impl Sequence {
+    fn generated(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+
+    /// Drop `pending` from the cache after a forward pass whose result is discarded (a no-op
+    /// if the pass failed and the model already rolled it back)
+    fn roll_back(&mut self) {
+        self.cache.truncate(self.tokens.len() - self.pending.len());
+        self.sync_pending();
+    }
+
+    /// Queue every token the cache does not hold, as after a failed forward pass that reset
+    /// a rolling cache it could not roll back
+    fn sync_pending(&mut self) {
+        self.pending = self.tokens[self.cache.len()..].to_vec();
+    }
+}
+
+/// Runs many generation requests at once with continuous batching
+pub struct Scheduler<'a> {
+    model: &'a TransformerModel,
+    device: Arc<dyn GpuDevice>,
+    config: SchedulerConfig,
+    waiting: VecDeque<Request>,
+    running: Vec<Sequence>,
+    completed: Vec<CompletedRequest>,
+    failed: Vec<FailedRequest>,
+    next_id: RequestId,
+    prefix_cache: Option<PrefixCache>,
+}
+
+impl<'a> Scheduler<'a> {
+    /// Create an idle scheduler for `model` running on `device`
+    pub fn new(
+        model: &'a TransformerModel,
+        device: Arc<dyn GpuDevice>,
+        config: SchedulerConfig,
+    ) -> Self {
+        Self {
+            model,
+            device,
+            config,
+            waiting: VecDeque::new(),
+            running: Vec::new(),
+            completed: Vec::new(),
+            failed: Vec::new(),
+            next_id: 0,
+            prefix_cache: None,
+        }
+    }
+
+    /// Start admitted requests from the longest cached prefix of their prompt, and cache
+    /// each prompt's KV state once it has been prefilled
+    pub fn with_prefix_cache(mut self, cache: PrefixCache) -> Self {
+        self.prefix_cache = Some(cache);
+        self
+    }
+
+    /// The prefix cache, if enabled
+    pub fn prefix_cache(&self) -> Option<&PrefixCache> {
+        self.prefix_cache.as_ref()
+    }
+
+    /// The scheduler capacity limits
+    pub fn config(&self) -> &SchedulerConfig {
+        &self.config
+    }
+
+    /// Queue a request; it joins the running batch at a later token boundary
+    pub fn submit(&mut self, prompt: Vec<u32>, config: GenerationConfig) -> Result<RequestId> {
+        config.validate()?;
+        let max_seq_len = self.model.config.context_len();
+        if prompt.is_empty() || prompt.len() > max_seq_len {
+            return Err(CoreError::InvalidDimension(format!(
+                "Prompt length must be in 1..={}, got {}",
+                max_seq_len,
+                prompt.len()
+            )));
+        }
+        let vocab_size = self.model.config.vocab_size;
+        if let Some(&id) = prompt.iter().find(|&&id| id as usize >= vocab_size) {
+            return Err(CoreError::InvalidDimension(format!(
+                "Token id {} out of range for vocabulary of {}",
+                id, vocab_size
+            )));
+        }
+        let reserved = prompt
+            .len()
+            .saturating_add(config.max_new_tokens)
+            .min(max_seq_len);
+        if reserved > self.config.max_tokens_in_flight {
+            return Err(CoreError::Other(format!(
+                "Request needs {} tokens but max_tokens_in_flight is {}",
+                reserved, self.config.max_tokens_in_flight
+            )));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiting.push_back(Request {
+            id,
+            prompt,
+            config,
+            reserved,
+        });
+        Ok(id)
+    }
+
+    /// Number of requests waiting for admission
+    pub fn num_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Number of sequences in the running batch
+    pub fn num_running(&self) -> usize {
+        self.running.len()
+    }
+
+    /// KV positions reserved by the running batch
+    pub fn tokens_in_flight(&self) -> usize {
+        self.running.iter().map(|seq| seq.reserved).sum()
+    }
+
+    /// Whether no request is waiting or running
+    pub fn is_idle(&self) -> bool {
+        self.waiting.is_empty() && self.running.is_empty()
+    }
+
+    /// Take the requests completed so far, in completion order
+    pub fn take_completed(&mut self) -> Vec<CompletedRequest> {
+        std::mem::take(&mut self.completed)
+    }
+
+    /// Take the requests evicted after failing on their own, in eviction order
+    pub fn take_failed(&mut self) -> Vec<FailedRequest> {
+        std::mem::take(&mut self.failed)
+    }
+
+    /// Advance by one token boundary: admit, run one batched forward pass, sample and evict
+    ///
+    /// Returns the token produced for every running sequence. If the batch fails, each
+    /// sequence is retried on its own: those that still fail are evicted as
+    /// [`FailedRequest`]s and the rest are stepped again. When every sequence fails the error
+    /// is returned and the batch is left as it was before the step.
+    pub fn step(&mut self) -> Result<Vec<ScheduledToken>> {
+        self.admit();
+        loop {
+            if self.running.is_empty() {
+                return Ok(Vec::new());
+            }
+            match self.step_batch() {
+                Ok(events) => return Ok(events),
+                Err(e) => {
+                    if !self.evict_failing() {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run one batched forward pass over the running sequences, sample and evict
+    ///
+    /// On failure every sequence is rolled back to its state before the call.
+    fn step_batch(&mut self) -> Result<Vec<ScheduledToken>> {
+        // Pending tokens are only cleared once the forward pass succeeds
+        let token_ids: Vec<Vec<u32>> = self.running.iter().map(|seq| seq.pending.clone()).collect();
+        let mut caches: Vec<&mut KvCache> =
+            self.running.iter_mut().map(|seq| &mut seq.cache).collect();
+        let logits = match self
+            .model
+            .forward_with_caches(&token_ids, &mut caches, &self.device)
+        {
+            Ok(logits) => logits,
+            Err(e) => {
+                self.running.iter_mut().for_each(Sequence::sync_pending);
+                return Err(e);
+            }
+        };
+
+        // Every sequence's last token sits in the last (left-padded) column. All rows are
+        // sampled with copies of the samplers before any sequence changes, so a failure rolls
+        // back like a failed forward pass.
+        let vocab_size = self.model.config.vocab_size;
+        let max_new = logits.shape[1];
+        let logits = logits.as_f32_slice()?;
+        let sampled: Result<Vec<(u32, Sampler)>> = self
+            .running
+            .iter()
+            .enumerate()
+            .map(|(b, seq)| {
+                let row = (b * max_new + max_new - 1) * vocab_size;
+                let mut sampler = seq.sampler.clone();
+                let token = sampler.sample(&logits[row..row + vocab_size], &seq.tokens)?;
+                Ok((token, sampler))
+            })
+            .collect();
+        let sampled = match sampled {
+            Ok(sampled) => sampled,
+            Err(e) => {
+                self.running.iter_mut().for_each(Sequence::roll_back);
+                return Err(e);
+            }
+        };
+
+        self.running.iter_mut().for_each(|seq| seq.pending.clear());
+        if let Some(prefix_cache) = &mut self.prefix_cache {
+            // Sequences whose cache holds exactly their prompt were just prefilled
+            for seq in self
+                .running
+                .iter()
+                .filter(|seq| seq.cache.len() == seq.prompt_len)
+            {
+                prefix_cache.insert(&seq.tokens[..seq.prompt_len], &seq.cache);
+            }
+        }
+
+        let max_seq_len = self.model.config.context_len();
+        let mut events = Vec::with_capacity(self.running.len());
+        for (seq, (token, sampler)) in self.running.iter_mut().zip(sampled) {
+            seq.sampler = sampler;
+            let finish_reason = if seq.sampler.config().stop_tokens.contains(&token) {
+                Some(FinishReason::StopToken(token))
+            } else {
+                seq.tokens.push(token);
+                seq.pending.push(token);
+                if seq.generated().len() >= seq.sampler.config().max_new_tokens {
+                    Some(FinishReason::MaxNewTokens)
+                } else if seq.tokens.len() >= max_seq_len {
+                    Some(FinishReason::ContextFull)
+                } else {
+                    None
+                }
+            };
+            events.push(ScheduledToken {
+                request_id: seq.id,
+                token,
+                finish_reason,
+            });
+        }
+
+        for event in &events {
+            if let Some(finish_reason) = event.finish_reason {
+                self.evict(event.request_id, finish_reason);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Step until every submitted request has completed and return them in completion order
+    pub fn run_to_completion(&mut self) -> Result<Vec<CompletedRequest>> {
+        while !self.is_idle() {
+            self.step()?;
+        }
+        Ok(self.take_completed())
+    }
+
+    /// Move waiting requests into the running batch while capacity allows, in FIFO order
+    fn admit(&mut self) {
+        let mut in_flight = self.tokens_in_flight();
+        while let Some(request) = self.waiting.front() {
+            if self.running.len() >= self.config.max_batch_size
+                || in_flight + request.reserved > self.config.max_tokens_in_flight
+            {
+                break;
+            }
+            let request = self.waiting.pop_front().expect("front exists");
+            if request.config.max_new_tokens == 0 {
+                self.completed.push(CompletedRequest {
+                    request_id: request.id,
+                    output: GenerationOutput {
+                        tokens: Vec::new(),
+                        finish_reason: FinishReason::MaxNewTokens,
+                    },
+                });
+                continue;
+            }
+            in_flight += request.reserved;
+            // Keep at least one prompt token to produce logits from
+            let prompt = &request.prompt;
+            let cache = self
+                .prefix_cache
+                .as_mut()
+                .and_then(|cache| cache.lookup(&prompt[..prompt.len() - 1]))
+                .unwrap_or_else(|| self.model.new_kv_cache());
+            self.running.push(Sequence {
+                id: request.id,
+                sampler: Sampler::new(request.config),
+                prompt_len: prompt.len(),
+                pending: prompt[cache.len()..].to_vec(),
+                cache,
+                tokens: request.prompt,
+                reserved: request.reserved,
+            });
+        }
+    }
+
+    /// Retry each running sequence on its own and evict those that fail
+    ///
+    /// Returns whether anything was evicted. Nothing is evicted when every sequence fails,
+    /// since the fault then lies with the model or device rather than with a request.
+    fn evict_failing(&mut self) -> bool {
+        let mut errors: Vec<Option<CoreError>> = self
+            .running
+            .iter_mut()
+            .map(|seq| {
+                let result = Self::probe(self.model, &self.device, seq);
+                seq.roll_back();
+                result.err()
+            })
+            .collect();
+        let num_failed = errors.iter().filter(|error| error.is_some()).count();
+        if num_failed == 0 || num_failed == self.running.len() {
+            return false;
+        }
+
+        let running = std::mem::take(&mut self.running);
+        for (seq, error) in running.into_iter().zip(errors.iter_mut()) {
+            match error.take() {
+                Some(error) => self.failed.push(FailedRequest {
+                    request_id: seq.id,
+                    tokens: seq.generated().to_vec(),
+                    error,
+                }),
+                None => self.running.push(seq),
+            }
+        }
+        true
+    }
+
+    /// Run one sequence's pending tokens alone and sample its next token, without keeping the
+    /// sampled token; the caller rolls the cache back
+    fn probe(
+        model: &TransformerModel,
+        device: &Arc<dyn GpuDevice>,
+        seq: &mut Sequence,
+    ) -> Result<()> {
+        let logits = model.forward_with_caches(
+            std::slice::from_ref(&seq.pending),
+            &mut [&mut seq.cache],
+            device,
+        )?;
+        let vocab_size = model.config.vocab_size;
+        let logits = logits.as_f32_slice()?;
+        let row = logits.len() - vocab_size;
+        seq.sampler
+            .clone()
+            .sample(&logits[row..], &seq.tokens)
+            .map(|_| ())
+    }
+
+    /// Remove a finished sequence from the running batch
+    fn evict(&mut self, id: RequestId, finish_reason: FinishReason) {
+        if let Some(index) = self.running.iter().position(|seq| seq.id == id) {
+            let seq = self.running.remove(index);
+            self.completed.push(CompletedRequest {
+                request_id: id,
+                output: GenerationOutput {
+                    tokens: seq.generated().to_vec(),
+                    finish_reason,
+                },
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generation::Generator;
+    use crate::test_utils::{random_model, reference_device, small_config};
+
+    fn prompts() -> Vec<Vec<u32>> {
+        vec![vec![1, 2, 3], vec![7], vec![4, 4, 9, 10, 2], vec![5, 6]]
+    }
+
+    #[test]
+    fn test_matches_individual_generation() {
+        let model = random_model(small_config(), 3);
+        let config = |i: usize| GenerationConfig {
+            max_new_tokens: 3 + i,
+            seed: i as u64,
+            ..GenerationConfig::default()
+        };
+
+        let mut scheduler = Scheduler::new(
+            &model,
+            reference_device(),
+            SchedulerConfig {
+                max_batch_size: 2,
+                max_tokens_in_flight: 64,
+            },
+        );
+        for (i, prompt) in prompts().into_iter().enumerate() {
+            assert_eq!(scheduler.submit(prompt, config(i)).unwrap(), i as u64);
+        }
+
+        let mut completed = Vec::new();
+        while !scheduler.is_idle() {
+            scheduler.step().unwrap();
+            assert!(scheduler.num_running() <= 2);
+            completed.extend(scheduler.take_completed());
+        }
+        assert_eq!(completed.len(), 4);
+
+        for request in completed {
+            let i = request.request_id as usize;
+            let expected = Generator::new(&model, reference_device(), config(i))
+                .generate(&prompts()[i])
+                .unwrap();
+            assert_eq!(request.output, expected);
+        }
+    }
+
+    #[test]
+    fn test_failed_step_keeps_sequences() {
+        use crate::test_utils::random_tensor;
+
+        let model = random_model(small_config(), 3);
+        // The second layer fails after the first has cached the step's keys and values
+        let mut broken = random_model(small_config(), 3);
+        broken.layers[1].attention.wq = random_tensor(vec![3, 3], 1);
+
+        let mut scheduler = Scheduler::new(
+            &model,
+            reference_device(),
+            SchedulerConfig {
+                max_batch_size: 4,
+                max_tokens_in_flight: 64,
+            },
+        );
+        for prompt in prompts() {
+            scheduler
+                .submit(prompt, GenerationConfig::greedy(4))
+                .unwrap();
+        }
+        scheduler.step().unwrap();
+        scheduler.model = &broken;
+        assert!(scheduler.step().is_err());
+        assert!(scheduler.step().is_err());
+        scheduler.model = &model;
+
+        for request in scheduler.run_to_completion().unwrap() {
+            let expected = Generator::new(&model, reference_device(), GenerationConfig::greedy(4))
+                .generate(&prompts()[request.request_id as usize])
+                .unwrap();
+            assert_eq!(request.output, expected);
+        }
+    }
+
+    #[test]
+    fn test_failed_sequence_is_evicted() {
+        let model = random_model(small_config(), 3);
+        // Position 4 embeds to NaN, so only the third (five-token) prompt fails to prefill
+        let mut poisoned = random_model(small_config(), 3);
+        let d_model = poisoned.config.d_model;
+        poisoned.position_embedding.as_f32_slice_mut().unwrap()[4 * d_model..5 * d_model]
+            .fill(f32::NAN);
+        let config = |i: usize| GenerationConfig {
+            max_new_tokens: 4,
+            temperature: if i == 1 { 1.0 } else { 0.0 },
+            seed: 9,
+            ..GenerationConfig::default()
+        };
+
+        let mut scheduler = Scheduler::new(
+            &poisoned,
+            reference_device(),
+            SchedulerConfig {
+                max_batch_size: 4,
+                max_tokens_in_flight: 64,
+            },
+        );
+        for (i, prompt) in prompts().into_iter().enumerate() {
+            scheduler.submit(prompt, config(i)).unwrap();
+        }
+        let events = scheduler.step().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(scheduler.num_running(), 3);
+        let failed = scheduler.take_failed();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].request_id, 2);
+        assert!(failed[0].tokens.is_empty());
+        scheduler.model = &model;
+
+        let completed = scheduler.run_to_completion().unwrap();
+        assert_eq!(completed.len(), 3);
+        for request in completed {
+            let i = request.request_id as usize;
+            let expected = Generator::new(&model, reference_device(), config(i))
+                .generate(&prompts()[i])
+                .unwrap();
+            assert_eq!(request.output, expected);
+        }
+    }
+
+    #[test]
+    fn test_rejects_out_of_range_prompt() {
+        let model = random_model(small_config(), 3);
+        let vocab_size = model.config.vocab_size as u32;
+        let mut scheduler = Scheduler::new(
+            &model,
+            reference_device(),
+            SchedulerConfig {
+                max_batch_size: 4,
+                max_tokens_in_flight: 64,
+            },
+        );
+        let good = prompts();
+        scheduler
+            .submit(good[0].clone(), GenerationConfig::greedy(3))
+            .unwrap();
+        assert!(matches!(
+            scheduler.submit(vec![1, vocab_size], GenerationConfig::greedy(3)),
+            Err(CoreError::InvalidDimension(_))
+        ));
+        scheduler
+            .submit(good[1].clone(), GenerationConfig::greedy(3))
+            .unwrap();
+
+        let completed = scheduler.run_to_completion().unwrap();
+        assert_eq!(completed.len(), 2);
+        for request in completed {
+            let expected = Generator::new(&model, reference_device(), GenerationConfig::greedy(3))
+                .generate(&good[request.request_id as usize])
+                .unwrap();
+            assert_eq!(request.output, expected);
+        }
+        assert!(scheduler.take_failed().is_empty());
+    }
+
+    #[test]
+    fn test_unbounded_budget_is_capped() {
+        let model = random_model(small_config(), 3);
+        let max_seq_len = model.config.context_len();
+        let mut scheduler = Scheduler::new(
+            &model,
+            reference_device(),
+            SchedulerConfig {
+                max_batch_size: 4,
+                max_tokens_in_flight: max_seq_len - 1,
+            },
+        );
+        assert!(scheduler
+            .submit(vec![1, 2], GenerationConfig::greedy(usize::MAX))
+            .is_err());
+
+        scheduler.config.max_tokens_in_flight = max_seq_len;
+        scheduler
+            .submit(vec![1, 2], GenerationConfig::greedy(usize::MAX))
+            .unwrap();
+        let completed = scheduler.run_to_completion().unwrap();
+        assert_eq!(completed[0].output.finish_reason, FinishReason::ContextFull);
+    }
+
+    #[test]
+    fn test_tokens_in_flight_limit() {
+        let model = random_model(small_config(), 3);
+        let mut scheduler = Scheduler::new(
+            &model,
+            reference_device(),
+            SchedulerConfig {
+                max_batch_size: 8,
+                max_tokens_in_flight: 8,
+            },
+        );
+        assert!(scheduler
+            .submit(vec![1; 4], GenerationConfig::greedy(5))
+            .is_err());
+
+        // Each request reserves 5 tokens, so only one fits at a time
+        for prompt in prompts().into_iter().take(2) {
+            let budget = 5 - prompt.len();
+            scheduler
+                .submit(prompt, GenerationConfig::greedy(budget))
+                .unwrap();
+        }
+        scheduler.step().unwrap();
+        assert_eq!((scheduler.num_running(), scheduler.num_waiting()), (1, 1));
+        assert_eq!(scheduler.tokens_in_flight(), 5);
+
+        let completed = scheduler.run_to_completion().unwrap();
+        assert_eq!(completed.len(), 2);
+        assert_eq!(completed[1].output.tokens.len(), 4);
+    }
+
+    #[test]
+    fn test_prefix_cache_shared_system_prompt() {
+        let model = random_model(small_config(), 3);
+        let system = [4, 8, 5, 6, 3];
+        let prompts: Vec<Vec<u32>> = [vec![1], vec![2, 3], vec![1, 9]]
+            .iter()
+            .map(|user| [&system[..], user].concat())
+            .collect();
+
+        let mut scheduler = Scheduler::new(
+            &model,
+            reference_device(),
+            SchedulerConfig {
+                max_batch_size: 1,
+                max_tokens_in_flight: 64,
+            },
+        )
+        .with_prefix_cache(PrefixCache::new(1 << 20));
+        for prompt in &prompts {
+            scheduler
+                .submit(prompt.clone(), GenerationConfig::greedy(3))
+                .unwrap();
+        }
+        let completed = scheduler.run_to_completion().unwrap();
+
+        for request in completed {
+            let expected = Generator::new(&model, reference_device(), GenerationConfig::greedy(3))
+                .generate(&prompts[request.request_id as usize])
+                .unwrap();
+            assert_eq!(request.output, expected);
+        }
+        let stats = scheduler.prefix_cache().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert_eq!(stats.reused_tokens, 5 + 6);
+    }
+}
@@ -0,0 +1,403 @@
+//! Speculative decoding with a small draft model
+//!
+//! A cheap draft [`TransformerModel`] proposes a few tokens, and the target model scores all of
+//! them in a single forward pass. Each proposal is accepted with probability
+//! `min(1, p(x) / q(x))`; on rejection a replacement is drawn from `max(0, p - q)`, so the
+//! output follows the target distribution `p` exactly.
+
+use crate::error::{CoreError, Result};
+use crate::generation::{FinishReason, GenerationConfig, Rng, Sampler};
+use crate::gpu::GpuDevice;
+use crate::kv_cache::KvCache;
+use crate::transformer::TransformerModel;
+use std::sync::Arc;
+
+/// Parameters for speculative decoding
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeculativeConfig {
+    /// Sampling and stopping parameters, applied to both models
+    pub generation: GenerationConfig,
+    /// Number of tokens the draft model proposes per target pass
+    pub num_draft_tokens: usize,
+}
+
+impl Default for SpeculativeConfig {
+    fn default() -> Self {
+        Self {
+            generation: GenerationConfig::default(),
+            num_draft_tokens: 4,
+        }
+    }
+}
+
+/// Acceptance statistics of a speculative decoding run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpeculativeStats {
+    /// Target forward passes over draft proposals
+    pub target_passes: usize,
+    /// Tokens proposed by the draft model
+    pub proposed_tokens: usize,
+    /// Proposed tokens accepted by the target model
+    pub accepted_tokens: usize,
+}
+
+impl SpeculativeStats {
+    /// Fraction of proposed tokens that were accepted (`0.0` if nothing was proposed)
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.proposed_tokens == 0 {
+            0.0
+        } else {
+            self.accepted_tokens as f32 / self.proposed_tokens as f32
+        }
+    }
+}
+
+/// Result of a speculative decoding call
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeculativeOutput {
+    /// Generated token ids, excluding the prompt and any stop token
+    pub tokens: Vec<u32>,
+    /// Why generation stopped
+    pub finish_reason: FinishReason,
+    /// Draft acceptance statistics
+    pub stats: SpeculativeStats,
+}
+
+/// Decoding state of one model: its cache holds every token so far, and `logits` is the
+/// `[vocab_size]` row for the next position
+struct ModelState<'a> {
+    model: &'a TransformerModel,
+    cache: KvCache,
+    logits: Vec<f32>,
+}
+
+impl<'a> ModelState<'a> {
+    fn prefill(
+        model: &'a TransformerModel,
+        prompt: &[u32],
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Self> {
+        let mut state = Self {
+            model,
+            cache: model.new_kv_cache(),
+            logits: Vec::new(),
+        };
+        state.feed(prompt, device)?;
+        Ok(state)
+    }
+
+    /// Feed `tokens` and return the logits of every fed position, keeping the last row as the
+    /// next-position logits
+    fn feed(&mut self, tokens: &[u32], device: &Arc<dyn GpuDevice>) -> Result<Vec<f32>> {
+        let vocab_size = self.model.config.vocab_size;
+        let logits = self
+            .model
+            .forward_with_cache(tokens, &mut self.cache, device)?;
+        let logits = logits.as_f32_slice()?.to_vec();
+        self.logits = logits[logits.len() - vocab_size..].to_vec();
+        Ok(logits)
+    }
+
+    /// Truncate the cache to `len` positions, or return to `snapshot` (taken at fewer
+    /// positions) if the rows `len` needs were already evicted
+    fn roll_back(&mut self, len: usize, snapshot: Option<KvCache>) {
+        match snapshot {
+            Some(snapshot) if !self.cache.can_truncate(len) => self.cache = snapshot,
+            _ => self.cache.truncate(len),
+        }
+    }
+}
+
+/// Generates from a target model using proposals from a smaller draft model
+pub struct SpeculativeDecoder<'a> {
+    draft: &'a TransformerModel,
+    target: &'a TransformerModel,
+    device: Arc<dyn GpuDevice>,
+    config: SpeculativeConfig,
+}
+
+impl<'a> SpeculativeDecoder<'a> {
+    /// Create a decoder; both models must share a vocabulary
+    pub fn new(
+        draft: &'a TransformerModel,
+        target: &'a TransformerModel,
+        device: Arc<dyn GpuDevice>,
+        config: SpeculativeConfig,
+    ) -> Result<Self> {
+        if draft.config.vocab_size != target.config.vocab_size {
+            return Err(CoreError::InvalidDimension(format!(
+                "Draft vocabulary ({}) does not match target vocabulary ({})",
+                draft.config.vocab_size, target.config.vocab_size
+            )));
+        }
+        Ok(Self {
+            draft,
+            target,
+            device,
+            config,
+        })
+    }
+
+    /// The speculative decoding configuration
+    pub fn config(&self) -> &SpeculativeConfig {
+        &self.config
+    }
+
+    /// Generate a continuation of `prompt` distributed as the target model would sample it
+    pub fn generate(&self, prompt: &[u32]) -> Result<SpeculativeOutput> {
+        let config = &self.config.generation;
+        config.validate()?;
+        if prompt.is_empty() {
+            return Err(CoreError::InvalidDimension(
+                "Prompt must contain at least one token".to_string(),
+            ));
+        }
+
+        let vocab_size = self.target.config.vocab_size;
+        let max_seq_len = self
+            .target
+            .config
+            .context_len()
+            .min(self.draft.config.context_len());
+        let sampler = Sampler::new(config.clone());
+        let mut rng = Rng::new(config.seed);
+        let mut stats = SpeculativeStats::default();
+        let mut tokens = prompt.to_vec();
+
+        if config.max_new_tokens == 0 {
+            return Ok(SpeculativeOutput {
+                tokens: Vec::new(),
+                finish_reason: FinishReason::MaxNewTokens,
+                stats,
+            });
+        }
+        let mut target = ModelState::prefill(self.target, prompt, &self.device)?;
+        let mut draft = ModelState::prefill(self.draft, prompt, &self.device)?;
+
+        loop {
+            let len = tokens.len();
+            let remaining = config.max_new_tokens - (len - prompt.len());
+            // Leave room for the token sampled from the target after the proposals
+            let k = self
+                .config
+                .num_draft_tokens
+                .min(remaining - 1)
+                .min(max_seq_len.saturating_sub(len + 1));
+
+            // A rolling cache may not be able to roll back over the proposals once they evict
+            // rows, so keep a copy of its window to return to instead of replaying everything
+            let target_snapshot = target.cache.is_rolling().then(|| target.cache.clone());
+            let draft_snapshot = draft.cache.is_rolling().then(|| draft.cache.clone());
+
+            // Draft k tokens autoregressively, remembering each proposal distribution
+            let mut proposals = Vec::with_capacity(k);
+            let mut history = tokens.clone();
+            for _ in 0..k {
+                let q = sampler.distribution(&draft.logits, &history);
+                let token = sample_dense(&q, &mut rng)?;
+                history.push(token);
+                draft.feed(&[token], &self.device)?;
+                proposals.push((token, q));
+            }
+
+            // Score every proposal with one target pass; row i is the distribution after
+            // accepting the first i proposals
+            let mut rows = vec![target.logits.clone()];
+            if k > 0 {
+                let logits = target.feed(&history[len..], &self.device)?;
+                rows.extend(logits.chunks_exact(vocab_size).map(<[f32]>::to_vec));
+                stats.target_passes += 1;
+                stats.proposed_tokens += k;
+            }
+
+            let mut emitted = Vec::with_capacity(k + 1);
+            let mut rejected = false;
+            for (i, (token, q)) in proposals.iter().enumerate() {
+                let p = sampler.distribution(&rows[i], &history[..len + i]);
+                let (next, accepted) = accept_or_resample(&p, q, *token, &mut rng)?;
+                emitted.push(next);
+                if !accepted {
+                    rejected = true;
+                    break;
+                }
+                stats.accepted_tokens += 1;
+            }
+            if !rejected {
+                let p = sampler.distribution(&rows[k], &history);
+                emitted.push(sample_dense(&p, &mut rng)?);
+            }
+
+            // Roll both caches back to the accepted prefix
+            let accepted = emitted.len() - 1;
+            target.roll_back(len + accepted, target_snapshot);
+            draft.roll_back(len + accepted, draft_snapshot);
+
+            for &token in &emitted {
+                if config.stop_tokens.contains(&token) {
+                    return Ok(SpeculativeOutput {
+                        tokens: tokens.split_off(prompt.len()),
+                        finish_reason: FinishReason::StopToken(token),
+                        stats,
+                    });
+                }
+                tokens.push(token);
+                let finish_reason = if tokens.len() - prompt.len() >= config.max_new_tokens {
+                    Some(FinishReason::MaxNewTokens)
+                } else if tokens.len() >= max_seq_len {
+                    Some(FinishReason::ContextFull)
+                } else {
+                    None
+                };
+                if let Some(finish_reason) = finish_reason {
+                    return Ok(SpeculativeOutput {
+                        tokens: tokens.split_off(prompt.len()),
+                        finish_reason,
+                        stats,
+                    });
+                }
+            }
+
+            // Bring both models up to date with the last emitted token; a rolling cache that
+            // was restored from its snapshot replays the accepted tokens too
+            target.feed(&tokens[target.cache.len()..], &self.device)?;
+            draft.feed(&tokens[draft.cache.len()..], &self.device)?;
+        }
+    }
+}
+
+/// Draw a token from a dense probability vector
+fn sample_dense(probs: &[f32], rng: &mut Rng) -> Result<u32> {
+    let mut threshold = rng.next_f32() * probs.iter().sum::<f32>();
+    let mut last = None;
+    for (token, &p) in probs.iter().enumerate() {
+        if p <= 0.0 {
+            continue;
+        }
+        if threshold < p {
+            return Ok(token as u32);
+        }
+        threshold -= p;
+        last = Some(token as u32);
+    }
+    // Rounding can leave a sliver of mass past the last candidate
+    last.ok_or_else(|| CoreError::Other("No token has non-zero probability".to_string()))
+}
+
+/// Accept the draft `token` with probability `min(1, p / q)`, otherwise draw a replacement
+/// from the residual `max(0, p - q)`; returns the emitted token and whether it was accepted
+fn accept_or_resample(p: &[f32], q: &[f32], token: u32, rng: &mut Rng) -> Result<(u32, bool)> {
+    let (p_token, q_token) = (p[token as usize], q[token as usize]);
+    if p_token >= q_token || rng.next_f32() < p_token / q_token {
+        return Ok((token, true));
+    }
+    let residual: Vec<f32> = p.iter().zip(q).map(|(p, q)| (p - q).max(0.0)).collect();
+    let next = if residual.iter().sum::<f32>() > 0.0 {
+        sample_dense(&residual, rng)?
+    } else {
+        sample_dense(p, rng)?
+    };
+    Ok((next, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generation::Generator;
+    use crate::test_utils::{random_model, reference_device, small_config};
+    use crate::transformer::{PositionEncoding, TransformerConfig};
+
+    #[test]
+    fn test_rejection_sampling_matches_target() {
+        let p = [0.1, 0.6, 0.0, 0.3];
+        let q = [0.4, 0.2, 0.3, 0.1];
+        let mut rng = Rng::new(11);
+        let trials = 20_000;
+        let mut counts = [0usize; 4];
+        for _ in 0..trials {
+            let draft = sample_dense(&q, &mut rng).unwrap();
+            let (token, _) = accept_or_resample(&p, &q, draft, &mut rng).unwrap();
+            counts[token as usize] += 1;
+        }
+        for (count, expected) in counts.iter().zip(p) {
+            assert!((*count as f32 / trials as f32 - expected).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn test_greedy_matches_target_generation() {
+        let target = random_model(small_config(), 3);
+        let draft = random_model(small_config(), 9);
+        let expected = Generator::new(&target, reference_device(), GenerationConfig::greedy(8))
+            .generate(&[1, 2, 3])
+            .unwrap();
+
+        let decoder = SpeculativeDecoder::new(
+            &draft,
+            &target,
+            reference_device(),
+            SpeculativeConfig {
+                generation: GenerationConfig::greedy(8),
+                num_draft_tokens: 3,
+            },
+        )
+        .unwrap();
+        let output = decoder.generate(&[1, 2, 3]).unwrap();
+        assert_eq!(output.tokens, expected.tokens);
+        assert_eq!(output.finish_reason, expected.finish_reason);
+        assert!(output.stats.accepted_tokens <= output.stats.proposed_tokens);
+        assert!(output.stats.target_passes > 0);
+    }
+
+    #[test]
+    fn test_rolling_caches_roll_back() {
+        // Windows of two keep too little to undo rejected proposals, so rejections return to
+        // a snapshot of the window; with every layer local and rotary positions, generation
+        // runs well past max_seq_len
+        let config = TransformerConfig {
+            attention_windows: vec![Some(2)],
+            position_encoding: PositionEncoding::rope(10000.0),
+            ..small_config()
+        };
+        let target = random_model(config.clone(), 3);
+        let draft = random_model(config, 9);
+        let expected = Generator::new(&target, reference_device(), GenerationConfig::greedy(40))
+            .generate(&[1, 2, 3])
+            .unwrap();
+        assert_eq!(expected.tokens.len(), 40);
+
+        let decoder = SpeculativeDecoder::new(
+            &draft,
+            &target,
+            reference_device(),
+            SpeculativeConfig {
+                generation: GenerationConfig::greedy(40),
+                num_draft_tokens: 3,
+            },
+        )
+        .unwrap();
+        let output = decoder.generate(&[1, 2, 3]).unwrap();
+        assert_eq!(output.tokens, expected.tokens);
+        assert_eq!(output.finish_reason, FinishReason::MaxNewTokens);
+        assert!(output.stats.accepted_tokens < output.stats.proposed_tokens);
+    }
+
+    #[test]
+    fn test_identical_draft_accepts_everything() {
+        let model = random_model(small_config(), 3);
+        let config = SpeculativeConfig {
+            generation: GenerationConfig {
+                max_new_tokens: 100,
+                seed: 5,
+                ..GenerationConfig::default()
+            },
+            num_draft_tokens: 4,
+        };
+        let output = SpeculativeDecoder::new(&model, &model, reference_device(), config)
+            .unwrap()
+            .generate(&[1])
+            .unwrap();
+        assert_eq!(output.finish_reason, FinishReason::ContextFull);
+        assert_eq!(output.tokens.len(), 15);
+        assert_eq!(output.stats.acceptance_rate(), 1.0);
+    }
+}
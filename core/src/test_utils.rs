@@ -0,0 +1,182 @@
+//! Shared fixtures for unit tests, also available to backend crates through the
+//! `test-utils` feature
+
+use crate::error::Result;
+use crate::gpu::{GpuDevice, GpuTensor, Kernel};
+use crate::ops;
+use crate::tensor::Tensor;
+use crate::transformer::{
+    AttentionWeights, CrossAttentionWeights, EncoderWeights, FeedForward, FeedForwardType,
+    FeedForwardWeights, LayerNormWeights, MoeWeights, NormType, TransformerConfig,
+    TransformerLayerWeights, TransformerModel,
+};
+use std::sync::Arc;
+
+/// A configuration small enough to run exhaustively in tests
+pub fn small_config() -> TransformerConfig {
+    TransformerConfig {
+        d_model: 8,
+        n_heads: 2,
+        n_layers: 2,
+        d_ff: 16,
+        vocab_size: 11,
+        max_seq_len: 16,
+        ..TransformerConfig::tiny()
+    }
+}
+
+/// Assert that `actual` and `expected` have the same length and agree elementwise within `tol`
+#[track_caller]
+pub fn assert_close(actual: &[f32], expected: &[f32], tol: f32) {
+    assert_eq!(actual.len(), expected.len(), "lengths differ");
+    for (i, (a, b)) in actual.iter().zip(expected).enumerate() {
+        assert!((a - b).abs() < tol, "element {}: {} != {}", i, a, b);
+    }
+}
+
+/// Deterministic pseudo-random tensor in `[-0.5, 0.5)`
+pub fn random_tensor(shape: Vec<usize>, seed: u64) -> Tensor {
+    let mut state = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    let numel = shape.iter().product();
+    let data = (0..numel)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+        })
+        .collect();
+    Tensor::from_f32(shape, data).unwrap()
+}
+
+fn layer_norm(config: &TransformerConfig, seed: u64) -> LayerNormWeights {
+    let d = config.d_model;
+    LayerNormWeights {
+        gamma: Tensor::from_f32(vec![d], vec![1.0; d]).unwrap(),
+        beta: (config.norm == NormType::LayerNorm).then(|| random_tensor(vec![d], seed)),
+    }
+}
+
+fn random_attention(config: &TransformerConfig, seed: u64) -> AttentionWeights {
+    let d = config.d_model;
+    AttentionWeights {
+        wq: random_tensor(vec![d, d], seed),
+        wk: random_tensor(vec![d, config.kv_width()], seed + 1),
+        wv: random_tensor(vec![d, config.kv_width()], seed + 2),
+        wo: random_tensor(vec![d, d], seed + 3),
+        bq: None,
+        bk: None,
+        bv: None,
+        bo: None,
+    }
+}
+
+fn random_feed_forward(config: &TransformerConfig, seed: u64) -> FeedForwardWeights {
+    let d = config.d_model;
+    FeedForwardWeights {
+        w1: random_tensor(vec![d, config.d_ff], seed),
+        w2: random_tensor(vec![config.d_ff, d], seed + 1),
+        w3: (config.feed_forward == FeedForwardType::SwiGlu)
+            .then(|| random_tensor(vec![d, config.d_ff], seed + 4)),
+        b1: None,
+        b2: None,
+        b3: None,
+    }
+}
+
+/// Random layer; with `config.moe` its feed-forward block is a mixture of random experts
+pub fn random_layer(config: &TransformerConfig, seed: u64) -> TransformerLayerWeights {
+    let d = config.d_model;
+    TransformerLayerWeights {
+        attention: random_attention(config, seed),
+        feed_forward: match config.moe {
+            Some(moe) => {
+                let experts = (0..moe.n_experts as u64)
+                    .map(|e| random_feed_forward(config, seed + 1000 * (e + 1)))
+                    .collect();
+                let router = random_tensor(vec![d, moe.n_experts], seed + 9);
+                FeedForward::Moe(MoeWeights::new(router, experts))
+            }
+            None => FeedForward::Dense(random_feed_forward(config, seed + 4)),
+        },
+        ln1: layer_norm(config, seed + 6),
+        ln2: layer_norm(config, seed + 7),
+        cross_attention: None,
+    }
+}
+
+/// Random model; with `n_encoder_layers` it gets an encoder and decoder cross-attention
+pub fn random_model(config: TransformerConfig, seed: u64) -> TransformerModel {
+    let d = config.d_model;
+    let encoder_decoder = config.n_encoder_layers > 0;
+    let layers = (0..config.n_layers as u64)
+        .map(|i| TransformerLayerWeights {
+            cross_attention: encoder_decoder.then(|| CrossAttentionWeights {
+                attention: random_attention(&config, seed + 100 * (i + 1) + 50),
+                norm: layer_norm(&config, seed + 100 * (i + 1) + 54),
+            }),
+            ..random_layer(&config, seed + 100 * (i + 1))
+        })
+        .collect();
+    let model = TransformerModel::new(
+        config.clone(),
+        random_tensor(vec![config.vocab_size, d], seed),
+        random_tensor(vec![config.max_seq_len, d], seed + 1),
+        layers,
+        layer_norm(&config, seed + 2),
+    );
+    if !encoder_decoder {
+        return model;
+    }
+    model.with_encoder(EncoderWeights {
+        layers: (0..config.n_encoder_layers as u64)
+            .map(|i| random_layer(&config, seed + 10_000 + 100 * i))
+            .collect(),
+        final_layer_norm: layer_norm(&config, seed + 3),
+    })
+}
+
+/// Minimal in-crate device that runs every kernel with the reference ops, so generation code
+/// can be tested without depending on a backend crate
+struct ReferenceDevice;
+
+impl GpuDevice for ReferenceDevice {
+    fn upload_tensor(&self, tensor: &Tensor) -> Result<GpuTensor> {
+        Ok(GpuTensor {
+            shape: tensor.shape.clone(),
+            handle: Arc::new(tensor.clone()),
+        })
+    }
+
+    fn run_kernel(&self, kernel: Kernel, inputs: &[GpuTensor]) -> Result<GpuTensor> {
+        let t = inputs
+            .iter()
+            .map(|input| input.handle.downcast_ref::<Tensor>().unwrap())
+            .collect::<Vec<_>>();
+        let output = ops::run_reference_kernel(&kernel, &t)?;
+        self.upload_tensor(&output)
+    }
+
+    fn download_tensor(&self, gpu_tensor: &GpuTensor) -> Result<Tensor> {
+        Ok(gpu_tensor.handle.downcast_ref::<Tensor>().unwrap().clone())
+    }
+
+    fn synchronize(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn device_name(&self) -> &str {
+        "Reference"
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+/// Device running every kernel with the reference ops in [`crate::ops`]
+pub fn reference_device() -> Arc<dyn GpuDevice> {
+    Arc::new(ReferenceDevice)
+}
@@ -1,8 +1,16 @@
 //! Transformer layer definitions and configuration
 
-use crate::error::Result;
-use crate::gpu::{GpuDevice, GpuTensor};
+use crate::batch::{PaddedBatch, PaddingSide};
+use crate::classification::{ClassificationHead, Prediction, SequenceClassifier};
+use crate::embeddings::{self, Pooling};
+use crate::error::{CoreError, Result};
+use crate::gpu::{GpuDevice, GpuTensor, Kernel, KernelType};
+use crate::kv_cache::{KvCache, LayerKvCache};
+use crate::moe::{self, RouterStats};
+use crate::ops;
+use crate::paged_kv_cache::{BlockTable, PagedKvCache};
 use crate::tensor::Tensor;
+use crate::vision::{self, PatchEmbedding};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
@@ -13,6 +21,11 @@ pub struct TransformerConfig {
     pub d_model: usize,
     /// Number of attention heads
     pub n_heads: usize,
+    /// Number of key/value heads shared by groups of query heads (grouped-query attention)
+    ///
+    /// `None` gives every query head its own keys and values; `Some(1)` is multi-query
+    /// attention. Must divide `n_heads`.
+    pub n_kv_heads: Option<usize>,
     /// Number of transformer layers
     pub n_layers: usize,
     /// Feedforward dimension
@@ -25,6 +38,178 @@ pub struct TransformerConfig {
     pub dropout: f32,
     /// Layer normalization epsilon
     pub layer_norm_eps: f32,
+    /// How token positions are encoded
+    pub position_encoding: PositionEncoding,
+    /// Normalization used by every [`LayerNormWeights`] in the model
+    pub norm: NormType,
+    /// Feed-forward network variant
+    pub feed_forward: FeedForwardType,
+    /// Where the norms and residual connections sit in each layer
+    pub block: BlockTopology,
+    /// Sliding attention window of each layer (local attention)
+    ///
+    /// Layer `i` uses entry `i % len`: `Some(w)` lets each query see itself and the `w - 1`
+    /// positions before it, `None` every earlier position. `vec![Some(4096)]` makes every
+    /// layer local and `vec![Some(4096), None]` alternates local and global layers; empty (the
+    /// default) is full attention everywhere. Caches of local layers only keep the positions
+    /// their window can still reach.
+    pub attention_windows: Vec<Option<usize>>,
+    /// Number of encoder layers of an encoder-decoder model (0 for decoder-only)
+    ///
+    /// The encoder attends bidirectionally over the input, and each of the `n_layers` decoder
+    /// layers adds a cross-attention block over the encoder output.
+    pub n_encoder_layers: usize,
+    /// Which positions the self-attention of `layers` can see
+    pub attention_mode: AttentionMode,
+    /// Routing of mixture-of-experts feed-forward blocks, used by layers whose feed-forward is
+    /// [`FeedForward::Moe`]
+    pub moe: Option<MoeConfig>,
+    /// Image input of a Vision Transformer, embedded by the model's [`PatchEmbedding`]
+    pub vision: Option<VisionConfig>,
+}
+
+/// Image input of a Vision Transformer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VisionConfig {
+    /// Height and width of the square input images
+    pub image_size: usize,
+    /// Height and width of each square patch; must divide `image_size`
+    pub patch_size: usize,
+    /// Image channels (3 for RGB)
+    pub channels: usize,
+}
+
+impl VisionConfig {
+    /// Patches per image, `(image_size / patch_size)^2`
+    pub fn n_patches(&self) -> usize {
+        let per_side = self.image_size / self.patch_size.max(1);
+        per_side * per_side
+    }
+
+    /// Length of a flattened patch, `channels * patch_size^2`
+    pub fn patch_dim(&self) -> usize {
+        self.channels * self.patch_size * self.patch_size
+    }
+}
+
+/// Mixture-of-experts routing configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MoeConfig {
+    /// Number of experts per layer
+    pub n_experts: usize,
+    /// Experts each token is routed to (e.g. 2 of 8 in Mixtral)
+    pub top_k: usize,
+}
+
+/// Masking of self-attention in the model's layers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AttentionMode {
+    /// Each position sees itself and earlier positions (GPT-style decoders)
+    #[default]
+    Causal,
+    /// Every position sees the whole sequence (BERT-style encoders)
+    ///
+    /// Outputs change as tokens are added, so the model runs full passes only: KV-cached
+    /// decoding is rejected.
+    Bidirectional,
+}
+
+/// Ordering of norms, sublayers and residual connections in a transformer layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum BlockTopology {
+    /// `h = x + Attn(LN1(x))`, `out = h + FFN(LN2(h))` (GPT-2, LLaMA)
+    #[default]
+    PreLn,
+    /// `h = LN1(x + Attn(x))`, `out = LN2(h + FFN(h))` (original Transformer, BERT)
+    ///
+    /// Layer outputs are already normalized, so the model's final layer norm is not applied.
+    PostLn,
+    /// `out = x + Attn(LN1(x)) + FFN(LN2(x))` (GPT-NeoX; GPT-J shares one norm, so give
+    /// `ln1` and `ln2` the same weights)
+    Parallel,
+}
+
+/// Normalization applied before attention, before the feed-forward network and at the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum NormType {
+    /// Mean/variance normalization with `gamma` and an optional `beta`
+    #[default]
+    LayerNorm,
+    /// Root-mean-square normalization with `gamma` only (LLaMA, Mistral)
+    RmsNorm,
+}
+
+/// Shape of the position-wise feed-forward network
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum FeedForwardType {
+    /// `gelu(x @ w1) @ w2`
+    #[default]
+    Gelu,
+    /// Gated `(silu(x @ w1) * (x @ w3)) @ w2` (LLaMA, Mistral); needs `w3`
+    SwiGlu,
+}
+
+/// How token positions are injected into the model
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum PositionEncoding {
+    /// Learned `[max_seq_len, d_model]` table added to the token embeddings
+    #[default]
+    Learned,
+    /// Rotary embeddings applied to queries and keys in every attention layer
+    Rope {
+        /// Frequency base, typically 10000
+        base: f32,
+        /// Optional context-extension scaling
+        scaling: Option<RopeScaling>,
+    },
+    /// Per-head linear distance biases added to the attention scores (ALiBi)
+    ///
+    /// Like RoPE this needs no position table, and since the biases only depend on distances
+    /// the model can run past its trained length by raising `max_seq_len`.
+    Alibi,
+    /// No positional information (attention is still masked per [`AttentionMode`])
+    None,
+}
+
+/// Context-extension scaling for rotary embeddings
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RopeScaling {
+    /// Divide positions by `factor` (position interpolation)
+    Linear {
+        /// Context extension factor
+        factor: f32,
+    },
+    /// Raise the frequency base so low frequencies stretch by `factor` ("NTK-aware")
+    Ntk {
+        /// Context extension factor
+        factor: f32,
+    },
+}
+
+impl PositionEncoding {
+    /// RoPE with the given base and no scaling
+    pub fn rope(base: f32) -> Self {
+        Self::Rope {
+            base,
+            scaling: None,
+        }
+    }
+
+    /// `[base, position_scale]` parameters of the `Rope` kernel for heads of `head_dim`, or
+    /// `None` if this encoding does not rotate queries and keys
+    pub fn rope_params(&self, head_dim: usize) -> Option<(f32, f32)> {
+        let Self::Rope { base, scaling } = *self else {
+            return None;
+        };
+        Some(match scaling {
+            None => (base, 1.0),
+            Some(RopeScaling::Linear { factor }) => (base, 1.0 / factor),
+            Some(RopeScaling::Ntk { factor }) => {
+                let exponent = head_dim as f32 / (head_dim as f32 - 2.0).max(1.0);
+                (base * factor.powf(exponent), 1.0)
+            }
+        })
+    }
 }
 
 impl TransformerConfig {
@@ -33,29 +218,148 @@ impl TransformerConfig {
         Self {
             d_model: 512,
             n_heads: 8,
+            n_kv_heads: None,
             n_layers: 6,
             d_ff: 2048,
             vocab_size: 32000,
             max_seq_len: 512,
             dropout: 0.1,
             layer_norm_eps: 1e-5,
+            position_encoding: PositionEncoding::Learned,
+            norm: NormType::LayerNorm,
+            feed_forward: FeedForwardType::Gelu,
+            block: BlockTopology::PreLn,
+            attention_windows: Vec::new(),
+            n_encoder_layers: 0,
+            attention_mode: AttentionMode::Causal,
+            moe: None,
+            vision: None,
+        }
+    }
+
+    /// ViT-Ti/16 configuration: 224x224 RGB images in 16x16 patches (~22MB model)
+    ///
+    /// The 196 patch tokens and the CLS token attend bidirectionally; attach a
+    /// [`PatchEmbedding`] and a [`SequenceClassifier`] with [`Pooling::Cls`] to classify images.
+    pub fn vit_tiny() -> Self {
+        let vision = VisionConfig {
+            image_size: 224,
+            patch_size: 16,
+            channels: 3,
+        };
+        Self {
+            d_model: 192,
+            n_heads: 3,
+            n_kv_heads: None,
+            n_layers: 12,
+            d_ff: 768,
+            vocab_size: 0,
+            max_seq_len: vision.n_patches() + 1,
+            dropout: 0.0,
+            layer_norm_eps: 1e-6,
+            position_encoding: PositionEncoding::Learned,
+            norm: NormType::LayerNorm,
+            feed_forward: FeedForwardType::Gelu,
+            block: BlockTopology::PreLn,
+            attention_windows: Vec::new(),
+            n_encoder_layers: 0,
+            attention_mode: AttentionMode::Bidirectional,
+            moe: None,
+            vision: Some(vision),
+        }
+    }
+
+    /// Number of key/value heads (`n_heads` unless grouped-query attention is configured)
+    pub fn kv_heads(&self) -> usize {
+        self.n_kv_heads.unwrap_or(self.n_heads)
+    }
+
+    /// Dimension of each attention head
+    pub fn head_dim(&self) -> usize {
+        self.d_model / self.n_heads.max(1)
+    }
+
+    /// Width of the key and value projections (`kv_heads() * head_dim()`)
+    pub fn kv_width(&self) -> usize {
+        self.kv_heads() * self.head_dim()
+    }
+
+    /// Sliding attention window of layer `layer`, see
+    /// [`attention_windows`](Self::attention_windows)
+    pub fn attention_window(&self, layer: usize) -> Option<usize> {
+        match self.attention_windows.len() {
+            0 => None,
+            n => self.attention_windows[layer % n],
         }
     }
 
+    /// Longest sequence the model can process: `max_seq_len`, or unbounded (`usize::MAX`)
+    /// when every layer is local and positions are not learned, so caches roll over their
+    /// windows and no position table limits the length
+    pub fn context_len(&self) -> usize {
+        let all_local = !self.attention_windows.is_empty()
+            && self.attention_windows.iter().all(Option::is_some);
+        if all_local
+            && self.attention_mode == AttentionMode::Causal
+            && self.position_encoding != PositionEncoding::Learned
+        {
+            usize::MAX
+        } else {
+            self.max_seq_len
+        }
+    }
+
+    /// Self-attention parameters of layer `layer`
+    pub fn attention_params(&self, layer: usize) -> ops::AttentionParams {
+        let causal = self.attention_mode == AttentionMode::Causal;
+        ops::AttentionParams::new(self.n_heads, causal)
+            .with_kv_heads(self.kv_heads())
+            .with_alibi(self.position_encoding == PositionEncoding::Alibi)
+            .with_window(self.attention_window(layer))
+    }
+
     /// Estimate model size in bytes
     pub fn estimate_size(&self) -> usize {
         // Embedding: vocab_size * d_model
         let embedding_size = self.vocab_size * self.d_model * 4; // f32
 
-        // Per layer: Q,K,V,O weights + 2 FF layers + layer norms
-        let per_layer_size = (
-            4 * self.d_model * self.d_model + // QKV + output projection
-            2 * self.d_model * self.d_ff +     // Two FF layers
-            4 * self.d_model
-            // Two layer norms (weight + bias each)
-        ) * 4; // f32
+        // Per layer: Q,K,V,O weights + 2 or 3 FF layers + layer norms
+        let ff_matrices = match self.feed_forward {
+            FeedForwardType::Gelu => 2,
+            FeedForwardType::SwiGlu => 3,
+        };
+        let norm_params = match self.norm {
+            NormType::LayerNorm => 2, // Weight + bias
+            NormType::RmsNorm => 1,   // Weight only
+        };
+        let attention_size = (2 * self.d_model * self.d_model + // Q + output projection
+            2 * self.d_model * self.kv_width() + // K + V projections
+            norm_params * self.d_model)
+            * 4; // f32
+        let (n_experts, router) = match self.moe {
+            Some(moe) => (moe.n_experts, self.d_model * moe.n_experts),
+            None => (1, 0),
+        };
+        let per_layer_size = attention_size
+            + (n_experts * ff_matrices * self.d_model * self.d_ff
+                + router
+                + norm_params * self.d_model)
+                * 4;
 
-        embedding_size + per_layer_size * self.n_layers
+        // Decoder layers of an encoder-decoder model add a cross-attention block
+        let cross_size = if self.n_encoder_layers > 0 {
+            attention_size * self.n_layers
+        } else {
+            0
+        };
+        // Patch projection, its bias and the CLS token of a Vision Transformer
+        let patch_size = self
+            .vision
+            .map_or(0, |vision| (vision.patch_dim() + 2) * self.d_model * 4);
+        embedding_size
+            + per_layer_size * (self.n_layers + self.n_encoder_layers)
+            + cross_size
+            + patch_size
     }
 }
 
@@ -64,12 +368,20 @@ impl TransformerConfig {
 pub struct AttentionWeights {
     /// Query projection weights [d_model, d_model]
     pub wq: Tensor,
-    /// Key projection weights [d_model, d_model]
+    /// Key projection weights [d_model, kv_width], see [`TransformerConfig::kv_width`]
     pub wk: Tensor,
-    /// Value projection weights [d_model, d_model]
+    /// Value projection weights [d_model, kv_width]
     pub wv: Tensor,
     /// Output projection weights [d_model, d_model]
     pub wo: Tensor,
+    /// Query projection bias [d_model]
+    pub bq: Option<Tensor>,
+    /// Key projection bias [kv_width]
+    pub bk: Option<Tensor>,
+    /// Value projection bias [kv_width]
+    pub bv: Option<Tensor>,
+    /// Output projection bias [d_model]
+    pub bo: Option<Tensor>,
 }
 
 /// Feed-forward network weights
@@ -79,6 +391,289 @@ pub struct FeedForwardWeights {
     pub w1: Tensor,
     /// Second linear layer [d_ff, d_model]
     pub w2: Tensor,
+    /// Up projection [d_model, d_ff] gated by `silu(x @ w1)`, used by
+    /// [`FeedForwardType::SwiGlu`]
+    pub w3: Option<Tensor>,
+    /// First linear layer bias [d_ff]
+    pub b1: Option<Tensor>,
+    /// Second linear layer bias [d_model]
+    pub b2: Option<Tensor>,
+    /// Up projection bias [d_ff]
+    pub b3: Option<Tensor>,
+}
+
+impl FeedForwardWeights {
+    /// Position-wise feed-forward network, GELU or SwiGLU
+    fn forward_cpu(&self, config: &TransformerConfig, x: &Tensor) -> Result<Tensor> {
+        let gate = ops::linear(x, &self.w1, self.b1.as_ref())?;
+        let hidden = match config.feed_forward {
+            FeedForwardType::Gelu => ops::gelu(&gate)?,
+            FeedForwardType::SwiGlu => {
+                let up = ops::linear(x, self.require_w3()?, self.b3.as_ref())?;
+                ops::swiglu(&gate, &up)?
+            }
+        };
+        ops::linear(&hidden, &self.w2, self.b2.as_ref())
+    }
+
+    /// Device version of [`forward_cpu`](Self::forward_cpu)
+    fn forward_gpu(
+        &self,
+        config: &TransformerConfig,
+        x: &GpuTensor,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<GpuTensor> {
+        let hidden = match config.feed_forward {
+            FeedForwardType::Gelu => {
+                let inputs = gemm_inputs(x, &self.w1, self.b1.as_ref(), device)?;
+                device.run_kernel(Kernel::new(KernelType::FusedGemmGelu), &inputs)?
+            }
+            FeedForwardType::SwiGlu => {
+                let gate = run_matmul(x, &self.w1, self.b1.as_ref(), device)?;
+                let up = run_matmul(x, self.require_w3()?, self.b3.as_ref(), device)?;
+                device.run_kernel(Kernel::new(KernelType::Silu), &[gate, up])?
+            }
+        };
+        run_matmul(&hidden, &self.w2, self.b2.as_ref(), device)
+    }
+
+    /// The SwiGLU up projection, which must be present for [`FeedForwardType::SwiGlu`]
+    fn require_w3(&self) -> Result<&Tensor> {
+        self.w3.as_ref().ok_or_else(|| {
+            CoreError::ModelLoadError("SwiGLU feed-forward needs a w3 projection".to_string())
+        })
+    }
+}
+
+/// Position-wise feed-forward block of a layer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
+pub enum FeedForward {
+    /// One dense network applied to every token
+    Dense(FeedForwardWeights),
+    /// Mixture of experts, each token routed to its [`MoeConfig::top_k`] best experts
+    Moe(MoeWeights),
+}
+
+impl FeedForward {
+    /// The dense network, if this is not a mixture of experts
+    pub fn dense(&self) -> Option<&FeedForwardWeights> {
+        match self {
+            Self::Dense(weights) => Some(weights),
+            Self::Moe(_) => None,
+        }
+    }
+
+    /// Mutable access to the dense network, if this is not a mixture of experts
+    pub fn dense_mut(&mut self) -> Option<&mut FeedForwardWeights> {
+        match self {
+            Self::Dense(weights) => Some(weights),
+            Self::Moe(_) => None,
+        }
+    }
+
+    /// The mixture of experts, if this is one
+    pub fn moe(&self) -> Option<&MoeWeights> {
+        match self {
+            Self::Dense(_) => None,
+            Self::Moe(moe) => Some(moe),
+        }
+    }
+
+    /// Mutable access to the mixture of experts, if this is one
+    pub fn moe_mut(&mut self) -> Option<&mut MoeWeights> {
+        match self {
+            Self::Dense(_) => None,
+            Self::Moe(moe) => Some(moe),
+        }
+    }
+
+    /// Feed-forward on CPU; padding rows (per `mask`) are not routed to experts
+    fn forward_cpu(
+        &self,
+        config: &TransformerConfig,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        match self {
+            Self::Dense(weights) => weights.forward_cpu(config, x),
+            Self::Moe(moe) => moe.forward_cpu(config, x, mask),
+        }
+    }
+
+    /// Device version of [`forward_cpu`](Self::forward_cpu)
+    fn forward_gpu(
+        &self,
+        config: &TransformerConfig,
+        x: &GpuTensor,
+        mask: Option<&GpuTensor>,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<GpuTensor> {
+        match self {
+            Self::Dense(weights) => weights.forward_gpu(config, x, device),
+            Self::Moe(moe) => moe.forward_gpu(config, x, mask, device),
+        }
+    }
+}
+
+impl From<FeedForwardWeights> for FeedForward {
+    fn from(weights: FeedForwardWeights) -> Self {
+        Self::Dense(weights)
+    }
+}
+
+impl From<MoeWeights> for FeedForward {
+    fn from(moe: MoeWeights) -> Self {
+        Self::Moe(moe)
+    }
+}
+
+/// Mixture-of-experts feed-forward weights
+///
+/// A router scores each token against every expert; the token then runs through only its
+/// [`MoeConfig::top_k`] best experts, whose outputs are mixed with the softmax of their
+/// router scores. Routing decisions are counted in [`stats`](Self::stats).
+#[derive(Debug, Clone)]
+pub struct MoeWeights {
+    /// Router projection [d_model, n_experts]
+    pub router: Tensor,
+    /// Expert networks, each a dense feed-forward network of the configured
+    /// [`FeedForwardType`]
+    pub experts: Vec<FeedForwardWeights>,
+    stats: RouterStats,
+}
+
+impl MoeWeights {
+    /// Create a mixture from its router projection and experts
+    pub fn new(router: Tensor, experts: Vec<FeedForwardWeights>) -> Self {
+        let stats = RouterStats::new(experts.len());
+        Self {
+            router,
+            experts,
+            stats,
+        }
+    }
+
+    /// Per-expert load accumulated over forward passes
+    pub fn stats(&self) -> &RouterStats {
+        &self.stats
+    }
+
+    /// Route the real rows of `x` `[.., d_model]` (those with a non-zero `mask` entry) from
+    /// their router logits, returning per expert the rows it runs on and their gate weights
+    fn route(
+        &self,
+        config: &TransformerConfig,
+        logits: &Tensor,
+        mask: Option<&Tensor>,
+    ) -> Result<Vec<Vec<(usize, f32)>>> {
+        let moe_config = config.moe.ok_or_else(|| {
+            CoreError::Other("Layer has experts but TransformerConfig::moe is not set".to_string())
+        })?;
+        let n_experts = self.experts.len();
+        if n_experts != moe_config.n_experts {
+            return Err(CoreError::ModelLoadError(format!(
+                "Layer has {} experts but MoeConfig::n_experts is {}",
+                n_experts, moe_config.n_experts
+            )));
+        }
+        if self.router.shape != [config.d_model, n_experts] {
+            return Err(CoreError::ShapeMismatch {
+                expected: vec![config.d_model, n_experts],
+                actual: self.router.shape.clone(),
+            });
+        }
+        let mask = mask.map(Tensor::as_f32_slice).transpose()?;
+        let mut assignments = vec![Vec::new(); n_experts];
+        for (row, scores) in logits.as_f32_slice()?.chunks(n_experts).enumerate() {
+            if mask.is_some_and(|mask| mask[row] == 0.0) {
+                continue;
+            }
+            let routed = moe::route(scores, moe_config.top_k)?;
+            self.stats.record(routed.iter().map(|&(expert, _)| expert));
+            for (expert, weight) in routed {
+                assignments[expert].push((row, weight));
+            }
+        }
+        Ok(assignments)
+    }
+
+    /// Mixture-of-experts feed-forward on CPU; padding rows (per `mask`) are left at zero
+    fn forward_cpu(
+        &self,
+        config: &TransformerConfig,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let logits = ops::linear(x, &self.router, None)?;
+        let assignments = self.route(config, &logits, mask)?;
+        let d = config.d_model;
+        let data = x.as_f32_slice()?;
+        let mut out = vec![0.0f32; data.len()];
+        for (expert, rows) in self.experts.iter().zip(&assignments) {
+            if rows.is_empty() {
+                continue;
+            }
+            let y = expert.forward_cpu(config, &gather_rows(data, d, rows)?)?;
+            scatter_rows(&mut out, y.as_f32_slice()?, d, rows);
+        }
+        Tensor::from_f32(x.shape.clone(), out)
+    }
+
+    /// Device version of [`forward_cpu`](Self::forward_cpu)
+    ///
+    /// The router and every selected expert run on `device`; rows are gathered per expert
+    /// and mixed back on the host.
+    fn forward_gpu(
+        &self,
+        config: &TransformerConfig,
+        x: &GpuTensor,
+        mask: Option<&GpuTensor>,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<GpuTensor> {
+        let logits = run_matmul(x, &self.router, None, device)?;
+        device.synchronize()?;
+        let mask = mask.map(|mask| device.download_tensor(mask)).transpose()?;
+        let assignments = self.route(config, &device.download_tensor(&logits)?, mask.as_ref())?;
+        let d = config.d_model;
+        let x_host = device.download_tensor(x)?;
+        let data = x_host.as_f32_slice()?;
+        let mut out = vec![0.0f32; data.len()];
+        for (expert, rows) in self.experts.iter().zip(&assignments) {
+            if rows.is_empty() {
+                continue;
+            }
+            let input = device.upload_tensor(&gather_rows(data, d, rows)?)?;
+            let y = expert.forward_gpu(config, &input, device)?;
+            device.synchronize()?;
+            scatter_rows(
+                &mut out,
+                device.download_tensor(&y)?.as_f32_slice()?,
+                d,
+                rows,
+            );
+        }
+        device.upload_tensor(&Tensor::from_f32(x.shape.clone(), out)?)
+    }
+}
+
+/// The `rows` of a `[.., d]` buffer as a `[rows.len(), d]` tensor
+fn gather_rows(data: &[f32], d: usize, rows: &[(usize, f32)]) -> Result<Tensor> {
+    let gathered = rows
+        .iter()
+        .flat_map(|&(row, _)| &data[row * d..(row + 1) * d])
+        .copied()
+        .collect();
+    Tensor::from_f32(vec![rows.len(), d], gathered)
+}
+
+/// Add gathered expert outputs `y`, scaled by their gate weights, back into `out`
+fn scatter_rows(out: &mut [f32], y: &[f32], d: usize, rows: &[(usize, f32)]) {
+    for (&(row, weight), y) in rows.iter().zip(y.chunks(d)) {
+        for (o, v) in out[row * d..(row + 1) * d].iter_mut().zip(y) {
+            *o += weight * v;
+        }
+    }
 }
 
 /// Layer normalization weights
@@ -86,8 +681,108 @@ pub struct FeedForwardWeights {
 pub struct LayerNormWeights {
     /// Scale parameter [d_model]
     pub gamma: Tensor,
-    /// Bias parameter [d_model]
-    pub beta: Tensor,
+    /// Bias parameter [d_model]; absent for [`NormType::RmsNorm`] or a bias-free LayerNorm
+    pub beta: Option<Tensor>,
+}
+
+impl LayerNormWeights {
+    /// Normalize `x` on CPU with the configured norm type
+    pub(crate) fn forward_cpu(&self, config: &TransformerConfig, x: &Tensor) -> Result<Tensor> {
+        let eps = config.layer_norm_eps;
+        match config.norm {
+            NormType::LayerNorm => ops::layer_norm(x, &self.gamma, &self.beta_or_zeros()?, eps),
+            NormType::RmsNorm => ops::rms_norm(x, &self.gamma, eps),
+        }
+    }
+
+    /// `beta`, or zeros shaped like `gamma` for a bias-free LayerNorm
+    fn beta_or_zeros(&self) -> Result<Tensor> {
+        match &self.beta {
+            Some(beta) => Ok(beta.clone()),
+            None => Tensor::from_f32(self.gamma.shape.clone(), vec![0.0; self.gamma.numel()]),
+        }
+    }
+}
+
+/// Cross-attention block of a decoder layer in an encoder-decoder model
+///
+/// Queries come from the decoder; keys and values are projected once from the encoder output
+/// by [`TransformerModel::encode`] and kept in the decoder's [`KvCache`].
+#[derive(Debug, Clone)]
+pub struct CrossAttentionWeights {
+    /// Attention projections; `wk`/`wv` apply to the encoder output
+    pub attention: AttentionWeights,
+    /// Layer norm before cross-attention (after its residual with [`BlockTopology::PostLn`])
+    pub norm: LayerNormWeights,
+}
+
+impl CrossAttentionWeights {
+    /// Attend from decoder rows `x` to the encoder keys/values of every sequence's cache
+    fn forward_cpu(
+        &self,
+        config: &TransformerConfig,
+        x: &Tensor,
+        caches: &Option<LayerCaches<'_>>,
+    ) -> Result<Tensor> {
+        let weights = &self.attention;
+        let (k, v, mask) = cross_keys_values(caches, &x.shape)?;
+        let q = ops::linear(x, &weights.wq, weights.bq.as_ref())?;
+        let context = ops::attention(&q, &k, &v, cross_attention_params(config), mask.as_ref())?;
+        ops::linear(&context, &weights.wo, weights.bo.as_ref())
+    }
+
+    /// Device version of [`forward_cpu`](Self::forward_cpu); the encoder keys/values are
+    /// uploaded from the host caches
+    fn forward_gpu(
+        &self,
+        config: &TransformerConfig,
+        x: &GpuTensor,
+        device: &Arc<dyn GpuDevice>,
+        caches: &Option<LayerCaches<'_>>,
+    ) -> Result<GpuTensor> {
+        let weights = &self.attention;
+        let (k, v, mask) = cross_keys_values(caches, &x.shape)?;
+        let q = run_matmul(x, &weights.wq, weights.bq.as_ref(), device)?;
+        let mut inputs = vec![q, device.upload_tensor(&k)?, device.upload_tensor(&v)?];
+        if let Some(mask) = mask {
+            inputs.push(device.upload_tensor(&mask)?);
+        }
+        let params = cross_attention_params(config).kernel_params(false);
+        let context =
+            device.run_kernel(Kernel::with_params(KernelType::Attention, params), &inputs)?;
+        run_matmul(&context, &weights.wo, weights.bo.as_ref(), device)
+    }
+
+    /// Project encoder output rows `[.., src_len, d_model]` to cross-attention keys and values
+    fn keys_values_cpu(&self, encoded: &Tensor) -> Result<(Tensor, Tensor)> {
+        let weights = &self.attention;
+        Ok((
+            ops::linear(encoded, &weights.wk, weights.bk.as_ref())?,
+            ops::linear(encoded, &weights.wv, weights.bv.as_ref())?,
+        ))
+    }
+
+    /// Device version of [`keys_values_cpu`](Self::keys_values_cpu)
+    fn keys_values_gpu(
+        &self,
+        encoded: &GpuTensor,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<(GpuTensor, GpuTensor)> {
+        let weights = &self.attention;
+        Ok((
+            run_matmul(encoded, &weights.wk, weights.bk.as_ref(), device)?,
+            run_matmul(encoded, &weights.wv, weights.bv.as_ref(), device)?,
+        ))
+    }
+}
+
+/// Encoder stack of an encoder-decoder model
+#[derive(Debug, Clone)]
+pub struct EncoderWeights {
+    /// Encoder layers, attending bidirectionally and without cross-attention
+    pub layers: Vec<TransformerLayerWeights>,
+    /// Final encoder layer norm, skipped with [`BlockTopology::PostLn`]
+    pub final_layer_norm: LayerNormWeights,
 }
 
 /// Complete transformer layer weights
@@ -95,58 +790,808 @@ pub struct LayerNormWeights {
 pub struct TransformerLayerWeights {
     /// Multi-head attention weights
     pub attention: AttentionWeights,
-    /// Feed-forward network weights
-    pub feed_forward: FeedForwardWeights,
-    /// Layer norm before attention
+    /// Feed-forward block, dense or a mixture of experts
+    pub feed_forward: FeedForward,
+    /// Layer norm before attention (after the attention residual with
+    /// [`BlockTopology::PostLn`])
     pub ln1: LayerNormWeights,
-    /// Layer norm before feed-forward
+    /// Layer norm before feed-forward (after the feed-forward residual with
+    /// [`BlockTopology::PostLn`])
     pub ln2: LayerNormWeights,
+    /// Cross-attention over the encoder output, between self-attention and feed-forward;
+    /// only decoder layers of an encoder-decoder model have one
+    pub cross_attention: Option<CrossAttentionWeights>,
+}
+
+impl TransformerLayerWeights {
+    /// Transformer block on CPU in the configured [`BlockTopology`], shared by
+    /// [`TransformerLayer`] and [`TransformerModel`]
+    ///
+    /// `mask` is an optional `[batch, seq_len]` padding mask and `positions` the `[batch,
+    /// seq_len]` position of every row, required with rotary embeddings. With caches (one per
+    /// sequence), the input rows are left-padded new positions: their keys/values are appended
+    /// to each sequence's cache, contiguous or paged, and attention runs over every cached
+    /// position.
+    pub(crate) fn forward_cpu(
+        &self,
+        config: &TransformerConfig,
+        attention: ops::AttentionParams,
+        input: &Tensor,
+        mask: Option<&Tensor>,
+        positions: Option<&Tensor>,
+        mut caches: Option<LayerCaches<'_>>,
+    ) -> Result<Tensor> {
+        check_hidden_shape(config, &input.shape, caches.as_ref().map(LayerCaches::len))?;
+
+        let cross = self.cross_attention.as_ref();
+        match config.block {
+            BlockTopology::PreLn => {
+                let normed = self.ln1.forward_cpu(config, input)?;
+                let attn =
+                    self.attention_cpu(config, attention, &normed, mask, positions, &mut caches)?;
+                let mut hidden = ops::add(input, &attn)?;
+                if let Some(cross) = cross {
+                    let normed = cross.norm.forward_cpu(config, &hidden)?;
+                    hidden = ops::add(&hidden, &cross.forward_cpu(config, &normed, &caches)?)?;
+                }
+
+                let normed = self.ln2.forward_cpu(config, &hidden)?;
+                let ff = self.feed_forward.forward_cpu(config, &normed, mask)?;
+                ops::add(&hidden, &ff)
+            }
+            BlockTopology::PostLn => {
+                let attn =
+                    self.attention_cpu(config, attention, input, mask, positions, &mut caches)?;
+                let mut hidden = self.ln1.forward_cpu(config, &ops::add(input, &attn)?)?;
+                if let Some(cross) = cross {
+                    let context = cross.forward_cpu(config, &hidden, &caches)?;
+                    hidden = cross
+                        .norm
+                        .forward_cpu(config, &ops::add(&hidden, &context)?)?;
+                }
+
+                let ff = self.feed_forward.forward_cpu(config, &hidden, mask)?;
+                self.ln2.forward_cpu(config, &ops::add(&hidden, &ff)?)
+            }
+            BlockTopology::Parallel => {
+                let normed = self.ln1.forward_cpu(config, input)?;
+                let attn =
+                    self.attention_cpu(config, attention, &normed, mask, positions, &mut caches)?;
+                let mut hidden = ops::add(input, &attn)?;
+                if let Some(cross) = cross {
+                    let normed = cross.norm.forward_cpu(config, input)?;
+                    hidden = ops::add(&hidden, &cross.forward_cpu(config, &normed, &caches)?)?;
+                }
+                let normed = self.ln2.forward_cpu(config, input)?;
+                let ff = self.feed_forward.forward_cpu(config, &normed, mask)?;
+                ops::add(&hidden, &ff)
+            }
+        }
+    }
+
+    /// Causal multi-head self-attention with output projection
+    fn attention_cpu(
+        &self,
+        config: &TransformerConfig,
+        params: ops::AttentionParams,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        positions: Option<&Tensor>,
+        caches: &mut Option<LayerCaches<'_>>,
+    ) -> Result<Tensor> {
+        let weights = &self.attention;
+        let mut q = ops::linear(x, &weights.wq, weights.bq.as_ref())?;
+        let mut k = ops::linear(x, &weights.wk, weights.bk.as_ref())?;
+        let v = ops::linear(x, &weights.wv, weights.bv.as_ref())?;
+        let (n_heads, n_kv_heads) = (config.n_heads, config.kv_heads());
+        if let Some((base, scale)) = rope_params(config) {
+            let positions = require_positions(positions)?;
+            q = ops::rope(&q, positions, n_heads, base, scale)?;
+            k = ops::rope(&k, positions, n_kv_heads, base, scale)?;
+        }
+        let context = match caches {
+            None => ops::attention(&q, &k, &v, params, mask)?,
+            Some(LayerCaches::Contiguous(caches)) => {
+                let (k, v, key_mask) = append_to_caches(caches, &k, &v, mask)?;
+                ops::attention(&q, &k, &v, params, key_mask.as_ref())?
+            }
+            Some(LayerCaches::Paged {
+                cache,
+                layer,
+                tables,
+                starts,
+            }) => {
+                cache.write_new_rows(*layer, tables, starts, &k, &v)?;
+                let (k_blocks, v_blocks) = cache.pools(*layer);
+                let (block_tables, context_lens) = cache.block_table_tensors(tables)?;
+                ops::paged_attention(&q, k_blocks, v_blocks, &block_tables, &context_lens, params)?
+            }
+        };
+        ops::linear(&context, &weights.wo, weights.bo.as_ref())
+    }
+
+    /// Transformer block in the configured [`BlockTopology`] expressed as device kernels
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn forward_gpu(
+        &self,
+        config: &TransformerConfig,
+        attention: ops::AttentionParams,
+        input: &GpuTensor,
+        mask: Option<&GpuTensor>,
+        positions: Option<&GpuTensor>,
+        device: &Arc<dyn GpuDevice>,
+        mut caches: Option<LayerCaches<'_>>,
+    ) -> Result<GpuTensor> {
+        check_hidden_shape(config, &input.shape, caches.as_ref().map(LayerCaches::len))?;
+
+        let add = |a: &GpuTensor, b: GpuTensor| {
+            device.run_kernel(Kernel::new(KernelType::Add), &[a.clone(), b])
+        };
+        let cross = self.cross_attention.as_ref();
+        match config.block {
+            BlockTopology::PreLn => {
+                let normed = run_layer_norm(config, &self.ln1, input, device)?;
+                let attn = self.attention_gpu(
+                    config,
+                    attention,
+                    &normed,
+                    mask,
+                    positions,
+                    device,
+                    &mut caches,
+                )?;
+                let mut hidden = add(input, attn)?;
+                if let Some(cross) = cross {
+                    let normed = run_layer_norm(config, &cross.norm, &hidden, device)?;
+                    hidden = add(
+                        &hidden,
+                        cross.forward_gpu(config, &normed, device, &caches)?,
+                    )?;
+                }
+
+                let normed = run_layer_norm(config, &self.ln2, &hidden, device)?;
+                let ff = self
+                    .feed_forward
+                    .forward_gpu(config, &normed, mask, device)?;
+                add(&hidden, ff)
+            }
+            BlockTopology::PostLn => {
+                let attn = self.attention_gpu(
+                    config,
+                    attention,
+                    input,
+                    mask,
+                    positions,
+                    device,
+                    &mut caches,
+                )?;
+                let mut hidden = run_layer_norm(config, &self.ln1, &add(input, attn)?, device)?;
+                if let Some(cross) = cross {
+                    let context = cross.forward_gpu(config, &hidden, device, &caches)?;
+                    hidden = run_layer_norm(config, &cross.norm, &add(&hidden, context)?, device)?;
+                }
+
+                let ff = self
+                    .feed_forward
+                    .forward_gpu(config, &hidden, mask, device)?;
+                run_layer_norm(config, &self.ln2, &add(&hidden, ff)?, device)
+            }
+            BlockTopology::Parallel => {
+                let normed = run_layer_norm(config, &self.ln1, input, device)?;
+                let attn = self.attention_gpu(
+                    config,
+                    attention,
+                    &normed,
+                    mask,
+                    positions,
+                    device,
+                    &mut caches,
+                )?;
+                let mut hidden = add(input, attn)?;
+                if let Some(cross) = cross {
+                    let normed = run_layer_norm(config, &cross.norm, input, device)?;
+                    hidden = add(
+                        &hidden,
+                        cross.forward_gpu(config, &normed, device, &caches)?,
+                    )?;
+                }
+                let normed = run_layer_norm(config, &self.ln2, input, device)?;
+                let ff = self
+                    .feed_forward
+                    .forward_gpu(config, &normed, mask, device)?;
+                add(&hidden, ff)
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn attention_gpu(
+        &self,
+        config: &TransformerConfig,
+        params: ops::AttentionParams,
+        x: &GpuTensor,
+        mask: Option<&GpuTensor>,
+        positions: Option<&GpuTensor>,
+        device: &Arc<dyn GpuDevice>,
+        caches: &mut Option<LayerCaches<'_>>,
+    ) -> Result<GpuTensor> {
+        let weights = &self.attention;
+        let mut q = run_matmul(x, &weights.wq, weights.bq.as_ref(), device)?;
+        let mut k = run_matmul(x, &weights.wk, weights.bk.as_ref(), device)?;
+        let v = run_matmul(x, &weights.wv, weights.bv.as_ref(), device)?;
+        if let Some((base, scale)) = rope_params(config) {
+            let positions = require_positions(positions)?;
+            let rope = |heads: usize| {
+                Kernel::with_params(KernelType::Rope, vec![heads as f32, base, scale])
+            };
+            q = device.run_kernel(rope(config.n_heads), &[q, positions.clone()])?;
+            k = device.run_kernel(rope(config.kv_heads()), &[k, positions.clone()])?;
+        }
+        let kernel_params = params.kernel_params(matches!(caches, Some(LayerCaches::Paged { .. })));
+        // The caches live on the host; round-trip the new rows through them
+        let inputs = match caches {
+            None => [q, k, v].into_iter().chain(mask.cloned()).collect(),
+            Some(LayerCaches::Contiguous(caches)) => {
+                let mask = mask.map(|mask| device.download_tensor(mask)).transpose()?;
+                let (keys, values, key_mask) = append_to_caches(
+                    caches,
+                    &device.download_tensor(&k)?,
+                    &device.download_tensor(&v)?,
+                    mask.as_ref(),
+                )?;
+                let mut inputs = vec![
+                    q,
+                    device.upload_tensor(&keys)?,
+                    device.upload_tensor(&values)?,
+                ];
+                if let Some(key_mask) = key_mask {
+                    inputs.push(device.upload_tensor(&key_mask)?);
+                }
+                inputs
+            }
+            Some(LayerCaches::Paged {
+                cache,
+                layer,
+                tables,
+                starts,
+            }) => {
+                cache.write_new_rows(
+                    *layer,
+                    tables,
+                    starts,
+                    &device.download_tensor(&k)?,
+                    &device.download_tensor(&v)?,
+                )?;
+                let (k_blocks, v_blocks) = cache.pools(*layer);
+                let (block_tables, context_lens) = cache.block_table_tensors(tables)?;
+                vec![
+                    q,
+                    device.upload_tensor(k_blocks)?,
+                    device.upload_tensor(v_blocks)?,
+                    device.upload_tensor(&block_tables)?,
+                    device.upload_tensor(&context_lens)?,
+                ]
+            }
+        };
+        let context = device.run_kernel(
+            Kernel::with_params(KernelType::Attention, kernel_params),
+            &inputs,
+        )?;
+        run_matmul(&context, &weights.wo, weights.bo.as_ref(), device)
+    }
+}
+
+/// KV caches of one layer for every sequence in a batch
+pub(crate) enum LayerCaches<'a> {
+    /// One contiguous cache per sequence
+    Contiguous(Vec<&'a mut LayerKvCache>),
+    /// Layer `layer` of a paged cache, with block tables already reserved for the new rows
+    /// and the position each sequence's new rows start at
+    Paged {
+        cache: &'a mut PagedKvCache,
+        layer: usize,
+        tables: &'a [&'a BlockTable],
+        starts: &'a [usize],
+    },
+}
+
+impl LayerCaches<'_> {
+    /// Number of sequences
+    fn len(&self) -> usize {
+        match self {
+            Self::Contiguous(caches) => caches.len(),
+            Self::Paged { tables, .. } => tables.len(),
+        }
+    }
+}
+
+/// KV caches of every layer for a batch, see [`LayerCaches`]
+enum ModelCaches<'a, 'c> {
+    Contiguous(&'a mut [&'c mut KvCache]),
+    Paged {
+        cache: &'a mut PagedKvCache,
+        tables: Vec<&'a BlockTable>,
+        starts: Vec<usize>,
+    },
+}
+
+impl ModelCaches<'_, '_> {
+    /// Borrow layer `layer` of every sequence's cache
+    fn layer(&mut self, layer: usize) -> LayerCaches<'_> {
+        match self {
+            Self::Contiguous(caches) => LayerCaches::Contiguous(
+                caches
+                    .iter_mut()
+                    .map(|cache| &mut cache.layers_mut()[layer])
+                    .collect(),
+            ),
+            Self::Paged {
+                cache,
+                tables,
+                starts,
+            } => LayerCaches::Paged {
+                cache,
+                layer,
+                tables,
+                starts,
+            },
+        }
+    }
+}
+
+/// Run `forward` over `caches`, truncating every cache back to its length on entry if it
+/// fails
+///
+/// Layers append their new rows one after another, so an error in a later layer would
+/// otherwise leave the earlier layers a step ahead. A rolling cache that cannot go back is
+/// reset instead, see [`KvCache::truncate`].
+fn roll_back_on_error<T>(
+    caches: &mut [&mut KvCache],
+    forward: impl FnOnce(&mut [&mut KvCache]) -> Result<T>,
+) -> Result<T> {
+    let lens: Vec<usize> = caches.iter().map(|cache| cache.len()).collect();
+    let result = forward(caches);
+    if result.is_err() {
+        for (cache, len) in caches.iter_mut().zip(lens) {
+            cache.truncate(len);
+        }
+    }
+    result
+}
+
+/// Shrink each paged table back to `starts[b]` positions, releasing blocks reserved for a
+/// pass that did not complete
+fn release_new_blocks(
+    tables: &mut [&mut BlockTable],
+    cache: &mut PagedKvCache,
+    starts: &[usize],
+) -> Result<()> {
+    for (table, &start) in tables.iter_mut().zip(starts) {
+        cache.truncate(table, start)?;
+    }
+    Ok(())
+}
+
+/// Append new keys/values to per-sequence caches and return every stored key/value
+///
+/// `k`/`v` are `[.., new_len, width]` with one batch entry per cache. New rows are
+/// left-padded, and `mask` (`[batch, new_len]`) marks the real ones; without a mask every row
+/// is real. The result is left-padded to the most stored rows, shaped with the same leading
+/// dimensions as the new rows, together with a `[batch, seq_k]` key mask when the caches
+/// differ in length.
+fn append_to_caches(
+    caches: &mut [&mut LayerKvCache],
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+) -> Result<(Tensor, Tensor, Option<Tensor>)> {
+    let seq_axis = k.ndim() - 2;
+    let (new_len, width) = (k.shape[seq_axis], k.shape[seq_axis + 1]);
+    let mask = mask.map(Tensor::as_f32_slice).transpose()?;
+    let (k_data, v_data) = (k.as_f32_slice()?, v.as_f32_slice()?);
+    for (b, cache) in caches.iter_mut().enumerate() {
+        let real = mask.map_or(new_len, |mask| {
+            mask[b * new_len..(b + 1) * new_len]
+                .iter()
+                .filter(|&&m| m != 0.0)
+                .count()
+        });
+        let rows = (b * new_len + new_len - real) * width..(b + 1) * new_len * width;
+        cache.append(
+            &Tensor::from_f32(vec![real, width], k_data[rows.clone()].to_vec())?,
+            &Tensor::from_f32(vec![real, width], v_data[rows].to_vec())?,
+        )?;
+    }
+
+    let seq_k = caches
+        .iter()
+        .map(|cache| cache.stored_len())
+        .max()
+        .unwrap_or(0);
+    let mut shape = k.shape.clone();
+    shape[seq_axis] = seq_k;
+    let mut keys = Vec::with_capacity(caches.len() * seq_k * width);
+    let mut values = Vec::with_capacity(caches.len() * seq_k * width);
+    let mut key_mask = Vec::with_capacity(caches.len() * seq_k);
+    for cache in caches.iter() {
+        let pad = seq_k - cache.stored_len();
+        keys.resize(keys.len() + pad * width, 0.0);
+        keys.extend_from_slice(cache.keys()?.as_f32_slice()?);
+        values.resize(values.len() + pad * width, 0.0);
+        values.extend_from_slice(cache.values()?.as_f32_slice()?);
+        key_mask.extend((0..seq_k).map(|j| if j < pad { 0.0 } else { 1.0 }));
+    }
+    let key_mask = if key_mask.contains(&0.0) {
+        Some(Tensor::from_f32(vec![caches.len(), seq_k], key_mask)?)
+    } else {
+        None
+    };
+    Ok((
+        Tensor::from_f32(shape.clone(), keys)?,
+        Tensor::from_f32(shape, values)?,
+        key_mask,
+    ))
+}
+
+/// Bidirectional attention from decoder queries to encoder keys and values
+fn cross_attention_params(config: &TransformerConfig) -> ops::AttentionParams {
+    ops::AttentionParams::new(config.n_heads, false).with_kv_heads(config.kv_heads())
+}
+
+/// Cross-attention keys/values stored in every sequence's cache, right-padded to the longest
+/// encoder output and shaped with the leading dimensions of the decoder rows `shape`, plus a
+/// `[batch, src_len]` key mask when their lengths differ
+fn cross_keys_values(
+    caches: &Option<LayerCaches<'_>>,
+    shape: &[usize],
+) -> Result<(Tensor, Tensor, Option<Tensor>)> {
+    let missing = || {
+        CoreError::Other(
+            "Cross-attention needs encoder keys and values; decode from the cache returned by \
+             TransformerModel::encode"
+                .to_string(),
+        )
+    };
+    let Some(LayerCaches::Contiguous(caches)) = caches else {
+        return Err(missing());
+    };
+    let cross = caches
+        .iter()
+        .map(|cache| cache.cross_attention().ok_or_else(missing))
+        .collect::<Result<Vec<_>>>()?;
+    let src_len = cross.iter().map(|(k, _)| k.shape[0]).max().unwrap_or(0);
+    let width = cross.first().map_or(0, |(k, _)| k.shape[1]);
+
+    let mut keys = Vec::with_capacity(cross.len() * src_len * width);
+    let mut values = Vec::with_capacity(cross.len() * src_len * width);
+    let mut key_mask = Vec::with_capacity(cross.len() * src_len);
+    for (k, v) in &cross {
+        let len = k.shape[0];
+        keys.extend_from_slice(k.as_f32_slice()?);
+        keys.resize(keys.len() + (src_len - len) * width, 0.0);
+        values.extend_from_slice(v.as_f32_slice()?);
+        values.resize(values.len() + (src_len - len) * width, 0.0);
+        key_mask.extend((0..src_len).map(|j| if j < len { 1.0 } else { 0.0 }));
+    }
+    let key_mask = if key_mask.contains(&0.0) {
+        Some(Tensor::from_f32(vec![cross.len(), src_len], key_mask)?)
+    } else {
+        None
+    };
+    let mut shape = shape.to_vec();
+    let seq_axis = shape.len() - 2;
+    shape[seq_axis] = src_len;
+    shape[seq_axis + 1] = width;
+    Ok((
+        Tensor::from_f32(shape.clone(), keys)?,
+        Tensor::from_f32(shape, values)?,
+        key_mask,
+    ))
+}
+
+/// Split per-row results of a right-padded batch into each sequence's real tokens
+fn split_rows<T>(rows: Vec<T>, batch: &PaddedBatch) -> Vec<Vec<T>> {
+    let mut rows = rows.into_iter();
+    batch
+        .lengths()
+        .iter()
+        .map(|&len| {
+            let sequence = rows.by_ref().take(batch.seq_len()).collect::<Vec<_>>();
+            sequence.into_iter().take(len).collect()
+        })
+        .collect()
+}
+
+/// RoPE kernel parameters `(base, position_scale)` if `config` uses rotary embeddings
+fn rope_params(config: &TransformerConfig) -> Option<(f32, f32)> {
+    config.position_encoding.rope_params(config.head_dim())
+}
+
+fn require_positions<T>(positions: Option<&T>) -> Result<&T> {
+    positions.ok_or_else(|| {
+        CoreError::InvalidDimension("Rotary embeddings need position ids".to_string())
+    })
+}
+
+/// `[batch, seq_len]` positions of the rows of a layer input, or `None` when the layer does
+/// not use them
+///
+/// Rows count up from `offset`; with a padding `mask` only real rows advance the count and
+/// padding gets position 0, as in [`PaddedBatch`].
+fn layer_positions(
+    config: &TransformerConfig,
+    shape: &[usize],
+    offset: usize,
+    mask: Option<&Tensor>,
+) -> Result<Option<Tensor>> {
+    if rope_params(config).is_none() || shape.len() < 2 {
+        return Ok(None);
+    }
+    let seq_len = shape[shape.len() - 2];
+    let batch = shape[..shape.len() - 2].iter().product::<usize>();
+    let mask = mask.map(Tensor::as_f32_slice).transpose()?;
+    let mut positions = Vec::with_capacity(batch * seq_len);
+    for b in 0..batch {
+        let mut next = offset;
+        for i in 0..seq_len {
+            if mask.map_or(true, |mask| mask[b * seq_len + i] != 0.0) {
+                positions.push(next as f32);
+                next += 1;
+            } else {
+                positions.push(0.0);
+            }
+        }
+    }
+    Tensor::from_f32(vec![batch, seq_len], positions).map(Some)
+}
+
+/// [`layer_positions`] uploaded to `device`
+fn layer_positions_gpu(
+    config: &TransformerConfig,
+    shape: &[usize],
+    offset: usize,
+    mask: Option<&GpuTensor>,
+    device: &Arc<dyn GpuDevice>,
+) -> Result<Option<GpuTensor>> {
+    if rope_params(config).is_none() {
+        return Ok(None);
+    }
+    let mask = mask.map(|mask| device.download_tensor(mask)).transpose()?;
+    layer_positions(config, shape, offset, mask.as_ref())?
+        .map(|positions| device.upload_tensor(&positions))
+        .transpose()
+}
+
+/// Validate that hidden states are `[seq_len, d_model]` or `[batch, seq_len, d_model]`
+///
+/// Cached passes need exactly one KV cache per sequence in the batch.
+fn check_hidden_shape(
+    config: &TransformerConfig,
+    shape: &[usize],
+    caches: Option<usize>,
+) -> Result<()> {
+    if !(2..=3).contains(&shape.len()) || shape.last() != Some(&config.d_model) {
+        let mut expected = shape[..shape.len().saturating_sub(1)].to_vec();
+        expected.push(config.d_model);
+        return Err(CoreError::ShapeMismatch {
+            expected,
+            actual: shape.to_vec(),
+        });
+    }
+    let batch = if shape.len() == 3 { shape[0] } else { 1 };
+    if let Some(caches) = caches.filter(|&caches| caches != batch) {
+        return Err(CoreError::InvalidDimension(format!(
+            "Expected one KV cache per sequence: {} caches for batch size {}",
+            caches, batch
+        )));
+    }
+    Ok(())
+}
+
+/// Position ids `offset..offset + seq_len` for every sequence of an unpadded batch
+fn sequential_positions(token_ids: &[Vec<u32>], offset: usize) -> Vec<Vec<usize>> {
+    token_ids
+        .iter()
+        .map(|seq| (offset..offset + seq.len()).collect())
+        .collect()
+}
+
+/// Score pooled `[batch, d_model]` hidden states with a classification head on the device
+fn predict_gpu(
+    head: &ClassificationHead,
+    pooled: &Tensor,
+    device: &Arc<dyn GpuDevice>,
+) -> Result<Vec<Prediction>> {
+    let logits = run_matmul(
+        &device.upload_tensor(pooled)?,
+        &head.weight,
+        head.bias.as_ref(),
+        device,
+    )?;
+    device.synchronize()?;
+    head.predict(&device.download_tensor(&logits)?)
+}
+
+/// Upload a weight matrix and optional bias and compute `x @ weight + bias` on the device
+fn run_matmul(
+    x: &GpuTensor,
+    weight: &Tensor,
+    bias: Option<&Tensor>,
+    device: &Arc<dyn GpuDevice>,
+) -> Result<GpuTensor> {
+    let inputs = gemm_inputs(x, weight, bias, device)?;
+    device.run_kernel(Kernel::new(KernelType::MatMul), &inputs)
+}
+
+/// `[x, weight]` or `[x, weight, bias]` inputs of a GEMM kernel
+fn gemm_inputs(
+    x: &GpuTensor,
+    weight: &Tensor,
+    bias: Option<&Tensor>,
+    device: &Arc<dyn GpuDevice>,
+) -> Result<Vec<GpuTensor>> {
+    let mut inputs = vec![x.clone(), device.upload_tensor(weight)?];
+    if let Some(bias) = bias {
+        inputs.push(device.upload_tensor(bias)?);
+    }
+    Ok(inputs)
+}
+
+/// Upload norm weights and normalize `x` on the device with the configured norm type
+fn run_layer_norm(
+    config: &TransformerConfig,
+    weights: &LayerNormWeights,
+    x: &GpuTensor,
+    device: &Arc<dyn GpuDevice>,
+) -> Result<GpuTensor> {
+    let gamma = device.upload_tensor(&weights.gamma)?;
+    let eps = vec![config.layer_norm_eps];
+    match config.norm {
+        NormType::LayerNorm => {
+            let beta = device.upload_tensor(&weights.beta_or_zeros()?)?;
+            device.run_kernel(
+                Kernel::with_params(KernelType::LayerNorm, eps),
+                &[x.clone(), gamma, beta],
+            )
+        }
+        NormType::RmsNorm => device.run_kernel(
+            Kernel::with_params(KernelType::RmsNorm, eps),
+            &[x.clone(), gamma],
+        ),
+    }
 }
 
 /// Transformer layer - performs forward pass computation
 pub struct TransformerLayer {
     config: TransformerConfig,
-    #[allow(dead_code)]
     weights: TransformerLayerWeights,
 }
 
 impl TransformerLayer {
     /// Create a new transformer layer with the given configuration and weights
+    ///
+    /// The layer attends with the window of layer 0 in `config.attention_windows`.
     pub fn new(config: TransformerConfig, weights: TransformerLayerWeights) -> Self {
         Self { config, weights }
     }
 
+    fn attention(&self) -> ops::AttentionParams {
+        self.config.attention_params(0)
+    }
+
     /// Forward pass on CPU (fallback implementation)
+    ///
+    /// `input` is `[seq_len, d_model]` or `[batch, seq_len, d_model]`. Runs the block in the
+    /// configured [`BlockTopology`] (pre-LN by default: `x + Attn(LN1(x))` followed by
+    /// `x + FFN(LN2(x))`), with self-attention masked per
+    /// [`TransformerConfig::attention_mode`].
     pub fn forward_cpu(&self, input: &Tensor) -> Result<Tensor> {
-        // Placeholder: Implement actual transformer forward pass
-        // 1. Layer norm
-        // 2. Multi-head attention
-        // 3. Residual connection
-        // 4. Layer norm
-        // 5. Feed-forward
-        // 6. Residual connection
+        log::debug!("Running transformer layer forward pass on CPU");
+        let positions = layer_positions(&self.config, &input.shape, 0, None)?;
+        self.weights.forward_cpu(
+            &self.config,
+            self.attention(),
+            input,
+            None,
+            positions.as_ref(),
+            None,
+        )
+    }
+
+    /// Forward pass on CPU over a padded batch
+    ///
+    /// `mask` is `[batch, seq_len]` with 0 marking padding positions, which no query attends to.
+    pub fn forward_cpu_with_mask(&self, input: &Tensor, mask: &Tensor) -> Result<Tensor> {
+        let positions = layer_positions(&self.config, &input.shape, 0, Some(mask))?;
+        self.weights.forward_cpu(
+            &self.config,
+            self.attention(),
+            input,
+            Some(mask),
+            positions.as_ref(),
+            None,
+        )
+    }
 
-        log::info!("Running transformer layer forward pass on CPU");
-        Ok(input.clone())
+    /// Incremental forward pass on CPU
+    ///
+    /// `input` holds only the new positions. Their keys/values are appended to `cache` and
+    /// attention runs over everything cached so far, so each decoding step costs O(seq_len).
+    pub fn forward_cpu_with_cache(
+        &self,
+        input: &Tensor,
+        cache: &mut LayerKvCache,
+    ) -> Result<Tensor> {
+        let positions = layer_positions(&self.config, &input.shape, cache.len(), None)?;
+        self.weights.forward_cpu(
+            &self.config,
+            self.attention(),
+            input,
+            None,
+            positions.as_ref(),
+            Some(LayerCaches::Contiguous(vec![cache])),
+        )
     }
 
     /// Forward pass on GPU
+    ///
+    /// Same computation as [`forward_cpu`](Self::forward_cpu), built from `LayerNorm`,
+    /// `MatMul`, `Attention`, `FusedGemmGelu` and `Add` kernels.
     pub fn forward_gpu(&self, input: &GpuTensor, device: &Arc<dyn GpuDevice>) -> Result<GpuTensor> {
-        // Placeholder: Use GPU kernels for computation
-        log::info!(
+        log::debug!(
             "Running transformer layer forward pass on GPU: {}",
             device.device_name()
         );
+        let positions = layer_positions_gpu(&self.config, &input.shape, 0, None, device)?;
+        self.weights.forward_gpu(
+            &self.config,
+            self.attention(),
+            input,
+            None,
+            positions.as_ref(),
+            device,
+            None,
+        )
+    }
 
-        // Example workflow:
-        // 1. Run layer norm kernel
-        // 2. Run attention kernel (fused QKV computation + softmax)
-        // 3. Add residual
-        // 4. Run layer norm kernel
-        // 5. Run fused GEMM+GELU for feed-forward
-        // 6. Add residual
+    /// Forward pass on GPU over a padded batch, see
+    /// [`forward_cpu_with_mask`](Self::forward_cpu_with_mask)
+    pub fn forward_gpu_with_mask(
+        &self,
+        input: &GpuTensor,
+        mask: &GpuTensor,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<GpuTensor> {
+        let positions = layer_positions_gpu(&self.config, &input.shape, 0, Some(mask), device)?;
+        self.weights.forward_gpu(
+            &self.config,
+            self.attention(),
+            input,
+            Some(mask),
+            positions.as_ref(),
+            device,
+            None,
+        )
+    }
 
-        Ok(input.clone())
+    /// Incremental forward pass on GPU, see
+    /// [`forward_cpu_with_cache`](Self::forward_cpu_with_cache)
+    pub fn forward_gpu_with_cache(
+        &self,
+        input: &GpuTensor,
+        device: &Arc<dyn GpuDevice>,
+        cache: &mut LayerKvCache,
+    ) -> Result<GpuTensor> {
+        let positions = layer_positions_gpu(&self.config, &input.shape, cache.len(), None, device)?;
+        self.weights.forward_gpu(
+            &self.config,
+            self.attention(),
+            input,
+            None,
+            positions.as_ref(),
+            device,
+            Some(LayerCaches::Contiguous(vec![cache])),
+        )
     }
 
     /// Get the layer configuration
@@ -161,12 +1606,21 @@ pub struct TransformerModel {
     pub config: TransformerConfig,
     /// Token embedding weights [vocab_size, d_model]
     pub token_embedding: Tensor,
-    /// Position embedding weights [max_seq_len, d_model]
+    /// Position embedding weights [max_seq_len, d_model], used with [`PositionEncoding::Learned`]
     pub position_embedding: Tensor,
     /// Transformer layers
     pub layers: Vec<TransformerLayerWeights>,
-    /// Final layer norm
+    /// Final layer norm, skipped with [`BlockTopology::PostLn`]
     pub final_layer_norm: LayerNormWeights,
+    /// Encoder of an encoder-decoder model; `layers` are then the decoder layers
+    pub encoder: Option<EncoderWeights>,
+    /// Optional sequence classification head, see
+    /// [`classify_sequences`](Self::classify_sequences)
+    pub sequence_classifier: Option<SequenceClassifier>,
+    /// Optional token classification head, see [`classify_tokens`](Self::classify_tokens)
+    pub token_classifier: Option<ClassificationHead>,
+    /// Patch embedding of a Vision Transformer, see [`classify_images`](Self::classify_images)
+    pub patch_embedding: Option<PatchEmbedding>,
 }
 
 impl TransformerModel {
@@ -184,12 +1638,978 @@ impl TransformerModel {
             position_embedding,
             layers,
             final_layer_norm,
+            encoder: None,
+            sequence_classifier: None,
+            token_classifier: None,
+            patch_embedding: None,
+        }
+    }
+
+    /// Attach an encoder, making this an encoder-decoder model
+    ///
+    /// Every decoder layer in `layers` then needs cross-attention weights. Decoding starts
+    /// from the cache returned by [`encode`](Self::encode).
+    pub fn with_encoder(mut self, encoder: EncoderWeights) -> Self {
+        self.encoder = Some(encoder);
+        self
+    }
+
+    /// Router statistics of every mixture-of-experts layer, as `(layer, stats)` pairs
+    pub fn router_stats(&self) -> Vec<(usize, &RouterStats)> {
+        self.layers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, layer)| layer.feed_forward.moe().map(|moe| (i, moe.stats())))
+            .collect()
+    }
+
+    /// Clear the router statistics of every mixture-of-experts layer
+    pub fn reset_router_stats(&self) {
+        for (_, stats) in self.router_stats() {
+            stats.reset();
+        }
+    }
+
+    /// Attach a sequence classification head (e.g. sentiment)
+    pub fn with_sequence_classifier(mut self, classifier: SequenceClassifier) -> Self {
+        self.sequence_classifier = Some(classifier);
+        self
+    }
+
+    /// Attach a token classification head (e.g. named-entity tags)
+    pub fn with_token_classifier(mut self, head: ClassificationHead) -> Self {
+        self.token_classifier = Some(head);
+        self
+    }
+
+    /// Attach the patch embedding of a Vision Transformer configured by
+    /// [`TransformerConfig::vision`]
+    pub fn with_patch_embedding(mut self, patch_embedding: PatchEmbedding) -> Self {
+        self.patch_embedding = Some(patch_embedding);
+        self
+    }
+
+    /// Run the model on a batch of token ids and return logits `[batch, seq_len, vocab_size]`
+    ///
+    /// Every sequence in the batch must have the same length. Embedding lookup happens on the
+    /// host; the layers, final layer norm and tied-embedding LM head run on `device`.
+    pub fn forward(&self, token_ids: &[Vec<u32>], device: &Arc<dyn GpuDevice>) -> Result<Tensor> {
+        log::debug!("Running model forward pass on {}", device.device_name());
+        let positions = sequential_positions(token_ids, 0);
+        self.run_gpu(token_ids, &positions, None, device, None)
+    }
+
+    /// Run the model on a [`PaddedBatch`] and return logits `[batch, seq_len, vocab_size]`
+    ///
+    /// Padding is excluded from attention through the batch's mask, and each sequence is
+    /// embedded at its own position ids. Logits at padding positions are unspecified; use
+    /// [`PaddedBatch::last_token_logits`] to pick each sequence's next-token row.
+    pub fn forward_batch(
+        &self,
+        batch: &PaddedBatch,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Tensor> {
+        self.run_gpu(
+            batch.token_ids(),
+            batch.position_ids(),
+            Some(batch.attention_mask()),
+            device,
+            None,
+        )
+    }
+
+    /// Run only the new `token_ids` of one sequence, reusing and extending `cache`
+    ///
+    /// Tokens are placed at positions `cache.len()..`, and the returned logits are
+    /// `[1, token_ids.len(), vocab_size]`. If the pass fails the cache keeps its previous
+    /// positions, or is reset if a rolling layer already evicted some of them.
+    pub fn forward_with_cache(
+        &self,
+        token_ids: &[u32],
+        cache: &mut KvCache,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Tensor> {
+        let token_ids = [token_ids.to_vec()];
+        let positions = sequential_positions(&token_ids, cache.len());
+        roll_back_on_error(&mut [cache], |caches| {
+            self.run_gpu(
+                &token_ids,
+                &positions,
+                None,
+                device,
+                Some(ModelCaches::Contiguous(caches)),
+            )
+        })
+    }
+
+    /// Run new tokens for several sequences at once, each extending its own cache
+    ///
+    /// `token_ids[b]` holds the new tokens of the sequence cached in `caches[b]`; they may
+    /// differ in length, so prefill and single-token decode steps can share one pass. Tokens
+    /// are left-padded to the longest entry: the returned `[batch, max_new, vocab_size]`
+    /// logits hold sequence `b` in its last `token_ids[b].len()` columns. Failed passes
+    /// leave the caches as [`forward_with_cache`](Self::forward_with_cache) does.
+    pub fn forward_with_caches(
+        &self,
+        token_ids: &[Vec<u32>],
+        caches: &mut [&mut KvCache],
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Tensor> {
+        let lens: Vec<usize> = caches.iter().map(|cache| cache.len()).collect();
+        let (batch, positions) = self.cached_batch(token_ids, &lens)?;
+        roll_back_on_error(caches, |caches| {
+            self.run_gpu(
+                batch.token_ids(),
+                &positions,
+                Some(batch.attention_mask()),
+                device,
+                Some(ModelCaches::Contiguous(caches)),
+            )
+        })
+    }
+
+    /// Run new tokens for several sequences whose keys/values live in a [`PagedKvCache`]
+    ///
+    /// Works like [`forward_with_caches`](Self::forward_with_caches), with `tables[b]`
+    /// naming the blocks of sequence `b`. Blocks for the new tokens are reserved up front,
+    /// copying shared blocks on write; if the cache runs out of blocks or the pass fails, every
+    /// table goes back to its previous length.
+    pub fn forward_paged(
+        &self,
+        token_ids: &[Vec<u32>],
+        tables: &mut [&mut BlockTable],
+        cache: &mut PagedKvCache,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Tensor> {
+        let (batch, positions, starts) = self.paged_batch(token_ids, tables, cache)?;
+        let caches = ModelCaches::Paged {
+            cache,
+            tables: tables.iter().map(|table| &**table).collect(),
+            starts: starts.clone(),
+        };
+        let result = self.run_gpu(
+            batch.token_ids(),
+            &positions,
+            Some(batch.attention_mask()),
+            device,
+            Some(caches),
+        );
+        if result.is_err() {
+            release_new_blocks(tables, cache, &starts)?;
+        }
+        result
+    }
+
+    /// CPU reference for [`forward`](Self::forward), used to validate GPU backends
+    pub fn forward_cpu(&self, token_ids: &[Vec<u32>]) -> Result<Tensor> {
+        let positions = sequential_positions(token_ids, 0);
+        self.run_cpu(token_ids, &positions, None, None)
+    }
+
+    /// CPU reference for [`forward_batch`](Self::forward_batch)
+    pub fn forward_batch_cpu(&self, batch: &PaddedBatch) -> Result<Tensor> {
+        self.run_cpu(
+            batch.token_ids(),
+            batch.position_ids(),
+            Some(batch.attention_mask()),
+            None,
+        )
+    }
+
+    /// CPU reference for [`forward_with_cache`](Self::forward_with_cache)
+    pub fn forward_cpu_with_cache(&self, token_ids: &[u32], cache: &mut KvCache) -> Result<Tensor> {
+        let token_ids = [token_ids.to_vec()];
+        let positions = sequential_positions(&token_ids, cache.len());
+        roll_back_on_error(&mut [cache], |caches| {
+            self.run_cpu(
+                &token_ids,
+                &positions,
+                None,
+                Some(ModelCaches::Contiguous(caches)),
+            )
+        })
+    }
+
+    /// CPU reference for [`forward_with_caches`](Self::forward_with_caches)
+    pub fn forward_cpu_with_caches(
+        &self,
+        token_ids: &[Vec<u32>],
+        caches: &mut [&mut KvCache],
+    ) -> Result<Tensor> {
+        let lens: Vec<usize> = caches.iter().map(|cache| cache.len()).collect();
+        let (batch, positions) = self.cached_batch(token_ids, &lens)?;
+        roll_back_on_error(caches, |caches| {
+            self.run_cpu(
+                batch.token_ids(),
+                &positions,
+                Some(batch.attention_mask()),
+                Some(ModelCaches::Contiguous(caches)),
+            )
+        })
+    }
+
+    /// CPU reference for [`forward_paged`](Self::forward_paged)
+    pub fn forward_cpu_paged(
+        &self,
+        token_ids: &[Vec<u32>],
+        tables: &mut [&mut BlockTable],
+        cache: &mut PagedKvCache,
+    ) -> Result<Tensor> {
+        let (batch, positions, starts) = self.paged_batch(token_ids, tables, cache)?;
+        let caches = ModelCaches::Paged {
+            cache,
+            tables: tables.iter().map(|table| &**table).collect(),
+            starts: starts.clone(),
+        };
+        let result = self.run_cpu(
+            batch.token_ids(),
+            &positions,
+            Some(batch.attention_mask()),
+            Some(caches),
+        );
+        if result.is_err() {
+            release_new_blocks(tables, cache, &starts)?;
+        }
+        result
+    }
+
+    /// Pooled sentence embeddings `[batch, d_model]` of `token_ids`, sequences of any length
+    ///
+    /// Sequences are right-padded into one batch and run through the layers and final layer
+    /// norm on `device` (BERT-style encoders use [`AttentionMode::Bidirectional`]); their
+    /// hidden states are then reduced per [`Pooling`] over the real tokens only, and scaled
+    /// to unit length when `normalize` is set (for cosine similarity as a dot product).
+    pub fn sentence_embeddings(
+        &self,
+        token_ids: &[Vec<u32>],
+        pooling: Pooling,
+        normalize: bool,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Tensor> {
+        let pooled = self.pooled_gpu(token_ids, pooling, device)?;
+        if normalize {
+            embeddings::l2_normalize(&pooled)
+        } else {
+            Ok(pooled)
+        }
+    }
+
+    /// CPU reference for [`sentence_embeddings`](Self::sentence_embeddings)
+    pub fn sentence_embeddings_cpu(
+        &self,
+        token_ids: &[Vec<u32>],
+        pooling: Pooling,
+        normalize: bool,
+    ) -> Result<Tensor> {
+        let pooled = self.pooled_cpu(token_ids, pooling)?;
+        if normalize {
+            embeddings::l2_normalize(&pooled)
+        } else {
+            Ok(pooled)
+        }
+    }
+
+    /// Classify each sequence of `token_ids` with the model's [`SequenceClassifier`]
+    ///
+    /// Hidden states are pooled as in [`sentence_embeddings`](Self::sentence_embeddings) and
+    /// the head runs on `device`; each [`Prediction`] carries the top label and the softmax
+    /// scores of all labels.
+    pub fn classify_sequences(
+        &self,
+        token_ids: &[Vec<u32>],
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Vec<Prediction>> {
+        let classifier = self.require_sequence_classifier()?;
+        let pooled = self.pooled_gpu(token_ids, classifier.pooling, device)?;
+        predict_gpu(&classifier.head, &pooled, device)
+    }
+
+    /// CPU reference for [`classify_sequences`](Self::classify_sequences)
+    pub fn classify_sequences_cpu(&self, token_ids: &[Vec<u32>]) -> Result<Vec<Prediction>> {
+        let classifier = self.require_sequence_classifier()?;
+        let pooled = self.pooled_cpu(token_ids, classifier.pooling)?;
+        let head = &classifier.head;
+        head.predict(&head.logits(&pooled)?)
+    }
+
+    /// Classify every token of `token_ids` with the model's token classification head
+    ///
+    /// Returns one [`Prediction`] per real token of each sequence; sequences may differ in
+    /// length and padding is excluded from attention.
+    pub fn classify_tokens(
+        &self,
+        token_ids: &[Vec<u32>],
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Vec<Vec<Prediction>>> {
+        let head = self.require_token_classifier()?;
+        let batch = PaddedBatch::new(token_ids, 0, PaddingSide::Right)?;
+        let hidden = self.hidden_gpu(
+            batch.token_ids(),
+            batch.position_ids(),
+            Some(batch.attention_mask()),
+            device,
+            None,
+        )?;
+        let logits = run_matmul(&hidden, &head.weight, head.bias.as_ref(), device)?;
+        device.synchronize()?;
+        let predictions = head.predict(&device.download_tensor(&logits)?)?;
+        Ok(split_rows(predictions, &batch))
+    }
+
+    /// CPU reference for [`classify_tokens`](Self::classify_tokens)
+    pub fn classify_tokens_cpu(&self, token_ids: &[Vec<u32>]) -> Result<Vec<Vec<Prediction>>> {
+        let head = self.require_token_classifier()?;
+        let batch = PaddedBatch::new(token_ids, 0, PaddingSide::Right)?;
+        let hidden = self.hidden_cpu(
+            batch.token_ids(),
+            batch.position_ids(),
+            Some(batch.attention_mask()),
+            None,
+        )?;
+        let predictions = head.predict(&head.logits(&hidden)?)?;
+        Ok(split_rows(predictions, &batch))
+    }
+
+    fn require_sequence_classifier(&self) -> Result<&SequenceClassifier> {
+        let classifier = self.sequence_classifier.as_ref().ok_or_else(|| {
+            CoreError::Other("Model has no sequence classification head".to_string())
+        })?;
+        classifier.head.validate(self.config.d_model)?;
+        Ok(classifier)
+    }
+
+    fn require_token_classifier(&self) -> Result<&ClassificationHead> {
+        let head = self.token_classifier.as_ref().ok_or_else(|| {
+            CoreError::Other("Model has no token classification head".to_string())
+        })?;
+        head.validate(self.config.d_model)?;
+        Ok(head)
+    }
+
+    /// Right-pad `token_ids`, run the model on `device` and pool each sequence's real tokens,
+    /// returning `[batch, d_model]`
+    fn pooled_gpu(
+        &self,
+        token_ids: &[Vec<u32>],
+        pooling: Pooling,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Tensor> {
+        let batch = PaddedBatch::new(token_ids, 0, PaddingSide::Right)?;
+        let mask = batch.attention_mask();
+        let hidden = self.hidden_gpu(
+            batch.token_ids(),
+            batch.position_ids(),
+            Some(mask),
+            device,
+            None,
+        )?;
+        device.synchronize()?;
+        embeddings::pool(&device.download_tensor(&hidden)?, Some(mask), pooling)
+    }
+
+    /// CPU reference for [`pooled_gpu`](Self::pooled_gpu)
+    fn pooled_cpu(&self, token_ids: &[Vec<u32>], pooling: Pooling) -> Result<Tensor> {
+        let batch = PaddedBatch::new(token_ids, 0, PaddingSide::Right)?;
+        let mask = batch.attention_mask();
+        let hidden = self.hidden_cpu(batch.token_ids(), batch.position_ids(), Some(mask), None)?;
+        embeddings::pool(&hidden, Some(mask), pooling)
+    }
+
+    /// Pooled embeddings `[batch, d_model]` of `[C, H, W]` images with a Vision Transformer
+    ///
+    /// Each image is cut into patches, projected by the model's [`PatchEmbedding`] behind the
+    /// CLS token and run through the layers and final layer norm on `device`; the sequence is
+    /// reduced per [`Pooling`] ([`Pooling::Cls`] for ViT) and optionally scaled to unit length.
+    /// Images must match [`TransformerConfig::vision`] and be normalized as in training, see
+    /// [`vision::normalize_image`].
+    pub fn image_embeddings(
+        &self,
+        images: &[Tensor],
+        pooling: Pooling,
+        normalize: bool,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Tensor> {
+        let pooled = self.image_pooled_gpu(images, pooling, device)?;
+        if normalize {
+            embeddings::l2_normalize(&pooled)
+        } else {
+            Ok(pooled)
         }
     }
 
+    /// CPU reference for [`image_embeddings`](Self::image_embeddings)
+    pub fn image_embeddings_cpu(
+        &self,
+        images: &[Tensor],
+        pooling: Pooling,
+        normalize: bool,
+    ) -> Result<Tensor> {
+        let pooled = self.image_pooled_cpu(images, pooling)?;
+        if normalize {
+            embeddings::l2_normalize(&pooled)
+        } else {
+            Ok(pooled)
+        }
+    }
+
+    /// Classify `[C, H, W]` images with the model's [`SequenceClassifier`]
+    ///
+    /// Images are embedded as in [`image_embeddings`](Self::image_embeddings), pooled per the
+    /// classifier and scored by its head on `device`.
+    pub fn classify_images(
+        &self,
+        images: &[Tensor],
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Vec<Prediction>> {
+        let classifier = self.require_sequence_classifier()?;
+        let pooled = self.image_pooled_gpu(images, classifier.pooling, device)?;
+        predict_gpu(&classifier.head, &pooled, device)
+    }
+
+    /// CPU reference for [`classify_images`](Self::classify_images)
+    pub fn classify_images_cpu(&self, images: &[Tensor]) -> Result<Vec<Prediction>> {
+        let classifier = self.require_sequence_classifier()?;
+        let pooled = self.image_pooled_cpu(images, classifier.pooling)?;
+        let head = &classifier.head;
+        head.predict(&head.logits(&pooled)?)
+    }
+
+    /// Embed `images` and run them through the layers on `device`, returning `[batch, d_model]`
+    /// pooled hidden states
+    fn image_pooled_gpu(
+        &self,
+        images: &[Tensor],
+        pooling: Pooling,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<Tensor> {
+        let (patch_embedding, patches, offsets) = self.image_inputs(images)?;
+        let projected = run_matmul(
+            &device.upload_tensor(&patches)?,
+            &patch_embedding.projection,
+            None,
+            device,
+        )?;
+        let embeddings = device.run_kernel(
+            Kernel::new(KernelType::Add),
+            &[projected, device.upload_tensor(&offsets)?],
+        )?;
+        let position_ids = vec![(0..patches.shape[1]).collect(); images.len()];
+        let hidden = self.layers_gpu(embeddings, &position_ids, None, device, None)?;
+        device.synchronize()?;
+        embeddings::pool(&device.download_tensor(&hidden)?, None, pooling)
+    }
+
+    /// CPU reference for [`image_pooled_gpu`](Self::image_pooled_gpu)
+    fn image_pooled_cpu(&self, images: &[Tensor], pooling: Pooling) -> Result<Tensor> {
+        let (patch_embedding, patches, offsets) = self.image_inputs(images)?;
+        let projected = ops::linear(&patches, &patch_embedding.projection, None)?;
+        let embeddings = ops::add(&projected, &offsets)?;
+        let position_ids = vec![(0..patches.shape[1]).collect(); images.len()];
+        let hidden = self.layers_cpu(embeddings, &position_ids, None, None)?;
+        embeddings::pool(&hidden, None, pooling)
+    }
+
+    /// Patches `[batch, 1 + n_patches, patch_dim]` of `images` and the
+    /// `[batch, 1 + n_patches, d_model]` terms added after their projection
+    ///
+    /// Each image gets an all-zero patch in the CLS slot, which projects to zero; the added
+    /// terms hold the CLS token there and the projection bias at every patch, plus learned
+    /// positions. One matmul and one add then embed the whole sequence.
+    fn image_inputs(&self, images: &[Tensor]) -> Result<(&PatchEmbedding, Tensor, Tensor)> {
+        let vision = self.config.vision.ok_or_else(|| {
+            CoreError::Other("Model has no vision configuration to embed images".to_string())
+        })?;
+        let patch_embedding = self
+            .patch_embedding
+            .as_ref()
+            .ok_or_else(|| CoreError::Other("Model has no patch embedding".to_string()))?;
+        if images.is_empty() {
+            return Err(CoreError::InvalidDimension(
+                "Image batch must not be empty".to_string(),
+            ));
+        }
+        let (d, patch_dim) = (self.config.d_model, vision.patch_dim());
+        let seq_len = vision.n_patches() + 1;
+        if seq_len > self.config.max_seq_len {
+            return Err(CoreError::InvalidDimension(format!(
+                "{} patch tokens and the CLS token exceed max_seq_len {}",
+                seq_len - 1,
+                self.config.max_seq_len
+            )));
+        }
+        let check_shape = |tensor: &Tensor, expected: Vec<usize>| {
+            if tensor.shape == expected {
+                Ok(())
+            } else {
+                Err(CoreError::ShapeMismatch {
+                    expected,
+                    actual: tensor.shape.clone(),
+                })
+            }
+        };
+        check_shape(&patch_embedding.projection, vec![patch_dim, d])?;
+        check_shape(&patch_embedding.cls_token, vec![d])?;
+        if let Some(bias) = &patch_embedding.bias {
+            check_shape(bias, vec![d])?;
+        }
+
+        let mut patches = Vec::with_capacity(images.len() * seq_len * patch_dim);
+        for image in images {
+            check_shape(
+                image,
+                vec![vision.channels, vision.image_size, vision.image_size],
+            )?;
+            patches.extend(std::iter::repeat(0.0).take(patch_dim));
+            patches.extend_from_slice(vision::patchify(image, vision.patch_size)?.as_f32_slice()?);
+        }
+
+        let mut offsets = patch_embedding.cls_token.as_f32_slice()?.to_vec();
+        let bias = match &patch_embedding.bias {
+            Some(bias) => bias.as_f32_slice()?.to_vec(),
+            None => vec![0.0; d],
+        };
+        for _ in 1..seq_len {
+            offsets.extend_from_slice(&bias);
+        }
+        if self.config.position_encoding == PositionEncoding::Learned {
+            let shape = &self.position_embedding.shape;
+            if shape.len() != 2 || shape[0] < seq_len || shape[1] != d {
+                return Err(CoreError::ShapeMismatch {
+                    expected: vec![seq_len, d],
+                    actual: shape.clone(),
+                });
+            }
+            let positions = self.position_embedding.as_f32_slice()?;
+            offsets
+                .iter_mut()
+                .zip(positions)
+                .for_each(|(offset, position)| *offset += position);
+        }
+        Ok((
+            patch_embedding,
+            Tensor::from_f32(vec![images.len(), seq_len, patch_dim], patches)?,
+            Tensor::from_f32(vec![images.len(), seq_len, d], offsets.repeat(images.len()))?,
+        ))
+    }
+
+    /// Run the encoder of an encoder-decoder model over `token_ids` and return a [`KvCache`]
+    /// primed with every decoder layer's cross-attention keys and values
+    ///
+    /// Decode by feeding target tokens to [`forward_with_cache`](Self::forward_with_cache) or
+    /// [`forward_with_caches`](Self::forward_with_caches) with the returned cache; the encoder
+    /// output is projected once here and reused at every decoded position. Resetting or
+    /// truncating the cache keeps the encoded input.
+    pub fn encode(&self, token_ids: &[u32], device: &Arc<dyn GpuDevice>) -> Result<KvCache> {
+        let token_ids = [token_ids.to_vec()];
+        let embeddings = self.embed(&token_ids, &sequential_positions(&token_ids, 0))?;
+        self.run_encoder_gpu(&embeddings, device)
+    }
+
+    /// Like [`encode`](Self::encode), for input embeddings `[src_len, d_model]` that are used
+    /// as-is instead of looking up tokens (e.g. projected audio features)
+    pub fn encode_embeddings(
+        &self,
+        embeddings: &Tensor,
+        device: &Arc<dyn GpuDevice>,
+    ) -> Result<KvCache> {
+        self.run_encoder_gpu(&self.encoder_input(embeddings)?, device)
+    }
+
+    /// CPU reference for [`encode`](Self::encode)
+    pub fn encode_cpu(&self, token_ids: &[u32]) -> Result<KvCache> {
+        let token_ids = [token_ids.to_vec()];
+        let embeddings = self.embed(&token_ids, &sequential_positions(&token_ids, 0))?;
+        self.run_encoder_cpu(&embeddings)
+    }
+
+    /// CPU reference for [`encode_embeddings`](Self::encode_embeddings)
+    pub fn encode_embeddings_cpu(&self, embeddings: &Tensor) -> Result<KvCache> {
+        self.run_encoder_cpu(&self.encoder_input(embeddings)?)
+    }
+
+    /// Check `[src_len, d_model]` input embeddings and add the batch dimension
+    fn encoder_input(&self, embeddings: &Tensor) -> Result<Tensor> {
+        let d = self.config.d_model;
+        if embeddings.shape.len() != 2 || embeddings.shape[0] == 0 || embeddings.shape[1] != d {
+            return Err(CoreError::ShapeMismatch {
+                expected: vec![embeddings.shape.first().copied().unwrap_or(1).max(1), d],
+                actual: embeddings.shape.clone(),
+            });
+        }
+        embeddings.reshape(vec![1, embeddings.shape[0], d])
+    }
+
+    /// The encoder and every decoder layer's cross-attention weights
+    fn encoder_decoder(&self) -> Result<(&EncoderWeights, Vec<&CrossAttentionWeights>)> {
+        let encoder = self.encoder.as_ref().ok_or_else(|| {
+            CoreError::Other("Model has no encoder to encode the input with".to_string())
+        })?;
+        let cross = self
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                layer.cross_attention.as_ref().ok_or_else(|| {
+                    CoreError::Other(format!("Decoder layer {} has no cross-attention", i))
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok((encoder, cross))
+    }
+
+    /// Bidirectional self-attention of the encoder layers
+    fn encoder_attention_params(&self) -> ops::AttentionParams {
+        self.config
+            .attention_params(0)
+            .with_causal(false)
+            .with_window(None)
+    }
+
+    /// Store the cross-attention keys/values of one encoded input in a new cache
+    fn cross_attention_cache(&self, projections: Vec<(Tensor, Tensor)>) -> Result<KvCache> {
+        let mut cache = self.new_kv_cache();
+        let width = self.config.kv_width();
+        for (layer, (keys, values)) in cache.layers_mut().iter_mut().zip(projections) {
+            let src_len = keys.numel() / width;
+            layer.set_cross_attention(
+                keys.reshape(vec![src_len, width])?,
+                values.reshape(vec![src_len, width])?,
+            )?;
+        }
+        Ok(cache)
+    }
+
+    /// Encoder on `device` over `[1, src_len, d_model]` embeddings
+    fn run_encoder_gpu(&self, embeddings: &Tensor, device: &Arc<dyn GpuDevice>) -> Result<KvCache> {
+        let (encoder, cross) = self.encoder_decoder()?;
+        let position_ids = [(0..embeddings.shape[1]).collect::<Vec<_>>()];
+        let positions = self
+            .rope_positions(&position_ids)?
+            .map(|positions| device.upload_tensor(&positions))
+            .transpose()?;
+        let mut hidden = device.upload_tensor(embeddings)?;
+        for layer in &encoder.layers {
+            hidden = layer.forward_gpu(
+                &self.config,
+                self.encoder_attention_params(),
+                &hidden,
+                None,
+                positions.as_ref(),
+                device,
+                None,
+            )?;
+        }
+        if self.config.block != BlockTopology::PostLn {
+            hidden = run_layer_norm(&self.config, &encoder.final_layer_norm, &hidden, device)?;
+        }
+
+        let projections = cross
+            .iter()
+            .map(|cross| cross.keys_values_gpu(&hidden, device))
+            .collect::<Result<Vec<_>>>()?;
+        device.synchronize()?;
+        let projections = projections
+            .iter()
+            .map(|(k, v)| Ok((device.download_tensor(k)?, device.download_tensor(v)?)))
+            .collect::<Result<_>>()?;
+        self.cross_attention_cache(projections)
+    }
+
+    /// CPU reference for [`run_encoder_gpu`](Self::run_encoder_gpu)
+    fn run_encoder_cpu(&self, embeddings: &Tensor) -> Result<KvCache> {
+        let (encoder, cross) = self.encoder_decoder()?;
+        let position_ids = [(0..embeddings.shape[1]).collect::<Vec<_>>()];
+        let positions = self.rope_positions(&position_ids)?;
+        let mut hidden = embeddings.clone();
+        for layer in &encoder.layers {
+            hidden = layer.forward_cpu(
+                &self.config,
+                self.encoder_attention_params(),
+                &hidden,
+                None,
+                positions.as_ref(),
+                None,
+            )?;
+        }
+        if self.config.block != BlockTopology::PostLn {
+            hidden = encoder
+                .final_layer_norm
+                .forward_cpu(&self.config, &hidden)?;
+        }
+
+        let projections = cross
+            .iter()
+            .map(|cross| cross.keys_values_cpu(&hidden))
+            .collect::<Result<_>>()?;
+        self.cross_attention_cache(projections)
+    }
+
+    /// Left-pad per-sequence new tokens and place them after each sequence's `cached_lens`
+    fn cached_batch(
+        &self,
+        token_ids: &[Vec<u32>],
+        cached_lens: &[usize],
+    ) -> Result<(PaddedBatch, Vec<Vec<usize>>)> {
+        if token_ids.len() != cached_lens.len() {
+            return Err(CoreError::InvalidDimension(format!(
+                "Expected one KV cache per sequence: {} caches for batch size {}",
+                cached_lens.len(),
+                token_ids.len()
+            )));
+        }
+        let batch = PaddedBatch::new(token_ids, 0, PaddingSide::Left)?;
+        let positions = batch
+            .position_ids()
+            .iter()
+            .zip(cached_lens)
+            .map(|(positions, len)| positions.iter().map(|p| p + len).collect())
+            .collect();
+        Ok((batch, positions))
+    }
+
+    /// Like [`cached_batch`](Self::cached_batch), then reserve paged blocks for the new
+    /// tokens, returning each sequence's start position
+    fn paged_batch(
+        &self,
+        token_ids: &[Vec<u32>],
+        tables: &mut [&mut BlockTable],
+        cache: &mut PagedKvCache,
+    ) -> Result<(PaddedBatch, Vec<Vec<usize>>, Vec<usize>)> {
+        let starts: Vec<usize> = tables.iter().map(|table| table.len()).collect();
+        let (batch, positions) = self.cached_batch(token_ids, &starts)?;
+        for b in 0..tables.len() {
+            if let Err(err) = cache.reserve(tables[b], token_ids[b].len()) {
+                release_new_blocks(&mut tables[..b], cache, &starts)?;
+                return Err(err);
+            }
+        }
+        Ok((batch, positions, starts))
+    }
+
+    fn run_gpu(
+        &self,
+        token_ids: &[Vec<u32>],
+        position_ids: &[Vec<usize>],
+        mask: Option<&Tensor>,
+        device: &Arc<dyn GpuDevice>,
+        caches: Option<ModelCaches<'_, '_>>,
+    ) -> Result<Tensor> {
+        let hidden = self.hidden_gpu(token_ids, position_ids, mask, device, caches)?;
+        let embedding = device.upload_tensor(&self.token_embedding)?;
+        let logits = device.run_kernel(
+            Kernel::with_params(KernelType::MatMul, vec![1.0]),
+            &[hidden, embedding],
+        )?;
+        device.synchronize()?;
+        let logits = device.download_tensor(&logits)?;
+
+        let expected = vec![token_ids.len(), token_ids[0].len(), self.config.vocab_size];
+        if logits.shape != expected {
+            return Err(CoreError::ShapeMismatch {
+                expected,
+                actual: logits.shape,
+            });
+        }
+        Ok(logits)
+    }
+
+    /// Final hidden states `[batch, seq_len, d_model]` on `device`, before the LM head
+    fn hidden_gpu(
+        &self,
+        token_ids: &[Vec<u32>],
+        position_ids: &[Vec<usize>],
+        mask: Option<&Tensor>,
+        device: &Arc<dyn GpuDevice>,
+        caches: Option<ModelCaches<'_, '_>>,
+    ) -> Result<GpuTensor> {
+        self.check_cacheable(caches.is_some())?;
+        let embeddings = device.upload_tensor(&self.embed(token_ids, position_ids)?)?;
+        self.layers_gpu(embeddings, position_ids, mask, device, caches)
+    }
+
+    /// Run `layers` and the final layer norm on `device` over input embeddings
+    /// `[batch, seq_len, d_model]` placed at `position_ids`
+    fn layers_gpu(
+        &self,
+        mut hidden: GpuTensor,
+        position_ids: &[Vec<usize>],
+        mask: Option<&Tensor>,
+        device: &Arc<dyn GpuDevice>,
+        mut caches: Option<ModelCaches<'_, '_>>,
+    ) -> Result<GpuTensor> {
+        let mask = mask.map(|mask| device.upload_tensor(mask)).transpose()?;
+        let positions = self
+            .rope_positions(position_ids)?
+            .map(|positions| device.upload_tensor(&positions))
+            .transpose()?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let layer_caches = caches.as_mut().map(|caches| caches.layer(i));
+            hidden = layer.forward_gpu(
+                &self.config,
+                self.config.attention_params(i),
+                &hidden,
+                mask.as_ref(),
+                positions.as_ref(),
+                device,
+                layer_caches,
+            )?;
+        }
+        if self.config.block != BlockTopology::PostLn {
+            hidden = run_layer_norm(&self.config, &self.final_layer_norm, &hidden, device)?;
+        }
+        Ok(hidden)
+    }
+
+    fn run_cpu(
+        &self,
+        token_ids: &[Vec<u32>],
+        position_ids: &[Vec<usize>],
+        mask: Option<&Tensor>,
+        caches: Option<ModelCaches<'_, '_>>,
+    ) -> Result<Tensor> {
+        let hidden = self.hidden_cpu(token_ids, position_ids, mask, caches)?;
+        ops::matmul_transposed(&hidden, &self.token_embedding)
+    }
+
+    /// CPU reference for [`hidden_gpu`](Self::hidden_gpu)
+    fn hidden_cpu(
+        &self,
+        token_ids: &[Vec<u32>],
+        position_ids: &[Vec<usize>],
+        mask: Option<&Tensor>,
+        caches: Option<ModelCaches<'_, '_>>,
+    ) -> Result<Tensor> {
+        self.check_cacheable(caches.is_some())?;
+        let embeddings = self.embed(token_ids, position_ids)?;
+        self.layers_cpu(embeddings, position_ids, mask, caches)
+    }
+
+    /// CPU reference for [`layers_gpu`](Self::layers_gpu)
+    fn layers_cpu(
+        &self,
+        mut hidden: Tensor,
+        position_ids: &[Vec<usize>],
+        mask: Option<&Tensor>,
+        mut caches: Option<ModelCaches<'_, '_>>,
+    ) -> Result<Tensor> {
+        let positions = self.rope_positions(position_ids)?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let layer_caches = caches.as_mut().map(|caches| caches.layer(i));
+            hidden = layer.forward_cpu(
+                &self.config,
+                self.config.attention_params(i),
+                &hidden,
+                mask,
+                positions.as_ref(),
+                layer_caches,
+            )?;
+        }
+        if self.config.block != BlockTopology::PostLn {
+            hidden = self.final_layer_norm.forward_cpu(&self.config, &hidden)?;
+        }
+        Ok(hidden)
+    }
+
+    /// Reject KV-cached passes of bidirectional models, whose cached keys and values would go
+    /// stale as tokens are added
+    fn check_cacheable(&self, cached: bool) -> Result<()> {
+        if cached && self.config.attention_mode == AttentionMode::Bidirectional {
+            return Err(CoreError::Other(
+                "KV-cached decoding needs causal attention; bidirectional models run full passes"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// `position_ids` as a `[batch, seq_len]` tensor for the layers' rotary embeddings, or
+    /// `None` if the model does not use them
+    fn rope_positions(&self, position_ids: &[Vec<usize>]) -> Result<Option<Tensor>> {
+        if rope_params(&self.config).is_none() {
+            return Ok(None);
+        }
+        let seq_len = position_ids.first().map_or(0, Vec::len);
+        let positions = position_ids.iter().flatten().map(|&p| p as f32).collect();
+        Tensor::from_f32(vec![position_ids.len(), seq_len], positions).map(Some)
+    }
+
+    /// Look up token embeddings, plus learned position embeddings when configured, returning
+    /// `[batch, seq_len, d_model]`
+    ///
+    /// `position_ids` has the same `[batch][seq_len]` layout as `token_ids`.
+    fn embed(&self, token_ids: &[Vec<u32>], position_ids: &[Vec<usize>]) -> Result<Tensor> {
+        let seq_len = token_ids.first().map_or(0, Vec::len);
+        if seq_len == 0 || token_ids.iter().any(|seq| seq.len() != seq_len) {
+            return Err(CoreError::InvalidDimension(
+                "Token batch must contain non-empty sequences of equal length".to_string(),
+            ));
+        }
+        let max_position = position_ids.iter().flatten().copied().max().unwrap_or(0);
+        if max_position >= self.config.context_len() {
+            return Err(CoreError::InvalidDimension(format!(
+                "Sequence length {} exceeds max_seq_len {}",
+                max_position + 1,
+                self.config.max_seq_len
+            )));
+        }
+
+        let d = self.config.d_model;
+        let check_shape = |tensor: &Tensor, expected: Vec<usize>| {
+            if tensor.shape == expected {
+                Ok(())
+            } else {
+                Err(CoreError::ShapeMismatch {
+                    expected,
+                    actual: tensor.shape.clone(),
+                })
+            }
+        };
+        check_shape(&self.token_embedding, vec![self.config.vocab_size, d])?;
+        if self.config.position_encoding == PositionEncoding::Learned {
+            check_shape(&self.position_embedding, vec![self.config.max_seq_len, d])?;
+        }
+        let tokens = self.token_embedding.as_f32_slice()?;
+        let positions = match self.config.position_encoding {
+            PositionEncoding::Learned => Some(self.position_embedding.as_f32_slice()?),
+            PositionEncoding::Rope { .. } | PositionEncoding::Alibi | PositionEncoding::None => {
+                None
+            }
+        };
+        let mut data = Vec::with_capacity(token_ids.len() * seq_len * d);
+        for (seq, seq_positions) in token_ids.iter().zip(position_ids) {
+            for (&id, &pos) in seq.iter().zip(seq_positions) {
+                let id = id as usize;
+                if id >= self.config.vocab_size {
+                    return Err(CoreError::InvalidDimension(format!(
+                        "Token id {} out of range for vocabulary of {}",
+                        id, self.config.vocab_size
+                    )));
+                }
+                let token = &tokens[id * d..(id + 1) * d];
+                match positions {
+                    Some(positions) => {
+                        let position = &positions[pos * d..(pos + 1) * d];
+                        data.extend(token.iter().zip(position).map(|(t, p)| t + p));
+                    }
+                    None => data.extend_from_slice(token),
+                }
+            }
+        }
+        Tensor::from_f32(vec![token_ids.len(), seq_len, d], data)
+    }
+
+    /// Create an empty KV cache sized for this model
+    pub fn new_kv_cache(&self) -> KvCache {
+        KvCache::new(&self.config)
+    }
+
+    /// Create a paged KV cache of `num_blocks` blocks of `block_size` positions
+    pub fn new_paged_kv_cache(&self, block_size: usize, num_blocks: usize) -> Result<PagedKvCache> {
+        PagedKvCache::new(&self.config, block_size, num_blocks)
+    }
+
     /// Load model from binary file
     pub fn load_from_file(path: &str) -> Result<Self> {
-        use crate::error::CoreError;
         use std::fs::File;
         use std::io::Read;
 
@@ -197,23 +2617,178 @@ impl TransformerModel {
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
 
-        bincode::deserialize(&buffer)
-            .map_err(|e| CoreError::ModelLoadError(format!("Failed to deserialize model: {}", e)))
+        Self::from_bytes(&buffer)
     }
 
     /// Save model to binary file
     pub fn save_to_file(&self, path: &str) -> Result<()> {
-        use crate::error::CoreError;
         use std::fs::File;
         use std::io::Write;
 
-        let encoded = bincode::serialize(self).map_err(|e| {
-            CoreError::SerializationError(format!("Failed to serialize model: {}", e))
-        })?;
+        let encoded = self.to_bytes()?;
+
+        let mut file = File::create(path)?;
+        file.write_all(&encoded)?;
+        Ok(())
+    }
+
+    /// Encode the model in the format read by [`TransformerModel::from_bytes`]
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self)
+            .map_err(|e| CoreError::SerializationError(format!("Failed to serialize model: {}", e)))
+    }
+
+    /// Decode a model written by [`TransformerModel::to_bytes`] or
+    /// [`TransformerModel::save_to_file`]
+    ///
+    /// Files from before projection biases and the architecture options of
+    /// [`TransformerConfig`] still load, with those options at their defaults (learned
+    /// positions, LayerNorm, GELU, no grouped heads). bincode is not self-describing, so the
+    /// current layout is tried first and the legacy one only if that fails.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        use bincode::Options;
+
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes();
+        options.deserialize(bytes).or_else(|error| {
+            options
+                .deserialize::<legacy::Model>(bytes)
+                .map(Self::from)
+                .map_err(|_| {
+                    CoreError::ModelLoadError(format!("Failed to deserialize model: {}", error))
+                })
+        })
+    }
+}
+
+/// The model file layout before optional biases and the architecture options of
+/// [`TransformerConfig`], kept so that older files still load
+mod legacy {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub(super) struct Model {
+        config: Config,
+        token_embedding: Tensor,
+        position_embedding: Tensor,
+        layers: Vec<Layer>,
+        final_layer_norm: Norm,
+    }
+
+    #[derive(Deserialize)]
+    struct Config {
+        d_model: usize,
+        n_heads: usize,
+        n_layers: usize,
+        d_ff: usize,
+        vocab_size: usize,
+        max_seq_len: usize,
+        dropout: f32,
+        layer_norm_eps: f32,
+    }
+
+    #[derive(Deserialize)]
+    struct Layer {
+        attention: Attention,
+        feed_forward: FeedForward,
+        ln1: Norm,
+        ln2: Norm,
+    }
+
+    #[derive(Deserialize)]
+    struct Attention {
+        wq: Tensor,
+        wk: Tensor,
+        wv: Tensor,
+        wo: Tensor,
+    }
+
+    #[derive(Deserialize)]
+    struct FeedForward {
+        w1: Tensor,
+        w2: Tensor,
+    }
+
+    #[derive(Deserialize)]
+    struct Norm {
+        gamma: Tensor,
+        beta: Tensor,
+    }
+
+    impl From<Norm> for LayerNormWeights {
+        fn from(norm: Norm) -> Self {
+            LayerNormWeights {
+                gamma: norm.gamma,
+                beta: Some(norm.beta),
+            }
+        }
+    }
+
+    impl From<Layer> for TransformerLayerWeights {
+        fn from(layer: Layer) -> Self {
+            let Attention { wq, wk, wv, wo } = layer.attention;
+            let FeedForward { w1, w2 } = layer.feed_forward;
+            TransformerLayerWeights {
+                attention: AttentionWeights {
+                    wq,
+                    wk,
+                    wv,
+                    wo,
+                    bq: None,
+                    bk: None,
+                    bv: None,
+                    bo: None,
+                },
+                feed_forward: super::FeedForward::Dense(FeedForwardWeights {
+                    w1,
+                    w2,
+                    w3: None,
+                    b1: None,
+                    b2: None,
+                    b3: None,
+                }),
+                ln1: layer.ln1.into(),
+                ln2: layer.ln2.into(),
+                cross_attention: None,
+            }
+        }
+    }
 
-        let mut file = File::create(path)?;
-        file.write_all(&encoded)?;
-        Ok(())
+    impl From<Model> for TransformerModel {
+        fn from(model: Model) -> Self {
+            let config = model.config;
+            TransformerModel {
+                config: TransformerConfig {
+                    d_model: config.d_model,
+                    n_heads: config.n_heads,
+                    n_kv_heads: None,
+                    n_layers: config.n_layers,
+                    d_ff: config.d_ff,
+                    vocab_size: config.vocab_size,
+                    max_seq_len: config.max_seq_len,
+                    dropout: config.dropout,
+                    layer_norm_eps: config.layer_norm_eps,
+                    position_encoding: PositionEncoding::Learned,
+                    norm: NormType::LayerNorm,
+                    feed_forward: FeedForwardType::Gelu,
+                    block: BlockTopology::PreLn,
+                    attention_windows: Vec::new(),
+                    n_encoder_layers: 0,
+                    attention_mode: AttentionMode::Causal,
+                    moe: None,
+                    vision: None,
+                },
+                token_embedding: model.token_embedding,
+                position_embedding: model.position_embedding,
+                layers: model.layers.into_iter().map(Into::into).collect(),
+                final_layer_norm: model.final_layer_norm.into(),
+                encoder: None,
+                sequence_classifier: None,
+                token_classifier: None,
+                patch_embedding: None,
+            }
+        }
     }
 }
 
@@ -224,12 +2799,16 @@ impl Serialize for TransformerModel {
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("TransformerModel", 5)?;
+        let mut state = serializer.serialize_struct("TransformerModel", 9)?;
         state.serialize_field("config", &self.config)?;
         state.serialize_field("token_embedding", &self.token_embedding)?;
         state.serialize_field("position_embedding", &self.position_embedding)?;
         state.serialize_field("layers", &self.layers)?;
         state.serialize_field("final_layer_norm", &self.final_layer_norm)?;
+        state.serialize_field("encoder", &self.encoder)?;
+        state.serialize_field("sequence_classifier", &self.sequence_classifier)?;
+        state.serialize_field("token_classifier", &self.token_classifier)?;
+        state.serialize_field("patch_embedding", &self.patch_embedding)?;
         state.end()
     }
 }
@@ -246,6 +2825,10 @@ impl<'de> Deserialize<'de> for TransformerModel {
             position_embedding: Tensor,
             layers: Vec<TransformerLayerWeights>,
             final_layer_norm: LayerNormWeights,
+            encoder: Option<EncoderWeights>,
+            sequence_classifier: Option<SequenceClassifier>,
+            token_classifier: Option<ClassificationHead>,
+            patch_embedding: Option<PatchEmbedding>,
         }
 
         let helper = TransformerModelHelper::deserialize(deserializer)?;
@@ -255,6 +2838,10 @@ impl<'de> Deserialize<'de> for TransformerModel {
             position_embedding: helper.position_embedding,
             layers: helper.layers,
             final_layer_norm: helper.final_layer_norm,
+            encoder: helper.encoder,
+            sequence_classifier: helper.sequence_classifier,
+            token_classifier: helper.token_classifier,
+            patch_embedding: helper.patch_embedding,
         })
     }
 }
@@ -266,11 +2853,15 @@ impl Serialize for AttentionWeights {
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("AttentionWeights", 4)?;
+        let mut state = serializer.serialize_struct("AttentionWeights", 8)?;
         state.serialize_field("wq", &self.wq)?;
         state.serialize_field("wk", &self.wk)?;
         state.serialize_field("wv", &self.wv)?;
         state.serialize_field("wo", &self.wo)?;
+        state.serialize_field("bq", &self.bq)?;
+        state.serialize_field("bk", &self.bk)?;
+        state.serialize_field("bv", &self.bv)?;
+        state.serialize_field("bo", &self.bo)?;
         state.end()
     }
 }
@@ -286,6 +2877,10 @@ impl<'de> Deserialize<'de> for AttentionWeights {
             wk: Tensor,
             wv: Tensor,
             wo: Tensor,
+            bq: Option<Tensor>,
+            bk: Option<Tensor>,
+            bv: Option<Tensor>,
+            bo: Option<Tensor>,
         }
         let helper = Helper::deserialize(deserializer)?;
         Ok(AttentionWeights {
@@ -293,6 +2888,10 @@ impl<'de> Deserialize<'de> for AttentionWeights {
             wk: helper.wk,
             wv: helper.wv,
             wo: helper.wo,
+            bq: helper.bq,
+            bk: helper.bk,
+            bv: helper.bv,
+            bo: helper.bo,
         })
     }
 }
@@ -303,9 +2902,13 @@ impl Serialize for FeedForwardWeights {
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("FeedForwardWeights", 2)?;
+        let mut state = serializer.serialize_struct("FeedForwardWeights", 6)?;
         state.serialize_field("w1", &self.w1)?;
         state.serialize_field("w2", &self.w2)?;
+        state.serialize_field("w3", &self.w3)?;
+        state.serialize_field("b1", &self.b1)?;
+        state.serialize_field("b2", &self.b2)?;
+        state.serialize_field("b3", &self.b3)?;
         state.end()
     }
 }
@@ -319,11 +2922,19 @@ impl<'de> Deserialize<'de> for FeedForwardWeights {
         struct Helper {
             w1: Tensor,
             w2: Tensor,
+            w3: Option<Tensor>,
+            b1: Option<Tensor>,
+            b2: Option<Tensor>,
+            b3: Option<Tensor>,
         }
         let helper = Helper::deserialize(deserializer)?;
         Ok(FeedForwardWeights {
             w1: helper.w1,
             w2: helper.w2,
+            w3: helper.w3,
+            b1: helper.b1,
+            b2: helper.b2,
+            b3: helper.b3,
         })
     }
 }
@@ -349,7 +2960,7 @@ impl<'de> Deserialize<'de> for LayerNormWeights {
         #[derive(Deserialize)]
         struct Helper {
             gamma: Tensor,
-            beta: Tensor,
+            beta: Option<Tensor>,
         }
         let helper = Helper::deserialize(deserializer)?;
         Ok(LayerNormWeights {
@@ -365,11 +2976,12 @@ impl Serialize for TransformerLayerWeights {
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("TransformerLayerWeights", 4)?;
+        let mut state = serializer.serialize_struct("TransformerLayerWeights", 5)?;
         state.serialize_field("attention", &self.attention)?;
         state.serialize_field("feed_forward", &self.feed_forward)?;
         state.serialize_field("ln1", &self.ln1)?;
         state.serialize_field("ln2", &self.ln2)?;
+        state.serialize_field("cross_attention", &self.cross_attention)?;
         state.end()
     }
 }
@@ -382,9 +2994,10 @@ impl<'de> Deserialize<'de> for TransformerLayerWeights {
         #[derive(Deserialize)]
         struct Helper {
             attention: AttentionWeights,
-            feed_forward: FeedForwardWeights,
+            feed_forward: FeedForward,
             ln1: LayerNormWeights,
             ln2: LayerNormWeights,
+            cross_attention: Option<CrossAttentionWeights>,
         }
         let helper = Helper::deserialize(deserializer)?;
         Ok(TransformerLayerWeights {
@@ -392,6 +3005,97 @@ impl<'de> Deserialize<'de> for TransformerLayerWeights {
             feed_forward: helper.feed_forward,
             ln1: helper.ln1,
             ln2: helper.ln2,
+            cross_attention: helper.cross_attention,
+        })
+    }
+}
+
+impl Serialize for MoeWeights {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("MoeWeights", 2)?;
+        state.serialize_field("router", &self.router)?;
+        state.serialize_field("experts", &self.experts)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for MoeWeights {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Helper {
+            router: Tensor,
+            experts: Vec<FeedForwardWeights>,
+        }
+        let helper = Helper::deserialize(deserializer)?;
+        Ok(MoeWeights::new(helper.router, helper.experts))
+    }
+}
+
+impl Serialize for CrossAttentionWeights {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("CrossAttentionWeights", 2)?;
+        state.serialize_field("attention", &self.attention)?;
+        state.serialize_field("norm", &self.norm)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for CrossAttentionWeights {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Helper {
+            attention: AttentionWeights,
+            norm: LayerNormWeights,
+        }
+        let helper = Helper::deserialize(deserializer)?;
+        Ok(CrossAttentionWeights {
+            attention: helper.attention,
+            norm: helper.norm,
+        })
+    }
+}
+
+impl Serialize for EncoderWeights {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("EncoderWeights", 2)?;
+        state.serialize_field("layers", &self.layers)?;
+        state.serialize_field("final_layer_norm", &self.final_layer_norm)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for EncoderWeights {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Helper {
+            layers: Vec<TransformerLayerWeights>,
+            final_layer_norm: LayerNormWeights,
+        }
+        let helper = Helper::deserialize(deserializer)?;
+        Ok(EncoderWeights {
+            layers: helper.layers,
+            final_layer_norm: helper.final_layer_norm,
         })
     }
 }
@@ -399,6 +3103,10 @@ impl<'de> Deserialize<'de> for TransformerLayerWeights {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tensor::DType;
+    use crate::test_utils::{
+        assert_close, random_layer, random_model, random_tensor, reference_device, small_config,
+    };
 
     #[test]
     fn test_config_creation() {
@@ -415,4 +3123,1063 @@ mod tests {
         // The estimate is around 90MB, so check a wider range
         assert!(size > 80_000_000 && size < 400_000_000, "Size was {}", size);
     }
+
+    #[test]
+    fn test_forward_cpu_zero_projections_is_identity() {
+        let config = small_config();
+        let mut weights = random_layer(&config, 1);
+        weights.attention.wo = Tensor::new(vec![8, 8], DType::F32);
+        weights.feed_forward.dense_mut().unwrap().w2 = Tensor::new(vec![16, 8], DType::F32);
+        let layer = TransformerLayer::new(config, weights);
+
+        let input = random_tensor(vec![3, 8], 42);
+        let output = layer.forward_cpu(&input).unwrap();
+        assert_eq!(output.shape, input.shape);
+        assert_eq!(
+            output.as_f32_slice().unwrap(),
+            input.as_f32_slice().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_forward_cpu_is_causal() {
+        let config = small_config();
+        let layer = TransformerLayer::new(config.clone(), random_layer(&config, 3));
+
+        let input = random_tensor(vec![1, 4, 8], 7);
+        let mut changed = input.clone();
+        changed.as_f32_slice_mut().unwrap()[3 * 8] += 1.0;
+
+        let a = layer.forward_cpu(&input).unwrap();
+        let b = layer.forward_cpu(&changed).unwrap();
+        let (a, b) = (a.as_f32_slice().unwrap(), b.as_f32_slice().unwrap());
+        // Perturbing the last position must not affect earlier positions
+        assert_eq!(&a[..3 * 8], &b[..3 * 8]);
+        assert_ne!(&a[3 * 8..], &b[3 * 8..]);
+    }
+
+    #[test]
+    fn test_forward_cpu_rejects_wrong_width() {
+        let config = small_config();
+        let layer = TransformerLayer::new(config.clone(), random_layer(&config, 5));
+        let input = Tensor::new(vec![2, 4], DType::F32);
+        assert!(layer.forward_cpu(&input).is_err());
+    }
+
+    #[test]
+    fn test_model_forward_cpu_logits_shape() {
+        let model = random_model(small_config(), 9);
+        let logits = model.forward_cpu(&[vec![1, 2, 3], vec![4, 5, 6]]).unwrap();
+        assert_eq!(logits.shape, vec![2, 3, 11]);
+
+        // Logits for a prefix do not depend on later tokens
+        let prefix = model.forward_cpu(&[vec![1, 2]]).unwrap();
+        let prefix = prefix.as_f32_slice().unwrap();
+        assert_eq!(prefix, &logits.as_f32_slice().unwrap()[..2 * 11]);
+    }
+
+    #[test]
+    fn test_model_forward_rejects_bad_tokens() {
+        let model = random_model(small_config(), 9);
+        assert!(model.forward_cpu(&[vec![1, 2], vec![3]]).is_err());
+        assert!(model.forward_cpu(&[vec![11]]).is_err());
+        assert!(model.forward_cpu(&[vec![0; 17]]).is_err());
+    }
+
+    #[test]
+    fn test_model_forward_rejects_short_embedding_tables() {
+        let mut short_tokens = random_model(small_config(), 9);
+        short_tokens.token_embedding = random_tensor(vec![4, 8], 1);
+        let bytes = short_tokens.to_bytes().unwrap();
+        let short_tokens = TransformerModel::from_bytes(&bytes).unwrap();
+        assert!(matches!(
+            short_tokens.forward_cpu(&[vec![1, 10]]),
+            Err(CoreError::ShapeMismatch { .. })
+        ));
+
+        let mut short_positions = random_model(small_config(), 9);
+        short_positions.position_embedding = random_tensor(vec![4, 8], 1);
+        assert!(matches!(
+            short_positions.forward_cpu(&[vec![1; 6]]),
+            Err(CoreError::ShapeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_padded_batch_matches_single_sequences() {
+        use crate::batch::PaddingSide;
+
+        let model = random_model(small_config(), 13);
+        let sequences = vec![vec![3, 1, 4, 1, 5], vec![9, 2], vec![6, 5, 3]];
+        for side in [PaddingSide::Left, PaddingSide::Right] {
+            let batch = PaddedBatch::new(&sequences, 0, side).unwrap();
+            let logits = model.forward_batch_cpu(&batch).unwrap();
+            assert_eq!(logits.shape, vec![3, 5, 11]);
+            let logits = logits.as_f32_slice().unwrap();
+
+            for (b, seq) in sequences.iter().enumerate() {
+                let single = model.forward_cpu(std::slice::from_ref(seq)).unwrap();
+                let start = match side {
+                    PaddingSide::Left => 5 - seq.len(),
+                    PaddingSide::Right => 0,
+                };
+                let rows = &logits[(b * 5 + start) * 11..(b * 5 + start + seq.len()) * 11];
+                assert_close(rows, single.as_f32_slice().unwrap(), 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_batched_caches_match_single_sequences() {
+        let model = random_model(small_config(), 17);
+        let (a, b) = (vec![2, 7, 1, 8], vec![3, 1]);
+
+        // Sequence a is already prefilled; b joins with its whole prompt in the same pass
+        let mut cache_a = model.new_kv_cache();
+        model.forward_cpu_with_cache(&a[..3], &mut cache_a).unwrap();
+        let mut cache_b = model.new_kv_cache();
+        let logits = model
+            .forward_cpu_with_caches(
+                &[a[3..].to_vec(), b.clone()],
+                &mut [&mut cache_a, &mut cache_b],
+            )
+            .unwrap();
+        assert_eq!(logits.shape, vec![2, 2, 11]);
+        assert_eq!((cache_a.len(), cache_b.len()), (4, 2));
+        let logits = logits.as_f32_slice().unwrap();
+
+        let full_a = model.forward_cpu(&[a]).unwrap();
+        let full_b = model.forward_cpu(&[b]).unwrap();
+        let expected = [
+            &full_a.as_f32_slice().unwrap()[3 * 11..],
+            full_b.as_f32_slice().unwrap(),
+        ];
+        // Row 0 of sequence a is padding; compare only real rows
+        for (actual, expected) in [(&logits[11..22], expected[0]), (&logits[22..], expected[1])] {
+            assert_close(actual, expected, 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_paged_caches_match_contiguous_caches() {
+        let model = random_model(small_config(), 19);
+        let mut paged = model.new_paged_kv_cache(2, 16).unwrap();
+        let (mut table_a, mut table_b) = (paged.new_sequence(), paged.new_sequence());
+        let mut cache_a = model.new_kv_cache();
+        let mut cache_b = model.new_kv_cache();
+
+        let steps: [[Vec<u32>; 2]; 3] = [
+            [vec![2, 7, 1], vec![3]],
+            [vec![8], vec![1, 4]],
+            [vec![5], vec![9]],
+        ];
+        for (i, tokens) in steps.iter().enumerate() {
+            if i == 2 {
+                // Fork b mid-block: its next write must copy the shared block
+                let fork = paged.fork(&table_b);
+                paged.free(std::mem::replace(&mut table_a, fork)).unwrap();
+                cache_a = cache_b.clone();
+            }
+            let expected = model
+                .forward_cpu_with_caches(tokens, &mut [&mut cache_a, &mut cache_b])
+                .unwrap();
+            let actual = model
+                .forward_cpu_paged(tokens, &mut [&mut table_a, &mut table_b], &mut paged)
+                .unwrap();
+            assert_eq!(actual.shape, expected.shape);
+            let max_new = expected.shape[1];
+            for (b, seq) in tokens.iter().enumerate() {
+                let real = (b * max_new + max_new - seq.len()) * 11..(b + 1) * max_new * 11;
+                let (x, y) = (
+                    actual.as_f32_slice().unwrap(),
+                    expected.as_f32_slice().unwrap(),
+                );
+                assert_close(&x[real.clone()], &y[real], 1e-5);
+            }
+        }
+        assert_eq!((table_a.len(), table_b.len()), (4, 4));
+        assert_eq!(table_a.blocks()[0], table_b.blocks()[0]);
+        assert_ne!(table_a.blocks()[1], table_b.blocks()[1]);
+
+        // Out of blocks: no table is extended
+        let mut small = model.new_paged_kv_cache(2, 2).unwrap();
+        let (mut x, mut y) = (small.new_sequence(), small.new_sequence());
+        let result = model.forward_cpu_paged(
+            &[vec![1, 2], vec![3, 4, 5]],
+            &mut [&mut x, &mut y],
+            &mut small,
+        );
+        assert!(result.is_err());
+        assert_eq!((x.len(), small.allocator().num_free()), (0, 2));
+    }
+
+    #[test]
+    fn test_cached_decoding_matches_full_forward() {
+        let model = random_model(small_config(), 21);
+        let tokens = vec![3, 1, 4, 1, 5, 9];
+        let full = model.forward_cpu(std::slice::from_ref(&tokens)).unwrap();
+        let full = full.as_f32_slice().unwrap();
+
+        // Prefill two tokens, then decode the rest one at a time
+        let mut cache = model.new_kv_cache();
+        let mut incremental = model
+            .forward_cpu_with_cache(&tokens[..2], &mut cache)
+            .unwrap()
+            .as_f32_slice()
+            .unwrap()
+            .to_vec();
+        for &token in &tokens[2..] {
+            let logits = model.forward_cpu_with_cache(&[token], &mut cache).unwrap();
+            assert_eq!(logits.shape, vec![1, 1, 11]);
+            incremental.extend_from_slice(logits.as_f32_slice().unwrap());
+        }
+        assert_eq!(cache.len(), tokens.len());
+        assert_close(full, &incremental, 1e-5);
+
+        // Rewinding the cache replays the same continuation
+        cache.truncate(3);
+        let replay = model
+            .forward_cpu_with_cache(&tokens[3..4], &mut cache)
+            .unwrap();
+        assert_eq!(replay.as_f32_slice().unwrap(), &incremental[3 * 11..4 * 11]);
+    }
+
+    #[test]
+    fn test_failed_cached_step_leaves_cache_unchanged() {
+        let model = random_model(small_config(), 21);
+        // The second layer fails after the first has appended the step's keys and values
+        let mut broken = random_model(small_config(), 21);
+        broken.layers[1].attention.wq = random_tensor(vec![3, 3], 1);
+        let device = reference_device();
+        let lens = |cache: &KvCache| -> Vec<usize> {
+            cache.layers().iter().map(LayerKvCache::len).collect()
+        };
+
+        let mut cache = model.new_kv_cache();
+        model
+            .forward_with_cache(&[3, 1], &mut cache, &device)
+            .unwrap();
+        assert!(broken
+            .forward_with_cache(&[4], &mut cache, &device)
+            .is_err());
+        assert!(broken.forward_cpu_with_cache(&[4], &mut cache).is_err());
+        assert!(broken
+            .forward_with_caches(&[vec![4]], &mut [&mut cache], &device)
+            .is_err());
+        assert_eq!(lens(&cache), vec![2, 2]);
+
+        let retried = model.forward_with_cache(&[4], &mut cache, &device).unwrap();
+        let mut fresh = model.new_kv_cache();
+        let expected = model
+            .forward_with_cache(&[3, 1, 4], &mut fresh, &device)
+            .unwrap();
+        assert_close(
+            retried.as_f32_slice().unwrap(),
+            &expected.as_f32_slice().unwrap()[2 * 11..],
+            1e-5,
+        );
+        assert_eq!(lens(&cache), lens(&fresh));
+
+        // Paged tables give back the blocks reserved for the failed step
+        let mut paged = model.new_paged_kv_cache(2, 4).unwrap();
+        let mut table = paged.new_sequence();
+        model
+            .forward_paged(&[vec![3, 1]], &mut [&mut table], &mut paged, &device)
+            .unwrap();
+        assert!(broken
+            .forward_paged(&[vec![4]], &mut [&mut table], &mut paged, &device)
+            .is_err());
+        assert_eq!((table.len(), paged.allocator().num_free()), (2, 3));
+    }
+
+    #[test]
+    fn test_rope_model_positions() {
+        let config = TransformerConfig {
+            position_encoding: PositionEncoding::Rope {
+                base: 10000.0,
+                scaling: Some(RopeScaling::Ntk { factor: 2.0 }),
+            },
+            ..small_config()
+        };
+        let mut model = random_model(config, 23);
+        let tokens = vec![5, 3, 9, 2];
+        let full = model.forward_cpu(std::slice::from_ref(&tokens)).unwrap();
+
+        // The learned table is unused
+        model.position_embedding = Tensor::from_f32(vec![0, 8], Vec::new()).unwrap();
+        let without_table = model.forward_cpu(std::slice::from_ref(&tokens)).unwrap();
+        assert_eq!(
+            full.as_f32_slice().unwrap(),
+            without_table.as_f32_slice().unwrap()
+        );
+
+        // Cached decoding rotates new keys at their absolute positions
+        let mut cache = model.new_kv_cache();
+        model
+            .forward_cpu_with_cache(&tokens[..3], &mut cache)
+            .unwrap();
+        let last = model
+            .forward_cpu_with_cache(&tokens[3..], &mut cache)
+            .unwrap();
+        let full = full.as_f32_slice().unwrap();
+        assert_close(last.as_f32_slice().unwrap(), &full[3 * 11..], 1e-5);
+
+        // Left padding does not shift the positions of real tokens
+        let batch =
+            PaddedBatch::new(&[vec![1, 1, 1, 1, 1], tokens.clone()], 0, PaddingSide::Left).unwrap();
+        let logits = model.forward_batch_cpu(&batch).unwrap();
+        let padded = &logits.as_f32_slice().unwrap()[(5 + 1) * 11..];
+        assert_close(padded, full, 1e-5);
+    }
+
+    #[test]
+    fn test_alibi_model_positions() {
+        let config = TransformerConfig {
+            position_encoding: PositionEncoding::Alibi,
+            ..small_config()
+        };
+        let mut model = random_model(config, 29);
+        let tokens = vec![4, 8, 1, 6];
+        let full = model.forward_cpu(std::slice::from_ref(&tokens)).unwrap();
+
+        // No table: positions only enter through the attention biases
+        model.position_embedding = Tensor::from_f32(vec![0, 8], Vec::new()).unwrap();
+        let without_table = model.forward_cpu(std::slice::from_ref(&tokens)).unwrap();
+        assert_eq!(
+            full.as_f32_slice().unwrap(),
+            without_table.as_f32_slice().unwrap()
+        );
+        model.config.position_encoding = PositionEncoding::None;
+        let unbiased = model.forward_cpu(std::slice::from_ref(&tokens)).unwrap();
+        model.config.position_encoding = PositionEncoding::Alibi;
+        assert_ne!(
+            full.as_f32_slice().unwrap(),
+            unbiased.as_f32_slice().unwrap()
+        );
+
+        // Cached keys keep their distances to later queries
+        let mut cache = model.new_kv_cache();
+        model
+            .forward_cpu_with_cache(&tokens[..2], &mut cache)
+            .unwrap();
+        let last = model
+            .forward_cpu_with_cache(&tokens[2..], &mut cache)
+            .unwrap();
+        let full = full.as_f32_slice().unwrap();
+        assert_close(last.as_f32_slice().unwrap(), &full[2 * 11..], 1e-5);
+
+        // Right padding leaves the distances between real tokens unchanged
+        let batch = PaddedBatch::new(
+            &[tokens.clone(), vec![1, 2, 3, 4, 5]],
+            0,
+            PaddingSide::Right,
+        )
+        .unwrap();
+        let logits = model.forward_batch_cpu(&batch).unwrap();
+        let padded = &logits.as_f32_slice().unwrap()[..4 * 11];
+        assert_close(padded, full, 1e-5);
+    }
+
+    #[test]
+    fn test_llama_style_model() {
+        let config = TransformerConfig {
+            position_encoding: PositionEncoding::rope(10000.0),
+            norm: NormType::RmsNorm,
+            feed_forward: FeedForwardType::SwiGlu,
+            ..small_config()
+        };
+        let mut model = random_model(config, 31);
+        assert!(model.final_layer_norm.beta.is_none());
+        let tokens = vec![vec![2, 7, 1], vec![9, 4, 4]];
+        let reference = model.forward_cpu(&tokens).unwrap();
+        let logits = model.forward(&tokens, &reference_device()).unwrap();
+        assert_close(
+            logits.as_f32_slice().unwrap(),
+            reference.as_f32_slice().unwrap(),
+            1e-5,
+        );
+
+        // The norm type, FFN type and the extra projection survive a round trip
+        let restored: TransformerModel =
+            bincode::deserialize(&bincode::serialize(&model).unwrap()).unwrap();
+        assert_eq!(restored.config.norm, NormType::RmsNorm);
+        assert_eq!(restored.config.feed_forward, FeedForwardType::SwiGlu);
+        assert_eq!(
+            restored
+                .forward_cpu(&tokens)
+                .unwrap()
+                .as_f32_slice()
+                .unwrap(),
+            reference.as_f32_slice().unwrap()
+        );
+
+        model.layers[1].feed_forward.dense_mut().unwrap().w3 = None;
+        assert!(model.forward_cpu(&tokens).is_err());
+    }
+
+    #[test]
+    fn test_multi_query_model() {
+        let config = TransformerConfig {
+            n_kv_heads: Some(1),
+            position_encoding: PositionEncoding::rope(10000.0),
+            ..small_config()
+        };
+        let model = random_model(config, 37);
+        assert_eq!(model.layers[0].attention.wk.shape, vec![8, 4]);
+        let tokens = vec![6, 2, 9, 3];
+        let full = model.forward_cpu(std::slice::from_ref(&tokens)).unwrap();
+        let full = full.as_f32_slice().unwrap();
+        let gpu = model
+            .forward(std::slice::from_ref(&tokens), &reference_device())
+            .unwrap();
+        assert_close(gpu.as_f32_slice().unwrap(), full, 1e-5);
+
+        // Caches only store the single shared key/value head
+        let mut cache = model.new_kv_cache();
+        model
+            .forward_cpu_with_cache(&tokens[..3], &mut cache)
+            .unwrap();
+        assert_eq!(cache.layers()[0].width(), 4);
+        assert_eq!(cache.memory_bytes(), 2 * 2 * 3 * 4 * 4);
+        let last = model
+            .forward_cpu_with_cache(&tokens[3..], &mut cache)
+            .unwrap();
+        assert_close(last.as_f32_slice().unwrap(), &full[3 * 11..], 1e-5);
+
+        let mut paged = model.new_paged_kv_cache(2, 4).unwrap();
+        let mut table = paged.new_sequence();
+        let logits = model
+            .forward_cpu_paged(std::slice::from_ref(&tokens), &mut [&mut table], &mut paged)
+            .unwrap();
+        assert_close(logits.as_f32_slice().unwrap(), full, 1e-5);
+    }
+
+    #[test]
+    fn test_projection_biases() {
+        let mut model = random_model(small_config(), 41);
+        let tokens = vec![vec![3, 8, 2], vec![7, 7, 1]];
+        let unbiased = model.forward_cpu(&tokens).unwrap();
+        for (i, layer) in model.layers.iter_mut().enumerate() {
+            let seed = 500 + 10 * i as u64;
+            let attention = &mut layer.attention;
+            attention.bq = Some(random_tensor(vec![8], seed));
+            attention.bk = Some(random_tensor(vec![8], seed + 1));
+            attention.bv = Some(random_tensor(vec![8], seed + 2));
+            attention.bo = Some(random_tensor(vec![8], seed + 3));
+            let feed_forward = layer.feed_forward.dense_mut().unwrap();
+            feed_forward.b1 = Some(random_tensor(vec![16], seed + 4));
+            feed_forward.b2 = Some(random_tensor(vec![8], seed + 5));
+        }
+        let reference = model.forward_cpu(&tokens).unwrap();
+        assert_ne!(
+            reference.as_f32_slice().unwrap(),
+            unbiased.as_f32_slice().unwrap()
+        );
+        let logits = model.forward(&tokens, &reference_device()).unwrap();
+        assert_close(
+            logits.as_f32_slice().unwrap(),
+            reference.as_f32_slice().unwrap(),
+            1e-5,
+        );
+
+        let restored = TransformerModel::from_bytes(&model.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            restored
+                .forward_cpu(&tokens)
+                .unwrap()
+                .as_f32_slice()
+                .unwrap(),
+            reference.as_f32_slice().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_block_topologies() {
+        let tokens = vec![vec![3, 8, 2, 5], vec![7, 7, 1, 4]];
+        let mut outputs = Vec::new();
+        for block in [
+            BlockTopology::PreLn,
+            BlockTopology::PostLn,
+            BlockTopology::Parallel,
+        ] {
+            let model = random_model(
+                TransformerConfig {
+                    block,
+                    ..small_config()
+                },
+                43,
+            );
+            let reference = model.forward_cpu(&tokens).unwrap();
+            let logits = model.forward(&tokens, &reference_device()).unwrap();
+            assert_close(
+                logits.as_f32_slice().unwrap(),
+                reference.as_f32_slice().unwrap(),
+                1e-5,
+            );
+
+            let mut cache = model.new_kv_cache();
+            model
+                .forward_cpu_with_cache(&tokens[0][..3], &mut cache)
+                .unwrap();
+            let step = model
+                .forward_cpu_with_cache(&tokens[0][3..], &mut cache)
+                .unwrap();
+            let full = &reference.as_f32_slice().unwrap()[3 * 11..4 * 11];
+            assert_close(step.as_f32_slice().unwrap(), full, 1e-5);
+            outputs.push(reference);
+        }
+        assert_ne!(
+            outputs[0].as_f32_slice().unwrap(),
+            outputs[1].as_f32_slice().unwrap()
+        );
+        assert_ne!(
+            outputs[0].as_f32_slice().unwrap(),
+            outputs[2].as_f32_slice().unwrap()
+        );
+        assert_ne!(
+            outputs[1].as_f32_slice().unwrap(),
+            outputs[2].as_f32_slice().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sliding_window_model() {
+        // Local layer 0 sees the last three positions, layer 1 attends globally
+        let config = TransformerConfig {
+            attention_windows: vec![Some(3), None],
+            ..small_config()
+        };
+        let model = random_model(config, 47);
+        let tokens = vec![vec![3, 8, 2, 5, 9, 1, 0, 4, 6, 7]];
+        let reference = model.forward_cpu(&tokens).unwrap();
+        let full = reference.as_f32_slice().unwrap();
+
+        let mut global = random_model(small_config(), 47);
+        assert_ne!(
+            global.forward_cpu(&tokens).unwrap().as_f32_slice().unwrap(),
+            full
+        );
+        global.config.attention_windows = vec![Some(3), None];
+        assert_eq!(
+            global.forward_cpu(&tokens).unwrap().as_f32_slice().unwrap(),
+            full
+        );
+
+        let logits = model.forward(&tokens, &reference_device()).unwrap();
+        assert_close(logits.as_f32_slice().unwrap(), full, 1e-5);
+
+        // Decoding one token at a time rolls the local layer's cache over its window
+        let mut cache = model.new_kv_cache();
+        model
+            .forward_with_cache(&tokens[0][..4], &mut cache, &reference_device())
+            .unwrap();
+        for (i, &token) in tokens[0].iter().enumerate().skip(4) {
+            let step = model.forward_cpu_with_cache(&[token], &mut cache).unwrap();
+            assert_close(
+                step.as_f32_slice().unwrap(),
+                &full[i * 11..(i + 1) * 11],
+                1e-5,
+            );
+            assert_eq!(cache.layers()[0].stored_len(), 3);
+            assert_eq!(cache.layers()[1].stored_len(), i + 1);
+        }
+
+        // Paged caches would keep the local layer's full history
+        assert!(model.new_paged_kv_cache(2, 8).is_err());
+    }
+
+    #[test]
+    fn test_encoder_decoder_model() {
+        let config = TransformerConfig {
+            n_encoder_layers: 2,
+            ..small_config()
+        };
+        let model = random_model(config, 53);
+        let (source, target) = ([4u32, 9, 1, 7, 2], [0u32, 6, 3, 10]);
+        assert!(model.forward_cpu(&[target.to_vec()]).is_err());
+
+        // Full-target decode from the encoded input is the reference
+        let mut cache = model.encode_cpu(&source).unwrap();
+        let reference = model.forward_cpu_with_cache(&target, &mut cache).unwrap();
+        let full = reference.as_f32_slice().unwrap();
+        assert_eq!(reference.shape, vec![1, 4, 11]);
+
+        // The encoder is bidirectional: changing the last input token moves the first row
+        let cross_keys = |cache: &KvCache| cache.layers()[0].cross_attention().unwrap().0.clone();
+        let changed = model.encode_cpu(&[4, 9, 1, 7, 3]).unwrap();
+        assert_ne!(
+            cross_keys(&cache).as_f32_slice().unwrap()[..8],
+            cross_keys(&changed).as_f32_slice().unwrap()[..8]
+        );
+
+        // Device path and one-token-at-a-time decoding reuse the cached encoder keys/values
+        let device = reference_device();
+        let mut cache = model.encode(&source, &device).unwrap();
+        let logits = model
+            .forward_with_cache(&target, &mut cache, &device)
+            .unwrap();
+        assert_close(logits.as_f32_slice().unwrap(), full, 1e-5);
+        cache.reset();
+        for (i, &token) in target.iter().enumerate() {
+            let step = model.forward_cpu_with_cache(&[token], &mut cache).unwrap();
+            assert_close(
+                step.as_f32_slice().unwrap(),
+                &full[i * 11..(i + 1) * 11],
+                1e-5,
+            );
+        }
+
+        // Batched decoding masks the padding of shorter encoded inputs
+        let other = model.encode_cpu(&source[..2]).unwrap();
+        let single = model
+            .forward_cpu_with_cache(&target[..2], &mut other.clone())
+            .unwrap();
+        let mut caches = [model.encode_cpu(&source).unwrap(), other];
+        let [first, second] = &mut caches;
+        let batched = model
+            .forward_cpu_with_caches(
+                &[target.to_vec(), target[..2].to_vec()],
+                &mut [first, second],
+            )
+            .unwrap();
+        let batched = batched.as_f32_slice().unwrap();
+        assert_close(&batched[..44], full, 1e-5);
+        assert_close(&batched[66..], single.as_f32_slice().unwrap(), 1e-5);
+
+        // Encoder and cross-attention weights survive serialization
+        let restored = TransformerModel::from_bytes(&model.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.config.n_encoder_layers, 2);
+        let mut cache = restored.encode_cpu(&source).unwrap();
+        let logits = restored
+            .forward_cpu_with_cache(&target, &mut cache)
+            .unwrap();
+        assert_eq!(logits.as_f32_slice().unwrap(), full);
+
+        // Input embeddings are used as-is, without token or position lookup
+        let (tokens, positions) = (
+            model.token_embedding.as_f32_slice().unwrap(),
+            model.position_embedding.as_f32_slice().unwrap(),
+        );
+        let embeddings = tokens[..40]
+            .iter()
+            .zip(&positions[..40])
+            .map(|(t, p)| t + p)
+            .collect();
+        let embeddings = Tensor::from_f32(vec![5, 8], embeddings).unwrap();
+        let mut cache = model.encode_embeddings(&embeddings, &device).unwrap();
+        let mut tokens = model.encode_cpu(&[0, 1, 2, 3, 4]).unwrap();
+        assert_eq!(
+            model
+                .forward_cpu_with_cache(&target, &mut cache)
+                .unwrap()
+                .as_f32_slice()
+                .unwrap(),
+            model
+                .forward_cpu_with_cache(&target, &mut tokens)
+                .unwrap()
+                .as_f32_slice()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_bidirectional_sentence_embeddings() {
+        let config = TransformerConfig {
+            attention_mode: AttentionMode::Bidirectional,
+            ..small_config()
+        };
+        let model = random_model(config, 59);
+        let cls = |model: &TransformerModel, tokens: Vec<u32>| {
+            model
+                .sentence_embeddings_cpu(&[tokens], Pooling::Cls, false)
+                .unwrap()
+                .as_f32_slice()
+                .unwrap()
+                .to_vec()
+        };
+        // The first token sees later ones only with bidirectional attention
+        assert_ne!(cls(&model, vec![3, 8, 2]), cls(&model, vec![3, 8, 4]));
+        let causal = random_model(small_config(), 59);
+        assert_eq!(cls(&causal, vec![3, 8, 2]), cls(&causal, vec![3, 8, 4]));
+        assert!(model
+            .forward_cpu_with_cache(&[3, 8], &mut model.new_kv_cache())
+            .is_err());
+
+        let batch = vec![vec![3, 8, 2, 5, 9], vec![1, 7]];
+        let device = reference_device();
+        for pooling in [Pooling::Cls, Pooling::Mean, Pooling::Max] {
+            let pooled = model
+                .sentence_embeddings_cpu(&batch, pooling, true)
+                .unwrap();
+            assert_eq!(pooled.shape, vec![2, 8]);
+            let pooled = pooled.as_f32_slice().unwrap();
+            let on_device = model
+                .sentence_embeddings(&batch, pooling, true, &device)
+                .unwrap();
+            assert_close(on_device.as_f32_slice().unwrap(), pooled, 1e-5);
+
+            // Padding neither reaches the real tokens nor the pooled vector
+            for (row, seq) in pooled.chunks(8).zip(&batch) {
+                let single = model
+                    .sentence_embeddings_cpu(std::slice::from_ref(seq), pooling, true)
+                    .unwrap();
+                assert_close(row, single.as_f32_slice().unwrap(), 1e-5);
+                let norm = row.iter().map(|x| x * x).sum::<f32>().sqrt();
+                assert!((norm - 1.0).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_classification_heads() {
+        let config = TransformerConfig {
+            attention_mode: AttentionMode::Bidirectional,
+            ..small_config()
+        };
+        let head = |labels: &[&str], seed| ClassificationHead {
+            weight: random_tensor(vec![8, labels.len()], seed),
+            bias: Some(random_tensor(vec![labels.len()], seed + 1)),
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+        };
+        let model = random_model(config, 61);
+        let batch = vec![vec![3, 8, 2, 5, 9], vec![1, 7]];
+        assert!(model.classify_sequences_cpu(&batch).is_err());
+        assert!(model.classify_tokens_cpu(&batch).is_err());
+        // A head without labels is an error, not a panic in the projection
+        let model = model.with_token_classifier(head(&[], 75));
+        assert!(model.classify_tokens_cpu(&batch).is_err());
+        assert!(model.classify_tokens(&batch, &reference_device()).is_err());
+
+        let model = model
+            .with_sequence_classifier(SequenceClassifier {
+                pooling: Pooling::Mean,
+                head: head(&["negative", "positive"], 71),
+            })
+            .with_token_classifier(head(&["O", "B-PER", "I-PER", "B-LOC"], 73));
+        let device = reference_device();
+
+        let sequences = model.classify_sequences_cpu(&batch).unwrap();
+        assert_eq!(sequences.len(), 2);
+        // The head applies to the same pooled vectors as sentence_embeddings
+        let pooled = model
+            .sentence_embeddings_cpu(&batch, Pooling::Mean, false)
+            .unwrap();
+        let expected = model
+            .sequence_classifier
+            .as_ref()
+            .unwrap()
+            .head
+            .logits(&pooled)
+            .unwrap();
+        for (prediction, logits) in sequences
+            .iter()
+            .zip(expected.as_f32_slice().unwrap().chunks(2))
+        {
+            let best = if logits[1] > logits[0] { 1 } else { 0 };
+            assert_eq!(prediction.label_id, best);
+            assert_eq!(prediction.label, ["negative", "positive"][best]);
+            assert!((prediction.scores.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+        for (a, b) in model
+            .classify_sequences(&batch, &device)
+            .unwrap()
+            .iter()
+            .zip(&sequences)
+        {
+            assert_eq!(a.label_id, b.label_id);
+            assert!((a.score - b.score).abs() < 1e-5);
+        }
+
+        // One prediction per real token; padding does not change the shorter sequence's tags
+        let tokens = model.classify_tokens_cpu(&batch).unwrap();
+        assert_eq!((tokens[0].len(), tokens[1].len()), (5, 2));
+        let single = model.classify_tokens_cpu(&batch[1..]).unwrap();
+        for (a, b) in tokens[1].iter().zip(&single[0]) {
+            assert_eq!(a.label_id, b.label_id);
+            assert!((a.score - b.score).abs() < 1e-5);
+        }
+        let on_device = model.classify_tokens(&batch, &device).unwrap();
+        for (a, b) in on_device.iter().flatten().zip(tokens.iter().flatten()) {
+            assert_eq!(a.label, b.label);
+            assert!((a.score - b.score).abs() < 1e-5);
+        }
+
+        // Heads and their labels are saved with the model
+        let restored = TransformerModel::from_bytes(&model.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.classify_sequences_cpu(&batch).unwrap(), sequences);
+        assert_eq!(restored.classify_tokens_cpu(&batch).unwrap(), tokens);
+    }
+
+    #[test]
+    fn test_mixture_of_experts_model() {
+        let config = TransformerConfig {
+            feed_forward: FeedForwardType::SwiGlu,
+            moe: Some(MoeConfig {
+                n_experts: 4,
+                top_k: 2,
+            }),
+            ..small_config()
+        };
+        let model = random_model(config.clone(), 67);
+        let tokens = vec![vec![3, 8, 2, 5, 9, 1]];
+        let reference = model.forward_cpu(&tokens).unwrap();
+        let full = reference.as_f32_slice().unwrap();
+        for (layer, stats) in model.router_stats() {
+            assert!(layer < 2);
+            assert_eq!(stats.tokens_per_expert().iter().sum::<u64>(), 6 * 2);
+            assert!((stats.load().iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        }
+
+        let device = reference_device();
+        let logits = model.forward(&tokens, &device).unwrap();
+        assert_close(logits.as_f32_slice().unwrap(), full, 1e-5);
+        let mut cache = model.new_kv_cache();
+        model
+            .forward_cpu_with_cache(&tokens[0][..4], &mut cache)
+            .unwrap();
+        let step = model
+            .forward_cpu_with_cache(&tokens[0][4..], &mut cache)
+            .unwrap();
+        assert_close(step.as_f32_slice().unwrap(), &full[4 * 11..], 1e-5);
+
+        // Padding is neither routed nor counted
+        model.reset_router_stats();
+        let batch = PaddedBatch::new(&[vec![3, 8, 2], vec![5]], 0, PaddingSide::Left).unwrap();
+        model.forward_batch_cpu(&batch).unwrap();
+        assert_eq!(
+            model.router_stats()[0]
+                .1
+                .tokens_per_expert()
+                .iter()
+                .sum::<u64>(),
+            4 * 2
+        );
+
+        // Only the selected experts run: with tied router scores the two lowest experts win,
+        // so the others may even hold NaNs
+        let mut skipped = random_model(config.clone(), 67);
+        for layer in &mut skipped.layers {
+            let moe = layer.feed_forward.moe_mut().unwrap();
+            let column = random_tensor(vec![8], 5);
+            let router = column
+                .as_f32_slice()
+                .unwrap()
+                .iter()
+                .flat_map(|&w| [w; 4])
+                .collect();
+            moe.router = Tensor::from_f32(vec![8, 4], router).unwrap();
+            for expert in &mut moe.experts[2..] {
+                expert.w2 = Tensor::from_f32(vec![16, 8], vec![f32::NAN; 128]).unwrap();
+            }
+        }
+        let logits = skipped.forward_cpu(&tokens).unwrap();
+        assert!(logits.as_f32_slice().unwrap().iter().all(|x| x.is_finite()));
+        for (_, stats) in skipped.router_stats() {
+            assert_eq!(stats.tokens_per_expert(), vec![6, 6, 0, 0]);
+        }
+
+        // Routing every token to all of several identical experts is the dense network
+        let dense_config = TransformerConfig {
+            moe: None,
+            ..config.clone()
+        };
+        let dense = random_model(dense_config, 67);
+        let mut mixed = random_model(config, 67);
+        for (layer, dense_layer) in mixed.layers.iter_mut().zip(&dense.layers) {
+            let moe = layer.feed_forward.moe_mut().unwrap();
+            moe.experts = vec![dense_layer.feed_forward.dense().unwrap().clone(); 4];
+        }
+        mixed.config.moe = Some(MoeConfig {
+            n_experts: 4,
+            top_k: 4,
+        });
+        let expected = dense.forward_cpu(&tokens).unwrap();
+        let logits = mixed.forward_cpu(&tokens).unwrap();
+        assert_close(
+            logits.as_f32_slice().unwrap(),
+            expected.as_f32_slice().unwrap(),
+            1e-4,
+        );
+
+        // The expert count must match the configuration
+        mixed.config.moe = Some(MoeConfig {
+            n_experts: 3,
+            top_k: 2,
+        });
+        assert!(mixed.forward_cpu(&tokens).is_err());
+
+        // Routers and experts are saved with the model; statistics start over
+        let restored = TransformerModel::from_bytes(&model.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.config.moe, model.config.moe);
+        assert_eq!(restored.router_stats()[0].1.tokens_per_expert(), vec![0; 4]);
+        assert_eq!(
+            restored
+                .forward_cpu(&tokens)
+                .unwrap()
+                .as_f32_slice()
+                .unwrap(),
+            full
+        );
+    }
+
+    #[test]
+    fn test_vision_transformer() {
+        let preset = TransformerConfig::vit_tiny();
+        assert_eq!(preset.vision.unwrap().n_patches(), 196);
+        assert_eq!(preset.max_seq_len, 197);
+
+        let vision = VisionConfig {
+            image_size: 4,
+            patch_size: 2,
+            channels: 3,
+        };
+        let config = TransformerConfig {
+            attention_mode: AttentionMode::Bidirectional,
+            vision: Some(vision),
+            ..small_config()
+        };
+        let d = config.d_model;
+        let images = vec![
+            random_tensor(vec![3, 4, 4], 81),
+            random_tensor(vec![3, 4, 4], 82),
+        ];
+        let model = random_model(config, 79);
+        assert!(model
+            .image_embeddings_cpu(&images, Pooling::Cls, false)
+            .is_err());
+
+        let model = model
+            .with_patch_embedding(PatchEmbedding {
+                projection: random_tensor(vec![vision.patch_dim(), d], 83),
+                bias: Some(random_tensor(vec![d], 84)),
+                cls_token: random_tensor(vec![d], 85),
+            })
+            .with_sequence_classifier(SequenceClassifier {
+                pooling: Pooling::Cls,
+                head: ClassificationHead {
+                    weight: random_tensor(vec![d, 3], 86),
+                    bias: None,
+                    labels: vec!["cat".into(), "dog".into(), "bird".into()],
+                },
+            });
+        let device = reference_device();
+
+        // The layers see the CLS token followed by the projected patches, at learned positions
+        let patch_embedding = model.patch_embedding.as_ref().unwrap();
+        let patches = vision::patchify(&images[1], 2).unwrap();
+        let projected = ops::linear(
+            &patches,
+            &patch_embedding.projection,
+            patch_embedding.bias.as_ref(),
+        )
+        .unwrap();
+        let mut input = patch_embedding.cls_token.as_f32_slice().unwrap().to_vec();
+        input.extend_from_slice(projected.as_f32_slice().unwrap());
+        for (x, p) in input
+            .iter_mut()
+            .zip(model.position_embedding.as_f32_slice().unwrap())
+        {
+            *x += p;
+        }
+        let input = Tensor::from_f32(vec![1, 5, d], input).unwrap();
+        let hidden = model
+            .layers_cpu(input, &[(0..5).collect()], None, None)
+            .unwrap();
+        let expected = embeddings::pool(&hidden, None, Pooling::Cls).unwrap();
+
+        let pooled = model
+            .image_embeddings_cpu(&images, Pooling::Cls, false)
+            .unwrap();
+        assert_eq!(pooled.shape, vec![2, d]);
+        assert_close(
+            &pooled.as_f32_slice().unwrap()[d..],
+            expected.as_f32_slice().unwrap(),
+            1e-5,
+        );
+        let on_device = model
+            .image_embeddings(&images, Pooling::Cls, false, &device)
+            .unwrap();
+        assert_close(
+            on_device.as_f32_slice().unwrap(),
+            pooled.as_f32_slice().unwrap(),
+            1e-5,
+        );
+
+        let predictions = model.classify_images_cpu(&images).unwrap();
+        assert_eq!(predictions.len(), 2);
+        for (a, b) in model
+            .classify_images(&images, &device)
+            .unwrap()
+            .iter()
+            .zip(&predictions)
+        {
+            assert_eq!(a.label, b.label);
+            assert!((a.score - b.score).abs() < 1e-5);
+        }
+        assert!(model
+            .classify_images_cpu(&[random_tensor(vec![3, 4, 6], 87)])
+            .is_err());
+
+        // Every patch token needs a learned position
+        let mut short_positions = TransformerModel::from_bytes(&model.to_bytes().unwrap()).unwrap();
+        short_positions.position_embedding = random_tensor(vec![4, d], 88);
+        assert!(matches!(
+            short_positions.image_embeddings_cpu(&images, Pooling::Cls, false),
+            Err(CoreError::ShapeMismatch { .. })
+        ));
+
+        // The patch embedding is saved with the model
+        let restored = TransformerModel::from_bytes(&model.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.config.vision, Some(vision));
+        assert_eq!(restored.classify_images_cpu(&images).unwrap(), predictions);
+    }
+
+    #[test]
+    fn test_loads_legacy_model_files() {
+        let model = random_model(small_config(), 43);
+        let c = &model.config;
+        let beta = |norm: &LayerNormWeights| norm.beta.clone().unwrap();
+        // The original layout: no bias or architecture fields anywhere
+        let layers: Vec<_> = model
+            .layers
+            .iter()
+            .map(|layer| {
+                let (a, f) = (&layer.attention, layer.feed_forward.dense().unwrap());
+                (
+                    (&a.wq, &a.wk, &a.wv, &a.wo),
+                    (&f.w1, &f.w2),
+                    (&layer.ln1.gamma, beta(&layer.ln1)),
+                    (&layer.ln2.gamma, beta(&layer.ln2)),
+                )
+            })
+            .collect();
+        let legacy = bincode::serialize(&(
+            (
+                c.d_model,
+                c.n_heads,
+                c.n_layers,
+                c.d_ff,
+                c.vocab_size,
+                c.max_seq_len,
+                c.dropout,
+                c.layer_norm_eps,
+            ),
+            &model.token_embedding,
+            &model.position_embedding,
+            layers,
+            (&model.final_layer_norm.gamma, beta(&model.final_layer_norm)),
+        ))
+        .unwrap();
+
+        let loaded = TransformerModel::from_bytes(&legacy).unwrap();
+        assert_eq!(loaded.config.position_encoding, PositionEncoding::Learned);
+        assert!(loaded.layers[0].attention.bq.is_none());
+        let tokens = vec![vec![1, 9, 4]];
+        assert_eq!(
+            loaded.forward_cpu(&tokens).unwrap().as_f32_slice().unwrap(),
+            model.forward_cpu(&tokens).unwrap().as_f32_slice().unwrap()
+        );
+
+        assert!(TransformerModel::from_bytes(&legacy[..legacy.len() - 1]).is_err());
+    }
 }
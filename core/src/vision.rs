@@ -0,0 +1,196 @@
+//! Image inputs for Vision Transformers
+//!
+//! A ViT cuts a `[C, H, W]` image into square patches ([`patchify`]), projects each
+//! flattened patch to `d_model` with a [`PatchEmbedding`] and prepends a learned CLS token.
+//! The resulting sequence runs through the model's layers like token embeddings; see
+//! [`TransformerModel::classify_images`](crate::transformer::TransformerModel::classify_images).
+//! Images are expected in the layout and normalization the checkpoint was trained with,
+//! which [`image_from_rgb8`] and [`normalize_image`] produce.
+
+use crate::error::{CoreError, Result};
+use crate::tensor::Tensor;
+use serde::{Deserialize, Serialize};
+
+/// Per-channel mean of ImageNet in RGB order, used by most ViT checkpoints
+pub const IMAGENET_MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+
+/// Per-channel standard deviation of ImageNet in RGB order
+pub const IMAGENET_STD: [f32; 3] = [0.229, 0.224, 0.225];
+
+/// Patch projection and CLS token of a Vision Transformer
+#[derive(Debug, Clone)]
+pub struct PatchEmbedding {
+    /// Projection of flattened patches `[channels * patch_size^2, d_model]`
+    ///
+    /// Rows follow the [`patchify`] layout, so a convolution kernel `[d_model, C, P, P]`
+    /// reshaped to `[d_model, C * P * P]` and transposed can be used as-is.
+    pub projection: Tensor,
+    /// Optional projection bias `[d_model]`
+    pub bias: Option<Tensor>,
+    /// Learned CLS token `[d_model]`, prepended to the patch tokens
+    pub cls_token: Tensor,
+}
+
+/// Convert interleaved 8-bit RGB pixels (row-major `H x W x 3`) to a `[3, H, W]` image
+/// with values in `[0, 1]`
+pub fn image_from_rgb8(pixels: &[u8], height: usize, width: usize) -> Result<Tensor> {
+    let plane = height * width;
+    if pixels.len() != plane * 3 {
+        return Err(CoreError::InvalidDimension(format!(
+            "Expected {} bytes for a {}x{} RGB image, got {}",
+            plane * 3,
+            height,
+            width,
+            pixels.len()
+        )));
+    }
+    let mut data = vec![0.0f32; plane * 3];
+    for (i, pixel) in pixels.chunks_exact(3).enumerate() {
+        for (c, &value) in pixel.iter().enumerate() {
+            data[c * plane + i] = value as f32 / 255.0;
+        }
+    }
+    Tensor::from_f32(vec![3, height, width], data)
+}
+
+/// Normalize each channel of a `[C, H, W]` image to `(x - mean[c]) / std[c]`
+pub fn normalize_image(image: &Tensor, mean: &[f32], std: &[f32]) -> Result<Tensor> {
+    let channels = match image.shape[..] {
+        [channels, _, _] => channels,
+        _ => {
+            return Err(CoreError::InvalidDimension(format!(
+                "Expected a [C, H, W] image, got {:?}",
+                image.shape
+            )))
+        }
+    };
+    if mean.len() != channels || std.len() != channels {
+        return Err(CoreError::InvalidDimension(format!(
+            "Image has {} channels but mean/std have {}/{} entries",
+            channels,
+            mean.len(),
+            std.len()
+        )));
+    }
+    let plane = image.shape[1] * image.shape[2];
+    let data = image
+        .as_f32_slice()?
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x - mean[i / plane]) / std[i / plane])
+        .collect();
+    Tensor::from_f32(image.shape.clone(), data)
+}
+
+/// Cut a `[C, H, W]` image into non-overlapping `patch_size` squares, returning
+/// `[n_patches, C * patch_size^2]`
+///
+/// Patches are ordered row by row over the image, and each is flattened channel first, then
+/// by row and column within the patch. Projecting the result is the strided convolution of
+/// the original ViT patch embedding.
+pub fn patchify(image: &Tensor, patch_size: usize) -> Result<Tensor> {
+    let (channels, height, width) = match image.shape[..] {
+        [channels, height, width] => (channels, height, width),
+        _ => {
+            return Err(CoreError::InvalidDimension(format!(
+                "Expected a [C, H, W] image, got {:?}",
+                image.shape
+            )))
+        }
+    };
+    if patch_size == 0 || height % patch_size != 0 || width % patch_size != 0 {
+        return Err(CoreError::InvalidDimension(format!(
+            "Image of {}x{} cannot be split into {}x{} patches",
+            height, width, patch_size, patch_size
+        )));
+    }
+    let data = image.as_f32_slice()?;
+    let (rows, cols) = (height / patch_size, width / patch_size);
+    let mut out = Vec::with_capacity(data.len());
+    for row in 0..rows {
+        for col in 0..cols {
+            for c in 0..channels {
+                for y in row * patch_size..(row + 1) * patch_size {
+                    let start = (c * height + y) * width + col * patch_size;
+                    out.extend_from_slice(&data[start..start + patch_size]);
+                }
+            }
+        }
+    }
+    Tensor::from_f32(vec![rows * cols, channels * patch_size * patch_size], out)
+}
+
+impl Serialize for PatchEmbedding {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("PatchEmbedding", 3)?;
+        state.serialize_field("projection", &self.projection)?;
+        state.serialize_field("bias", &self.bias)?;
+        state.serialize_field("cls_token", &self.cls_token)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for PatchEmbedding {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Helper {
+            projection: Tensor,
+            bias: Option<Tensor>,
+            cls_token: Tensor,
+        }
+        let helper = Helper::deserialize(deserializer)?;
+        Ok(PatchEmbedding {
+            projection: helper.projection,
+            bias: helper.bias,
+            cls_token: helper.cls_token,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patchify_layout() {
+        // Two channels of a 2x4 image: channel 0 holds 0..8, channel 1 holds 10..18
+        let data = (0..8).chain(10..18).map(|v| v as f32).collect();
+        let image = Tensor::from_f32(vec![2, 2, 4], data).unwrap();
+        let patches = patchify(&image, 2).unwrap();
+        assert_eq!(patches.shape, vec![2, 8]);
+        assert_eq!(
+            patches.as_f32_slice().unwrap(),
+            &[
+                0.0, 1.0, 4.0, 5.0, 10.0, 11.0, 14.0, 15.0, // left patch
+                2.0, 3.0, 6.0, 7.0, 12.0, 13.0, 16.0, 17.0, // right patch
+            ]
+        );
+        assert!(patchify(&image, 3).is_err());
+    }
+
+    #[test]
+    fn test_image_normalization() {
+        // One 1x2 image: a red and a white pixel
+        let image = image_from_rgb8(&[255, 0, 0, 255, 255, 255], 1, 2).unwrap();
+        assert_eq!(image.shape, vec![3, 1, 2]);
+        assert_eq!(
+            image.as_f32_slice().unwrap(),
+            &[1.0, 1.0, 0.0, 1.0, 0.0, 1.0]
+        );
+
+        let normalized = normalize_image(&image, &[0.5, 0.5, 0.5], &[0.5, 0.25, 0.5]).unwrap();
+        assert_eq!(
+            normalized.as_f32_slice().unwrap(),
+            &[1.0, 1.0, -2.0, 2.0, -1.0, 1.0]
+        );
+        assert!(normalize_image(&image, &IMAGENET_MEAN[..2], &IMAGENET_STD).is_err());
+        assert!(image_from_rgb8(&[0; 5], 1, 2).is_err());
+    }
+}
@@ -27,7 +27,7 @@ use crossgpu_core::{
     quantization::{dequantize_tensor, quantize_tensor, QuantParams},
     tensor::{DType, Tensor},
     transformer::{
-        AttentionWeights, FeedForwardWeights, LayerNormWeights, TransformerConfig,
+        AttentionWeights, FeedForward, FeedForwardWeights, LayerNormWeights, TransformerConfig,
         TransformerLayerWeights, TransformerModel,
     },
 };
@@ -114,34 +114,43 @@ fn create_transformer_model(config: &TransformerConfig) -> Result<TransformerMod
             wk: Tensor::new(vec![config.d_model, config.d_model], DType::F32),
             wv: Tensor::new(vec![config.d_model, config.d_model], DType::F32),
             wo: Tensor::new(vec![config.d_model, config.d_model], DType::F32),
+            bq: None,
+            bk: None,
+            bv: None,
+            bo: None,
         };
 
         let feed_forward = FeedForwardWeights {
             w1: Tensor::new(vec![config.d_model, config.d_ff], DType::F32),
             w2: Tensor::new(vec![config.d_ff, config.d_model], DType::F32),
+            w3: None,
+            b1: None,
+            b2: None,
+            b3: None,
         };
 
         let ln1 = LayerNormWeights {
             gamma: Tensor::new(vec![config.d_model], DType::F32),
-            beta: Tensor::new(vec![config.d_model], DType::F32),
+            beta: Some(Tensor::new(vec![config.d_model], DType::F32)),
         };
 
         let ln2 = LayerNormWeights {
             gamma: Tensor::new(vec![config.d_model], DType::F32),
-            beta: Tensor::new(vec![config.d_model], DType::F32),
+            beta: Some(Tensor::new(vec![config.d_model], DType::F32)),
         };
 
         layers.push(TransformerLayerWeights {
             attention,
-            feed_forward,
+            feed_forward: FeedForward::Dense(feed_forward),
             ln1,
             ln2,
+            cross_attention: None,
         });
     }
 
     let final_layer_norm = LayerNormWeights {
         gamma: Tensor::new(vec![config.d_model], DType::F32),
-        beta: Tensor::new(vec![config.d_model], DType::F32),
+        beta: Some(Tensor::new(vec![config.d_model], DType::F32)),
     };
 
     let model = TransformerModel::new(
@@ -3,15 +3,16 @@
 //! This example demonstrates how to:
 //! 1. Auto-detect the best available GPU backend
 //! 2. Load a tiny transformer model
-//! 3. Run inference on CPU or GPU
+//! 3. Run batched inference on CPU or GPU
 //! 4. Output results
 
 use anyhow::Result;
 use crossgpu_core::{
+    batch::{PaddedBatch, PaddingSide},
     gpu::{DeviceType, GpuDevice},
     tensor::{DType, Tensor},
     transformer::{
-        AttentionWeights, FeedForwardWeights, LayerNormWeights, TransformerConfig,
+        AttentionWeights, FeedForward, FeedForwardWeights, LayerNormWeights, TransformerConfig,
         TransformerLayerWeights, TransformerModel,
     },
 };
@@ -91,34 +92,43 @@ fn create_dummy_model() -> Result<TransformerModel> {
             wk: Tensor::new(vec![config.d_model, config.d_model], DType::F32),
             wv: Tensor::new(vec![config.d_model, config.d_model], DType::F32),
             wo: Tensor::new(vec![config.d_model, config.d_model], DType::F32),
+            bq: None,
+            bk: None,
+            bv: None,
+            bo: None,
         };
 
         let feed_forward = FeedForwardWeights {
             w1: Tensor::new(vec![config.d_model, config.d_ff], DType::F32),
             w2: Tensor::new(vec![config.d_ff, config.d_model], DType::F32),
+            w3: None,
+            b1: None,
+            b2: None,
+            b3: None,
         };
 
         let ln1 = LayerNormWeights {
             gamma: Tensor::new(vec![config.d_model], DType::F32),
-            beta: Tensor::new(vec![config.d_model], DType::F32),
+            beta: Some(Tensor::new(vec![config.d_model], DType::F32)),
         };
 
         let ln2 = LayerNormWeights {
             gamma: Tensor::new(vec![config.d_model], DType::F32),
-            beta: Tensor::new(vec![config.d_model], DType::F32),
+            beta: Some(Tensor::new(vec![config.d_model], DType::F32)),
         };
 
         layers.push(TransformerLayerWeights {
             attention,
-            feed_forward,
+            feed_forward: FeedForward::Dense(feed_forward),
             ln1,
             ln2,
+            cross_attention: None,
         });
     }
 
     let final_layer_norm = LayerNormWeights {
         gamma: Tensor::new(vec![config.d_model], DType::F32),
-        beta: Tensor::new(vec![config.d_model], DType::F32),
+        beta: Some(Tensor::new(vec![config.d_model], DType::F32)),
     };
 
     Ok(TransformerModel::new(
@@ -130,38 +140,40 @@ fn create_dummy_model() -> Result<TransformerModel> {
     ))
 }
 
-/// Run inference with the model
-fn run_inference(_model: &TransformerModel, device: &Arc<dyn GpuDevice>) -> Result<()> {
+/// Run batched inference with the model
+fn run_inference(model: &TransformerModel, device: &Arc<dyn GpuDevice>) -> Result<()> {
     log::info!("Running inference on device: {}", device.device_name());
 
-    // Create dummy input tensor (batch_size=1, seq_len=10)
-    let input_ids = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-    let input_tensor = Tensor::from_f32(
-        vec![1, input_ids.len()],
-        input_ids.iter().map(|&x| x as f32).collect(),
-    )?;
+    // Create dummy prompts of different lengths and left-pad them into one batch
+    let prompts: Vec<Vec<u32>> = vec![(1..=10).collect(), (20..=23).collect(), vec![42]];
+    log::info!("Input token ids: {:?}", prompts);
+    let batch = PaddedBatch::new(&prompts, 0, PaddingSide::Left)?;
+
+    // Embed tokens, run every layer, final layer norm and LM head
+    let logits = model.forward_batch(&batch, device)?;
+    log::info!("Logits shape: {:?}", logits.shape);
+
+    // Take argmax over the vocabulary at each sequence's last real token
+    let vocab_size = model.config.vocab_size;
+    let last = batch.last_token_logits(&logits)?;
+    let predicted: Vec<usize> = last
+        .as_f32_slice()?
+        .chunks_exact(vocab_size)
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .fold((0, f32::NEG_INFINITY), |best, (i, &v)| {
+                    if v > best.1 {
+                        (i, v)
+                    } else {
+                        best
+                    }
+                })
+                .0
+        })
+        .collect();
+    log::info!("Predicted next tokens: {:?}", predicted);
 
-    log::info!("Input shape: {:?}", input_tensor.shape);
-
-    // Upload to GPU (if GPU backend)
-    let gpu_input = device.upload_tensor(&input_tensor)?;
-    log::debug!("Tensor uploaded to GPU");
-
-    // In a real implementation, we would:
-    // 1. Embed input tokens
-    // 2. Add positional embeddings
-    // 3. Run through transformer layers
-    // 4. Apply final layer norm
-    // 5. Project to vocabulary logits
-    // 6. Take argmax to get predicted tokens
-
-    log::info!("Forward pass complete (placeholder)");
-
-    // Download result (if GPU backend)
-    let output = device.download_tensor(&gpu_input)?;
-    log::info!("Output shape: {:?}", output.shape);
-
-    device.synchronize()?;
     log::info!("Inference complete!");
 
     Ok(())
@@ -189,8 +201,16 @@ fn main() -> Result<()> {
     // model.save_to_file("tiny_transformer.bin")?;
     // log::info!("Model saved to tiny_transformer.bin");
 
-    // Run inference
-    run_inference(&model, &device)?;
+    // Run inference, falling back to CPU if the backend cannot run the full forward pass yet
+    if let Err(e) = run_inference(&model, &device) {
+        log::warn!(
+            "Inference on {} failed ({}), falling back to CPU backend",
+            device.device_name(),
+            e
+        );
+        let cpu: Arc<dyn GpuDevice> = Arc::new(crossgpu_backend_cpu::CpuDevice::new());
+        run_inference(&model, &cpu)?;
+    }
 
     log::info!("=== Example completed successfully ===");
     Ok(())